@@ -24,6 +24,7 @@ mod kernel_proto;
 
 static PATH_CONFIG: &'static [u16] = ::utf16_literal::utf16!("Tifflin\\boot.cfg\0");
 static PATH_FALLBACK_KERNEL: &'static [u16] = ::utf16_literal::utf16!("Tifflin\\kernel-amd4.bin\0");
+static PATH_INITRD: &'static [u16] = ::utf16_literal::utf16!("Tifflin\\initrd.img\0");
 
 // Globals used for panic handling and loging
 static mut S_CONOUT: *const ::uefi::SimpleTextOutputInterface = 1 as *const _;
@@ -99,6 +100,18 @@ pub extern "win64" fn efi_main(image_handle: ::uefi::Handle, system_table: &::ue
 			};
 		// - Load the kernel.
 		let entrypoint = load_kernel_file(boot_services, &system_volume_root, &config.kernel).expect("Unable to load kernel");
+		// - Load the initial ramdisk (if present)
+		let modules = match load_module_file(boot_services, &system_volume_root, PATH_INITRD.into())
+			{
+			Ok(Some((base, size))) => Some(kernel_proto::Module {
+				base: base,
+				size: size,
+				name_ptr: "initrd".as_ptr(),
+				name_len: "initrd".len(),
+				}),
+			Ok(None) => None,
+			Err(e) => panic!("Failed to load initrd: {:?}", e),
+			};
 
 		// TODO: Set a sane video mode
 		
@@ -150,6 +163,9 @@ pub extern "win64" fn efi_main(image_handle: ::uefi::Handle, system_table: &::ue
 			map_addr: map.as_ptr() as usize as u64,
 			map_entnum: map.len() as u32,
 			map_entsz: size_of::<uefi::boot_services::MemoryDescriptor>() as u32,
+
+			modules_ptr: modules.as_ref().map(|m| m as *const _ as usize as u64).unwrap_or(0),
+			modules_count: if modules.is_some() { 1 } else { 0 },
 			};
 		
 		
@@ -212,6 +228,47 @@ fn load_kernel_file(boot_services: &::uefi::boot_services::BootServices, sys_vol
 	Ok(unsafe { ::core::mem::transmute(elf_hdr.e_entry as usize) })
 }
 
+/// Load a file into freshly allocated pages, returning `None` if the file doesn't exist
+fn load_module_file(boot_services: &::uefi::boot_services::BootServices, sys_vol: &protocols::File, filename: &::uefi::CStr16) -> Result<Option<(u64,u64)>, ::uefi::Status>
+{
+	let mut file = match sys_vol.open_read(filename)
+		{
+		Ok(f) => f,
+		Err(::uefi::status::NOT_FOUND) => return Ok(None),
+		Err(e) => return Err(e),
+		};
+	// Seeking to !0 moves to the end of the file, giving the size
+	file.set_position(!0)?;
+	let size = file.get_position()?;
+	file.set_position(0)?;
+	log!("- Module '{}' {:#x} bytes", filename, size);
+
+	let mut addr = 0;
+	// SAFE: Correct call to FFI
+	unsafe {
+		(boot_services.allocate_pages)(
+			::uefi::boot_services::AllocateType::AnyPages,
+			::uefi::boot_services::MemoryType::LoaderData,
+			(size as usize + 0xFFF) / 0x1000,
+			&mut addr
+			)
+			.err_or( () )?;
+	}
+
+	// SAFE: This memory has just been allocated by the above
+	let data = unsafe { ::core::slice::from_raw_parts_mut(addr as usize as *mut u8, size as usize) };
+	let mut ofs = 0;
+	while ofs < data.len()
+	{
+		match file.read(&mut data[ofs..])?
+		{
+		0 => panic!("Unexpected end of file reading '{}' ({}/{})", filename, ofs, data.len()),
+		n => ofs += n,
+		}
+	}
+	Ok( Some( (addr, size) ) )
+}
+
 
 #[panic_handler]
 fn handle_panic(info: &::core::panic::PanicInfo) -> ! {
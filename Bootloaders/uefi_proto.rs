@@ -10,6 +10,20 @@ pub struct Info
 	pub map_addr: u64,
 	pub map_entnum: u32,
 	pub map_entsz: u32,
+
+	/// Files loaded by the bootloader for the kernel (e.g. the initial ramdisk)
+	pub modules_ptr: u64,
+	pub modules_count: u32,
+}
+
+#[repr(C)]
+pub struct Module
+{
+	pub base: u64,
+	pub size: u64,
+
+	pub name_ptr: *const u8,
+	pub name_len: usize,
 }
 
 // TODO: Grab this from libuefi
@@ -9,6 +9,7 @@
 use prelude::*;
 use super::memory::addresses::{IDENT_START, IDENT_END};
 use metadevs::video::bootvideo::{VideoMode,VideoFormat};
+use arch::boot::BootModule;
 
 #[path="../../../../Bootloaders/uefi_proto.rs"]
 mod uefi_proto;
@@ -52,6 +53,15 @@ struct MultibootInfo
 	vbe_interface_len: u32,
 }
 
+#[repr(C)]
+struct MultibootModule
+{
+	start: u32,
+	end: u32,
+	string: u32,
+	_resvd: u32,
+}
+
 #[repr(C)]
 #[allow(unused)]
 #[derive(Debug)]
@@ -99,12 +109,14 @@ struct MultibootParsed
 	vidmode: Option<VideoMode>,
 	memmap: &'static [::memory::MemoryMapEnt],
 	symbol_info: SymbolInfo,
+	modules: &'static [BootModule],
 }
 struct UefiParsed
 {
 	cmdline: &'static str,
 	vidmode: Option<VideoMode>,
 	memmap: &'static [::memory::MemoryMapEnt],
+	modules: &'static [BootModule],
 }
 
 enum BootInfo
@@ -128,6 +140,9 @@ extern "C"
 	static s_multiboot_pointer : *const ::Void;
 }
 static mut S_MEMMAP_DATA: [::memory::MemoryMapEnt; 16] = [::memory::MAP_PAD; 16];
+const MAX_MODULES: usize = 8;
+const MODULE_PAD: BootModule = BootModule { name: "", base: 0, size: 0 };
+static mut S_MODULES_DATA: [BootModule; MAX_MODULES] = [MODULE_PAD; MAX_MODULES];
 static mut S_BOOTINFO: BootInfo = BootInfo::Uninit;
 
 fn get_bootinfo() -> &'static BootInfo
@@ -197,6 +212,16 @@ impl BootInfo
 		BootInfo::Uefi(ref i) => i.memmap,
 		}
 	}
+	pub fn modules(&self) -> &'static [BootModule]
+	{
+		match *self
+		{
+		BootInfo::Uninit => &[],
+		BootInfo::Invalid => &[],
+		BootInfo::Multiboot(ref i) => i.modules,
+		BootInfo::Uefi(ref i) => i.modules,
+		}
+	}
 }
 
 unsafe fn valid_c_str_to_slice(ptr: *const i8) -> Option<&'static str>
@@ -233,6 +258,8 @@ impl MultibootParsed
 				cmdline: MultibootParsed::_cmdline(info),
 				vidmode: MultibootParsed::_vidmode(info),
 				symbol_info: MultibootParsed::_syminfo(info),
+				// SAFE: Should only be called before threading is initialised, so no race
+				modules: unsafe { MultibootParsed::_modules(info, &mut S_MODULES_DATA) },
 				memmap: &[],
 			};
 		// SAFE: Should only be called before threading is initialised, so no race
@@ -324,6 +351,53 @@ impl MultibootParsed
 		}
 	}
 	
+	fn _modules(info: &MultibootInfo, buf: &'static mut [BootModule]) -> &'static [BootModule]
+	{
+		use memory::PAddr;
+
+		if (info.flags & 1 << 3) == 0 || info.module_count == 0 {
+			return &[];
+		}
+		// SAFE: No aliasing
+		let mods: &'static [MultibootModule] = match unsafe { ::memory::virt::map_static_slice(info.module_first as PAddr, info.module_count as usize) }
+			{
+			Ok(v) => v,
+			Err(_) => {
+				log_error!("Multiboot module list {:#x}+{} is not accessible", info.module_first, info.module_count);
+				return &[];
+				},
+			};
+		if mods.len() > buf.len() {
+			log_warning!("Too many multiboot modules ({}), only using the first {}", mods.len(), buf.len());
+		}
+		let mut count = 0;
+		for m in mods
+		{
+			if count == buf.len() {
+				break;
+			}
+			if m.end < m.start {
+				log_error!("Multiboot module has a bad range ({:#x}--{:#x})", m.start, m.end);
+				continue ;
+			}
+			let name = if m.string != 0 && (m.string as usize) + IDENT_START < IDENT_END {
+					// SAFE: Module string is valid for 'static
+					unsafe { valid_c_str_to_slice( (m.string as usize + IDENT_START) as *const i8 ).unwrap_or("-INVALID-") }
+				}
+				else {
+					""
+				};
+			log_log!("Boot module {:#x}--{:#x} '{}'", m.start, m.end, name);
+			buf[count] = BootModule {
+				name: name,
+				base: m.start as PAddr,
+				size: (m.end - m.start) as usize,
+				};
+			count += 1;
+		}
+		&buf[..count]
+	}
+	
 	fn _cmdline(info: &MultibootInfo) -> &'static str
 	{
 		if (info.flags & 1 << 2) == 0 {
@@ -434,6 +508,12 @@ impl MultibootParsed
 					::memory::MemoryState::Used, 0).ok().unwrap();
 				},
 			}
+			// - Modules (initial ramdisk)
+			for m in self.modules
+			{
+				mapbuilder.set_range( m.base as u64, m.size as u64,
+					::memory::MemoryState::Used, 0 ).ok().unwrap();
+			}
 			
 			mapbuilder.size()
 			};
@@ -452,6 +532,8 @@ impl UefiParsed
 				cmdline: Self::_cmdline(info),
 				vidmode: None,//MultibootParsed::_vidmode(info),
 				//symbol_info: MultibootParsed::_syminfo(info),
+				// SAFE: Should only be called before threading is initialised, so no race
+				modules: unsafe { Self::_modules(info, &mut S_MODULES_DATA) },
 				memmap: &[],
 			};
 		// - Memory map is initialised afterwards so it gets easy access to used addresses
@@ -466,6 +548,30 @@ impl UefiParsed
 			::core::str::from_utf8( ::core::slice::from_raw_parts(info.cmdline_ptr, info.cmdline_len) ).expect("UefiParsed::_cmdline")
 		}
 	}
+	fn _modules(info: &uefi_proto::Info, buf: &'static mut [BootModule]) -> &'static [BootModule] {
+		if info.modules_count == 0 {
+			return &[];
+		}
+		// SAFE: We can't easily check, so trust the bootloader
+		let mods = unsafe { ::core::slice::from_raw_parts(info.modules_ptr as usize as *const uefi_proto::Module, info.modules_count as usize) };
+		if mods.len() > buf.len() {
+			log_warning!("Too many boot modules ({}), only using the first {}", mods.len(), buf.len());
+		}
+		let mut count = 0;
+		for (m, slot) in Iterator::zip(mods.iter(), buf.iter_mut())
+		{
+			// SAFE: We can't easily check, so trust the bootloader
+			let name = unsafe { ::core::str::from_utf8( ::core::slice::from_raw_parts(m.name_ptr, m.name_len) ).unwrap_or("-INVALID-") };
+			log_log!("Boot module {:#x}+{:#x} '{}'", m.base, m.size, name);
+			*slot = BootModule {
+				name: name,
+				base: m.base as ::memory::PAddr,
+				size: m.size as usize,
+				};
+			count += 1;
+		}
+		&buf[..count]
+	}
 	fn _memmap<'a>(&self, info: &uefi_proto::Info, buf: &'a mut[::memory::MemoryMapEnt]) -> &'a [::memory::MemoryMapEnt] {
 		// TODO: Put this elsewhere
 		struct StrideSlice<T> {
@@ -534,5 +640,11 @@ pub fn get_memory_map() -> &'static[::memory::MemoryMapEnt]
 	get_bootinfo().memmap()
 }
 
+/// Obtain the list of bootloader-provided modules
+pub fn get_boot_modules() -> &'static [BootModule]
+{
+	get_bootinfo().modules()
+}
+
 // vim: ft=rust
 
@@ -0,0 +1,101 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// arch/amd64/hw/rtc.rs
+// - PC CMOS Real-Time Clock
+//
+// Only read once at startup to set the wall-clock, the timer ticks are used after that.
+#[allow(unused_imports)]
+use prelude::*;
+use arch::x86_io::{inb,outb};
+
+module_define!{RTC, [HPET], init}
+
+const PORT_INDEX: u16 = 0x70;
+const PORT_DATA: u16 = 0x71;
+
+// NOTE: Bit 7 of the index is the NMI disable flag, left clear
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATING: u8 = 0x80;
+const STATUS_B_24HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+
+/// Number of status polls to wait for an update to finish before giving up
+const MAX_UPDATE_POLLS: usize = 10_000;
+/// Number of times to retry reading if an update happens part-way through
+const MAX_READ_ATTEMPTS: usize = 5;
+
+fn init()
+{
+	let (year, month, day, hour, minute, second) = match read_time()
+		{
+		Some(v) => v,
+		None => {
+			log_warning!("RTC: No stable reading (stuck updating?), wall-clock not set");
+			return ;
+			},
+		};
+	log_notice!("RTC: {:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second);
+	if month < 1 || month > 12 || day < 1 || day > 31 || hour > 23 || minute > 59 || second > 59 {
+		log_warning!("RTC: Time is invalid, wall-clock not set");
+		return ;
+	}
+	::time::set_wall_clock( ::time::timestamp_from_date(year, month, day, hour, minute, second) );
+}
+
+fn read_reg(reg: u8) -> u8 {
+	// SAFE: The CMOS ports are only accessed here, and reads have no side-effects
+	unsafe {
+		outb(PORT_INDEX, reg);
+		inb(PORT_DATA)
+	}
+}
+
+/// Read a consistent set of values (the registers can change part-way through reading)
+///
+/// Returns `None` if the RTC appears stuck updating (e.g. missing or faulty hardware)
+fn read_raw() -> Option<[u8; 6]> {
+	for _ in 0 .. MAX_READ_ATTEMPTS
+	{
+		// An update takes under 2ms, each port access is around a microsecond
+		if (0 .. MAX_UPDATE_POLLS).all(|_| read_reg(REG_STATUS_A) & STATUS_A_UPDATING != 0) {
+			return None;
+		}
+		let v = [
+			read_reg(REG_YEAR), read_reg(REG_MONTH), read_reg(REG_DAY),
+			read_reg(REG_HOURS), read_reg(REG_MINUTES), read_reg(REG_SECONDS),
+			];
+		if read_reg(REG_STATUS_A) & STATUS_A_UPDATING == 0 && v[5] == read_reg(REG_SECONDS) {
+			return Some(v);
+		}
+	}
+	None
+}
+
+/// Returns (year, month, day, hour, minute, second) in UTC (assuming the RTC is set to UTC)
+fn read_time() -> Option<(i32, u8, u8, u8, u8, u8)> {
+	let [year, month, day, hours, minutes, seconds] = match read_raw()
+		{
+		Some(v) => v,
+		None => return None,
+		};
+	let status_b = read_reg(REG_STATUS_B);
+	let is_binary = status_b & STATUS_B_BINARY != 0;
+	let from_bcd = |v: u8| if is_binary { v } else { (v >> 4) * 10 + (v & 0xF) };
+
+	// - In 12 hour mode, bit 7 of the hour is the PM flag
+	let pm = status_b & STATUS_B_24HOUR == 0 && hours & 0x80 != 0;
+	let hour = from_bcd(hours & 0x7F);
+	let hour = if status_b & STATUS_B_24HOUR != 0 { hour } else { hour % 12 + if pm { 12 } else { 0 } };
+
+	// TODO: Use the ACPI FADT's century register (if present), for now assume the 21st century
+	Some( (2000 + from_bcd(year) as i32, from_bcd(month), from_bcd(day), hour, from_bcd(minutes), from_bcd(seconds)) )
+}
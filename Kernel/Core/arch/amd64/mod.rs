@@ -7,7 +7,7 @@ use core::option::Option;
 
 pub use self::log::{puts, puth};
 
-module_define!{arch, [APIC, HPET], init}
+module_define!{arch, [APIC, HPET, RTC], init}
 
 pub mod interrupts;
 #[doc(hidden)]
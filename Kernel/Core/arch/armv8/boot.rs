@@ -104,6 +104,11 @@ pub fn get_boot_string() -> &'static str {
 	}
 }
 
+pub fn get_boot_modules() -> &'static [::arch::boot::BootModule] {
+	// TODO: Report the `linux,initrd-start`/`linux,initrd-end` range from the FDT's /chosen node
+	&[]
+}
+
 pub fn get_memory_map() -> &'static [::memory::MemoryMapEnt] {
 	// TODO: Assert that this is only ever called once
 	// SAFE: Assuming this function is called only once (which it is)
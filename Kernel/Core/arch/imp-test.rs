@@ -215,6 +215,9 @@ pub mod boot {
 				}
 		]
 	}
+	pub fn get_boot_modules() -> &'static [::arch::boot::BootModule] {
+		&[]
+	}
 }
 pub mod pci {
 	pub fn read(_a: u32) -> u32 {
@@ -335,6 +335,18 @@ pub mod interrupts {
 pub mod boot {
 	use super::imp::boot as imp;
 
+	/// A module (e.g. an initial ramdisk archive) loaded into memory by the bootloader
+	#[derive(Copy,Clone)]
+	pub struct BootModule
+	{
+		/// Name (command line) given to the module by the bootloader
+		pub name: &'static str,
+		/// Physical address of the start of the module
+		pub base: ::memory::PAddr,
+		/// Size of the module in bytes
+		pub size: usize,
+	}
+
 	#[inline]
 	pub fn get_boot_string() -> &'static str {
 		imp::get_boot_string()
@@ -347,6 +359,10 @@ pub mod boot {
 	pub fn get_memory_map() -> &'static [::memory::MemoryMapEnt] {
 		imp::get_memory_map()
 	}
+	#[inline]
+	pub fn get_boot_modules() -> &'static [BootModule] {
+		imp::get_boot_modules()
+	}
 }
 pub mod pci {
 	use super::imp::pci as imp;
@@ -21,6 +21,15 @@ pub fn get_string(val: Value) -> &'static str
 	}
 }
 
+/// Returns true if the value was set on the command line (instead of using the default)
+pub fn is_set(val: Value) -> bool
+{
+	// SAFE: No mutation should happen when is_set is being called
+	unsafe {
+		S_CONFIG.is_set(val)
+	}
+}
+
 
 macro_rules! def_config_set {
 	(
@@ -74,6 +83,16 @@ macro_rules! def_config_set {
 				)*
 				}
 			}
+
+			fn is_set(&self, val: Value) -> bool
+			{
+				match val
+				{
+				$(
+				Value::$name => self.$name.is_some(),
+				)*
+				}
+			}
 		}
 	};
 }
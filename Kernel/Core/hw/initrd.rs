@@ -0,0 +1,71 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/hw/initrd.rs
+//! Read-only volumes backed by bootloader-provided modules (initial ramdisk images)
+use prelude::*;
+use metadevs::storage;
+use memory::virt::SliceAllocHandle;
+
+module_define!{InitRd, [Storage], init}
+
+const BLOCK_SIZE: usize = 512;
+
+fn init()
+{
+	for (i, m) in ::arch::boot::get_boot_modules().iter().enumerate()
+	{
+		// SAFE: The module's memory is reserved by the boot memory map, and is only ever read
+		let data = match unsafe { ::memory::virt::map_hw_slice::<u8>(m.base, m.size) }
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("Unable to map boot module '{}' ({:#x}+{:#x}): {}", m.name, m.base, m.size, e);
+				continue ;
+				},
+			};
+		let vol = Volume {
+			name: format!("initrd{}", i),
+			data: data,
+			};
+		log_notice!("Boot module '{}' ({} bytes) registered as {}", m.name, m.size, vol.name);
+		::core::mem::forget( storage::register_pv(Box::new(vol)) );
+	}
+}
+
+struct Volume
+{
+	name: String,
+	data: SliceAllocHandle<u8>,
+}
+
+impl storage::PhysicalVolume for Volume
+{
+	fn name(&self) -> &str { &self.name }
+	fn blocksize(&self) -> usize { BLOCK_SIZE }
+	fn capacity(&self) -> Option<u64> { Some( ((self.data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE) as u64 ) }
+
+	fn read<'a>(&'a self, _prio: u8, blockidx: u64, count: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a, usize> {
+		assert_eq!( dst.len(), count * BLOCK_SIZE );
+		let rv = if blockidx > self.capacity().unwrap() || count as u64 > self.capacity().unwrap() - blockidx {
+				Err( storage::IoError::BadAddr )
+			}
+			else {
+				let ofs = blockidx as usize * BLOCK_SIZE;
+				// The final block can be partial, pad it with zeroes
+				let len = ::core::cmp::min(dst.len(), self.data.len() - ofs);
+				dst[..len].clone_from_slice( &self.data[ofs..][..len] );
+				for b in dst[len..].iter_mut() {
+					*b = 0;
+				}
+				Ok(count)
+			};
+		Box::new(::async::NullResultWaiter::new(move || rv))
+	}
+	fn write<'a>(&'a self, _prio: u8, _blockidx: u64, _count: usize, _src: &'a [u8]) -> storage::AsyncIoResult<'a, usize> {
+		Box::new(::async::NullResultWaiter::new(|| Err(storage::IoError::ReadOnly)))
+	}
+	fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> storage::AsyncIoResult<'a,()> {
+		Box::new(::async::NullResultWaiter::new(|| Err(storage::IoError::ReadOnly)))
+	}
+}
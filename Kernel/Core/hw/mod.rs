@@ -8,5 +8,7 @@ pub mod bus_pci;
 
 pub mod mapper_mbr;
 
+pub mod initrd;
+
 // vim: ft=rust
 
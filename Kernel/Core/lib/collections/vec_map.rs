@@ -75,8 +75,12 @@ impl<K: Ord, V> VecMap<K,V>
 		}
 	}
 	/// Remove an item from the map
-	pub fn remove(&mut self, k: &K) -> Option<V> {
-		match self.ents.binary_search_by(|e| e.0.cmp(k))
+	pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+	where
+		Q: Ord,
+		K: Borrow<Q>
+	{
+		match self.ents.binary_search_by(|e| e.0.borrow().cmp(k))
 		{
 		Ok(idx) => Some( self.ents.remove(idx).1 ),
 		Err(_) => None,
@@ -98,6 +98,12 @@ impl<T> ArefInner<T>
 			data: val,
 		}
 	}
+	/// Extract the inner value. Panics if any borrows are still active
+	pub fn into_inner(this: Self) -> T {
+		let cur_count = this.count.load(Ordering::SeqCst);
+		assert!(cur_count == 0, "BUG: Unwrapping ArefInner<{}> while {} references are outstanding", type_name!(T), cur_count);
+		this.data
+	}
 }
 impl<T: ?Sized> ArefInner<T>
 {
@@ -148,7 +148,11 @@ struct LogicalVolume
 	chunk_size: Option<usize>,
 	/// Physical regions that compose this logical volume
 	regions: Vec<PhysicalRegion>,
+	/// Device node in `/dev/volumes`
+	devfs_reg: Option<::vfs::devfs::Registration>,
 }
+/// devfs interface to a logical volume (by index)
+struct VolumeDevice(usize);
 /// Physical region used by a logical volume
 struct PhysicalRegion
 {
@@ -328,6 +332,7 @@ fn new_simple_lv(name: String, pv_id: usize, block_size: usize, base: u64, size:
 	let lvidx = S_NEXT_LV_IDX.fetch_add(1, ::core::sync::atomic::Ordering::Relaxed);
 	
 	assert!(size <= !0usize as u64);
+	let devfs_reg = ::vfs::devfs::register(::vfs::devfs::DeviceClass::Storage, &name, Box::new(VolumeDevice(lvidx)));
 	let lv = Arc::new( LogicalVolume {
 		index: lvidx,
 		name: name,
@@ -335,6 +340,7 @@ fn new_simple_lv(name: String, pv_id: usize, block_size: usize, base: u64, size:
 		block_size: block_size,
 		chunk_size: None,
 		regions: vec![ PhysicalRegion{ volume: pv_id, block_count: size as usize, first_block: base } ],
+		devfs_reg: Some(devfs_reg),
 		} );
 	
 	log_log!("Logical Volume: {} {}", lv.name, SizePrinter(size*block_size as u64));
@@ -412,6 +418,10 @@ impl VolumeHandle
 	pub fn block_size(&self) -> usize {
 		self.handle.block_size
 	}
+	/// Total number of blocks in the volume
+	pub fn block_count(&self) -> u64 {
+		self.handle.regions.iter().map(|r| r.block_count as u64).sum()
+	}
 
 	pub fn idx(&self) -> usize {
 		self.handle.index
@@ -579,6 +589,108 @@ impl PhysicalVolumeInfo
 	}
 }
 
+/// Control codes for volume device nodes (responses are little-endian u64s)
+pub const DEVCTL_VOL_BLOCKSIZE: u32 = 0;
+pub const DEVCTL_VOL_BLOCKCOUNT: u32 = 1;
+
+impl VolumeDevice
+{
+	/// Obtain a temporary handle to the volume
+	///
+	/// Shared handles are allowed even if the volume is open (e.g. mounted), exclusive ones are not.
+	fn get_handle(&self, exclusive: bool) -> Result<VolumeHandle, ::vfs::Error>
+	{
+		let mut lh = S_LOGICAL_VOLUMES.lock();
+		match lh.get_mut(&self.0)
+		{
+		Some(v) => {
+			if exclusive && Arc::get_mut(v).is_none() {
+				Err( ::vfs::Error::Locked )
+			}
+			else {
+				Ok( VolumeHandle { handle: v.clone() } )
+			}
+			},
+		None => Err( ::vfs::Error::NotFound ),
+		}
+	}
+}
+impl ::vfs::devfs::Device for VolumeDevice
+{
+	fn typename(&self) -> &str {
+		"volume"
+	}
+	fn size(&self) -> u64 {
+		match self.get_handle(false)
+		{
+		Ok(vh) => vh.block_count() * vh.block_size() as u64,
+		Err(_) => 0,
+		}
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> ::vfs::Result<usize> {
+		let vh = try!(self.get_handle(false));
+		let bs = vh.block_size() as u64;
+		let total = vh.block_count() * bs;
+		if ofs >= total {
+			return Ok(0);
+		}
+		let len = ::core::cmp::min(buf.len() as u64, total - ofs) as usize;
+		// Read via a bounce buffer, so unaligned accesses work
+		let mut bounce = Vec::from_elem(bs as usize, 0u8);
+		let mut done = 0;
+		while done < len
+		{
+			let pos = ofs + done as u64;
+			let blk_ofs = (pos % bs) as usize;
+			let n = ::core::cmp::min(bs as usize - blk_ofs, len - done);
+			try!(vh.read_blocks(pos / bs, &mut bounce));
+			buf[done..][..n].clone_from_slice(&bounce[blk_ofs..][..n]);
+			done += n;
+		}
+		Ok(len)
+	}
+	fn write(&self, ofs: u64, buf: &[u8]) -> ::vfs::Result<usize> {
+		let vh = try!(self.get_handle(true));
+		let bs = vh.block_size() as u64;
+		let total = vh.block_count() * bs;
+		if ofs >= total {
+			return Err( ::vfs::Error::OutOfSpace );
+		}
+		let len = ::core::cmp::min(buf.len() as u64, total - ofs) as usize;
+		let mut bounce = Vec::from_elem(bs as usize, 0u8);
+		let mut done = 0;
+		while done < len
+		{
+			let pos = ofs + done as u64;
+			let blk_ofs = (pos % bs) as usize;
+			let n = ::core::cmp::min(bs as usize - blk_ofs, len - done);
+			// Partial blocks are read-modify-write
+			if n < bs as usize {
+				try!(vh.read_blocks(pos / bs, &mut bounce));
+			}
+			bounce[blk_ofs..][..n].clone_from_slice(&buf[done..][..n]);
+			try!(vh.write_blocks(pos / bs, &bounce));
+			done += n;
+		}
+		Ok(len)
+	}
+	fn control(&self, code: u32, data: &mut [u8]) -> ::vfs::Result<usize> {
+		use lib::byteorder::{ByteOrder,LittleEndian};
+		let vh = try!(self.get_handle(false));
+		let val = match code
+			{
+			DEVCTL_VOL_BLOCKSIZE => vh.block_size() as u64,
+			DEVCTL_VOL_BLOCKCOUNT => vh.block_count(),
+			_ => return Err( ::vfs::Error::InvalidParameter ),
+			};
+		if data.len() < 8 {
+			return Err( ::vfs::Error::InvalidParameter );
+		}
+		LittleEndian::write_u64(&mut data[..8], val);
+		Ok(8)
+	}
+}
+
 impl ::core::ops::Drop for PhysicalVolumeReg
 {
 	fn drop(&mut self)
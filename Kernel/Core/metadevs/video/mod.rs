@@ -132,14 +132,24 @@ struct DisplaySurface
 {
 	region: Rect,
 	fb: Box<dyn Framebuffer>,
+	/// Device node in `/dev/video`
+	devfs_reg: Option<::vfs::devfs::Registration>,
 }
 
+/// devfs interface to a display surface (by index)
+struct DisplayDevice(usize);
+
+/// Control code for display device nodes, returns the width and height (little-endian u32s)
+pub const DEVCTL_VIDEO_GETDIMS: u32 = 0;
+
 /// Sparse list of registered display devices
 static S_DISPLAY_SURFACES: LazyMutex<SparseVec<DisplaySurface>> = lazymutex_init!( );
 /// Boot video mode
 static S_BOOT_MODE: Mutex<Option<bootvideo::VideoMode>> = Mutex::new(None);
 /// Function called when display geometry changes
 static S_GEOM_UPDATE_SIGNAL: Mutex<Option<fn(new_total: Rect)>> = Mutex::new(None);
+/// Function called to draw pixels written to a display's device node (see `register_devfs_write`)
+static S_DEVFS_WRITE_HANDLER: Mutex<Option<fn(pos: Pos, data: &[u32])>> = Mutex::new(None);
 
 fn init()
 {
@@ -150,10 +160,13 @@ fn init()
 		log_notice!("Using boot video mode {:?}", mode);
 		let fb = box bootvideo::Framebuffer::new(*mode) as Box<dyn Framebuffer>;
 		let dims = fb.get_size();
-		S_DISPLAY_SURFACES.lock().insert( DisplaySurface {
+		let mut lh = S_DISPLAY_SURFACES.lock();
+		let idx = lh.insert( DisplaySurface {
 			region: Rect::new(0,0, dims.w,dims.h),
-			fb: fb
+			fb: fb,
+			devfs_reg: None,
 			} );
+		lh[idx].devfs_reg = Some( register_device(idx) );
 	}
 	else
 	{
@@ -276,6 +289,17 @@ pub fn register_geom_update(fcn: fn(new_total: Rect))
 	*lh = Some(fcn);
 }
 
+/// Register the compositor's handler for pixels written to display device nodes (`/dev/video/*`)
+///
+/// The handler is passed a single row of pixels and its position in the global display space. Until
+/// a handler is registered, writes go directly to the framebuffer.
+pub fn register_devfs_write(fcn: fn(pos: Pos, data: &[u32]))
+{
+	let mut lh = S_DEVFS_WRITE_HANDLER.lock();
+	assert!(lh.is_none(), "register_devfs_write called multiple times (prev {:p}, new {:p})", lh.as_ref().unwrap(), &fcn);
+	*lh = Some(fcn);
+}
+
 fn signal_geom_update(surfs: ::sync::mutex::HeldLazyMutex<SparseVec<DisplaySurface>>)
 {
 	// API Requirements
@@ -313,8 +337,10 @@ pub fn add_output(output: Box<dyn Framebuffer>) -> FramebufferRegistration
 		};
 	let idx = lh.insert( DisplaySurface {
 		region: Rect::new(pos.x,pos.y,dims.w,dims.h),
-		fb: output
+		fb: output,
+		devfs_reg: None,
 		} );
+	lh[idx].devfs_reg = Some( register_device(idx) );
 	
 	signal_geom_update(lh);
 	
@@ -406,6 +432,79 @@ pub /*unsafe*/ fn write_buf(pos: Pos, data: StrideBuf<'_, u32>)
 	}
 }
 
+fn register_device(idx: usize) -> ::vfs::devfs::Registration
+{
+	::vfs::devfs::register(::vfs::devfs::DeviceClass::Video, &format!("display{}", idx), Box::new(DisplayDevice(idx)))
+}
+impl DisplayDevice
+{
+	fn region(&self) -> ::vfs::Result<Rect> {
+		S_DISPLAY_SURFACES.lock().get(self.0).map(|s| s.region).ok_or(::vfs::Error::NotFound)
+	}
+}
+impl ::vfs::devfs::Device for DisplayDevice
+{
+	fn typename(&self) -> &str {
+		"display"
+	}
+	fn size(&self) -> u64 {
+		self.region().map(|r| r.w() as u64 * r.h() as u64 * 4).unwrap_or(0)
+	}
+	/// Write 32-bit pixels (little-endian) to the display, `ofs` is the byte offset of the first pixel
+	///
+	/// The pixels are passed to the compositor (if one is registered), so they don't overwrite its output.
+	fn write(&self, ofs: u64, buf: &[u8]) -> ::vfs::Result<usize> {
+		let region = try!(self.region());
+		if ofs % 4 != 0 || buf.len() % 4 != 0 {
+			return Err( ::vfs::Error::InvalidParameter );
+		}
+		let width = region.w() as u64;
+		let npix = region.w() as u64 * region.h() as u64;
+		let first = ofs / 4;
+		if first >= npix {
+			return Err( ::vfs::Error::InvalidParameter );
+		}
+		let count = ::core::cmp::min(buf.len() as u64 / 4, npix - first) as usize;
+
+		// Write a row (or partial row) at a time
+		let handler = *S_DEVFS_WRITE_HANDLER.lock();
+		let mut row = Vec::new();
+		let mut done = 0;
+		while done < count
+		{
+			let pix = first + done as u64;
+			let (x, y) = ((pix % width) as u32, (pix / width) as u32);
+			let n = ::core::cmp::min(width as usize - x as usize, count - done);
+			row.clear();
+			row.extend( buf[done*4 ..][.. n*4].chunks(4).map(|c| c[0] as u32 | (c[1] as u32) << 8 | (c[2] as u32) << 16 | (c[3] as u32) << 24) );
+			let pos = Pos::new(region.x() + x, region.y() + y);
+			match handler
+			{
+			Some(fcn) => fcn(pos, &row),
+			None => write_buf(pos, StrideBuf::new(&row, n)),
+			}
+			done += n;
+		}
+		Ok(count * 4)
+	}
+	fn control(&self, code: u32, data: &mut [u8]) -> ::vfs::Result<usize> {
+		use lib::byteorder::{ByteOrder,LittleEndian};
+		match code
+		{
+		DEVCTL_VIDEO_GETDIMS => {
+			let region = try!(self.region());
+			if data.len() < 8 {
+				return Err( ::vfs::Error::InvalidParameter );
+			}
+			LittleEndian::write_u32(&mut data[0..4], region.w());
+			LittleEndian::write_u32(&mut data[4..8], region.h());
+			Ok(8)
+			},
+		_ => Err( ::vfs::Error::InvalidParameter ),
+		}
+	}
+}
+
 impl ::core::ops::Drop for FramebufferRegistration
 {
 	fn drop(&mut self)
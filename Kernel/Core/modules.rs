@@ -15,14 +15,17 @@ pub struct ModuleInfo
 	pub _rsvd: [usize; 3],
 }
 
-#[derive(Clone,PartialEq)]
-enum ModuleState
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum ModuleState
 {
 	Uninitialised,
 	Resolving,
 	Initialised,
 }
 
+/// Statically linked modules, and their initialisation state
+static S_MODULES: ::sync::Mutex<(&'static [ModuleInfo], Vec<ModuleState>)> = ::sync::Mutex::new( (&[], Vec::new_const()) );
+
 #[cfg(feature="test")]
 mod _test {
 	#[no_mangle]
@@ -62,30 +65,42 @@ pub fn init(requests: &[&str])
 	}
 }
 
+/// Enumerate statically linked modules, with their initialisation state
+pub fn enum_modules() -> Vec<(&'static str, ModuleState)>
+{
+	let lh = S_MODULES.lock();
+	lh.0.iter().zip(lh.1.iter()).map(|(m,s)| (m.name, *s)).collect()
+}
+
 /// Initialise modules from a slice
-fn init_modules(mods: &[ModuleInfo], requests: &[&str])
+fn init_modules(mods: &'static [ModuleInfo], requests: &[&str])
 {
 	log_debug!("s_modules={:p}+{:#x}", mods.as_ptr(), mods.len());
 	for m in mods.iter() {
 		log_debug!("mod = {:p} {:?} '{}'", &m.name, m.name.as_ptr(), m.name);
 	}
 
-	let mut modstates = vec![ModuleState::Uninitialised; mods.len()];
+	*S_MODULES.lock() = (mods, vec![ModuleState::Uninitialised; mods.len()]);
 	for req in requests
 	{
-		init_module_by_name(modstates.slice_mut(), mods, "", req);
+		init_module_by_name(mods, "", req);
 	}
 	
 	for i in 0 .. mods.len()
 	{
-		init_module(modstates.slice_mut(), mods, i);
+		init_module(mods, i);
 	}
 }
 
+fn set_state(i: usize, state: ModuleState)
+{
+	S_MODULES.lock().1[i] = state;
+}
+
 /// Initialise a module by name, as required by another module
 ///
 /// `req` = requesting module, `name` = required module
-fn init_module_by_name(modstates: &mut [ModuleState], mods: &[ModuleInfo], req: &str, name: &str)
+fn init_module_by_name(mods: &[ModuleInfo], req: &str, name: &str)
 {
 	// Locate module
 	let depid = match mods.iter().enumerate().find( |&(_,v)| v.name == name ) {
@@ -93,29 +108,37 @@ fn init_module_by_name(modstates: &mut [ModuleState], mods: &[ModuleInfo], req:
 		None => panic!("Dependency '{}' for module '{}' missing", name, req),
 		};
 	// Check if not being initialised
-	if modstates[depid] == ModuleState::Resolving {
+	if S_MODULES.lock().1[depid] == ModuleState::Resolving {
 		panic!("Circular dependency '{}' requires '{}' which is already being resolved", req, name);
 	}
 	
 	// Initialise
-	init_module(modstates, mods, depid);
+	init_module(mods, depid);
 }
 
 /// Initialise a module (does nothing if the module is already initialised)
-fn init_module(modstates: &mut [ModuleState], mods: &[ModuleInfo], i: usize)
+fn init_module(mods: &[ModuleInfo], i: usize)
 {
 	let module = &mods[i];
-	if modstates[i] == ModuleState::Uninitialised
+	// NOTE: The state lock is not held while initialising (module init can take a while, and may enumerate modules)
+	let prev_state = {
+		let mut lh = S_MODULES.lock();
+		let prev = lh.1[i];
+		if prev == ModuleState::Uninitialised {
+			lh.1[i] = ModuleState::Resolving;
+		}
+		prev
+		};
+	if prev_state == ModuleState::Uninitialised
 	{
-		modstates[i] = ModuleState::Resolving;
 		log_debug!("#{}: {} Deps", i, module.name);
 		for name in module.deps.iter() {
-			init_module_by_name(modstates, mods, module.name, *name);
+			init_module_by_name(mods, module.name, *name);
 		}
 		// TODO: Do module initialisation in worker threads, and handle waiting for deps before calling init
 		log_debug!("#{}: {} Init", i, module.name);
 		(module.init)();
-		modstates[i] = ModuleState::Initialised;
+		set_state(i, ModuleState::Initialised);
 	}
 }
 
@@ -15,6 +15,7 @@ mod sleep_object;
 pub use self::thread::{Thread,ThreadPtr,ThreadID,ProcessID};
 pub use self::thread::{ThreadHandle,ProcessHandle};
 pub use self::thread::new_idle_thread;
+pub use self::thread::{ThreadInfo,enum_threads};
 
 pub use self::worker_thread::WorkerThread;
 
@@ -29,7 +30,6 @@ pub type EventMask = u32;
 
 // ----------------------------------------------
 // Statics
-//static s_all_threads:	::sync::Mutex<Map<uint,*const Thread>> = mutex_init!(Map{});
 #[allow(non_upper_case_globals)]
 static s_runnable_threads: ::sync::Spinlock<ThreadList> = ::sync::Spinlock::new(THREADLIST_INIT);
 static S_PID0: ::lib::LazyStatic<::lib::mem::Arc<thread::Process>> = ::lib::LazyStatic::new();
@@ -68,6 +68,15 @@ pub struct ThreadHandle
 	// - Race problems
 }
 
+/// Summary of a thread's state, returned by `enum_threads`
+pub struct ThreadInfo
+{
+	pub tid: ThreadID,
+	pub name: String,
+	pub pid: ProcessID,
+	pub process_name: String,
+}
+
 /// "Owned" pointer to a thread (panics if dropped)
 pub struct ThreadPtr(::lib::mem::Unique<Thread>);
 
@@ -91,6 +100,9 @@ const C_MAX_TID: usize = 0x7FFF_FFF0;	// Leave 16 TIDs spare at end of 31 bit nu
 static S_LAST_PID: ::core::sync::atomic::AtomicUsize = ::core::sync::atomic::AtomicUsize::new(0);
 const C_MAX_PID: usize = 0x007F_FFF0;	// Leave 16 PIDs spare at end of 23 bit number
 
+/// All live threads (added on creation, removed when the `Thread` is destroyed)
+static S_ALL_THREADS: ::sync::Mutex<::lib::VecMap<ThreadID, Arc<SharedBlock>>> = ::sync::Mutex::new(::lib::VecMap::new_const());
+
 fn allocate_tid() -> ThreadID
 {
 	// Preemptively prevent rollover
@@ -148,6 +160,7 @@ impl Process
 	}
 
 	pub fn get_pid(&self) -> ProcessID { self.pid }
+	pub fn get_name(&self) -> &str { &self.name }
 
 	pub fn mark_exit(&self, status: u32) -> Result<(),()> {
 		let mut lh = self.exit_status.lock();
@@ -411,8 +424,8 @@ impl Thread
 			next: None,
 			};
 		
-		// TODO: Add to global list of threads (removed on destroy)
 		log_debug!("Creating thread {:?}", rv);
+		S_ALL_THREADS.lock().insert(tid, rv.block.clone());
 		
 		ThreadPtr::new( rv )
 	}
@@ -443,6 +456,19 @@ impl Thread
 	}
 }
 
+/// Enumerate all live threads (sorted by thread ID)
+pub fn enum_threads() -> Vec<ThreadInfo>
+{
+	S_ALL_THREADS.lock().iter()
+		.map(|(_,b)| ThreadInfo {
+			tid: b.tid,
+			name: b.name.clone(),
+			pid: b.process.pid,
+			process_name: b.process.name.clone(),
+			})
+		.collect()
+}
+
 pub fn new_idle_thread(cpu: usize) -> ThreadPtr {
 	let mut thread = Thread::new_boxed(allocate_tid(), format!("Idle#{}", cpu), super::S_PID0.clone());
 	::arch::threads::start_thread(&mut thread, super::idle_thread);
@@ -475,7 +501,7 @@ impl ::core::ops::Drop for Thread
 {
 	fn drop(&mut self)
 	{
-		// TODO: Remove self from the global thread map
+		S_ALL_THREADS.lock().remove(&self.block.tid);
 		log_debug!("Destroying thread {:?} - {} handles to block, {} to process", self, Arc::strong_count(&self.block), Arc::strong_count(&self.block.process));
 	}
 }
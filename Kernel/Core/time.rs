@@ -13,6 +13,63 @@ pub fn ticks() -> u64
 	::arch::cur_timestamp()
 }
 
+/// Wall-clock time, in seconds since 1970-01-01 00:00:00 UTC
+pub type Timestamp = i64;
+
+/// Convert a UTC calendar date and time into a `Timestamp`
+///
+/// `month` and `day` are one-based.
+pub fn timestamp_from_date(year: i32, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Timestamp
+{
+	// Days since the epoch, using a year starting in March (so the leap day is the last day)
+	let y = if month <= 2 { year as i64 - 1 } else { year as i64 };
+	let era = (if y >= 0 { y } else { y - 399 }) / 400;
+	let year_of_era = y - era * 400;
+	let m = month as i64;
+	let day_of_year = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	let days = era * 146097 + day_of_era - 719468;
+
+	days * 86400 + hour as i64 * 3600 + minute as i64 * 60 + second as i64
+}
+
+/// Convert a `Timestamp` into a UTC calendar date and time, the inverse of `timestamp_from_date`
+///
+/// Returns `(year, month, day, hour, minute, second)`
+pub fn date_from_timestamp(ts: Timestamp) -> (i32, u8, u8, u8, u8, u8)
+{
+	let (days, secs) = (ts.div_euclid(86400), ts.rem_euclid(86400));
+	// Same March-based years as `timestamp_from_date`
+	let z = days + 719468;
+	let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+	let day_of_era = z - era * 146097;
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+	(year as i32, month as u8, day as u8, (secs / 3600) as u8, (secs / 60 % 60) as u8, (secs % 60) as u8)
+}
+
+/// Wall-clock time at tick zero (stored as the bit pattern of a `Timestamp`)
+static S_WALL_CLOCK_BASE: ::sync::atomic::AtomicValue<u64> = ::sync::atomic::AtomicValue::new(0);
+
+/// Set the current wall-clock time (called by the clock source, e.g. a RTC driver)
+pub fn set_wall_clock(now: Timestamp)
+{
+	let base = now - (ticks() / 1000) as Timestamp;
+	S_WALL_CLOCK_BASE.store(base as u64, ::core::sync::atomic::Ordering::Relaxed);
+}
+
+/// Obtain the current wall-clock time
+///
+/// If no clock source has set the time, this counts from the epoch at system startup.
+pub fn now() -> Timestamp
+{
+	S_WALL_CLOCK_BASE.load(::core::sync::atomic::Ordering::Relaxed) as Timestamp + (ticks() / 1000) as Timestamp
+}
 
 /// Records the current time on construction, and prints the elapsed time with {:?} / {}
 pub struct ElapsedLogger(TickCount);
@@ -0,0 +1,156 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/dentry.rs
+//! Directory entry (name to inode) lookup cache
+//!
+//! Caches the result of `node::Dir::lookup`, including failed lookups (negative entries). Names
+//! are cached per directory, and all names for a directory are dropped whenever that directory is
+//! modified (this also handles case-insensitive filesystems, where one entry matches many names).
+use prelude::*;
+use sync::mutex::LazyMutex;
+use lib::VecMap;
+use lib::byte_str::{ByteStr,ByteString};
+use super::node::InodeId;
+
+/// Number of hash buckets (directories are distributed over these)
+const NUM_BUCKETS: usize = 64;
+/// Maximum number of names held in the cache
+const MAX_ENTRIES: usize = 1024;
+
+/// Identifies a directory: (mount ID, inode)
+pub type DirId = (usize, InodeId);
+
+struct DentryCache
+{
+	buckets: Vec< VecMap<DirId, VecMap<ByteString, Option<InodeId>>> >,
+	/// Total number of cached names
+	count: usize,
+	/// Incremented on each invalidation, used to detect a change during an uncached lookup
+	generation: u64,
+	/// Next bucket to evict from when the cache is full
+	evict_pos: usize,
+}
+
+static S_DENTRY_CACHE: LazyMutex<DentryCache> = lazymutex_init!();
+
+pub fn init()
+{
+	S_DENTRY_CACHE.init(|| DentryCache {
+		buckets: Vec::from_fn(NUM_BUCKETS, |_| VecMap::new()),
+		count: 0,
+		generation: 0,
+		evict_pos: 0,
+		});
+}
+
+/// Hash a (mount, inode) pair into a bucket index (also used by the node cache)
+pub fn hash_ids(mountpt: usize, inode: InodeId) -> usize
+{
+	let v = (mountpt as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ inode.wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+	(v ^ (v >> 29)) as usize
+}
+
+/// Look up `name` in the directory `dir`, calling `lookup` on a cache miss
+///
+/// Only `Ok` and `Error::NotFound` results are cached.
+pub fn lookup<F>(dir: DirId, name: &ByteStr, lookup: F) -> super::Result<InodeId>
+where
+	F: FnOnce(&ByteStr) -> super::Result<InodeId>
+{
+	// `.` and `..` are handled by the path walker (and `..` changes when a directory is moved)
+	if name == "" || name == "." || name == ".." {
+		return lookup(name);
+	}
+
+	let generation = {
+		let lh = S_DENTRY_CACHE.lock();
+		match lh.buckets[hash_ids(dir.0, dir.1) % NUM_BUCKETS].get(&dir).and_then(|names| names.get(name))
+		{
+		Some(&Some(inode)) => return Ok(inode),
+		Some(&None) => return Err(super::Error::NotFound),
+		None => lh.generation,
+		}
+		};
+
+	let rv = lookup(name);
+	let ent = match rv
+		{
+		Ok(inode) => Some(inode),
+		Err(super::Error::NotFound) => None,
+		Err(_) => return rv,
+		};
+
+	let mut lh = S_DENTRY_CACHE.lock();
+	// If the cache was invalidated while the lookup ran, the result may be stale
+	if lh.generation == generation {
+		lh.insert(dir, name, ent);
+	}
+	rv
+}
+
+/// Drop all cached names within a directory (call after any modification to it)
+pub fn invalidate_dir(dir: DirId)
+{
+	let mut lh = S_DENTRY_CACHE.lock();
+	lh.generation += 1;
+	let bucket = hash_ids(dir.0, dir.1) % NUM_BUCKETS;
+	if let Some(names) = lh.buckets[bucket].remove(&dir) {
+		lh.count -= names.iter().count();
+	}
+}
+
+/// Drop all cached names for a mount (when it is unmounted, as the ID can be reused)
+pub fn purge_mount(mountpt: usize)
+{
+	let mut lh = S_DENTRY_CACHE.lock();
+	lh.generation += 1;
+	for i in 0 .. NUM_BUCKETS
+	{
+		let dirs: Vec<DirId> = lh.buckets[i].iter().map(|(k,_)| *k).filter(|k| k.0 == mountpt).collect();
+		for dir in dirs
+		{
+			if let Some(names) = lh.buckets[i].remove(&dir) {
+				lh.count -= names.iter().count();
+			}
+		}
+	}
+}
+
+impl DentryCache
+{
+	fn insert(&mut self, dir: DirId, name: &ByteStr, ent: Option<InodeId>)
+	{
+		if self.count >= MAX_ENTRIES {
+			self.evict();
+		}
+		let bucket = hash_ids(dir.0, dir.1) % NUM_BUCKETS;
+		let names = match self.buckets[bucket].entry(dir)
+			{
+			::lib::vec_map::Entry::Occupied(e) => e.into_mut(),
+			::lib::vec_map::Entry::Vacant(e) => e.insert(VecMap::new()),
+			};
+		if names.insert(ByteString::from(name), ent).is_none() {
+			self.count += 1;
+		}
+	}
+
+	/// Drop the names of one directory (rotating through the buckets)
+	fn evict(&mut self)
+	{
+		for _ in 0 .. NUM_BUCKETS
+		{
+			let bucket = self.evict_pos;
+			self.evict_pos = (self.evict_pos + 1) % NUM_BUCKETS;
+			let dir = match self.buckets[bucket].iter().next()
+				{
+				Some((k,_)) => *k,
+				None => continue,
+				};
+			if let Some(names) = self.buckets[bucket].remove(&dir) {
+				self.count -= names.iter().count();
+			}
+			return ;
+		}
+	}
+}
@@ -0,0 +1,328 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/devfs.rs
+//! Device filesystem (mounted at `/dev`)
+//!
+//! Drivers register devices using `register`, which then appear as special nodes within a
+//! per-class directory (e.g. `/dev/volumes/ATA0p0`).
+use prelude::*;
+use vfs;
+use super::{mount, node};
+use metadevs::storage::VolumeHandle;
+use lib::{VecMap,SparseVec};
+use lib::byte_str::{ByteStr,ByteString};
+use lib::mem::Arc;
+use sync::mutex::LazyMutex;
+
+pub struct Driver;
+pub static S_DRIVER: Driver = Driver;
+
+/// Device class, selects the directory the device appears in
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum DeviceClass
+{
+	/// Logical storage volumes (`/dev/volumes`)
+	Storage,
+	/// Display outputs (`/dev/video`)
+	Video,
+	/// Keyboards, mice, ... (`/dev/input`)
+	Input,
+}
+const CLASSES: [DeviceClass; 3] = [DeviceClass::Storage, DeviceClass::Video, DeviceClass::Input];
+
+/// Interface for a device exposed through devfs
+///
+/// Operations that the device doesn't support fail with `Error::TypeMismatch`.
+pub trait Device: Send + Sync
+{
+	/// Short description of the device type (returned by `node::Special::typename`)
+	fn typename(&self) -> &str;
+	/// Size of the device's data in bytes (reported in the node's metadata)
+	fn size(&self) -> u64 {
+		0
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+		let _ = (ofs, buf);
+		Err(vfs::Error::TypeMismatch)
+	}
+	fn write(&self, ofs: u64, buf: &[u8]) -> vfs::Result<usize> {
+		let _ = (ofs, buf);
+		Err(vfs::Error::TypeMismatch)
+	}
+	/// Device-specific control operation (see `node::Special::control`)
+	fn control(&self, code: u32, data: &mut [u8]) -> vfs::Result<usize> {
+		let _ = (code, data);
+		Err(vfs::Error::TypeMismatch)
+	}
+}
+
+/// Handle to a registered device, removes the device from devfs when dropped
+pub struct Registration(node::InodeId);
+
+const ROOT_INODE: node::InodeId = 1;
+/// Inode of the first class directory (the rest follow in `CLASSES` order)
+const CLASS_INODE_BASE: node::InodeId = 2;
+/// Inode of the first device, device inodes are never reused (so stale cached nodes can't alias)
+const DEVICE_INODE_BASE: node::InodeId = 0x100;
+
+struct DeviceEnt
+{
+	class: DeviceClass,
+	name: ByteString,
+	dev: Arc<Box<dyn Device>>,
+}
+struct Registry
+{
+	devices: VecMap<node::InodeId, DeviceEnt>,
+	next_inode: node::InodeId,
+	/// Mounted instances (told when a directory's contents change)
+	mounts: SparseVec<mount::SelfHandle>,
+}
+impl Default for Registry {
+	fn default() -> Registry {
+		Registry {
+			devices: VecMap::new(),
+			next_inode: DEVICE_INODE_BASE,
+			mounts: SparseVec::new(),
+		}
+	}
+}
+
+/// NOTE: Initialised on first use, as devices can be registered before the VFS is initialised
+static S_REGISTRY: LazyMutex<Registry> = lazymutex_init!();
+
+struct DevFs
+{
+	mount_idx: usize,
+}
+struct RootDir;
+struct ClassDir(DeviceClass);
+struct DevNode(node::InodeId, Arc<Box<dyn Device>>);
+
+pub fn init()
+{
+	let h = mount::DriverRegistration::new("devfs", &S_DRIVER);
+	::core::mem::forget(h);
+}
+
+/// Add a device to devfs, returning a handle that removes it when dropped
+///
+/// `name` should be unique within the class (lookups return the first match).
+pub fn register(class: DeviceClass, name: &str, dev: Box<dyn Device>) -> Registration
+{
+	let mut lh = S_REGISTRY.lock_init(|| Default::default());
+	let inode = lh.next_inode;
+	lh.next_inode += 1;
+	log_debug!("devfs::register({:?}, {:?}) = {:#x}", class, name, inode);
+	lh.devices.insert(inode, DeviceEnt {
+		class: class,
+		name: ByteString::from(ByteStr::new(name)),
+		dev: Arc::new(dev),
+		});
+	lh.invalidate_class(class);
+	Registration(inode)
+}
+
+impl ::core::ops::Drop for Registration
+{
+	fn drop(&mut self)
+	{
+		let ent = {
+			let mut lh = S_REGISTRY.lock_init(|| Default::default());
+			let ent = lh.devices.remove(&self.0);
+			if let Some(ref e) = ent {
+				lh.invalidate_class(e.class);
+			}
+			ent
+			};
+		// Dropped after the registry is released (the device may call back into devfs)
+		drop(ent);
+	}
+}
+impl_fmt! {
+	Debug(self, f) for Registration {
+		write!(f, "devfs::Registration({:#x})", self.0)
+	}
+}
+
+impl DeviceClass
+{
+	fn dir_name(&self) -> &'static str {
+		match *self
+		{
+		DeviceClass::Storage => "volumes",
+		DeviceClass::Video => "video",
+		DeviceClass::Input => "input",
+		}
+	}
+	fn inode(&self) -> node::InodeId {
+		CLASS_INODE_BASE + CLASSES.iter().position(|c| c == self).unwrap() as node::InodeId
+	}
+}
+
+impl Registry
+{
+	/// Drop cached names for a class directory (after a device is added or removed)
+	fn invalidate_class(&self, class: DeviceClass) {
+		for m in self.mounts.iter()
+		{
+			m.invalidate_dir(class.inode());
+		}
+	}
+}
+
+impl mount::Driver for Driver
+{
+	fn detect(&self, _vol: &VolumeHandle) -> vfs::Result<usize> {
+		// devfs is never backed by a real volume
+		Ok(0)
+	}
+	fn mount(&self, _vol: VolumeHandle, handle: mount::SelfHandle, options: &[&str]) -> vfs::Result<Box<dyn mount::Filesystem>> {
+		for opt in options
+		{
+			log_notice!("devfs: Unknown option '{}'", opt);
+		}
+		let idx = S_REGISTRY.lock_init(|| Default::default()).mounts.insert(handle);
+		Ok( Box::new(DevFs { mount_idx: idx }) )
+	}
+}
+
+impl mount::Filesystem for DevFs
+{
+	fn root_inode(&self) -> node::InodeId {
+		ROOT_INODE
+	}
+	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
+		if id == ROOT_INODE {
+			Some(node::Node::Dir(Box::new(RootDir)))
+		}
+		else if id < DEVICE_INODE_BASE {
+			id.checked_sub(CLASS_INODE_BASE)
+				.and_then(|i| CLASSES.get(i as usize))
+				.map(|&c| node::Node::Dir(Box::new(ClassDir(c))))
+		}
+		else {
+			let lh = S_REGISTRY.lock_init(|| Default::default());
+			lh.devices.get(&id).map(|e| node::Node::Special(Box::new(DevNode(id, e.dev.clone()))))
+		}
+	}
+}
+impl ::core::ops::Drop for DevFs
+{
+	fn drop(&mut self)
+	{
+		S_REGISTRY.lock_init(|| Default::default()).mounts.remove(self.mount_idx);
+	}
+}
+
+impl node::NodeBase for RootDir {
+	fn get_id(&self) -> node::InodeId {
+		ROOT_INODE
+	}
+	fn get_any(&self) -> &dyn (::core::any::Any) {
+		self
+	}
+}
+impl node::Dir for RootDir {
+	fn lookup(&self, name: &ByteStr) -> vfs::Result<node::InodeId> {
+		match CLASSES.iter().find(|c| name == c.dir_name())
+		{
+		Some(c) => Ok(c.inode()),
+		None => Err(vfs::Error::NotFound),
+		}
+	}
+	fn read(&self, start_ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize> {
+		let mut count = 0;
+		for c in CLASSES.iter().skip(start_ofs)
+		{
+			count += 1;
+			if ! callback(c.inode(), &mut c.dir_name().bytes()) {
+				break ;
+			}
+		}
+		Ok(start_ofs + count)
+	}
+	fn create(&self, _name: &ByteStr, _nodetype: node::NodeType) -> vfs::Result<node::InodeId> {
+		Err(vfs::Error::PermissionDenied)
+	}
+	fn link(&self, _name: &ByteStr, _node: &dyn node::NodeBase) -> vfs::Result<()> {
+		Err(vfs::Error::PermissionDenied)
+	}
+	fn unlink(&self, _name: &ByteStr) -> vfs::Result<()> {
+		Err(vfs::Error::PermissionDenied)
+	}
+	fn rename(&self, _src_name: &ByteStr, _dst_dir: &dyn node::Dir, _dst_name: &ByteStr) -> vfs::Result<()> {
+		Err(vfs::Error::PermissionDenied)
+	}
+}
+
+impl node::NodeBase for ClassDir {
+	fn get_id(&self) -> node::InodeId {
+		self.0.inode()
+	}
+	fn get_any(&self) -> &dyn (::core::any::Any) {
+		self
+	}
+}
+impl node::Dir for ClassDir {
+	fn lookup(&self, name: &ByteStr) -> vfs::Result<node::InodeId> {
+		let lh = S_REGISTRY.lock_init(|| Default::default());
+		match lh.devices.iter().find(|&(_, e)| e.class == self.0 && &*e.name == name)
+		{
+		Some((&inode, _)) => Ok(inode),
+		None => Err(vfs::Error::NotFound),
+		}
+	}
+	fn read(&self, start_ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize> {
+		let lh = S_REGISTRY.lock_init(|| Default::default());
+		let mut count = 0;
+		// NOTE: This will skip/repeat entries if devices are added/removed between calls
+		for (&inode, e) in lh.devices.iter().filter(|&(_, e)| e.class == self.0).skip(start_ofs)
+		{
+			count += 1;
+			if ! callback(inode, &mut e.name.as_bytes().iter().cloned()) {
+				break ;
+			}
+		}
+		Ok(start_ofs + count)
+	}
+	fn create(&self, _name: &ByteStr, _nodetype: node::NodeType) -> vfs::Result<node::InodeId> {
+		Err(vfs::Error::PermissionDenied)
+	}
+	fn link(&self, _name: &ByteStr, _node: &dyn node::NodeBase) -> vfs::Result<()> {
+		Err(vfs::Error::PermissionDenied)
+	}
+	fn unlink(&self, _name: &ByteStr) -> vfs::Result<()> {
+		Err(vfs::Error::PermissionDenied)
+	}
+	fn rename(&self, _src_name: &ByteStr, _dst_dir: &dyn node::Dir, _dst_name: &ByteStr) -> vfs::Result<()> {
+		Err(vfs::Error::PermissionDenied)
+	}
+}
+
+impl node::NodeBase for DevNode {
+	fn get_id(&self) -> node::InodeId {
+		self.0
+	}
+	fn get_any(&self) -> &dyn (::core::any::Any) {
+		self
+	}
+	fn get_metadata(&self) -> vfs::Result<node::Metadata> {
+		Ok(node::Metadata { permissions: 0o600, size: self.1.size(), ..Default::default() })
+	}
+}
+impl node::Special for DevNode {
+	fn typename(&self) -> &str {
+		self.1.typename()
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+		self.1.read(ofs, buf)
+	}
+	fn write(&self, ofs: u64, buf: &[u8]) -> vfs::Result<usize> {
+		self.1.write(ofs, buf)
+	}
+	fn control(&self, code: u32, data: &mut [u8]) -> vfs::Result<usize> {
+		self.1.control(code, data)
+	}
+}
@@ -8,17 +8,20 @@ use prelude::*;
 use super::node::{CacheHandle,NodeType};
 use lib::byte_str::{ByteStr,ByteString};
 use super::Path;
+use lib::mem::Arc;
+use lib::VecMap;
 
 #[derive(Debug,Clone)]
 /// Open without caring what the file type is (e.g. enumeration)
 pub struct Any {
 	node: CacheHandle,
 }
-#[derive(Debug,Clone)]
 /// Normal file
 pub struct File {
 	node: CacheHandle,
 	mode: FileOpenMode,
+	/// Private copy of the file data (only for `UniqueRW`)
+	private: Option<Arc<::sync::Mutex<PrivateCopy>>>,
 }
 #[derive(Debug,Clone)]
 /// Directory (for enumeration)
@@ -31,7 +34,7 @@ pub struct Symlink {
 	node: CacheHandle,
 }
 #[derive(Debug,Clone)]
-/// Special file (?API exposed)
+/// Special file (e.g. a device node), exposes read/write/control operations
 pub struct Special {
 	node: CacheHandle,
 }
@@ -75,6 +78,8 @@ pub enum MemoryMapMode
 	/// Copy-on-write (used for executable files)
 	COW,
 	/// Allows writing to the backing file
+	///
+	/// Changes are written back when the mapping is dropped, or explicitly with `MemoryMapHandle::sync`
 	WriteBack,
 }
 
@@ -90,6 +95,14 @@ impl Any
 	pub fn get_class(&self) -> super::node::NodeClass {
 		self.node.get_class()
 	}
+	/// Inode number of the node (unique within its mount)
+	pub fn get_inode(&self) -> super::node::InodeId {
+		self.node.get_inode()
+	}
+	/// Obtain the node's metadata
+	pub fn get_metadata(&self) -> super::Result<super::node::Metadata> {
+		self.node.get_metadata()
+	}
 	
 	/// Upgrade the handle to a directory handle
 	pub fn to_dir(self) -> super::Result<Dir> {
@@ -118,13 +131,104 @@ impl Any
 			Err(super::Error::TypeMismatch)
 		}
 	}
+
+	pub fn to_special(self) -> super::Result<Special> {
+		if self.node.is_special() {
+			Ok(Special { node: self.node })
+		}
+		else {
+			Err(super::Error::TypeMismatch)
+		}
+	}
+}
+
+/// Copy-on-write overlay of a file's contents, used by `UniqueRW` handles
+///
+/// Pages are copied from the backing file on first write, and discarded when the last handle
+/// is closed.
+struct PrivateCopy
+{
+	size: u64,
+	pages: VecMap<u64, Box<[u8]>>,
+}
+impl PrivateCopy
+{
+	fn new(size: u64) -> PrivateCopy {
+		PrivateCopy {
+			size: size,
+			pages: VecMap::new(),
+		}
+	}
+
+	fn read(&self, node: &CacheHandle, ofs: u64, dst: &mut [u8]) -> super::Result<usize> {
+		if ofs >= self.size {
+			return Ok(0);
+		}
+		let len = ::core::cmp::min(dst.len() as u64, self.size - ofs) as usize;
+		let mut done = 0;
+		while done < len {
+			let pos = ofs + done as u64;
+			let page = pos / ::PAGE_SIZE as u64;
+			let page_ofs = (pos % ::PAGE_SIZE as u64) as usize;
+			let count = ::core::cmp::min(len - done, ::PAGE_SIZE - page_ofs);
+			let dst = &mut dst[done .. done + count];
+			match self.pages.get(&page)
+			{
+			Some(data) => dst.copy_from_slice( &data[page_ofs .. page_ofs + count] ),
+			None => {
+				// Unmodified page, read from the backing file (anything past its end is zero)
+				let n = try!(node.read(pos, dst));
+				for b in &mut dst[n..] {
+					*b = 0;
+				}
+				},
+			}
+			done += count;
+		}
+		Ok(len)
+	}
+
+	fn write(&mut self, node: &CacheHandle, ofs: u64, src: &[u8]) -> super::Result<usize> {
+		use lib::vec_map::Entry;
+		let end = try!(ofs.checked_add(src.len() as u64).ok_or(super::Error::InvalidParameter));
+		let mut done = 0;
+		while done < src.len() {
+			let pos = ofs + done as u64;
+			let page = pos / ::PAGE_SIZE as u64;
+			let page_ofs = (pos % ::PAGE_SIZE as u64) as usize;
+			let count = ::core::cmp::min(src.len() - done, ::PAGE_SIZE - page_ofs);
+			let data = match self.pages.entry(page)
+				{
+				Entry::Occupied(e) => e.into_mut(),
+				Entry::Vacant(e) => {
+					let mut data = Vec::from_elem(::PAGE_SIZE, 0u8).into_boxed_slice();
+					let page_start = page * ::PAGE_SIZE as u64;
+					if page_start < self.size {
+						let valid = ::core::cmp::min(::PAGE_SIZE as u64, self.size - page_start) as usize;
+						try!(node.read(page_start, &mut data[..valid]));
+					}
+					e.insert(data)
+					},
+				};
+			data[page_ofs .. page_ofs + count].copy_from_slice( &src[done .. done + count] );
+			done += count;
+		}
+		self.size = ::core::cmp::max(self.size, end);
+		Ok(src.len())
+	}
 }
 
-pub struct MemoryMapHandle<'a>
+/// A memory mapping of a file, unmapped (and written back, for `WriteBack` mappings) when dropped
+pub struct MemoryMapHandle
 {
-	handle: &'a File,
+	/// Handle to the file (a clone of the handle used to create the mapping)
+	handle: File,
 	base: *mut (),
 	len: usize,
+	/// File offset of `base`
+	ofs: u64,
+	/// Changes are written back to the file (`MemoryMapMode::WriteBack`)
+	writeback: bool,
 }
 
 impl File
@@ -139,21 +243,29 @@ impl File
 		if !node.is_file() {
 			return Err(super::Error::TypeMismatch);
 		}
-		match mode
-		{
-		// TODO: Mark file as shared
-		// TODO: Check permissions (must be readable in current context)
-		FileOpenMode::SharedRO => {},
-		// TODO: Mark file as shared
-		// TODO: Check permissions (must be executable in current context)
-		FileOpenMode::Execute => {},
-		_ => todo!("Acquire lock depending on mode({:?})", mode),
-		}
-		Ok(File { node: node, mode: mode })
+		// TODO: Check permissions (readable/writable/executable in current context)
+		try!(node.file_lock(&mode));
+		let private = match mode
+			{
+			FileOpenMode::UniqueRW => Some(Arc::new( ::sync::Mutex::new( PrivateCopy::new(node.get_valid_size()) ) )),
+			_ => None,
+			};
+		Ok(File { node: node, mode: mode, private: private })
 	}
 	
+	/// Obtain the file's metadata (the size reflects any private changes)
+	pub fn get_metadata(&self) -> super::Result<super::node::Metadata> {
+		let mut rv = try!(self.node.get_metadata());
+		rv.size = self.size();
+		Ok(rv)
+	}
+
 	pub fn size(&self) -> u64 {
-		self.node.get_valid_size()
+		match self.private
+		{
+		Some(ref p) => p.lock().size,
+		None => self.node.get_valid_size(),
+		}
 	}
 
 	/// Read data from the file at the specified offset
@@ -162,15 +274,40 @@ impl File
 	/// slice).
 	pub fn read(&self, ofs: u64, dst: &mut [u8]) -> super::Result<usize> {
 		assert!(self.node.is_file());
-		self.node.read(ofs, dst)
+		match self.mode
+		{
+		FileOpenMode::Append => Err(super::Error::PermissionDenied),
+		FileOpenMode::UniqueRW => self.private.as_ref().unwrap().lock().read(&self.node, ofs, dst),
+		_ => self.node.read(ofs, dst),
+		}
 	}
+	/// Write data to the file at the specified offset
+	///
+	/// Writes that pass the end of the file extend it (a gap between the end and `ofs` is
+	/// zero-filled). For `Append` handles, `ofs` is ignored and the data is written at the end.
+	///
+	/// Returns the number of bytes written. On a `sync` mount, an error from the flush that follows the
+	/// write is returned as-is, in which case the data may already be in the file (or on disk).
 	pub fn write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
 		assert!(self.node.is_file());
-		todo!("Handle::write({:#x}, {:p}+{}", ofs, src.as_ptr(), src.len());
+		match self.mode
+		{
+		FileOpenMode::SharedRO => Err(super::Error::PermissionDenied),
+		FileOpenMode::Execute => Err(super::Error::PermissionDenied),
+		FileOpenMode::Append => {
+			let (_ofs, count) = try!(self.node.append(src));
+			Ok(count)
+			},
+		FileOpenMode::ExclRW => self.node.write(ofs, src),
+		FileOpenMode::Unsynch => self.node.write(ofs, src),
+		FileOpenMode::UniqueRW => self.private.as_ref().unwrap().lock().write(&self.node, ofs, src),
+		}
 	}
 
 	
 	/// Map a file into the address space
+	///
+	/// The returned handle keeps a clone of this handle (so the file stays open while it's mapped)
 	pub fn memory_map(&self, address: usize, ofs: u64, size: usize, mode: MemoryMapMode) -> super::Result<MemoryMapHandle> {
 		log_debug!("memory_map(self={{mode:{:?}}}, address={:#x}, ofs={:#x}, size={:#x}, mode={:?})",
 			self.mode, address, ofs, size, mode);
@@ -200,28 +337,37 @@ impl File
 			//FileOpenMode::SharedRO => {},
 			_ => return Err(super::Error::PermissionDenied),
 			},
-		// Writeback - Requires exclusive access to the file (or a copy)
+		// Writeback - Requires exclusive access to the file
+		// - No other handle can see the file's contents change, so the mapping is the only view
+		// - Not allowed for `UniqueRW`, as the private copy is discarded on close (so the writes would be lost)
 		MemoryMapMode::WriteBack => match self.mode
 			{
-			//FileOpenMode::ExclRW => /* NOTE: Needs extra checks to ensure that aliasing does not occur */
-			//FileOpenMode::UniqueRW => /* NOTE: Needs extra checks to ensure that aliasing does not occur */
+			FileOpenMode::ExclRW => {},
 			_ => return Err(super::Error::PermissionDenied),
 			},
 		}
 		
-		// TODO: Handle unaligned mappings somehow
-		// - Depends on several qirks:
-		//  > Unaligned address could write to an existing page (converting it to a private) - But how would that interact with existing mappings?
-		//  > Unaligned sizes would usually cause a new anon mapping, but if its unaligned becuase of EOF, it should just be COW as usual
-		assert!(address % ::PAGE_SIZE == 0, "TODO: Unaligned memory_map (address={})", address);
-		assert!(size % ::PAGE_SIZE == 0, "TODO: Unaligned memory_map (size={})", size);
+		if size == 0 {
+			return Err( super::Error::InvalidParameter );
+		}
 		if address % ::PAGE_SIZE != (ofs % ::PAGE_SIZE as u64) as usize {
 			return Err( super::Error::Unknown("memory_map alignment mismatch") );
 		}
+		// - Unaligned mappings are expanded to whole pages (the address and offset have the same alignment)
+		//  > The page containing an unaligned start must not already be mapped (the reservation fails)
+		//  > Data past the end of the file is zero, and is never written back
+		let page_ofs = address % ::PAGE_SIZE;
+		let address = address - page_ofs;
+		let ofs = ofs - page_ofs as u64;
+		let page_count = (page_ofs + size + ::PAGE_SIZE - 1) / ::PAGE_SIZE;
 		// - Limit checking (ofs + size must be within size of the file)
-		// TODO: Limit checking
+		// TODO: Limit checking for read-only mappings
+		let writeback = match mode { MemoryMapMode::WriteBack => true, _ => false };
+		if writeback && ofs + ((page_count - 1) * ::PAGE_SIZE) as u64 >= self.size() {
+			// Every page of a writeback mapping must contain part of the file (they don't extend it)
+			return Err( super::Error::InvalidParameter );
+		}
 		// - Reserve the region to be mapped (reserve sticks a zero page in)
-		let page_count = size / ::PAGE_SIZE;
 		let mut resv = match ::memory::virt::reserve(address as *mut (), page_count)
 			{
 			Ok(v) => v,
@@ -238,7 +384,7 @@ impl File
 			//  - If found, map over region
 			// 2. Drop lock, read data from file, and try again
 			//drop(lh)
-			try!( self.node.read(page * ::PAGE_SIZE as u64, resv.get_mut_page(i)) );
+			try!( self.read(page * ::PAGE_SIZE as u64, resv.get_mut_page(i)) );
 			// 3. Acquire write on lock, and attempt to insert a handle to this page
 			//let lh = self.page_cache.write();
 			//match lh.try_insert(pag, self.get_page_handle(i))
@@ -258,37 +404,149 @@ impl File
 			})
 			.unwrap();
 		log_debug!("- Mapped at {:p} + {:#x}", address as *mut (), page_count * ::PAGE_SIZE);
+		if writeback {
+			if let FileOpenMode::ExclRW = self.mode {
+				self.node.file_writeback_map(true);
+			}
+		}
 		Ok(MemoryMapHandle {
-			handle: self,
+			handle: self.clone(),
 			base: address as *mut (),
 			len: page_count * ::PAGE_SIZE,
+			ofs: ofs,
+			writeback: writeback,
 			})
 	}
+
+	/// Write back the contents of a `WriteBack` memory mapping of this file
+	///
+	/// `data` is the mapped memory, starting at file offset `ofs`. Only pages that differ from the
+	/// file are written, and nothing past the end of the file is written (the file isn't extended).
+	fn write_back_mapping(&self, ofs: u64, data: &[u8]) -> super::Result<()> {
+		match self.mode
+		{
+		FileOpenMode::ExclRW => {},
+		_ => return Err(super::Error::PermissionDenied),
+		}
+		let size = self.size();
+		let mut buf = vec![0u8; ::PAGE_SIZE];
+		for (i, page) in data.chunks(::PAGE_SIZE).enumerate()
+		{
+			let page_ofs = ofs + (i * ::PAGE_SIZE) as u64;
+			if page_ofs >= size {
+				break;
+			}
+			// - Unaligned tail: only the part within the file is written
+			let len = ::core::cmp::min(page.len() as u64, size - page_ofs) as usize;
+			let page = &page[..len];
+			// No hardware dirty tracking, so compare against the file's current contents
+			let count = try!(self.read(page_ofs, &mut buf[..len]));
+			if count != len || &buf[..len] != page {
+				try!(self.write(page_ofs, page));
+			}
+		}
+		Ok( () )
+	}
+}
+impl ::core::fmt::Debug for File
+{
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		write!(f, "File {{ node: {:?}, mode: {:?} }}", self.node, self.mode)
+	}
+}
+impl Clone for File
+{
+	fn clone(&self) -> File {
+		// A clone shares the original's lock (and private copy), so can't conflict with it
+		// - An error means the lock counts are already inconsistent, logged by `file_lock_dup`
+		let _ = self.node.file_lock_dup(&self.mode);
+		File {
+			node: self.node.clone(),
+			mode: self.mode.clone(),
+			private: self.private.clone(),
+		}
+	}
 }
 impl ::core::ops::Drop for File
 {
 	fn drop(&mut self) {
-		match self.mode
-		{
-		FileOpenMode::SharedRO => {},
-		FileOpenMode::Execute => {},
-		_ => todo!("File::drop() - mode={:?}", self.mode),
-		}
-		// TODO: For files, we need to release the lock
+		// - Errors are logged by `file_unlock`, there's nothing else to do with them here
+		let _ = self.node.file_unlock(&self.mode);
 	}
 }
 
-impl<'a> Drop for MemoryMapHandle<'a>
+// SAFE: The mapped region is owned by the handle, and only accessed through it
+unsafe impl Send for MemoryMapHandle {}
+
+impl MemoryMapHandle
+{
+	/// Returns `true` if this is a `WriteBack` mapping of the same file as `file`
+	pub fn is_writeback_of(&self, file: &File) -> bool {
+		self.writeback && self.handle.node.get_mount_id() == file.node.get_mount_id() && self.handle.node.get_inode() == file.node.get_inode()
+	}
+	/// Offset within the file of `len` bytes at `addr`, or `None` if they aren't all within the mapping
+	pub fn file_offset(&self, addr: usize, len: usize) -> Option<u64> {
+		let base = self.base as usize;
+		if addr < base || len > self.len || addr - base > self.len - len {
+			None
+		}
+		else {
+			Some(self.ofs + (addr - base) as u64)
+		}
+	}
+
+	/// Write changes back to the file (for `MemoryMapMode::WriteBack` mappings)
+	pub fn sync(&self) -> super::Result<()> {
+		self.sync_range(self.base as usize, self.len)
+	}
+	/// Write back the changes to `len` bytes at `addr` (which must be within the mapping)
+	pub fn sync_range(&self, addr: usize, len: usize) -> super::Result<()> {
+		if !self.writeback {
+			return Err(super::Error::PermissionDenied);
+		}
+		let ofs = match self.file_offset(addr, len)
+			{
+			Some(v) => v,
+			None => return Err(super::Error::InvalidParameter),
+			};
+		// SAFE: The range is within the region, which is mapped and owned by this handle (and only read)
+		let data = unsafe { ::core::slice::from_raw_parts(addr as *const u8, len) };
+		self.handle.write_back_mapping(ofs, data)
+	}
+
+	/// Release the handle without writing back or unmapping the memory
+	///
+	/// For when the address space holding the mapping is being destroyed (and isn't the current one).
+	pub fn abandon(mut self) {
+		if self.writeback {
+			if let FileOpenMode::ExclRW = self.handle.mode {
+				self.handle.node.file_writeback_map(false);
+			}
+			self.writeback = false;
+		}
+		self.len = 0;
+	}
+}
+impl Drop for MemoryMapHandle
 {
 	fn drop(&mut self)
 	{
-		let _ = self.handle;
-		assert_eq!(self.len % ::PAGE_SIZE, 0, "TODO: Handle unaligned lengths in MemoryMapHandle::drop");
-		assert_eq!(self.base as usize % ::PAGE_SIZE, 0, "TODO: Handle unaligned addresses in MemoryMapHandle::drop");
+		if self.writeback {
+			if let Err(e) = self.sync() {
+				log_error!("Error writing back memory map {:p}+{:#x} on unmap: {:?}", self.base, self.len, e);
+			}
+			if let FileOpenMode::ExclRW = self.handle.mode {
+				self.handle.node.file_writeback_map(false);
+			}
+		}
+		// NOTE: `base` and `len` are always page aligned (unaligned mappings are expanded)
+		// - `len` is zero for abandoned handles
 		let npages = self.len / ::PAGE_SIZE;
-		// SAFE: This is a uniquely owned handle
-		unsafe {
-			::memory::virt::unmap(self.base, npages);
+		if npages > 0 {
+			// SAFE: This is a uniquely owned handle
+			unsafe {
+				::memory::virt::unmap(self.base, npages);
+			}
 		}
 	}
 }
@@ -313,6 +571,11 @@ impl Dir
 		}
 	}
 	
+	/// Obtain the directory's metadata
+	pub fn get_metadata(&self) -> super::Result<super::node::Metadata> {
+		self.node.get_metadata()
+	}
+	
 	/// Create a new directory
 	pub fn mkdir(&self, name: &str) -> super::Result<Dir> {
 		let node = try!(self.node.create(name.as_ref(), NodeType::Dir));
@@ -325,6 +588,20 @@ impl Dir
 		Ok( () )
 	}
 
+	/// Remove the child `name` from this directory
+	///
+	/// Open handles to the node remain valid, non-empty directories can't be removed.
+	pub fn unlink(&self, name: &ByteStr) -> super::Result<()> {
+		self.node.unlink(name)
+	}
+
+	/// Move the child `src_name` to `dst_name` within `dst_dir`
+	///
+	/// The destination must be on the same mount, and the name must not already exist.
+	pub fn rename(&self, src_name: &ByteStr, dst_dir: &Dir, dst_name: &ByteStr) -> super::Result<()> {
+		self.node.rename(src_name, &dst_dir.node, dst_name)
+	}
+
 	/// Open a child of this node
 	pub fn open_child(&self, name: &ByteStr) -> super::Result<Any> {
 		let node = try!(self.node.open_child(name));
@@ -341,6 +618,11 @@ impl Dir
 	pub fn read_ents(&self, pos: usize, ents: &mut super::node::ReadDirCallback) -> super::Result<usize> {
 		self.node.read_dir(pos, ents)
 	}
+
+	/// Subscribe to changes to this directory's entries (see `vfs::watch`)
+	pub fn watch(&self) -> super::Result<super::watch::Watch> {
+		super::watch::Watch::new(self.node.clone())
+	}
 }
 
 pub struct DirIter<'a> {
@@ -393,3 +675,24 @@ impl Symlink
 		self.node.get_target()
 	}
 }
+
+impl Special
+{
+	pub fn open(path: &Path) -> super::Result<Special> {
+		try!(Any::open(path)).to_special()
+	}
+	/// String describing the kind of node (e.g. "volume")
+	pub fn typename(&self) -> super::Result<&str> {
+		self.node.get_typename()
+	}
+	pub fn read(&self, ofs: u64, dst: &mut [u8]) -> super::Result<usize> {
+		self.node.special_read(ofs, dst)
+	}
+	pub fn write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
+		self.node.special_write(ofs, src)
+	}
+	/// Send a node-specific control request, `data` is replaced by the response (returning its length)
+	pub fn control(&self, code: u32, data: &mut [u8]) -> super::Result<usize> {
+		self.node.special_control(code, data)
+	}
+}
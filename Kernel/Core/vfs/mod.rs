@@ -33,6 +33,10 @@ pub enum Error
 	NonDirComponent,
 	/// Symbolic link recursion limit reached
 	RecursionDepthExceeded,
+	/// Operation would cross between filesystems (e.g. renaming to another mount)
+	CrossFilesystem,
+	/// Directory still has entries (e.g. when removing it)
+	DirectoryNotEmpty,
 
 
 	/// Block-level IO Error
@@ -43,6 +47,8 @@ pub enum Error
 	InconsistentFilesystem,
 	/// Volume ran out of space
 	OutOfSpace,
+	/// File would exceed the maximum size supported by the filesystem
+	FileTooLarge,
 
 	/// System has run out of memory
 	OutOfMemory,
@@ -74,14 +80,22 @@ pub mod node;
 pub mod mount;
 pub mod handle;
 mod path;
+mod dentry;
 mod ramfs;
+pub mod devfs;
+mod procfs;
+pub mod watch;
 
 fn init()
 {
 	// 1. Initialise global structures
 	mount::init();
 	node::init();
+	dentry::init();
+	watch::init();
 	ramfs::init();
+	devfs::init();
+	procfs::init();
 	// 2. Start the root/builtin filesystems
 	mount::mount("/".as_ref(), VolumeHandle::new_ramdisk(0), "ramfs", &[]).expect("Unable to mount /");
 	// 3. Initialise root filesystem layout
@@ -93,5 +107,9 @@ fn init()
 	root.mkdir("system").unwrap();
 	root.mkdir("volumes").unwrap();
 	root.mkdir("temp").unwrap();
+	root.mkdir("dev").unwrap();
+	mount::mount("/dev".as_ref(), VolumeHandle::new_ramdisk(0), "devfs", &[]).expect("Unable to mount /dev");
+	root.mkdir("proc").unwrap();
+	mount::mount("/proc".as_ref(), VolumeHandle::new_ramdisk(0), "procfs", &[]).expect("Unable to mount /proc");
 }
 
@@ -4,9 +4,10 @@
 // Core/vfs/mount.rs
 //! Mountpoint managment
 use prelude::*;
-use super::path::Path;
+use super::path::{Path,PathBuf};
 use super::node::{InodeId,Node,CacheHandle};
 use sync::RwLock;
+use core::sync::atomic::{AtomicBool,Ordering};
 use lib::{LazyStatic,SparseVec,VecMap};
 
 use metadevs::storage::VolumeHandle;
@@ -26,6 +27,77 @@ struct MountedVolume
 {
 	mountpoint_node: CacheHandle,
 	fs: Box<dyn Filesystem>,
+	flags: MountFlags,
+	desc: MountDesc,
+}
+
+/// Description of a mount (for `enum_mounts`)
+struct MountDesc
+{
+	path: PathBuf,
+	volume: String,
+	fs: &'static str,
+}
+
+/// Information about a mounted volume, returned by `enum_mounts`
+pub struct MountInfo
+{
+	/// Mountpoint
+	pub path: PathBuf,
+	/// Name of the logical volume (empty for volume-less filesystems, e.g. ramfs)
+	pub volume: String,
+	/// Filesystem driver name
+	pub fs: &'static str,
+	pub readonly: bool,
+	pub noexec: bool,
+	pub sync: bool,
+}
+
+/// VFS-level mount flags (enforced by the node cache, not the filesystem)
+struct MountFlags
+{
+	/// `ro`/`rw` - Refuse all modifications
+	readonly: AtomicBool,
+	/// `noexec`/`exec` - Refuse to open files for execution
+	noexec: AtomicBool,
+	/// `sync`/`async` - Flush the filesystem after every write
+	sync: AtomicBool,
+}
+impl MountFlags
+{
+	const fn new() -> MountFlags {
+		MountFlags {
+			readonly: AtomicBool::new(false),
+			noexec: AtomicBool::new(false),
+			sync: AtomicBool::new(false),
+			}
+	}
+	/// Apply the generic options from `options`, returning the remaining (driver-specific) options
+	///
+	/// Later options override earlier ones (e.g. `ro,rw` is read-write)
+	fn parse<'a>(&self, options: &[&'a str]) -> Vec<&'a str> {
+		let mut rv = Vec::new();
+		for &opt in options
+		{
+			match opt
+			{
+			"ro" => self.readonly.store(true, Ordering::Relaxed),
+			"rw" => self.readonly.store(false, Ordering::Relaxed),
+			"noexec" => self.noexec.store(true, Ordering::Relaxed),
+			"exec" => self.noexec.store(false, Ordering::Relaxed),
+			"sync" => self.sync.store(true, Ordering::Relaxed),
+			"async" => self.sync.store(false, Ordering::Relaxed),
+			"" => {},
+			_ => rv.push(opt),
+			}
+		}
+		rv
+	}
+	fn copy_from(&self, other: &MountFlags) {
+		self.readonly.store(other.readonly.load(Ordering::Relaxed), Ordering::Relaxed);
+		self.noexec.store(other.noexec.load(Ordering::Relaxed), Ordering::Relaxed);
+		self.sync.store(other.sync.load(Ordering::Relaxed), Ordering::Relaxed);
+	}
 }
 
 
@@ -35,6 +107,20 @@ pub trait Filesystem:
 {
 	fn root_inode(&self) -> InodeId;
 	fn get_node_by_inode(&self, InodeId) -> Option<Node>;
+
+	/// Write any cached state back to the volume
+	///
+	/// Called before unmounting (the instance is then dropped) and when switching to read-only
+	fn flush(&self) -> super::Result<()> {
+		Ok( () )
+	}
+	/// Notification that the mount is switching between read-only and read-write
+	///
+	/// Return an error to refuse the change (e.g. if the volume can't be written)
+	fn set_readonly(&self, readonly: bool) -> super::Result<()> {
+		let _ = readonly;
+		Ok( () )
+	}
 }
 
 struct NullFs;
@@ -54,8 +140,10 @@ pub trait Driver:
 
 	/// Mount the provided volume as this filesystem
 	///
+	/// `options` contains the mount options (e.g. `size=16M` for ramfs)
+	///
 	/// NOTE: `handle` isn't actually usable until after this function returns
-	fn mount(&self, vol: VolumeHandle, handle: SelfHandle) -> super::Result<Box<dyn Filesystem>>;
+	fn mount(&self, vol: VolumeHandle, handle: SelfHandle, options: &[&str]) -> super::Result<Box<dyn Filesystem>>;
 }
 
 pub struct DriverRegistration(&'static str);
@@ -66,6 +154,9 @@ static S_DRIVERS: LazyStatic<RwLock< VecMap<&'static str, &'static dyn Driver> >
 static S_VOLUMES: LazyStatic<RwLock< SparseVec<MountedVolume> >> = lazystatic_init!();
 /// Root mount
 static S_ROOT_VOLUME: RwLock<Option<Box<dyn Filesystem>>> = RwLock::new(None);
+/// Flags for the root mount
+static S_ROOT_FLAGS: MountFlags = MountFlags::new();
+static S_ROOT_DESC: RwLock<Option<MountDesc>> = RwLock::new(None);
 
 pub fn init()
 {
@@ -77,44 +168,62 @@ pub fn init()
 }
 
 /// Mount a volume at the provided location
-// TODO: Parse options
-pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) -> Result<(),MountError>
+///
+/// The generic options `ro`, `rw`, `noexec`, `exec`, `sync` and `async` are handled by the VFS, all
+/// other options are passed to the filesystem driver.
+pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, options: &[&str]) -> Result<(),MountError>
 {
+	let flags = MountFlags::new();
+	// An explicit `rw` must be honoured (the filesystem may otherwise fall back to read-only)
+	let explicit_rw = options.iter().rev().find(|&&o| o == "ro" || o == "rw") == Some(&"rw");
+	let options = flags.parse(options);
+	let readonly = flags.readonly.load(Ordering::Relaxed);
+
 	let drivers = S_DRIVERS.read();
 	// 1. (maybe) detect filesystem
-	let driver = if fs == "" {
+	let (fs_name, driver) = if fs == "" {
 			match drivers.iter()
 				.filter_map(|(n,fs)| fs.detect(&vol).ok().map(|r| (r, n, fs)))
 				.max_by_key(|&(l,_,_)| l)
 			{
 			Some((0,_,_)) => return Err(MountError::NoHandler),
-			Some((_,_name,fs)) => fs,
+			Some((_,name,fs)) => (*name, *fs),
 			None => return Err(MountError::NoHandler),
 			}
 		}
 		else {
-			match drivers.get(fs)
+			match drivers.iter().find(|&(n,_)| *n == fs)
 			{
-			Some(d) => d,
+			Some((name,d)) => (*name, *d),
 			None => {
 				log_notice!("Filesystem '{}' not registered", fs);
 				return Err(MountError::UnknownFilesystem);
 				},
 			}
 		};
+	let desc = MountDesc {
+		path: PathBuf::from(location),
+		volume: String::from(vol.name()),
+		fs: fs_name,
+		};
 	
 	if location == Path::new("/")
 	{
-		let fs: Box<_> = match driver.mount(vol, SelfHandle(0))
+		let fs: Box<_> = match driver.mount(vol, SelfHandle(0), &options)
 			{
 			Ok(v) => v,
 			Err(_) => return Err(MountError::CallFailed),
 			};
+		if (readonly || explicit_rw) && fs.set_readonly(readonly).is_err() {
+			return Err(MountError::CallFailed);
+		}
 		let mut lh = S_ROOT_VOLUME.write();
 		if lh.is_some() {
-			log_warning!("TODO: Support remounting /");
+			log_warning!("TODO: Support replacing the root volume");
 			return Err(MountError::MountpointUsed);
 		}
+		S_ROOT_FLAGS.copy_from(&flags);
+		*S_ROOT_DESC.write() = Some(desc);
 		*lh = Some(fs);
 	}
 	else
@@ -134,23 +243,35 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 		
 		// 3. Reserve the mountpoint ID (using a placeholder instance)
 		// NOTE: Nothing should know of this index until after mount is completed
-		let vidx = S_VOLUMES.write().insert(MountedVolume { mountpoint_node: nh, fs: Box::new(NullFs) });
+		let vidx = S_VOLUMES.write().insert(MountedVolume { mountpoint_node: nh, fs: Box::new(NullFs), flags: flags, desc: desc });
 
 		// 4. Mount and register volume
-		let fs = match driver.mount(vol, SelfHandle(vidx))
+		// - A read-only (or explicitly read-write) mount needs the filesystem's agreement too
+		let fs = match driver.mount(vol, SelfHandle(vidx + 1), &options)
 			{
-			Ok(v) => v,
-			Err(_) => return Err(MountError::CallFailed),
+			Ok(ref fs) if (readonly || explicit_rw) && fs.set_readonly(readonly).is_err() => None,
+			Ok(v) => Some(v),
+			Err(_) => None,
+			};
+		let fs = match fs
+			{
+			Some(v) => v,
+			None => {
+				// NOTE: The removed entry is dropped after the lock is released (dropping the mountpoint handle locks the node cache)
+				let _v = S_VOLUMES.write().remove(vidx);
+				return Err(MountError::CallFailed);
+				},
 			};
 
 		// 5. Store and bind to mountpoint
-		{
+		let bound = {
 			let mut lh = S_VOLUMES.write();
 			lh[vidx].fs = fs;
-			if lh[vidx].mountpoint_node.mount(vidx + 1) == false {
-				lh.remove(vidx);
-				return Err(MountError::MountpointUsed);
-			}
+			lh[vidx].mountpoint_node.mount(vidx + 1)
+			};
+		if ! bound {
+			let _v = S_VOLUMES.write().remove(vidx);
+			return Err(MountError::MountpointUsed);
 		}
 	}
 
@@ -164,6 +285,8 @@ pub enum MountError
 	InvalidMountpoint,
 	MountpointUsed,
 	CallFailed,
+	NotMounted,
+	Busy,
 }
 impl_fmt! {
 	Display(self,f) for MountError {
@@ -174,10 +297,141 @@ impl_fmt! {
 			&MountError::InvalidMountpoint => "The specified mountpoint was invalid",
 			&MountError::MountpointUsed => "The specified mountpoint was already used",
 			&MountError::CallFailed => "Driver's mount call failed",
+			&MountError::NotMounted => "No volume is mounted at the specified location",
+			&MountError::Busy => "The volume has open handles",
 			})
 	}
 }
 
+/// Unmount the volume mounted at the provided location
+///
+/// Fails with `MountError::Busy` if any node on the volume is still in use.
+pub fn unmount(location: &Path) -> Result<(),MountError>
+{
+	let nh = match CacheHandle::from_path(location)
+		{
+		Ok(nh) => nh,
+		Err(_) => return Err(MountError::InvalidMountpoint),
+		};
+	let id = nh.get_mount_id();
+	if id == 0 {
+		if nh.get_inode() == Handle(0).root_inode() {
+			log_warning!("TODO: Support unmounting /");
+			return Err(MountError::InvalidMountpoint);
+		}
+		return Err(MountError::NotMounted);
+	}
+	let h = Handle(id);
+	if nh.get_inode() != h.root_inode() {
+		return Err(MountError::NotMounted);
+	}
+
+	// 1. Unbind from the mountpoint (so new lookups don't enter the volume)
+	let mountpoint_node = match h.mountpoint_node()
+		{
+		Ok(Some(v)) => v,
+		// - Removed by a concurrent unmount
+		_ => return Err(MountError::NotMounted),
+		};
+	if ! mountpoint_node.unmount(id) {
+		// Another unmount is in progress
+		return Err(MountError::NotMounted);
+	}
+
+	// 2. Release the root node and remove the volume, checking that nothing else is in use
+	// - The volume list is locked across the check and the removal, so the volume can't be used in between
+	// NOTE: The entry is removed with the lock held, but dropped after (the instance may do IO when dropped)
+	let v = match nh.release_mount_root(|in_use| {
+			let mut lh = S_VOLUMES.write();
+			if in_use() {
+				None
+			}
+			else {
+				lh.remove(id - 1)
+			}
+			})
+		{
+		Ok(v) => v,
+		Err(_nh) => {
+			mountpoint_node.mount(id);
+			return Err(MountError::Busy);
+			},
+		};
+
+	// 3. Flush and tear down the filesystem
+	if let Err(e) = v.fs.flush() {
+		log_error!("Error flushing volume {} on unmount: {:?}", id, e);
+	}
+	drop(v);
+	log_log!("Unmounted volume {} from {:?}", id, location);
+
+	Ok( () )
+}
+
+/// Switch the volume mounted at the provided location between read-only and read-write
+///
+/// Switching to read-only fails with `MountError::Busy` if any file on the volume is open for writing.
+pub fn remount(location: &Path, readonly: bool) -> Result<(),MountError>
+{
+	let nh = match CacheHandle::from_path(location)
+		{
+		Ok(nh) => nh,
+		Err(_) => return Err(MountError::InvalidMountpoint),
+		};
+	let h = Handle(nh.get_mount_id());
+	if nh.get_inode() != h.root_inode() {
+		return Err(MountError::NotMounted);
+	}
+
+	if h.with_flags(|fl| fl.readonly.load(Ordering::SeqCst)) == readonly {
+		return Ok( () );
+	}
+	if let Err(e) = h.with_fs(|fs| fs.set_readonly(readonly)) {
+		log_notice!("Volume {} refused remount (readonly={}): {:?}", h.id(), readonly, e);
+		return Err(MountError::CallFailed);
+	}
+	if readonly {
+		// Set the flag before checking for writers, so no new writers can appear
+		h.with_flags(|fl| fl.readonly.store(true, Ordering::SeqCst));
+		if CacheHandle::mount_has_writers(h.id()) {
+			h.with_flags(|fl| fl.readonly.store(false, Ordering::SeqCst));
+			let _ = h.with_fs(|fs| fs.set_readonly(false));
+			return Err(MountError::Busy);
+		}
+		if let Err(e) = h.with_fs(|fs| fs.flush()) {
+			log_error!("Error flushing volume {} on remount: {:?}", h.id(), e);
+		}
+	}
+	else {
+		h.with_flags(|fl| fl.readonly.store(false, Ordering::SeqCst));
+	}
+
+	Ok( () )
+}
+
+/// Enumerate mounted volumes (the root volume first)
+pub fn enum_mounts() -> Vec<MountInfo>
+{
+	fn info(desc: &MountDesc, flags: &MountFlags) -> MountInfo {
+		MountInfo {
+			path: PathBuf::from(&*desc.path),
+			volume: desc.volume.clone(),
+			fs: desc.fs,
+			readonly: flags.readonly.load(Ordering::Relaxed),
+			noexec: flags.noexec.load(Ordering::Relaxed),
+			sync: flags.sync.load(Ordering::Relaxed),
+			}
+	}
+	let mut rv = Vec::new();
+	if let Some(ref desc) = *S_ROOT_DESC.read() {
+		rv.push( info(desc, &S_ROOT_FLAGS) );
+	}
+	for v in S_VOLUMES.read().iter()
+	{
+		rv.push( info(&v.desc, &v.flags) );
+	}
+	rv
+}
 
 impl DriverRegistration
 {
@@ -195,6 +449,15 @@ impl DriverRegistration
 
 impl Handle
 {
+	/// Obtain a handle to the specified mount, returning `None` if it isn't mounted (any more)
+	pub fn try_from_id(id: usize) -> Option<Handle> {
+		if id == 0 || S_VOLUMES.read().get(id-1).is_some() {
+			Some( Handle(id) )
+		}
+		else {
+			None
+		}
+	}
 	pub fn from_id(id: usize) -> Handle {
 		if id == 0 {
 			Handle(0)
@@ -217,6 +480,38 @@ impl Handle
 	pub fn get_node(&self, id: InodeId) -> Option<Node> {
 		self.with_fs(|fs| fs.get_node_by_inode(id))
 	}
+	/// Returns `true` if the volume is mounted read-only
+	pub fn is_readonly(&self) -> bool {
+		self.with_flags(|fl| fl.readonly.load(Ordering::Relaxed))
+	}
+	/// Returns `true` if files on the volume can't be executed
+	pub fn is_noexec(&self) -> bool {
+		self.with_flags(|fl| fl.noexec.load(Ordering::Relaxed))
+	}
+	/// Returns `true` if writes should be flushed to the volume immediately
+	pub fn is_sync(&self) -> bool {
+		self.with_flags(|fl| fl.sync.load(Ordering::Relaxed))
+	}
+	/// Write any cached state back to the volume
+	pub fn flush(&self) -> super::Result<()> {
+		self.with_fs(|fs| fs.flush())
+	}
+
+	/// Obtain the directory this volume is mounted on (`None` for the root volume)
+	///
+	/// Fails with `Error::NotFound` if the volume has been unmounted
+	pub fn mountpoint_node(&self) -> super::Result<Option<CacheHandle>> {
+		if self.0 == 0 {
+			Ok( None )
+		}
+		else {
+			match S_VOLUMES.read().get(self.0 - 1)
+			{
+			Some(v) => Ok( Some(v.mountpoint_node.clone()) ),
+			None => Err( super::Error::NotFound ),
+			}
+		}
+	}
 
 	fn with_fs<R, F: FnOnce(&dyn Filesystem)->R>(&self, f: F) -> R {
 		if self.0 == 0 {
@@ -226,6 +521,14 @@ impl Handle
 			f(&*S_VOLUMES.read().get(self.0 - 1).unwrap().fs)
 		}
 	}
+	fn with_flags<R, F: FnOnce(&MountFlags)->R>(&self, f: F) -> R {
+		if self.0 == 0 {
+			f(&S_ROOT_FLAGS)
+		}
+		else {
+			f(&S_VOLUMES.read().get(self.0 - 1).unwrap().flags)
+		}
+	}
 }
 
 
@@ -234,5 +537,11 @@ impl SelfHandle
 	pub fn get_node(&self, inode: InodeId) -> super::Result<super::node::CacheHandle> {
 		super::node::CacheHandle::from_ids(self.0, inode)
 	}
+	/// Drop any cached names within the directory `inode`
+	///
+	/// For filesystems whose contents change without going through the VFS (e.g. devfs)
+	pub fn invalidate_dir(&self, inode: InodeId) {
+		super::dentry::invalidate_dir( (self.0, inode) );
+	}
 }
 
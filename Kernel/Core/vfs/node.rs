@@ -8,10 +8,14 @@ use super::Path;
 use sync::mutex::LazyMutex;
 use lib::byte_str::{ByteStr,ByteString};
 use core::sync::atomic::{self,AtomicUsize};
+use super::handle::FileOpenMode;
 
 pub type InodeId = u64;
 pub type Result<T> = ::core::result::Result<T,super::Error>;
 
+/// Maximum number of symbolic links followed when resolving a path
+const MAX_SYMLINK_HOPS: usize = 40;
+
 /// Node type used by `Dir::create`
 #[derive(Debug,PartialEq)]
 pub enum NodeType<'a> {
@@ -27,12 +31,55 @@ pub enum NodeClass {
 	Special,
 }
 
+/// Node metadata (size, link count, ownership, permissions and timestamps)
+///
+/// Fields that a filesystem doesn't support are left at their default values.
+#[derive(Debug,Clone)]
+pub struct Metadata {
+	/// Size of the node's data in bytes (for directories, the on-disk size of the entry list)
+	pub size: u64,
+	/// Number of directory entries that reference this node
+	pub link_count: u32,
+	/// Owning user
+	pub uid: u32,
+	/// Owning group
+	pub gid: u32,
+	/// UNIX-style permission bits (`rwxrwxrwx` plus setuid/setgid/sticky)
+	pub permissions: u16,
+	/// Creation (or status change) time
+	pub ctime: ::time::Timestamp,
+	/// Last modification time
+	pub mtime: ::time::Timestamp,
+	/// Last access time
+	pub atime: ::time::Timestamp,
+}
+impl Default for Metadata {
+	fn default() -> Metadata {
+		Metadata {
+			size: 0,
+			link_count: 1,
+			uid: 0,
+			gid: 0,
+			permissions: 0o755,
+			ctime: 0,
+			mtime: 0,
+			atime: 0,
+		}
+	}
+}
+
 /// Base trait for a VFS node, defines common operation on nodes
 pub trait NodeBase: Send {
 	/// Return the volume's inode number
 	fn get_id(&self) -> InodeId;
 	/// Return an &Any associated with this node (not nessesarily same as `self`, up to the driver)
 	fn get_any(&self) -> &dyn Any;
+	/// Obtain the node's metadata
+	///
+	/// The default returns `Metadata::default()`, the VFS fills in the size of files.
+	fn get_metadata(&self) -> Result<Metadata> {
+		Ok( Default::default() )
+	}
 }
 /// Trait for "File" nodes
 pub trait File: NodeBase {
@@ -73,6 +120,11 @@ pub trait Dir: NodeBase {
 	fn link(&self, name: &ByteStr, inode: &dyn NodeBase) -> Result<()>;
 	/// Remove the specified name
 	fn unlink(&self, name: &ByteStr) -> Result<()>;
+	/// Atomically move the entry `src_name` to `dst_name` in `dst_dir`
+	///
+	/// `dst_dir` is on the same filesystem (checked by the VFS). Fails with `AlreadyExists` if
+	/// the destination name is in use.
+	fn rename(&self, src_name: &ByteStr, dst_dir: &dyn Dir, dst_name: &ByteStr) -> Result<()>;
 }
 /// Trait for symbolic link nodes.
 pub trait Symlink: NodeBase {
@@ -82,11 +134,30 @@ pub trait Symlink: NodeBase {
 	fn read(&self) -> ByteString;
 }
 /// Trait for special files (e.g. unix device files, named pipes)
+///
+/// The data operations default to failing with `Error::TypeMismatch`, nodes implement the ones
+/// that make sense for them.
 pub trait Special: NodeBase {
 	/// Returns a string indicating the type of special node
 	fn typename(&self) -> &str;
-	
-	// TODO: Include an API (similar to the syscall API) to communicate with the node
+
+	/// Read data from the node (the meaning of `ofs` depends on the node type)
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> Result<usize> {
+		let _ = (ofs, buf);
+		Err(super::Error::TypeMismatch)
+	}
+	/// Write data to the node
+	fn write(&self, ofs: u64, buf: &[u8]) -> Result<usize> {
+		let _ = (ofs, buf);
+		Err(super::Error::TypeMismatch)
+	}
+	/// Node-specific control operation
+	///
+	/// `data` holds the request on entry, and the response on return. Returns the length of the response.
+	fn control(&self, code: u32, data: &mut [u8]) -> Result<usize> {
+		let _ = (code, data);
+		Err(super::Error::TypeMismatch)
+	}
 }
 
 /// VFS Node
@@ -101,7 +172,13 @@ pub enum Node
 enum CacheNodeInt
 {
 	File {
-		fsnode: Box<dyn File>
+		/// Serialises operations that change the file's size (extending writes and appends)
+		extend_lock: ::sync::Mutex<()>,
+		/// Open handle counts (used to enforce `FileOpenMode` locking)
+		locks: ::sync::Mutex<FileLocks>,
+		/// Directory this file was last opened through (told about writes, for `watch`)
+		parent: ::sync::Mutex<Option<InodeId>>,
+		fsnode: Box<dyn File>,
 		
 		// File memory map data
 		//mapped_pages: HashMap<u64,FrameHandle>,
@@ -122,7 +199,7 @@ impl_from!{
 	From<Node>(v) for CacheNodeInt {
 		match v
 		{
-		Node::File(f) => CacheNodeInt::File { fsnode: f },
+		Node::File(f) => CacheNodeInt::File { extend_lock: ::sync::Mutex::new(()), locks: ::sync::Mutex::new(FileLocks::new()), parent: ::sync::Mutex::new(None), fsnode: f },
 		Node::Dir(f) => CacheNodeInt::Dir { fsnode: f, mountpoint: AtomicUsize::new(0) },
 		Node::Symlink(f) => CacheNodeInt::Symlink { target: f.read(), fsnode: f },
 		Node::Special(f) => CacheNodeInt::Special { fsnode: f },
@@ -130,31 +207,132 @@ impl_from!{
 	}
 }
 
+/// Number of open handles in each `FileOpenMode`
+struct FileLocks
+{
+	shared_ro: usize,
+	execute: usize,
+	excl_rw: usize,
+	unique_rw: usize,
+	append: usize,
+	unsynch: usize,
+	/// Live `WriteBack` memory mappings through `ExclRW` handles (these modify the file when unmapped)
+	writeback_maps: usize,
+}
+impl FileLocks
+{
+	const fn new() -> FileLocks {
+		FileLocks { shared_ro: 0, execute: 0, excl_rw: 0, unique_rw: 0, append: 0, unsynch: 0, writeback_maps: 0, }
+	}
+	fn count_mut(&mut self, mode: &FileOpenMode) -> &mut usize {
+		match *mode
+		{
+		FileOpenMode::SharedRO => &mut self.shared_ro,
+		FileOpenMode::Execute  => &mut self.execute,
+		FileOpenMode::ExclRW   => &mut self.excl_rw,
+		FileOpenMode::UniqueRW => &mut self.unique_rw,
+		FileOpenMode::Append   => &mut self.append,
+		FileOpenMode::Unsynch  => &mut self.unsynch,
+		}
+	}
+	/// Check if a new handle with the specified mode can coexist with the existing handles
+	fn is_compatible(&self, mode: &FileOpenMode) -> bool {
+		// NOTE: This table is symmetric (if A allows B, then B allows A)
+		match *mode
+		{
+		// Readers only see the file extend, so appenders and private copies are fine
+		FileOpenMode::SharedRO => self.excl_rw == 0 && self.unsynch == 0,
+		// Executables must not change at all
+		FileOpenMode::Execute  => self.excl_rw == 0 && self.append == 0 && self.unsynch == 0,
+		// Exclusive allows only appenders alongside it
+		FileOpenMode::ExclRW   => self.shared_ro == 0 && self.execute == 0 && self.excl_rw == 0 && self.unique_rw == 0 && self.unsynch == 0,
+		// A private copy can't see changes, so is only compatible with non-modifying opens
+		FileOpenMode::UniqueRW => self.excl_rw == 0 && self.append == 0 && self.unsynch == 0,
+		FileOpenMode::Append   => self.execute == 0 && self.unique_rw == 0 && self.unsynch == 0,
+		// Unsynchronised access is only allowed with other unsynchronised handles
+		FileOpenMode::Unsynch  => self.shared_ro == 0 && self.execute == 0 && self.excl_rw == 0 && self.unique_rw == 0 && self.append == 0,
+		}
+	}
+}
+
 struct CachedNode
 {
 	refcount: AtomicUsize,
+	/// Inode number the node is cached under (only changed with the cache locked, see `CacheHandle::update_inode`)
+	inode: ::sync::atomic::AtomicValue<InodeId>,
 	node: CacheNodeInt,
 }
 
 pub struct CacheHandle
 {
 	mountpt: usize,
-	inode: InodeId,
 	ptr: *const CachedNode,
 }
 unsafe impl Sync for CacheHandle {}
 unsafe impl Send for CacheHandle {}
 
-static S_NODE_CACHE: LazyMutex<::lib::VecMap<(usize,InodeId),Box<CachedNode>>> = lazymutex_init!();
+/// Number of buckets in the node cache's hash table
+const NODE_CACHE_BUCKETS: usize = 64;
+
+/// Hash table of cached nodes, keyed on (mount ID, inode)
+///
+/// Each bucket is a sorted map, so collisions only cost a binary search.
+struct NodeTable
+{
+	buckets: Vec< ::lib::VecMap<(usize,InodeId),Box<CachedNode>> >,
+	/// Nodes evicted from `buckets` by a rename re-keying another node over them (freed when their last handle goes)
+	detached: Vec< ((usize,InodeId),Box<CachedNode>) >,
+}
+impl NodeTable
+{
+	fn bucket(key: &(usize,InodeId)) -> usize {
+		super::dentry::hash_ids(key.0, key.1) % NODE_CACHE_BUCKETS
+	}
+	fn entry(&mut self, key: (usize,InodeId)) -> ::lib::vec_map::Entry<(usize,InodeId),Box<CachedNode>> {
+		self.buckets[Self::bucket(&key)].entry(key)
+	}
+	fn get(&self, key: &(usize,InodeId)) -> Option<&Box<CachedNode>> {
+		self.buckets[Self::bucket(key)].get(key)
+	}
+	fn insert(&mut self, key: (usize,InodeId), node: Box<CachedNode>) {
+		self.buckets[Self::bucket(&key)].insert(key, node);
+	}
+	fn remove(&mut self, key: &(usize,InodeId)) -> Option<Box<CachedNode>> {
+		self.buckets[Self::bucket(key)].remove(key)
+	}
+	fn iter<'a>(&'a self) -> impl Iterator<Item=(&'a (usize,InodeId), &'a Box<CachedNode>)> + 'a {
+		self.buckets.iter().flat_map(|b| b.iter())
+			.chain( self.detached.iter().map(|&(ref k, ref v)| (k, v)) )
+	}
+	/// Remove a node with no remaining handles, wherever it is stored
+	fn remove_node(&mut self, mountpt: usize, ptr: *const CachedNode) -> Option<Box<CachedNode>> {
+		// SAFE: Caller guarantees that the pointer is to a node in this table
+		let key = (mountpt, unsafe { (*ptr).inode.load(atomic::Ordering::Relaxed) });
+		if self.get(&key).map(|v| &**v as *const CachedNode) == Some(ptr) {
+			self.remove(&key)
+		}
+		else if let Some(i) = self.detached.iter().position(|&(_, ref v)| &**v as *const CachedNode == ptr) {
+			Some( self.detached.swap_remove(i).1 )
+		}
+		else {
+			None
+		}
+	}
+}
+
+static S_NODE_CACHE: LazyMutex<NodeTable> = lazymutex_init!();
 
 pub fn init()
 {
-	S_NODE_CACHE.init(|| Default::default());
+	S_NODE_CACHE.init(|| NodeTable {
+		buckets: Vec::from_fn(NODE_CACHE_BUCKETS, |_| ::lib::VecMap::new()),
+		detached: Vec::new(),
+		});
 }
 
 impl_fmt! {
 	Debug(self, f) for CacheHandle {
-		write!(f, "CacheHandle {{ {}:{:#x} {:p} }}", self.mountpt, self.inode, self.ptr)
+		write!(f, "CacheHandle {{ {}:{:#x} {:p} }}", self.mountpt, self.get_inode(), self.ptr)
 	}
 }
 
@@ -167,11 +345,28 @@ impl Clone for CacheHandle
 		}
 		CacheHandle {
 			mountpt: self.mountpt,
-			inode: self.inode,
 			ptr: self.ptr,
 			}
 	}
 }
+impl Drop for CacheHandle
+{
+	fn drop(&mut self) {
+		let removed = {
+			let mut lh = S_NODE_CACHE.lock();
+			// SAFE: self.ptr is valid while this handle exists, and the final release is done with the cache locked
+			if unsafe { (*self.ptr).refcount.fetch_sub(1, atomic::Ordering::Relaxed) } == 1 {
+				// - Found by pointer, as the node may have been re-keyed (or evicted) by a rename since this handle was created
+				lh.remove_node(self.mountpt, self.ptr)
+			}
+			else {
+				None
+			}
+			};
+		// Release the node outside of the cache lock (the filesystem may need to open other nodes when flushing)
+		drop(removed);
+	}
+}
 
 impl CacheHandle
 {
@@ -179,7 +374,6 @@ impl CacheHandle
 	pub fn from_ids(mountpoint: usize, inode: InodeId) -> super::Result<CacheHandle>
 	{
 		use lib::vec_map::Entry;
-		// TODO: Use a hashmap of some form and use a fixed-range allocation (same as VMM code)
 		let ptr: *const _ = &**match S_NODE_CACHE.lock().entry( (mountpoint, inode) )
 			{
 			Entry::Occupied(mut e) =>
@@ -188,9 +382,10 @@ impl CacheHandle
 				e.into_mut()
 				},
 			Entry::Vacant(e) =>
-				match super::mount::Handle::from_id(mountpoint).get_node(inode)
+				// NOTE: The volume may have been unmounted since the ID was obtained
+				match super::mount::Handle::try_from_id(mountpoint).and_then(|h| h.get_node(inode))
 				{
-				Some(node) => e.insert(Box::new(CachedNode { node: node.into(), refcount: AtomicUsize::new(1) })),
+				Some(node) => e.insert(Box::new(CachedNode { node: node.into(), inode: ::sync::atomic::AtomicValue::new(inode), refcount: AtomicUsize::new(1) })),
 				None => return Err( super::Error::NotFound ),
 				},
 			};
@@ -202,7 +397,6 @@ impl CacheHandle
 		// - This handles the edge case where a volume is being unmounted
 		let rv = CacheHandle {
 			mountpt: mountpoint,
-			inode: inode,
 			ptr: ptr,
 			};
 
@@ -211,7 +405,12 @@ impl CacheHandle
 			let new_mountpoint = new_mountpoint.load(atomic::Ordering::Relaxed);
 			if new_mountpoint != 0 {
 				// Then recurse (hopefully only once) with the new mountpoint
-				let new_inode = super::mount::Handle::from_id(new_mountpoint).root_inode();
+				let new_inode = match super::mount::Handle::try_from_id(new_mountpoint)
+					{
+					Some(h) => h.root_inode(),
+					// Unmounted while this node was being opened, use the underlying directory
+					None => return Ok(rv),
+					};
 				log_trace!("CacheHandle::from_ids({},{}) => Mount {}, {}",
 					mountpoint, inode,  new_mountpoint, new_inode);
 				return CacheHandle::from_ids(new_mountpoint, new_inode);
@@ -223,10 +422,30 @@ impl CacheHandle
 	}
 	
 	
-	pub fn from_path_at_node(mut node_h: CacheHandle, path: &Path) -> super::Result<CacheHandle>
+	/// Obtain a node handle using a path relative to `node_h`
+	///
+	/// Symbolic links are followed for all but the final component
+	pub fn from_path_at_node(node_h: CacheHandle, path: &Path) -> super::Result<CacheHandle>
 	{
 		log_function!("CacheHandle::from_path_at_node(node_h={:?}, {:?})", node_h, path);
+		let mut parents = Vec::new();
+		let mut hops = 0;
+		let rv = try!(CacheHandle::resolve_path(&mut parents, node_h, path, &mut hops));
+		log_trace!("CacheHandle::from_path_at_node() {:?}", rv);
+		Ok( rv )
+	}
+
+	/// Walk `path` starting at `node_h`
+	///
+	/// `parents` is the stack of directories traversed to reach `node_h` (used for `..` and relative
+	/// symbolic links), and `hops` counts the symbolic links followed so far.
+	fn resolve_path(parents: &mut Vec<CacheHandle>, mut node_h: CacheHandle, path: &Path, hops: &mut usize) -> super::Result<CacheHandle>
+	{
 		let path = if path.is_absolute() {
+				// Absolute paths (e.g. symlink targets) restart at the root
+				parents.clear();
+				let mph = super::mount::Handle::from_id(0);
+				node_h = try!(CacheHandle::from_ids( mph.id(), mph.root_inode() ));
 				try!(path.split_off_first().ok_or(super::Error::MalformedPath)).1
 			}
 			else {
@@ -236,60 +455,86 @@ impl CacheHandle
 		for seg in path
 		{
 			log_trace!("seg = {:?}", seg);
-			// Loop resolving symbolic links
+			// Resolve symbolic links (relative to the directory containing the link)
 			loop
 			{
-				// TODO: Should symlinks be handled in this function? Or should the passed path be without symlinks?
-				node_h = if let CacheNodeInt::Symlink { ref target, .. } = *node_h.as_ref() {
-					//log_debug!("- seg={:?} : SYMLINK {:?}", seg, name);
-					let linkpath = Path::new(&target);
-					if linkpath.is_absolute() {
-						try!(CacheHandle::from_path(linkpath))
-					}
-					else {
-						//TODO: To make this work (or any path-relative symlink), the current position in
-						//      `path` needs to be known.
-						// It can be hacked up though...
-						let parent_segs_len = seg.as_ref().as_ptr() as usize - AsRef::<[u8]>::as_ref(path).as_ptr() as usize;
-						let parent = ByteStr::new( &AsRef::<[u8]>::as_ref(path)[..parent_segs_len] );
-
-						//let segs = [ &path[..pos], &link ];
-						//let p = PathChain::new(&segs);
-						//try!( CacheHandle::from_path(p) )
-						// Recurse with a special chained path type
-						// (that iterates but can't be sliced).
-						todo!("Relative symbolic links {:?} relative to {:?}", target, parent)
-					}
+				let target = match *node_h.as_ref()
+					{
+					CacheNodeInt::Symlink { ref target, .. } => target.clone(),
+					_ => break,
+					};
+				*hops += 1;
+				if *hops > MAX_SYMLINK_HOPS {
+					log_notice!("Symbolic link hop limit reached resolving {:?}", path);
+					return Err(super::Error::RecursionDepthExceeded);
 				}
-				else {
-					break;
-				};
+				let dir = try!(parents.pop().ok_or(super::Error::NonDirComponent));
+				node_h = try!(CacheHandle::resolve_path(parents, dir, Path::new(&target), hops));
+			}
+
+			if ! node_h.is_dir() {
+				return Err(super::Error::NonDirComponent);
+			}
+			if seg == "" || seg == "." {
+				continue ;
+			}
+			if seg == ".." {
+				node_h = match parents.pop()
+					{
+					Some(v) => v,
+					None => try!(node_h.parent_dir()),
+					};
+				continue ;
 			}
 
 			// Look up this component in the current node
-			node_h = match *node_h.as_ref()
+			let next_id = match *node_h.as_ref()
 				{
-				CacheNodeInt::Dir { fsnode: ref dir, .. } => {
-					//log_debug!("- seg={:?} : DIR", seg);
-					let next_id = match dir.lookup(seg)
-						{
-						Ok(v) => v,
-						Err(_) => return Err(super::Error::NotFound),
-						};
-					try!(CacheHandle::from_ids( node_h.mountpt, next_id ))
+				CacheNodeInt::Dir { fsnode: ref dir, .. } => match super::dentry::lookup((node_h.mountpt, node_h.get_inode()), seg, |n| dir.lookup(n))
+					{
+					Ok(v) => v,
+					Err(_) => return Err(super::Error::NotFound),
 					},
 				_ => return Err(super::Error::NonDirComponent),
 				};
+			let next = try!(CacheHandle::from_ids( node_h.mountpt, next_id ));
+			next.set_parent(&node_h);
+			parents.push(node_h);
+			node_h = next;
 		}
-		log_trace!("CacheHandle::from_path_at_node() {:?}", node_h);
 		Ok( node_h )
 	}
 
+	/// Obtain the parent of this directory, moving out of mounted volumes as required
+	///
+	/// The root's parent is itself.
+	fn parent_dir(&self) -> super::Result<CacheHandle>
+	{
+		let mut cur = self.clone();
+		// If this is the root of a mounted volume, the parent is that of the mountpoint
+		while cur.mountpt != 0 && cur.get_inode() == super::mount::Handle::from_id(cur.mountpt).root_inode()
+		{
+			cur = match try!(super::mount::Handle::from_id(cur.mountpt).mountpoint_node())
+				{
+				Some(v) => v,
+				None => break,
+				};
+		}
+		if cur.mountpt == 0 && cur.get_inode() == super::mount::Handle::from_id(0).root_inode() {
+			return Ok(cur);
+		}
+		let parent_id = match *cur.as_ref()
+			{
+			CacheNodeInt::Dir { ref fsnode, .. } => try!(fsnode.lookup(ByteStr::new(".."))),
+			_ => return Err(super::Error::NonDirComponent),
+			};
+		CacheHandle::from_ids(cur.mountpt, parent_id)
+	}
+
 	/// Obtain a node handle using a path
 	pub fn from_path(path: &Path) -> super::Result<CacheHandle>
 	{
 		log_function!("CacheHandle::from_path({:?})", path);
-		// TODO: Support path caching?
 		
 		// - Remove the leading / from the absolute path
 		//  > Also checks that it's actually abolsute
@@ -323,6 +568,9 @@ impl CacheHandle
 	pub fn is_symlink(&self) -> bool {
 		self.get_class() == NodeClass::Symlink
 	}
+	pub fn is_special(&self) -> bool {
+		self.get_class() == NodeClass::Special
+	}
 
 	pub fn get_any(&self) -> &dyn Any {
 		match self.as_ref()
@@ -341,8 +589,16 @@ impl CacheHandle
 		match self.as_ref()
 		{
 		&CacheNodeInt::Dir { ref fsnode, .. } => {
-			let inode = try!(fsnode.create(name, ty));
-			Ok( try!(CacheHandle::from_ids(self.mountpt, inode)) )
+			try!(self.check_writable());
+			let rv = fsnode.create(name, ty);
+			// Creation can replace a negative (or, on case-insensitive filesystems, aliased) cache entry
+			super::dentry::invalidate_dir((self.mountpt, self.get_inode()));
+			let inode = try!(rv);
+			super::watch::notify((self.mountpt, self.get_inode()), super::watch::EV_CREATE);
+			try!(self.sync_if_required());
+			let rv = try!(CacheHandle::from_ids(self.mountpt, inode));
+			rv.set_parent(self);
+			Ok(rv)
 			},
 		_ => Err( super::Error::Unknown("Calling create on non-directory") ),
 		}
@@ -358,16 +614,221 @@ impl CacheHandle
 		match self.as_ref()
 		{
 		&CacheNodeInt::Dir { ref fsnode, .. } => {
-			let inode = try!(fsnode.lookup(name));
-			Ok( try!(CacheHandle::from_ids(self.mountpt, inode)) )
+			let inode = try!(super::dentry::lookup((self.mountpt, self.get_inode()), name, |n| fsnode.lookup(n)));
+			let rv = try!(CacheHandle::from_ids(self.mountpt, inode));
+			rv.set_parent(self);
+			Ok(rv)
 			},
 		_ => Err( super::Error::Unknown("Calling open_child on non-directory") ),
 		}
 	}
+	/// Remove the entry `name` from this directory
+	pub fn unlink(&self, name: &ByteStr) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Dir { ref fsnode, .. } => {
+			try!(self.check_writable());
+			if name == "" || name == "." || name == ".." {
+				return Err( super::Error::InvalidParameter );
+			}
+			let inode = try!(super::dentry::lookup((self.mountpt, self.get_inode()), name, |n| fsnode.lookup(n)));
+			// - Mounted-on directories can't be removed (opening one redirects into the mounted volume)
+			if try!(CacheHandle::from_ids(self.mountpt, inode)).mountpt != self.mountpt {
+				return Err( super::Error::Locked );
+			}
+			let rv = fsnode.unlink(name);
+			super::dentry::invalidate_dir((self.mountpt, self.get_inode()));
+			// The inode number could be reused, so forget any names cached within it
+			super::dentry::invalidate_dir((self.mountpt, inode));
+			try!(rv);
+			super::watch::notify((self.mountpt, self.get_inode()), super::watch::EV_UNLINK);
+			self.sync_if_required()
+			},
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
 }
 /// Directory methods (mountpoint)
 impl CacheHandle
 {
+	/// Move the entry `src_name` in this directory to `dst_name` in `dst_dir`
+	///
+	/// Both directories must be on the same mount (otherwise `Error::CrossFilesystem`)
+	pub fn rename(&self, src_name: &ByteStr, dst_dir: &CacheHandle, dst_name: &ByteStr) -> super::Result<()> {
+		let (fsnode, dst_fsnode) = match (self.as_ref(), dst_dir.as_ref())
+			{
+			(&CacheNodeInt::Dir { ref fsnode, .. }, &CacheNodeInt::Dir { fsnode: ref dst_fsnode, .. }) => (fsnode, dst_fsnode),
+			_ => return Err( super::Error::TypeMismatch ),
+			};
+		if self.mountpt != dst_dir.mountpt {
+			return Err( super::Error::CrossFilesystem );
+		}
+		try!(self.check_writable());
+		if src_name == "" || src_name == "." || src_name == ".." || dst_name == "" || dst_name == "." || dst_name == ".." {
+			return Err( super::Error::InvalidParameter );
+		}
+		if self.get_inode() == dst_dir.get_inode() && src_name == dst_name {
+			return Ok( () );
+		}
+
+		let src_inode = try!(super::dentry::lookup((self.mountpt, self.get_inode()), src_name, |n| fsnode.lookup(n)));
+		let src = try!(CacheHandle::from_ids(self.mountpt, src_inode));
+		if src.mountpt != self.mountpt {
+			return Err( super::Error::Locked );
+		}
+		if src.is_dir() {
+			// A directory can't be moved into itself, so walk up from the destination checking
+			// each parent (stopping at the root, or when the filesystem can't resolve `..`)
+			let mut cur = dst_dir.clone();
+			loop
+			{
+				if cur.get_inode() == src_inode {
+					return Err( super::Error::InvalidParameter );
+				}
+				let parent_inode = match cur.as_ref()
+					{
+					&CacheNodeInt::Dir { ref fsnode, .. } => match fsnode.lookup(ByteStr::new(".."))
+						{
+						Ok(v) => v,
+						Err(_) => break,
+						},
+					_ => break,
+					};
+				if parent_inode == cur.get_inode() {
+					break;
+				}
+				cur = match CacheHandle::from_ids(self.mountpt, parent_inode)
+					{
+					Ok(v) => v,
+					Err(_) => break,
+					};
+			}
+		}
+
+		let rv = fsnode.rename(src_name, &**dst_fsnode, dst_name);
+		if rv.is_ok() {
+			src.update_inode();
+			src.set_parent(dst_dir);
+		}
+		super::dentry::invalidate_dir((self.mountpt, self.get_inode()));
+		super::dentry::invalidate_dir((dst_dir.mountpt, dst_dir.get_inode()));
+		try!(rv);
+		super::watch::notify((self.mountpt, self.get_inode()), super::watch::EV_RENAME);
+		if dst_dir.get_inode() != self.get_inode() {
+			super::watch::notify((dst_dir.mountpt, dst_dir.get_inode()), super::watch::EV_RENAME);
+		}
+		self.sync_if_required()
+	}
+}
+
+impl CacheHandle
+{
+	/// Obtain the metadata for this node
+	pub fn get_metadata(&self) -> super::Result<Metadata> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, .. } => {
+			let mut rv = try!(fsnode.get_metadata());
+			// The file's size is authoritative (the metadata could lag behind writes)
+			rv.size = fsnode.size();
+			Ok(rv)
+			},
+		&CacheNodeInt::Dir { ref fsnode, .. } => fsnode.get_metadata(),
+		&CacheNodeInt::Symlink { ref fsnode, ref target } => {
+			let mut rv = try!(fsnode.get_metadata());
+			if rv.size == 0 {
+				rv.size = target.len() as u64;
+			}
+			Ok(rv)
+			},
+		&CacheNodeInt::Special { ref fsnode, .. } => fsnode.get_metadata(),
+		}
+	}
+	/// Inode number of this node (unique within the mount)
+	pub fn get_inode(&self) -> InodeId {
+		// SAFE: self.ptr is valid while this handle exists
+		unsafe { (*self.ptr).inode.load(atomic::Ordering::Relaxed) }
+	}
+	/// Re-key the cached node if the filesystem changed its inode number
+	///
+	/// Some filesystems (e.g. FAT) number files by the location of their directory entry, so a rename
+	/// changes the number. Leaving the node cached under the old number would make it alias whatever
+	/// node is later given that number.
+	fn update_inode(&self) {
+		let new_inode = match self.as_ref()
+			{
+			&CacheNodeInt::File { ref fsnode, .. } => fsnode.get_id(),
+			&CacheNodeInt::Dir { ref fsnode, .. } => fsnode.get_id(),
+			&CacheNodeInt::Symlink { ref fsnode, .. } => fsnode.get_id(),
+			&CacheNodeInt::Special { ref fsnode, .. } => fsnode.get_id(),
+			};
+		let mut lh = S_NODE_CACHE.lock();
+		// SAFE: self.ptr is valid while this handle exists
+		let cur = unsafe { &*self.ptr };
+		let old_inode = cur.inode.load(atomic::Ordering::Relaxed);
+		if new_inode == old_inode {
+			return ;
+		}
+		if lh.get( &(self.mountpt, old_inode) ).map(|v| &**v as *const CachedNode) != Some(self.ptr) {
+			// Evicted by an earlier rename, stays detached (just update the number)
+			if let Some(e) = lh.detached.iter_mut().find(|e| &*e.1 as *const CachedNode == self.ptr) {
+				e.0 = (self.mountpt, new_inode);
+			}
+			cur.inode.store(new_inode, atomic::Ordering::Relaxed);
+			return ;
+		}
+		let node = lh.remove( &(self.mountpt, old_inode) ).expect("Cached node with open handle absent");
+		if let Some(other) = lh.remove( &(self.mountpt, new_inode) ) {
+			// The new number was opened between the rename and this call, evict that node so this one takes its place
+			// - Its existing handles stay valid, new opens will get this node
+			log_notice!("Renamed node {}:{:#x} replaces cached {:#x}", self.mountpt, old_inode, new_inode);
+			lh.detached.push( ((self.mountpt, new_inode), other) );
+		}
+		cur.inode.store(new_inode, atomic::Ordering::Relaxed);
+		lh.insert( (self.mountpt, new_inode), node );
+	}
+	/// ID of the mount this node is on (see `mount::Handle`)
+	pub fn get_mount_id(&self) -> usize {
+		self.mountpt
+	}
+	/// Flush the filesystem after a modification if the mount is `sync`
+	///
+	/// NOTE: Called after the modification has been made, so an error here doesn't mean it was undone
+	fn sync_if_required(&self) -> super::Result<()> {
+		let mh = super::mount::Handle::from_id(self.mountpt);
+		if mh.is_sync() {
+			mh.flush()
+		}
+		else {
+			Ok( () )
+		}
+	}
+	/// Returns `Error::ReadOnlyFilesystem` if this node's mount doesn't allow modification
+	fn check_writable(&self) -> super::Result<()> {
+		if super::mount::Handle::from_id(self.mountpt).is_readonly() {
+			Err( super::Error::ReadOnlyFilesystem )
+		}
+		else {
+			Ok( () )
+		}
+	}
+	/// Record the directory a file was reached through (ignored for other node types)
+	fn set_parent(&self, dir: &CacheHandle) {
+		if let &CacheNodeInt::File { ref parent, .. } = self.as_ref() {
+			if dir.mountpt == self.mountpt {
+				*parent.lock() = Some(dir.get_inode());
+			}
+		}
+	}
+	/// Tell watchers of this file's directory that it was written
+	fn notify_write(&self) {
+		if let &CacheNodeInt::File { ref parent, .. } = self.as_ref() {
+			if let Some(dir) = *parent.lock() {
+				super::watch::notify((self.mountpt, dir), super::watch::EV_WRITE);
+			}
+		}
+	}
+
 	pub fn is_mountpoint(&self) -> bool {
 		match self.as_ref()
 		{
@@ -387,10 +848,155 @@ impl CacheHandle
 		_ => false,
 		}
 	}
+	/// Remove a mount binding created by `mount`, returns `true` if `filesystem_id` was bound here
+	pub fn unmount(&self, filesystem_id: usize) -> bool {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Dir { ref mountpoint, .. } => {
+			mountpoint.compare_and_swap(filesystem_id, 0, atomic::Ordering::Relaxed) == filesystem_id
+			},
+		_ => false,
+		}
+	}
+
+	/// Release the root node of a mount that is being unmounted
+	///
+	/// `remove` is called with the cache locked (so no nodes on the mount can be opened), and is passed a
+	/// check for other open handles on the mount. It returns `None` if the check failed, in which case
+	/// the handle is returned. Otherwise the root node is released (before `remove`'s result is returned).
+	pub fn release_mount_root<R, F>(self, remove: F) -> ::core::result::Result<R, CacheHandle>
+	where
+		F: FnOnce(&dyn Fn()->bool) -> Option<R>
+	{
+		let removed = {
+			let mut lh = S_NODE_CACHE.lock();
+			let rv = {
+				let lh_r = &*lh;
+				let in_use = || lh_r.iter().any(|(&(mountpt, inode), n)|
+					mountpt == self.mountpt && (inode != self.get_inode() || n.refcount.load(atomic::Ordering::Relaxed) != 1)
+					);
+				remove(&in_use)
+				};
+			match rv
+			{
+			Some(rv) => Some( (rv, lh.remove( &(self.mountpt, self.get_inode()) )) ),
+			None => None,
+			}
+			};
+		match removed
+		{
+		Some( (rv, v) ) => {
+			super::dentry::purge_mount(self.mountpt);
+			// The reference held by `self` was released with the entry
+			::core::mem::forget(self);
+			drop(v);
+			Ok( rv )
+			},
+		None => Err(self),
+		}
+	}
+	/// Returns `true` if any file on the specified mount is open in a modifying mode (or has a `WriteBack` mapping)
+	pub fn mount_has_writers(mountpt: usize) -> bool {
+		let lh = S_NODE_CACHE.lock();
+		lh.iter().any(|(&(m, _), n)|
+			m == mountpt && match n.node
+				{
+				CacheNodeInt::File { ref locks, .. } => {
+					let l = locks.lock();
+					l.excl_rw != 0 || l.append != 0 || l.unsynch != 0 || l.writeback_maps != 0
+					},
+				_ => false,
+				}
+			)
+	}
 }
 /// Normal file methods
 impl CacheHandle
 {
+	/// Register a new file handle with the specified open mode
+	///
+	/// Fails with `Error::Locked` if the mode conflicts with an existing handle, with
+	/// `Error::ReadOnlyFilesystem` if the mode can modify the file and the mount is read-only, and
+	/// with `Error::PermissionDenied` when opening for execution on a `noexec` mount.
+	pub fn file_lock(&self, mode: &FileOpenMode) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref locks, .. } => {
+			match *mode
+			{
+			FileOpenMode::ExclRW | FileOpenMode::Append | FileOpenMode::Unsynch => try!(self.check_writable()),
+			FileOpenMode::Execute => if super::mount::Handle::from_id(self.mountpt).is_noexec() {
+					return Err( super::Error::PermissionDenied );
+				},
+			_ => {},
+			}
+			let mut lh = locks.lock();
+			if !lh.is_compatible(mode) {
+				return Err( super::Error::Locked );
+			}
+			*lh.count_mut(mode) += 1;
+			Ok( () )
+			},
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+	/// Add another reference to an already-held lock (used when duplicating a file handle)
+	///
+	/// Fails with `Error::InvalidParameter` if the lock isn't held (the count is left unchanged)
+	pub fn file_lock_dup(&self, mode: &FileOpenMode) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref locks, .. } => {
+			let mut lh = locks.lock();
+			let count = lh.count_mut(mode);
+			if *count == 0 {
+				log_error!("CacheHandle::file_lock_dup - {:?} not held", mode);
+				return Err( super::Error::InvalidParameter );
+			}
+			*count += 1;
+			Ok( () )
+			},
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+	/// Release a lock acquired by `file_lock`
+	///
+	/// Fails with `Error::InvalidParameter` if the lock isn't held (the count is left unchanged)
+	pub fn file_unlock(&self, mode: &FileOpenMode) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref locks, .. } => {
+			let mut lh = locks.lock();
+			let count = lh.count_mut(mode);
+			if *count == 0 {
+				log_error!("CacheHandle::file_unlock - {:?} not held", mode);
+				return Err( super::Error::InvalidParameter );
+			}
+			*count -= 1;
+			Ok( () )
+			},
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+
+	/// Record the creation (`add=true`) or removal of a `WriteBack` memory mapping of this file
+	pub fn file_writeback_map(&self, add: bool) {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref locks, .. } => {
+			let mut lh = locks.lock();
+			if add {
+				lh.writeback_maps += 1;
+			}
+			else {
+				assert!(lh.writeback_maps > 0, "CacheHandle::file_writeback_map - No mappings to remove");
+				lh.writeback_maps -= 1;
+			}
+			},
+		_ => panic!("CacheHandle::file_writeback_map called on non-file"),
+		}
+	}
+
 	/// Valid size = maximum offset in the file
 	pub fn get_valid_size(&self) -> u64 {
 		match self.as_ref()
@@ -406,6 +1012,81 @@ impl CacheHandle
 		_ => Err( super::Error::Unknown("Calling read on non-file") ),
 		}
 	}
+	/// Write data to the file, extending it if the write passes the current end
+	///
+	/// If `ofs` is past the end of the file, the gap is zero-filled.
+	///
+	/// On a `sync` mount, a failure to flush is returned as an error even though the data has already
+	/// been written to the file (and may have partially reached the disk).
+	pub fn write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, ref extend_lock, .. } => {
+			try!(self.check_writable());
+			// Fast path: Writes entirely within the file don't change the size
+			let end = try!(ofs.checked_add(src.len() as u64).ok_or(super::Error::InvalidParameter));
+			let rv = if end <= fsnode.size() {
+					try!(fsnode.write(ofs, src))
+				}
+				else {
+					let _lh = extend_lock.lock();
+					try!(Self::write_extend(&**fsnode, ofs, src))
+				};
+			self.notify_write();
+			try!(self.sync_if_required());
+			Ok(rv)
+			},
+		_ => Err( super::Error::Unknown("Calling write on non-file") ),
+		}
+	}
+	/// Atomically append data to the end of the file
+	///
+	/// Returns the offset the data was written at, and the number of bytes written. As with `write`, a
+	/// failed flush on a `sync` mount is reported after the data has been appended.
+	pub fn append(&self, src: &[u8]) -> super::Result<(u64, usize)> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, ref extend_lock, .. } => {
+			try!(self.check_writable());
+			let (ofs, count) = {
+				let _lh = extend_lock.lock();
+				let ofs = fsnode.size();
+				(ofs, try!(fsnode.write(ofs, src)))
+				};
+			self.notify_write();
+			try!(self.sync_if_required());
+			Ok( (ofs, count) )
+			},
+		_ => Err( super::Error::Unknown("Calling append on non-file") ),
+		}
+	}
+
+	/// Handle a write that extends the file (must be called with the extend lock held)
+	fn write_extend(fsnode: &dyn File, ofs: u64, src: &[u8]) -> super::Result<usize> {
+		let size = fsnode.size();
+		if ofs > size {
+			// Zero-fill up to the start of the write
+			let new_size = try!(fsnode.truncate(ofs));
+			if new_size != ofs {
+				return Err( super::Error::OutOfSpace );
+			}
+		}
+		let size = ::core::cmp::max(size, ofs);
+
+		// `node::File::write` can only grow the file when writing at the end, so split the write there
+		let inner_len = ::core::cmp::min(src.len() as u64, size - ofs) as usize;
+		let mut written = 0;
+		if inner_len > 0 {
+			written = try!(fsnode.write(ofs, &src[..inner_len]));
+			if written < inner_len {
+				return Ok(written);
+			}
+		}
+		if inner_len < src.len() {
+			written += try!(fsnode.write(size, &src[inner_len..]));
+		}
+		Ok(written)
+	}
 }
 
 
@@ -421,18 +1102,49 @@ impl CacheHandle
 	}
 }
 
+/// Special node methods
 impl CacheHandle
 {
-	fn as_ref(&self) -> &CacheNodeInt {
-		let lh = S_NODE_CACHE.lock();
-		// SAFE: While this handle is active, the box will be present
-		unsafe {
-			let box_r = lh.get( &(self.mountpt, self.inode) ).expect("Cached node with open handle absent");
-			let cn_ref: &CachedNode = &**box_r;
-			// Dereference a raw pointer to disconnect the lifetimes
-			&*(&cn_ref.node as *const _)
+	pub fn get_typename(&self) -> super::Result<&str> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Special { ref fsnode } => Ok(fsnode.typename()),
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+	pub fn special_read(&self, ofs: u64, dst: &mut [u8]) -> super::Result<usize> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Special { ref fsnode } => fsnode.read(ofs, dst),
+		_ => Err( super::Error::TypeMismatch ),
 		}
 	}
+	pub fn special_write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Special { ref fsnode } => {
+			try!(self.check_writable());
+			fsnode.write(ofs, src)
+			},
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+	pub fn special_control(&self, code: u32, data: &mut [u8]) -> super::Result<usize> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::Special { ref fsnode } => fsnode.control(code, data),
+		_ => Err( super::Error::TypeMismatch ),
+		}
+	}
+}
+
+impl CacheHandle
+{
+	fn as_ref(&self) -> &CacheNodeInt {
+		// NOTE: Uses the pointer instead of a cache lookup, as the node can be re-keyed by a rename
+		// SAFE: While this handle is active, the box will be present (and boxed, so doesn't move)
+		unsafe { &(*self.ptr).node }
+	}
 }
 //impl ::core::convert::AsMut<Node> for CacheHandle
 //{
@@ -0,0 +1,248 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/procfs.rs
+//! Kernel information filesystem (mounted at `/proc`)
+//!
+//! Exposes kernel state (threads, processes, volumes, mounts and modules) as read-only text files.
+//! A file's contents are generated on first access, and again on each read from the start of the
+//! file - so a reader that reads the file in order sees a fresh snapshot.
+use prelude::*;
+use vfs;
+use super::{mount, node};
+use metadevs::storage::VolumeHandle;
+use lib::byte_str::ByteStr;
+use core::fmt::Write;
+
+pub struct Driver;
+pub static S_DRIVER: Driver = Driver;
+
+const ROOT_INODE: node::InodeId = 1;
+/// Inode of the first file (the rest follow in `FILES` order)
+const FILE_INODE_BASE: node::InodeId = 2;
+
+/// Files in the root directory, and the functions that generate their contents
+const FILES: &'static [(&'static str, fn(&mut String))] = &[
+	("threads", gen_threads),
+	("processes", gen_processes),
+	("pvs", gen_pvs),
+	("lvs", gen_lvs),
+	("mounts", gen_mounts),
+	("modules", gen_modules),
+	];
+
+struct ProcFs;
+struct RootDir;
+struct InfoFile
+{
+	inode: node::InodeId,
+	gen: fn(&mut String),
+	/// Generated contents (NOTE: Not generated on load, as `get_node_by_inode` is called with the mount table locked)
+	data: ::sync::Mutex<Option<Vec<u8>>>,
+}
+
+pub fn init()
+{
+	let h = mount::DriverRegistration::new("procfs", &S_DRIVER);
+	::core::mem::forget(h);
+}
+
+impl mount::Driver for Driver
+{
+	fn detect(&self, _vol: &VolumeHandle) -> vfs::Result<usize> {
+		// procfs is never backed by a real volume
+		Ok(0)
+	}
+	fn mount(&self, _vol: VolumeHandle, _handle: mount::SelfHandle, options: &[&str]) -> vfs::Result<Box<dyn mount::Filesystem>> {
+		for opt in options
+		{
+			log_notice!("procfs: Unknown option '{}'", opt);
+		}
+		Ok( Box::new(ProcFs) )
+	}
+}
+
+impl mount::Filesystem for ProcFs
+{
+	fn root_inode(&self) -> node::InodeId {
+		ROOT_INODE
+	}
+	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
+		if id == ROOT_INODE {
+			Some(node::Node::Dir(Box::new(RootDir)))
+		}
+		else {
+			id.checked_sub(FILE_INODE_BASE)
+				.and_then(|i| FILES.get(i as usize))
+				.map(|&(_, gen)| node::Node::File(Box::new(InfoFile { inode: id, gen: gen, data: Default::default() })))
+		}
+	}
+}
+
+impl node::NodeBase for RootDir {
+	fn get_id(&self) -> node::InodeId {
+		ROOT_INODE
+	}
+	fn get_any(&self) -> &dyn (::core::any::Any) {
+		self
+	}
+}
+impl node::Dir for RootDir {
+	fn lookup(&self, name: &ByteStr) -> vfs::Result<node::InodeId> {
+		match FILES.iter().position(|&(n,_)| name == n)
+		{
+		Some(i) => Ok(FILE_INODE_BASE + i as node::InodeId),
+		None => Err(vfs::Error::NotFound),
+		}
+	}
+	fn read(&self, start_ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize> {
+		let mut count = 0;
+		for (i, &(name, _)) in FILES.iter().enumerate().skip(start_ofs)
+		{
+			count += 1;
+			if ! callback(FILE_INODE_BASE + i as node::InodeId, &mut name.bytes()) {
+				break ;
+			}
+		}
+		Ok(start_ofs + count)
+	}
+	fn create(&self, _name: &ByteStr, _nodetype: node::NodeType) -> vfs::Result<node::InodeId> {
+		Err(vfs::Error::PermissionDenied)
+	}
+	fn link(&self, _name: &ByteStr, _node: &dyn node::NodeBase) -> vfs::Result<()> {
+		Err(vfs::Error::PermissionDenied)
+	}
+	fn unlink(&self, _name: &ByteStr) -> vfs::Result<()> {
+		Err(vfs::Error::PermissionDenied)
+	}
+	fn rename(&self, _src_name: &ByteStr, _dst_dir: &dyn node::Dir, _dst_name: &ByteStr) -> vfs::Result<()> {
+		Err(vfs::Error::PermissionDenied)
+	}
+}
+
+impl InfoFile {
+	/// Call `f` with the file contents, generating them if not yet present (or if `regenerate` is set)
+	fn with_data<R, F: FnOnce(&[u8])->R>(&self, regenerate: bool, f: F) -> R {
+		let mut lh = self.data.lock();
+		if lh.is_none() || regenerate {
+			let mut s = String::new();
+			(self.gen)(&mut s);
+			*lh = Some( Vec::from(s.as_bytes()) );
+		}
+		f(lh.as_ref().unwrap())
+	}
+}
+impl node::NodeBase for InfoFile {
+	fn get_id(&self) -> node::InodeId {
+		self.inode
+	}
+	fn get_any(&self) -> &dyn (::core::any::Any) {
+		self
+	}
+	fn get_metadata(&self) -> vfs::Result<node::Metadata> {
+		Ok(node::Metadata { permissions: 0o444, ..Default::default() })
+	}
+}
+impl node::File for InfoFile {
+	fn size(&self) -> u64 {
+		self.with_data(false, |d| d.len() as u64)
+	}
+	fn truncate(&self, _newsize: u64) -> vfs::Result<u64> {
+		Err(vfs::Error::PermissionDenied)
+	}
+	fn clear(&self, _ofs: u64, _size: u64) -> vfs::Result<()> {
+		Err(vfs::Error::PermissionDenied)
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+		// A read from the start gets the current state
+		self.with_data(ofs == 0, |d| {
+			if ofs >= d.len() as u64 {
+				return Ok(0);
+			}
+			let src = &d[ofs as usize ..];
+			let len = ::core::cmp::min(src.len(), buf.len());
+			buf[..len].copy_from_slice(&src[..len]);
+			Ok(len)
+			})
+	}
+	fn write(&self, _ofs: u64, _buf: &[u8]) -> vfs::Result<usize> {
+		Err(vfs::Error::PermissionDenied)
+	}
+}
+
+// NOTE: Names are printed last on each line, as they can contain spaces
+/// `threads` - One line per thread
+fn gen_threads(s: &mut String)
+{
+	let _ = writeln!(s, "{:>8} {:>8} NAME", "TID", "PID");
+	for t in ::threads::enum_threads()
+	{
+		let _ = writeln!(s, "{:>8} {:>8} {}", t.tid, t.pid, t.name);
+	}
+}
+/// `processes` - One line per process with live threads
+fn gen_processes(s: &mut String)
+{
+	// PID => (Name, Thread count)
+	let mut procs: ::lib::VecMap<::threads::ProcessID, (String, usize)> = ::lib::VecMap::new();
+	for t in ::threads::enum_threads()
+	{
+		match procs.entry(t.pid)
+		{
+		::lib::vec_map::Entry::Occupied(e) => e.into_mut().1 += 1,
+		::lib::vec_map::Entry::Vacant(e) => { e.insert( (t.process_name, 1) ); },
+		}
+	}
+	let _ = writeln!(s, "{:>8} {:>8} NAME", "PID", "THREADS");
+	for (pid, &(ref name, count)) in procs.iter()
+	{
+		let _ = writeln!(s, "{:>8} {:>8} {}", pid, count, name);
+	}
+}
+/// `pvs` - Physical volumes
+fn gen_pvs(s: &mut String)
+{
+	let _ = writeln!(s, "{:>4} NAME", "IDX");
+	for (idx, name) in ::metadevs::storage::enum_pvs()
+	{
+		let _ = writeln!(s, "{:>4} {}", idx, name);
+	}
+}
+/// `lvs` - Logical volumes
+fn gen_lvs(s: &mut String)
+{
+	let _ = writeln!(s, "{:>4} NAME", "IDX");
+	for (idx, name) in ::metadevs::storage::enum_lvs()
+	{
+		let _ = writeln!(s, "{:>4} {}", idx, name);
+	}
+}
+/// `mounts` - Mounted volumes: `<volume> <path> <fs> <options>`
+fn gen_mounts(s: &mut String)
+{
+	for m in mount::enum_mounts()
+	{
+		let path: &[u8] = (*m.path).as_ref();
+		let _ = writeln!(s, "{} {} {} {}{}{}",
+			if m.volume.len() == 0 { "none" } else { &*m.volume },
+			::core::str::from_utf8(path).unwrap_or("?"),
+			m.fs,
+			if m.readonly { "ro" } else { "rw" },
+			if m.noexec { ",noexec" } else { "" },
+			if m.sync { ",sync" } else { "" },
+			);
+	}
+}
+/// `modules` - Statically linked modules and their initialisation state
+fn gen_modules(s: &mut String)
+{
+	for (name, state) in ::modules::enum_modules()
+	{
+		let _ = writeln!(s, "{} {}", name, match state
+			{
+			::modules::ModuleState::Uninitialised => "uninitialised",
+			::modules::ModuleState::Resolving => "initialising",
+			::modules::ModuleState::Initialised => "initialised",
+			});
+	}
+}
@@ -10,13 +10,20 @@ use metadevs::storage::VolumeHandle;
 use lib::{VecMap,SparseVec};
 use lib::byte_str::{ByteStr,ByteString};
 use lib::mem::aref::{Aref,ArefInner,ArefBorrow};
+use core::sync::atomic::{AtomicUsize,Ordering};
 
 pub struct Driver;
 pub static S_DRIVER: Driver = Driver;
 
+struct RamNode
+{
+	/// Number of directory entries referring to this node
+	link_count: AtomicUsize,
+	file: RamFile,
+}
 enum RamFile
 {
-	//File(RamFileFile),
+	File(RamFileFile),
 	Dir(RamFileDir),
 	Symlink(RamFileSymlink),
 }
@@ -30,13 +37,20 @@ struct RamFileSymlink
 {
 	target: super::PathBuf,
 }
-//#[derive(Default)]
-//struct RamFileFile
-//{
-//	ofs: usize,
-//	size: usize,
-//}
-struct FileRef(ArefBorrow<RamFSInner>,ArefBorrow<RamFile>);
+#[derive(Default)]
+struct RamFileFile
+{
+	data: ::sync::RwLock<RamFileData>,
+}
+#[derive(Default)]
+struct RamFileData
+{
+	size: u64,
+	/// File contents, one entry per page (`None` for pages that have never been written)
+	pages: Vec<Option<Box<[u8]>>>,
+}
+/// NOTE: The node borrow is manually dropped, so an orphaned node can be freed along with its last reference
+struct FileRef(ArefBorrow<RamFSInner>,::core::mem::ManuallyDrop<ArefBorrow<RamNode>>,node::InodeId);
 
 struct RamFS
 {
@@ -47,7 +61,13 @@ struct RamFSInner
 	_vh: VolumeHandle,
 	// TODO: Store as much data (and metadata) as possible on the volume
 	// - Possibly by using an allocation pool backed onto the volume
-	nodes: ::sync::Mutex< SparseVec<Aref<RamFile>> >,
+	nodes: ::sync::Mutex< SparseVec<Aref<RamNode>> >,
+	/// Unlinked nodes that were still in use when their last link was removed
+	orphans: ::sync::Mutex< Vec<usize> >,
+	/// Maximum number of bytes of file data (from the `size=` option)
+	size_limit: Option<u64>,
+	/// Number of file data pages allocated
+	used_pages: AtomicUsize,
 }
 
 pub fn init()
@@ -56,21 +76,53 @@ pub fn init()
 	::core::mem::forget(h);
 }
 
+/// Parse a size with an optional `k`/`m`/`g` suffix
+fn parse_size(s: &str) -> Option<u64> {
+	let (num, mult) = match s.as_bytes().last()
+		{
+		Some(&b'k') | Some(&b'K') => (&s[..s.len()-1], 1 << 10),
+		Some(&b'm') | Some(&b'M') => (&s[..s.len()-1], 1 << 20),
+		Some(&b'g') | Some(&b'G') => (&s[..s.len()-1], 1 << 30),
+		_ => (s, 1),
+		};
+	num.parse::<u64>().ok().and_then(|v| v.checked_mul(mult))
+}
+
 impl mount::Driver for Driver
 {
 	fn detect(&self, _vol: &VolumeHandle) -> super::Result<usize> {
 		// RAMFS should never bind to an arbitary volume
 		Ok(0)
 	}
-	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle) -> super::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _: mount::SelfHandle, options: &[&str]) -> super::Result<Box<dyn mount::Filesystem>> {
+		let mut size_limit = None;
+		for opt in options
+		{
+			if opt.starts_with("size=") {
+				match parse_size(&opt[5..])
+				{
+				Some(v) => size_limit = Some(v),
+				None => {
+					log_notice!("ramfs: Invalid size option '{}'", opt);
+					return Err(vfs::Error::InvalidParameter);
+					},
+				}
+			}
+			else {
+				log_notice!("ramfs: Unknown option '{}'", opt);
+			}
+		}
 		let rv = Box::new(RamFS {
 			// SAFE: ArefInner must not change addresses, but because you can't move out of a boxed trait, we're good
 			inner: unsafe { ArefInner::new( RamFSInner {
 				_vh: vol,
 				nodes: Default::default(),
+				orphans: Default::default(),
+				size_limit: size_limit,
+				used_pages: AtomicUsize::new(0),
 				}) },
 			});
-		let root_inode = rv.inner.nodes.lock().insert( Aref::new(RamFile::Dir(Default::default())) );
+		let root_inode = rv.inner.nodes.lock().insert( Aref::new(RamNode::new(RamFile::Dir(Default::default()))) );
 		assert_eq!(root_inode, 0);
 		Ok(rv)
 	}
@@ -84,48 +136,137 @@ impl mount::Filesystem for RamFS
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
 		log_trace!("RamFS::get_node_by_inode({})", id);
 		let nodes = self.inner.nodes.lock();
-		if id >= nodes.len() as node::InodeId {
-			log_log!("RamFile::get_node_by_inode - Inode {} out of range", id);
-			None
-		}
-		else {
-			let fr = Box::new(FileRef(
-				self.inner.borrow(),
-				nodes[id as usize].borrow()
-				));
-			match *nodes[id as usize]
+		let n = match nodes.get(id as usize)
 			{
-			RamFile::Dir(_) => Some(node::Node::Dir(fr)),
-			RamFile::Symlink(_) => Some(node::Node::Symlink(fr)),
-			//RamFile::File(_) => todo!("normal files"),
+			Some(v) => v,
+			None => {
+				log_log!("RamFile::get_node_by_inode - Inode {} out of range", id);
+				return None;
+				},
+			};
+		let fr = Box::new(FileRef(
+			self.inner.borrow(),
+			::core::mem::ManuallyDrop::new(n.borrow()),
+			id,
+			));
+		match n.file
+		{
+		RamFile::Dir(_) => Some(node::Node::Dir(fr)),
+		RamFile::Symlink(_) => Some(node::Node::Symlink(fr)),
+		RamFile::File(_) => Some(node::Node::File(fr)),
+		}
+	}
+}
+
+impl RamNode {
+	fn new(file: RamFile) -> RamNode {
+		RamNode {
+			link_count: AtomicUsize::new(1),
+			file: file,
+		}
+	}
+}
+
+impl RamFSInner {
+	/// Allocate a zeroed page of file data (checking the size limit)
+	fn alloc_page(&self) -> vfs::Result<Box<[u8]>> {
+		let used = self.used_pages.fetch_add(1, Ordering::Relaxed) + 1;
+		if let Some(limit) = self.size_limit {
+			if used as u64 * ::PAGE_SIZE as u64 > limit {
+				self.used_pages.fetch_sub(1, Ordering::Relaxed);
+				return Err(vfs::Error::OutOfSpace);
+			}
+		}
+		Ok( Vec::from_elem(::PAGE_SIZE, 0u8).into_boxed_slice() )
+	}
+	fn release_pages(&self, count: usize) {
+		self.used_pages.fetch_sub(count, Ordering::Relaxed);
+	}
+
+	/// Remove a node once it has no links, deferring if it's still in use
+	fn release_node(&self, inode: usize) {
+		self.orphans.lock().push(inode);
+		self.reap_orphans();
+	}
+	/// Free any orphaned nodes that are no longer referenced
+	fn reap_orphans(&self) {
+		let mut orphans = self.orphans.lock();
+		let mut nodes = self.nodes.lock();
+		let mut i = 0;
+		while i < orphans.len()
+		{
+			let inode = orphans[i];
+			// `get_mut` only succeeds if there are no outstanding borrows (i.e. the node isn't cached)
+			let freed_pages = match Aref::get_mut(&mut nodes[inode])
+				{
+				Some(n) => match n.file
+					{
+					RamFile::File(ref mut f) => Some( f.data.get_mut().pages.iter().filter(|p| p.is_some()).count() ),
+					_ => Some(0),
+					},
+				None => None,
+				};
+			if let Some(count) = freed_pages {
+				self.release_pages(count);
+				nodes.remove(inode);
+				orphans.remove(i);
+			}
+			else {
+				i += 1;
 			}
 		}
 	}
 }
 
+impl ::core::ops::Drop for FileRef {
+	fn drop(&mut self) {
+		let unlinked = self.1.link_count.load(Ordering::Relaxed) == 0;
+		// SAFE: The borrow is dropped exactly once, and not accessed afterwards
+		unsafe { ::core::mem::ManuallyDrop::drop(&mut self.1); }
+		if unlinked {
+			self.0.reap_orphans();
+		}
+	}
+}
 impl FileRef {
 	fn dir(&self) -> &RamFileDir {
-		match &*self.1
+		match &self.1.file
 		{
 		&RamFile::Dir(ref e) => e,
 		_ => panic!("Called FileRef::dir() on non-dir"),
 		}
 	}
 	fn symlink(&self) -> &RamFileSymlink {
-		match &*self.1
+		match &self.1.file
 		{
 		&RamFile::Symlink(ref e) => e,
 		_ => panic!("Called FileRef::symlink() on non-symlink"),
 		}
 	}
+	fn file(&self) -> &RamFileFile {
+		match &self.1.file
+		{
+		&RamFile::File(ref e) => e,
+		_ => panic!("Called FileRef::file() on non-file"),
+		}
+	}
 }
 impl node::NodeBase for FileRef {
 	fn get_id(&self) -> node::InodeId {
-		unimplemented!()
+		self.2
 	}
 	fn get_any(&self) -> &dyn (::core::any::Any) {
 		self
 	}
+	fn get_metadata(&self) -> vfs::Result<node::Metadata> {
+		let link_count = self.1.link_count.load(Ordering::Relaxed) as u32;
+		Ok(match &self.1.file
+			{
+			&RamFile::Dir(_) => node::Metadata { permissions: 0o755, link_count: link_count, ..Default::default() },
+			&RamFile::Symlink(_) => node::Metadata { permissions: 0o777, link_count: link_count, ..Default::default() },
+			&RamFile::File(ref f) => node::Metadata { permissions: 0o644, link_count: link_count, size: f.data.read().size, ..Default::default() },
+			})
+	}
 }
 impl node::Dir for FileRef {
 	fn lookup(&self, name: &ByteStr) -> vfs::Result<node::InodeId> {
@@ -136,7 +277,7 @@ impl node::Dir for FileRef {
 		None => Err(vfs::Error::NotFound),
 		}
 	}
-	
+
 	fn read(&self, start_ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize> {
 		let lh = self.dir().ents.read();
 		let mut count = 0;
@@ -150,7 +291,7 @@ impl node::Dir for FileRef {
 		}
 		Ok(start_ofs + count)
 	}
-	
+
 	fn create(&self, name: &ByteStr, nodetype: node::NodeType) -> vfs::Result<node::InodeId> {
 		use lib::vec_map::Entry;
 		let mut lh = self.dir().ents.write();
@@ -161,22 +302,103 @@ impl node::Dir for FileRef {
 			let nn = match nodetype
 				{
 				node::NodeType::Dir  => RamFile::Dir (Default::default()),
-				//node::NodeType::File => RamFile::File(Default::default()),
-				node::NodeType::File => return Err(vfs::Error::Unknown("TODO: Files")),
+				node::NodeType::File => RamFile::File(Default::default()),
 				node::NodeType::Symlink(v) =>
 					RamFile::Symlink(RamFileSymlink{target: From::from(v)}),
 				};
-			let inode = self.0.nodes.lock().insert( Aref::new(nn) );
+			let inode = self.0.nodes.lock().insert( Aref::new(RamNode::new(nn)) );
 			e.insert(inode);
 			Ok(inode as node::InodeId)
 			},
 		}
 	}
 	fn link(&self, name: &ByteStr, node: &dyn node::NodeBase) -> vfs::Result<()> {
-		todo!("<FileRef as Dir>::link({:?}, inode={})", name, node.get_id())
+		use lib::vec_map::Entry;
+		let target = match node.get_any().downcast_ref::<FileRef>()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::CrossFilesystem),
+			};
+		if &*self.0 as *const RamFSInner != &*target.0 as *const RamFSInner {
+			return Err(vfs::Error::CrossFilesystem);
+		}
+		// Directories can't be hard linked (it would create loops)
+		if let RamFile::Dir(_) = target.1.file {
+			return Err(vfs::Error::TypeMismatch);
+		}
+		let mut lh = self.dir().ents.write();
+		match lh.entry(From::from(name))
+		{
+		Entry::Occupied(_) => Err(vfs::Error::AlreadyExists),
+		Entry::Vacant(e) => {
+			target.1.link_count.fetch_add(1, Ordering::Relaxed);
+			e.insert(target.2 as usize);
+			Ok( () )
+			},
+		}
 	}
 	fn unlink(&self, name: &ByteStr) -> vfs::Result<()> {
-		todo!("<FileRef as Dir>::unlink({:?})", name)
+		let mut lh = self.dir().ents.write();
+		let inode = match lh.get(name)
+			{
+			Some(&v) => v,
+			None => return Err(vfs::Error::NotFound),
+			};
+		let node = match self.0.nodes.lock().get(inode)
+			{
+			Some(v) => v.borrow(),
+			None => return Err(vfs::Error::InconsistentFilesystem),
+			};
+		if let RamFile::Dir(ref d) = node.file {
+			if d.ents.read().iter().next().is_some() {
+				return Err(vfs::Error::DirectoryNotEmpty);
+			}
+		}
+		lh.remove(name);
+		let last_link = node.link_count.fetch_sub(1, Ordering::Relaxed) == 1;
+		drop(node);
+		if last_link {
+			self.0.release_node(inode);
+		}
+		Ok( () )
+	}
+	fn rename(&self, src_name: &ByteStr, dst_dir: &dyn node::Dir, dst_name: &ByteStr) -> vfs::Result<()> {
+		let dst = match dst_dir.get_any().downcast_ref::<FileRef>()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::CrossFilesystem),
+			};
+		if &*self.0 as *const RamFSInner != &*dst.0 as *const RamFSInner {
+			return Err(vfs::Error::CrossFilesystem);
+		}
+		let (src_ents, dst_ents) = (&self.dir().ents, &dst.dir().ents);
+
+		if src_ents as *const _ == dst_ents as *const _ {
+			let mut lh = src_ents.write();
+			if lh.get(dst_name).is_some() {
+				return Err(vfs::Error::AlreadyExists);
+			}
+			let inode = try!(lh.remove(src_name).ok_or(vfs::Error::NotFound));
+			lh.insert(From::from(dst_name), inode);
+		}
+		else {
+			// Lock the directories in address order, to avoid deadlocks with a reversed rename
+			let (mut src_lh, mut dst_lh);
+			if (src_ents as *const _ as usize) < (dst_ents as *const _ as usize) {
+				src_lh = src_ents.write();
+				dst_lh = dst_ents.write();
+			}
+			else {
+				dst_lh = dst_ents.write();
+				src_lh = src_ents.write();
+			}
+			if dst_lh.get(dst_name).is_some() {
+				return Err(vfs::Error::AlreadyExists);
+			}
+			let inode = try!(src_lh.remove(src_name).ok_or(vfs::Error::NotFound));
+			dst_lh.insert(From::from(dst_name), inode);
+		}
+		Ok( () )
 	}
 }
 impl node::Symlink for FileRef {
@@ -184,4 +406,117 @@ impl node::Symlink for FileRef {
 		ByteString::from( ByteStr::new(&*self.symlink().target) )
 	}
 }
+impl node::File for FileRef {
+	fn size(&self) -> u64 {
+		self.file().data.read().size
+	}
+	fn truncate(&self, newsize: u64) -> node::Result<u64> {
+		let mut lh = self.file().data.write();
+		if newsize < lh.size {
+			// Zero the tail of the new last page (so a later extension reads zeroes)
+			let tail_ofs = (newsize % ::PAGE_SIZE as u64) as usize;
+			let npages = ((newsize + ::PAGE_SIZE as u64 - 1) / ::PAGE_SIZE as u64) as usize;
+			if tail_ofs != 0 {
+				if let Some(&mut Some(ref mut p)) = lh.pages.get_mut(npages - 1) {
+					for b in &mut p[tail_ofs..] {
+						*b = 0;
+					}
+				}
+			}
+			// Release whole pages past the end
+			if npages < lh.pages.len() {
+				let freed = lh.pages[npages..].iter().filter(|p| p.is_some()).count();
+				lh.pages.truncate(npages);
+				self.0.release_pages(freed);
+			}
+		}
+		// NOTE: Extending just changes the size, unallocated pages read as zero
+		lh.size = newsize;
+		Ok(newsize)
+	}
+	fn clear(&self, ofs: u64, size: u64) -> node::Result<()> {
+		let mut lh = self.file().data.write();
+		let end = match ofs.checked_add(size)
+			{
+			Some(v) if v <= lh.size => v,
+			_ => return Err(vfs::Error::InvalidParameter),
+			};
+		let mut pos = ofs;
+		while pos < end
+		{
+			let page = (pos / ::PAGE_SIZE as u64) as usize;
+			let page_ofs = (pos % ::PAGE_SIZE as u64) as usize;
+			let count = ::core::cmp::min(end - pos, (::PAGE_SIZE - page_ofs) as u64) as usize;
+			if page < lh.pages.len() {
+				if count == ::PAGE_SIZE {
+					// Entire page cleared, release it
+					if lh.pages[page].take().is_some() {
+						self.0.release_pages(1);
+					}
+				}
+				else if let Some(ref mut p) = lh.pages[page] {
+					for b in &mut p[page_ofs .. page_ofs + count] {
+						*b = 0;
+					}
+				}
+			}
+			pos += count as u64;
+		}
+		Ok( () )
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
+		let lh = self.file().data.read();
+		if ofs >= lh.size {
+			return Ok(0);
+		}
+		let len = ::core::cmp::min(buf.len() as u64, lh.size - ofs) as usize;
+		let mut done = 0;
+		while done < len
+		{
+			let pos = ofs + done as u64;
+			let page = (pos / ::PAGE_SIZE as u64) as usize;
+			let page_ofs = (pos % ::PAGE_SIZE as u64) as usize;
+			let count = ::core::cmp::min(len - done, ::PAGE_SIZE - page_ofs);
+			let dst = &mut buf[done .. done + count];
+			match lh.pages.get(page)
+			{
+			Some(&Some(ref p)) => dst.copy_from_slice( &p[page_ofs .. page_ofs + count] ),
+			_ => for b in dst { *b = 0; },
+			}
+			done += count;
+		}
+		Ok(len)
+	}
+	fn write(&self, ofs: u64, buf: &[u8]) -> node::Result<usize> {
+		let mut lh = self.file().data.write();
+		if ofs > lh.size {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let mut done = 0;
+		while done < buf.len()
+		{
+			let pos = ofs + done as u64;
+			let page = (pos / ::PAGE_SIZE as u64) as usize;
+			let page_ofs = (pos % ::PAGE_SIZE as u64) as usize;
+			let count = ::core::cmp::min(buf.len() - done, ::PAGE_SIZE - page_ofs);
+			if page >= lh.pages.len() {
+				lh.pages.resize_with(page + 1, || None);
+			}
+			if lh.pages[page].is_none() {
+				match self.0.alloc_page()
+				{
+				Ok(p) => lh.pages[page] = Some(p),
+				Err(e) => if done == 0 { return Err(e) } else { break },
+				}
+			}
+			lh.pages[page].as_mut().unwrap()[page_ofs .. page_ofs + count].copy_from_slice( &buf[done .. done + count] );
+			done += count;
+		}
+		let end = ofs + done as u64;
+		if end > lh.size {
+			lh.size = end;
+		}
+		Ok(done)
+	}
+}
 
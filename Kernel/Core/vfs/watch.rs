@@ -0,0 +1,125 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/watch.rs
+//! Directory change notifications
+//!
+//! A `Watch` subscribes to a directory, and collects events when entries are created, removed,
+//! renamed or written. Events are raised by the node cache, so they work with every filesystem.
+use prelude::*;
+use lib::VecMap;
+use lib::mem::Arc;
+use sync::mutex::LazyMutex;
+use core::sync::atomic::{AtomicU32,Ordering};
+use super::node::{CacheHandle,InodeId};
+
+/// An entry was created in the directory
+pub const EV_CREATE: u32 = 1 << 0;
+/// An entry was removed from the directory
+pub const EV_UNLINK: u32 = 1 << 1;
+/// An entry was renamed, or moved into/out of the directory
+pub const EV_RENAME: u32 = 1 << 2;
+/// A file in the directory was written
+pub const EV_WRITE: u32 = 1 << 3;
+
+struct WatchInner
+{
+	/// Events raised since the last `take_events`
+	pending: AtomicU32,
+	waiters: ::async::queue::Source,
+}
+
+/// Registered watches, keyed on the watched directory (mount ID, inode)
+static S_WATCHES: LazyMutex< VecMap<(usize, InodeId), Vec<Arc<WatchInner>>> > = lazymutex_init!();
+
+/// Subscription to changes in a directory (unsubscribes when dropped)
+pub struct Watch
+{
+	/// Handle to the directory (keeps the inode number valid while watched)
+	dir: CacheHandle,
+	inner: Arc<WatchInner>,
+}
+
+pub fn init()
+{
+	S_WATCHES.init(|| VecMap::new());
+}
+
+/// Raise `events` on all watches of the directory `dir`
+pub fn notify(dir: (usize, InodeId), events: u32)
+{
+	let lh = S_WATCHES.lock();
+	if let Some(list) = lh.get(&dir)
+	{
+		for w in list
+		{
+			w.pending.fetch_or(events, Ordering::SeqCst);
+			while w.waiters.wake_one() {
+			}
+		}
+	}
+}
+
+impl Watch
+{
+	/// Start watching a directory
+	pub fn new(dir: CacheHandle) -> super::Result<Watch> {
+		if ! dir.is_dir() {
+			return Err(super::Error::TypeMismatch);
+		}
+		let inner = Arc::new(WatchInner {
+			pending: AtomicU32::new(0),
+			waiters: ::async::queue::Source::new(),
+			});
+		match S_WATCHES.lock().entry( (dir.get_mount_id(), dir.get_inode()) )
+		{
+		::lib::vec_map::Entry::Occupied(e) => e.into_mut().push(inner.clone()),
+		::lib::vec_map::Entry::Vacant(e) => { e.insert(vec![inner.clone()]); },
+		}
+		Ok(Watch { dir: dir, inner: inner })
+	}
+
+	/// Return (and clear) the events raised since the last call
+	pub fn take_events(&self) -> u32 {
+		self.inner.pending.swap(0, Ordering::SeqCst)
+	}
+
+	/// Register a sleep object to be signalled when an event is raised
+	pub fn wait_upon(&self, obj: &mut ::threads::SleepObject) {
+		self.inner.waiters.wait_upon(obj);
+		if self.inner.pending.load(Ordering::SeqCst) != 0 {
+			obj.signal();
+		}
+	}
+	/// Remove a sleep object registered with `wait_upon`, returning `true` if events are pending
+	pub fn clear_wait(&self, obj: &mut ::threads::SleepObject) -> bool {
+		self.inner.waiters.clear_wait(obj);
+		self.inner.pending.load(Ordering::SeqCst) != 0
+	}
+}
+impl ::core::ops::Drop for Watch
+{
+	fn drop(&mut self)
+	{
+		let key = (self.dir.get_mount_id(), self.dir.get_inode());
+		let mut lh = S_WATCHES.lock();
+		let is_empty = match lh.get_mut(&key)
+			{
+			Some(list) => {
+				if let Some(pos) = list.iter().position(|w| &**w as *const _ == &*self.inner as *const _) {
+					list.remove(pos);
+				}
+				list.len() == 0
+				},
+			None => false,
+			};
+		if is_empty {
+			lh.remove(&key);
+		}
+	}
+}
+impl_fmt! {
+	Debug(self, f) for Watch {
+		write!(f, "Watch({:?})", self.dir)
+	}
+}
@@ -17,7 +17,7 @@ use kernel::sync::mutex::LazyMutex;
 // NOTES:
 // - Handles wrap logical volume handles
 // - Presents:
-//  > read/write (unbuffered)
+//  > read/write (unbuffered, writes update any cached copy)
 //  > read_inner/get/edit (buffered)
 //
 // - The global cache is registered with the PMM as a source of reclaimable memory
@@ -98,7 +98,10 @@ impl CacheHandle
 	}
 	pub fn write_blocks(&self, block: u64, data: &[u8]) -> Result<(), IoError>
 	{
-		self.vh.write_blocks(block, data)
+		try!( self.vh.write_blocks(block, data) );
+		// Keep cached copies in sync, otherwise a later flush of the page would revert this write
+		self.update_cached(block, data);
+		Ok( () )
 	}
 }
 
@@ -122,6 +125,36 @@ impl CacheHandle
 		Ok(handle)
 	}
 
+	/// Obtain a handle to a cached block (if it's present in the cache)
+	fn lookup_block_meta(&self, cache_block: u64) -> Option<MetaBlockHandle>
+	{
+		let lh = S_BLOCK_CACHE.lock_init(|| Default::default());
+		lh.map.get( &(self.vh.idx(), cache_block) ).map(|v| {
+			let handle = v.borrow();
+			// SAFE: 1. The internal data is boxed, 2. The box won't be dropped while a borrow exists.
+			unsafe { ::core::mem::transmute::<MetaBlockHandle, MetaBlockHandle>(handle) }
+			})
+	}
+	/// Copy data that has just been written to disk into any cached pages covering it
+	fn update_cached(&self, block: u64, data: &[u8])
+	{
+		let bs = self.block_size();
+		let end = block + (data.len() / bs) as u64;
+		let mut cache_block = block - block % self.blocks_per_page();
+		while cache_block < end
+		{
+			let page_end = cache_block + self.blocks_per_page();
+			if let Some(cached_block) = self.lookup_block_meta(cache_block)
+			{
+				let first = ::core::cmp::max(block, cache_block);
+				let last = ::core::cmp::min(end, page_end);
+				let src = &data[(first - block) as usize * bs ..][ .. (last - first) as usize * bs];
+				cached_block.overwrite( (first - cache_block) as usize * bs, src );
+			}
+			cache_block = page_end;
+		}
+	}
+
 	/// Obtain a handle to a cached block.
 	/// NOTE: The returned handle will point to the start of the cache block, which may be larger than the disk block. Remember to check the returned block index.
 	pub fn get_block(&self, block: u64) -> Result<CachedBlockHandle, IoError>
@@ -135,13 +168,9 @@ impl CacheHandle
 		let cached_block = try!(self.get_block(block));
 		let blk_ofs = (block - cached_block.index()) as usize * self.block_size();
 
-		if offset >= self.block_size() {
-			return Err(IoError::InvalidParameter);
-		}
-		if offset >= self.block_size() - blk_ofs {
+		if offset >= self.block_size() || data.len() > self.block_size() - offset {
 			return Err(IoError::InvalidParameter);
 		}
-		assert!(data.len() <= self.block_size() - offset);
 		let bytes = data.len();
 		data.clone_from_slice( &cached_block.data()[blk_ofs + offset .. ][ .. bytes] );
 		Ok( () )
@@ -152,15 +181,12 @@ impl CacheHandle
 		let cached_block = try!(self.get_block_meta(block));
 		let blk_ofs = (block - cached_block.index()) as usize * self.block_size();
 
-		if offset >= self.block_size() {
-			return Err(IoError::InvalidParameter);
-		}
-		if blk_ofs + offset >= self.block_size() {
+		if offset >= self.block_size() || data.len() > self.block_size() - offset {
 			return Err(IoError::InvalidParameter);
 		}
 
 		cached_block.edit(|block_data| {
-			block_data[blk_ofs + offset ..].clone_from_slice( data );
+			block_data[blk_ofs + offset ..][.. data.len()].clone_from_slice( data );
 			Ok( () )
 			})
 	}
@@ -182,6 +208,19 @@ impl CacheHandle
 
 		Ok( rv )
 	}
+
+	/// Write all modified blocks for this volume back to disk
+	pub fn flush(&self) -> Result<(), IoError>
+	{
+		let lh = S_BLOCK_CACHE.lock_init(|| Default::default());
+		for (&(vol_idx, _), block) in lh.map.iter()
+		{
+			if vol_idx == self.vh.idx() {
+				try!( block.flush(&self.vh) );
+			}
+		}
+		Ok( () )
+	}
 }
 
 fn map_cached_frame(frame: &::kernel::memory::phys::FrameHandle) -> ::kernel::memory::page_cache::CachedPage
@@ -253,6 +292,13 @@ impl<'a> MetaBlockHandle<'a>
 		f(dataptr)
 	}
 
+	/// Replace part of the cached data with data already written to disk (so doesn't mark the block as dirty)
+	pub fn overwrite(&self, ofs: usize, src: &[u8]) {
+		let mut lh = self.0.mapping.write();
+		let dataptr = lh.as_mut().expect("CachedBlock mapping is None").data_mut();
+		dataptr[ofs ..][.. src.len()].clone_from_slice(src);
+	}
+
 	pub fn into_ro(self) -> CachedBlockHandle<'a> {
 		let read_handle = self.0.mapping.read();
 		::core::mem::forget(read_handle);
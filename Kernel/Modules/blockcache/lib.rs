@@ -77,5 +77,17 @@ impl BlockCache
 		
 		Ok( data )
 	}
+	
+	/// Remove a block from the cache (e.g. after it has been written back)
+	pub fn invalidate(&self, lba: u32)
+	{
+		let mut lh = self.lru_blocks.lock();
+		for e in lh.iter_mut()
+		{
+			if e.as_ref().map(|e| e.lba == lba).unwrap_or(false) {
+				*e = None;
+			}
+		}
+	}
 }
 
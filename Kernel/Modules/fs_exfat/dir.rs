@@ -0,0 +1,637 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/dir.rs
+use kernel::prelude::*;
+use kernel::lib::mem::aref::ArefBorrow;
+use kernel::lib::mem::Arc;
+use kernel::vfs::{self, node};
+use kernel::lib::byte_str::ByteStr;
+use utf16::Str16;
+use super::on_disk;
+use super::file::FileNode;
+use super::{FilesystemInner,Chain,ClusterList,InodeRef};
+
+/// Maximum size of a directory
+const MAX_DIR_SIZE: u64 = 256 << 20;
+
+/// State shared by all `DirNode`s for the same directory
+pub struct DirState
+{
+	/// First cluster (identifies the directory, and never changes)
+	pub first_cluster: u32,
+	/// Location of the directory's entry set (unused for the root)
+	pub loc: ::kernel::sync::Mutex<DirLoc>,
+	/// Clusters holding the entries (only modified with the directory lock held)
+	pub chain: ::kernel::sync::Mutex<Chain>,
+	/// Metadata from the entry set (default for the root)
+	metadata: node::Metadata,
+	/// Number of `DirNode`s for this directory (only modified with the `dirs` lock held)
+	pub nodes: ::core::sync::atomic::AtomicUsize,
+}
+pub struct DirLoc
+{
+	/// Parent directory (`None` for the root), held so that it stays registered
+	pub parent: Option<Arc<DirState>>,
+	/// Index of the entry set in the parent
+	pub index: u32,
+}
+
+impl DirState
+{
+	pub fn new_root(chain: Chain) -> DirState {
+		Self::new_inner(None, 0, chain, Default::default())
+	}
+	pub fn new(parent: Arc<DirState>, index: u32, chain: Chain, metadata: node::Metadata) -> DirState {
+		Self::new_inner(Some(parent), index, chain, metadata)
+	}
+	fn new_inner(parent: Option<Arc<DirState>>, index: u32, chain: Chain, metadata: node::Metadata) -> DirState {
+		DirState {
+			first_cluster: chain.first_cluster,
+			loc: ::kernel::sync::Mutex::new(DirLoc {
+				parent: parent,
+				index: index,
+				}),
+			chain: ::kernel::sync::Mutex::new(chain),
+			metadata: metadata,
+			nodes: ::core::sync::atomic::AtomicUsize::new(0),
+		}
+	}
+	/// The directory has a node loaded (so the VFS can look up its children)
+	pub fn is_loaded(&self) -> bool {
+		self.nodes.load(::core::sync::atomic::Ordering::Relaxed) > 0
+	}
+}
+
+pub struct DirNode
+{
+	fs: ArefBorrow<FilesystemInner>,
+	first_cluster: u32,
+	state: Arc<DirState>,
+}
+impl_fmt! {
+	Debug(self, f) for DirNode {
+		write!(f, "{{cluster={:#x}}}", self.first_cluster)
+	}
+}
+
+impl DirNode
+{
+	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, state: Arc<DirState>) -> Box<DirNode> {
+		Box::new(Self::new(fs, state))
+	}
+	fn new(fs: ArefBorrow<FilesystemInner>, state: Arc<DirState>) -> DirNode {
+		fs.dir_node_added(&state);
+		DirNode {
+			fs: fs,
+			first_cluster: state.first_cluster,
+			state: state,
+			}
+	}
+
+	/// Load a file from its entry set (at `index` in the directory starting at `dir`)
+	pub fn load_file(fs: ArefBorrow<FilesystemInner>, dir: u32, index: u32) -> Option<node::Node>
+	{
+		let state = match fs.get_dir(dir)
+			{
+			Some(v) => v,
+			None => {
+				log_notice!("load_file: Directory {:#x} hasn't been seen by a lookup", dir);
+				return None;
+				},
+			};
+		let chain = *state.chain.lock();
+		let raw = match fs.read_ent_set(&chain, index)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("load_file: Error reading entry {} of {:#x}: {:?}", index, dir, e);
+				return None;
+				},
+			};
+		match EntSet::parse(&fs, index, raw)
+		{
+		Some(ref set) if !set.is_dir() => {
+			let inode = set.inode(dir);
+			Some(node::Node::File(FileNode::new_boxed(fs, inode, &state, set.chain(), set.metadata())))
+			},
+		_ => None,
+		}
+	}
+}
+
+impl ::core::ops::Drop for DirNode {
+	fn drop(&mut self) {
+		self.fs.dir_node_removed(&self.state);
+	}
+}
+impl node::NodeBase for DirNode {
+	fn get_id(&self) -> node::InodeId {
+		InodeRef::Dir(self.first_cluster).to_id()
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok(node::Metadata {
+			size: self.state.chain.lock().size,
+			..self.state.metadata.clone()
+			})
+	}
+}
+
+/// A parsed entry set (File entry, Stream Extension, and File Name entries)
+struct EntSet
+{
+	/// Index of the File entry within the directory
+	index: u32,
+	/// Raw contents of the set
+	raw: Vec<u8>,
+	file: on_disk::FileEnt,
+	stream: on_disk::StreamEnt,
+	name: Vec<u16>,
+}
+impl EntSet
+{
+	fn parse(fs: &FilesystemInner, index: u32, raw: Vec<u8>) -> Option<EntSet> {
+		if raw.len() < 3*32 || raw[32] != on_disk::ENT_STREAM {
+			return None;
+		}
+		let file = on_disk::FileEnt::read(&raw);
+		let stream = on_disk::StreamEnt::read(&raw[32..]);
+		if on_disk::set_checksum(&raw) != file.set_checksum {
+			log_warning!("exFAT: Entry set checksum mismatch at index {}", index);
+		}
+		// Directories always have clusters (and are identified by the first)
+		if file.attributes & on_disk::ATTR_DIRECTORY != 0 && stream.first_cluster == 0 {
+			return None;
+		}
+		let name_length = stream.name_length as usize;
+		let mut name = Vec::new();
+		for ent in raw[64..].chunks(32)
+		{
+			if ent[0] != on_disk::ENT_NAME || name.len() == name_length {
+				break;
+			}
+			let mut chars = [0u16; on_disk::NAME_ENT_CHARS];
+			on_disk::read_name_ent(ent, &mut chars);
+			let count = ::core::cmp::min(chars.len(), name_length - name.len());
+			name.extend_from_slice(&chars[..count]);
+		}
+		if name_length == 0 || name.len() != name_length {
+			return None;
+		}
+		let rv = EntSet {
+			index: index,
+			raw: raw,
+			file: file,
+			stream: stream,
+			name: name,
+			};
+		// The clusters are used without further checks
+		if !fs.chain_valid(&rv.chain()) {
+			log_warning!("exFAT: Entry set at index {} has an invalid cluster chain", index);
+			return None;
+		}
+		Some(rv)
+	}
+
+	fn is_dir(&self) -> bool {
+		self.file.attributes & on_disk::ATTR_DIRECTORY != 0
+	}
+	fn chain(&self) -> Chain {
+		let allocated = self.stream.flags & on_disk::STREAM_ALLOC_POSSIBLE != 0;
+		Chain {
+			first_cluster: if allocated { self.stream.first_cluster } else { 0 },
+			no_fat_chain: self.stream.flags & on_disk::STREAM_NO_FAT_CHAIN != 0,
+			size: self.stream.data_length,
+			valid_size: ::core::cmp::min(self.stream.valid_data_length, self.stream.data_length),
+		}
+	}
+	/// Inode number for this set (in the directory starting at `dir`)
+	fn inode(&self, dir: u32) -> node::InodeId {
+		if self.is_dir() {
+			InodeRef::Dir(self.stream.first_cluster).to_id()
+		}
+		else {
+			InodeRef::File { dir: dir, index: self.index }.to_id()
+		}
+	}
+	fn metadata(&self) -> node::Metadata {
+		ent_metadata(&self.file, self.stream.data_length)
+	}
+}
+
+fn ent_metadata(file: &on_disk::FileEnt, size: u64) -> node::Metadata {
+	// exFAT has no ownership or permissions, only a read-only flag
+	let mut permissions = if file.attributes & on_disk::ATTR_DIRECTORY != 0 { 0o755 } else { 0o644 };
+	if file.attributes & on_disk::ATTR_READONLY != 0 {
+		permissions &= !0o222;
+	}
+	node::Metadata {
+		size: size,
+		permissions: permissions,
+		ctime: on_disk::timestamp_from_exfat(file.create_time, file.create_10ms, file.create_utc_ofs),
+		mtime: on_disk::timestamp_from_exfat(file.modified_time, file.modified_10ms, file.modified_utc_ofs),
+		atime: on_disk::timestamp_from_exfat(file.accessed_time, 0, file.accessed_utc_ofs),
+		..Default::default()
+	}
+}
+
+impl DirNode
+{
+	/// Iterate the entry sets from entry `start`, stopping when `f` returns `Some`
+	///
+	/// Returns the index after the last set passed to `f` (or of the end marker), and the value returned by `f`
+	fn scan<R, F: FnMut(EntSet)->Option<R>>(&self, start: u32, mut f: F) -> node::Result<(u32, Option<R>)>
+	{
+		let chain = *self.state.chain.lock();
+		let ents_per_cluster = (self.fs.cluster_size / 32) as u32;
+		let mut set = Vec::new();
+		let mut set_start = 0;
+		let mut remaining = 0;
+		let mut idx = start / ents_per_cluster * ents_per_cluster;
+		for c in ClusterList::new(self.fs.reborrow(), &chain).skip((start / ents_per_cluster) as usize)
+		{
+			let cluster = try!(self.fs.load_cluster(c));
+			for ent in cluster.chunks(32)
+			{
+				let i = idx;
+				idx += 1;
+				if i < start {
+					continue ;
+				}
+				// Sets can span clusters, so are accumulated one entry at a time
+				if remaining > 0 {
+					// - In-use secondary entry
+					if ent[0] & 0xC0 == 0xC0 {
+						set.extend_from_slice(ent);
+						remaining -= 1;
+						if remaining == 0 {
+							match EntSet::parse(&self.fs, set_start, ::core::mem::replace(&mut set, Vec::new()))
+							{
+							Some(s) => if let Some(rv) = f(s) {
+								return Ok( (idx, Some(rv)) );
+								},
+							None => log_notice!("exFAT: Malformed entry set at {} of {:#x}", set_start, self.first_cluster),
+							}
+						}
+						continue ;
+					}
+					log_notice!("exFAT: Truncated entry set at {} of {:#x}", set_start, self.first_cluster);
+					remaining = 0;
+					set.clear();
+				}
+				match ent[0]
+				{
+				on_disk::ENT_END => return Ok( (i, None) ),
+				on_disk::ENT_FILE if ent[1] >= 2 => {
+					set.extend_from_slice(ent);
+					set_start = i;
+					remaining = ent[1];
+					},
+				_ => {},
+				}
+			}
+		}
+		Ok( (idx, None) )
+	}
+
+	fn name_hash(&self, name: &[u16]) -> u16 {
+		let upcased: Vec<u16> = name.iter().map(|&c| self.fs.upcase.upcase(c)).collect();
+		on_disk::name_hash(&upcased)
+	}
+	/// Locate the entry set for a name (case-insensitive)
+	fn find_set(&self, name: &ByteStr) -> node::Result<EntSet> {
+		match ::core::str::from_utf8(name.as_bytes())
+		{
+		Ok(s) => self.find_set16( &s.encode_utf16().collect::<Vec<_>>() ),
+		Err(_) => Err(vfs::Error::NotFound),
+		}
+	}
+	fn find_set16(&self, name: &[u16]) -> node::Result<EntSet> {
+		let hash = self.name_hash(name);
+		let (_, rv) = try!(self.scan(0, |set| {
+			if set.stream.name_hash == hash && self.fs.upcase.names_equal(&set.name, name) {
+				Some(set)
+			}
+			else {
+				None
+			}
+			}));
+		rv.ok_or(vfs::Error::NotFound)
+	}
+	fn is_empty(&self) -> node::Result<bool> {
+		let (_, rv) = try!(self.scan(0, |_| Some( () )));
+		Ok(rv.is_none())
+	}
+	/// Inode number for an entry set in this directory (registering directories, so they can be loaded by inode)
+	fn set_inode(&self, set: &EntSet) -> node::InodeId {
+		if set.is_dir() {
+			self.fs.register_dir(&self.state, set.index, set.chain(), set.metadata());
+		}
+		set.inode(self.first_cluster)
+	}
+
+	/// Find a run of `count` free entries, extending the directory if required
+	///
+	/// Must be called with the directory lock held.
+	fn find_free_run(&self, count: u32) -> node::Result<u32> {
+		let chain = *self.state.chain.lock();
+		let mut run_start = 0;
+		let mut run_len = 0;
+		let mut idx = 0;
+		for c in ClusterList::new(self.fs.reborrow(), &chain)
+		{
+			let cluster = try!(self.fs.load_cluster(c));
+			for ent in cluster.chunks(32)
+			{
+				let i = idx;
+				idx += 1;
+				// - The entries of an unlinked (but still open) file keep its inode number reserved
+				if ent[0] & on_disk::ENT_INUSE != 0 || self.fs.is_ent_open(InodeRef::File { dir: self.first_cluster, index: i }.to_id()) {
+					run_len = 0;
+				}
+				else {
+					if run_len == 0 {
+						run_start = i;
+					}
+					run_len += 1;
+					if run_len == count {
+						return Ok(run_start);
+					}
+				}
+			}
+		}
+
+		// No space, add clusters (continuing any free run at the end)
+		if run_len == 0 {
+			run_start = idx;
+		}
+		let ents_per_cluster = (self.fs.cluster_size / 32) as u32;
+		try!(self.extend( (count - run_len + ents_per_cluster - 1) / ents_per_cluster ));
+		Ok(run_start)
+	}
+	/// Add `count` zeroed clusters to the directory
+	///
+	/// Must be called with the directory lock held.
+	fn extend(&self, count: u32) -> node::Result<()> {
+		let cluster_size = self.fs.cluster_size as u64;
+		let mut chain = *self.state.chain.lock();
+		if chain.size + count as u64 * cluster_size > MAX_DIR_SIZE {
+			return Err(vfs::Error::OutOfSpace);
+		}
+		let mut last = try!(self.fs.chain_cluster(&chain, self.fs.chain_clusters(&chain) - 1));
+		for _ in 0 .. count
+		{
+			let cluster = try!(self.fs.extend_chain(&mut chain, last));
+			chain.size += cluster_size;
+			chain.valid_size = chain.size;
+			*self.state.chain.lock() = chain;
+			try!(self.fs.zero_cluster(cluster));
+			last = cluster;
+		}
+		// Record the new size in the parent (the root has no entry)
+		let (parent, index) = {
+			let loc = self.state.loc.lock();
+			(loc.parent.as_ref().map(|p| p.first_cluster), loc.index)
+			};
+		if let Some(parent) = parent {
+			try!(self.fs.save_chain(parent, index, &chain));
+		}
+		Ok( () )
+	}
+
+	/// Build an entry set for `name`, with the attributes and times from `file` and data from `chain`
+	fn build_set(&self, name: &[u16], file: &on_disk::FileEnt, chain: &Chain) -> Vec<u8> {
+		let name_ents = (name.len() + on_disk::NAME_ENT_CHARS - 1) / on_disk::NAME_ENT_CHARS;
+		let mut set = vec![0u8; (2 + name_ents) * 32];
+		on_disk::FileEnt {
+			secondary_count: 1 + name_ents as u8,
+			..*file
+			}.write(&mut set[..32]);
+		on_disk::StreamEnt {
+			flags: on_disk::STREAM_ALLOC_POSSIBLE | if chain.no_fat_chain { on_disk::STREAM_NO_FAT_CHAIN } else { 0 },
+			name_length: name.len() as u8,
+			name_hash: self.name_hash(name),
+			valid_data_length: chain.valid_size,
+			first_cluster: chain.first_cluster,
+			data_length: chain.size,
+			}.write(&mut set[32..64]);
+		for (ent, chars) in Iterator::zip( set[64..].chunks_mut(32), name.chunks(on_disk::NAME_ENT_CHARS) )
+		{
+			on_disk::write_name_ent(ent, chars);
+		}
+		on_disk::update_set_checksum(&mut set);
+		set
+	}
+	/// Write a new entry set, returning its index
+	///
+	/// Must be called with the directory lock held.
+	fn add_set(&self, name: &[u16], file: &on_disk::FileEnt, chain: &Chain) -> node::Result<u32> {
+		let set = self.build_set(name, file, chain);
+		let index = try!(self.find_free_run( (set.len() / 32) as u32 ));
+		let dir_chain = *self.state.chain.lock();
+		try!(self.fs.write_ents(&dir_chain, index, &set));
+		Ok(index)
+	}
+	/// Mark all entries in a set as unused
+	///
+	/// Must be called with the directory lock held.
+	fn remove_set(&self, set: &EntSet) -> node::Result<()> {
+		let mut raw = set.raw.clone();
+		for ent in raw.chunks_mut(32) {
+			ent[0] &= !on_disk::ENT_INUSE;
+		}
+		let chain = *self.state.chain.lock();
+		self.fs.write_ents(&chain, set.index, &raw)
+	}
+}
+
+/// Convert a name to UTF-16, checking that it's valid for exFAT
+fn encode_name(name: &ByteStr) -> node::Result<Vec<u16>> {
+	let s = match ::core::str::from_utf8(name.as_bytes())
+		{
+		Ok(v) => v,
+		Err(_) => return Err(vfs::Error::InvalidParameter),
+		};
+	if s == "" || s == "." || s == ".." {
+		return Err(vfs::Error::InvalidParameter);
+	}
+	if s.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)) {
+		return Err(vfs::Error::InvalidParameter);
+	}
+	let rv: Vec<u16> = s.encode_utf16().collect();
+	if rv.len() > on_disk::MAX_NAME_LEN {
+		return Err(vfs::Error::InvalidParameter);
+	}
+	Ok(rv)
+}
+
+impl node::Dir for DirNode {
+	fn lookup(&self, name: &ByteStr) -> node::Result<node::InodeId> {
+		if name == "." {
+			return Ok( InodeRef::Dir(self.first_cluster).to_id() );
+		}
+		if name == ".." {
+			// exFAT has no `..` entries, use the parent recorded when this directory was found
+			return match self.state.loc.lock().parent
+				{
+				Some(ref p) => Ok( InodeRef::Dir(p.first_cluster).to_id() ),
+				None => Err(vfs::Error::NotFound),
+				};
+		}
+		let set = try!(self.find_set(name));
+		Ok( self.set_inode(&set) )
+	}
+	fn read(&self, ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize> {
+		let (next, _) = try!(self.scan(ofs as u32, |set| {
+			let inode = self.set_inode(&set);
+			let name = Str16::new(&set.name).unwrap_or( Str16::new(&[]).unwrap() );
+			if callback(inode, &mut name.wtf8()) {
+				None
+			}
+			else {
+				Some( () )
+			}
+			}));
+		Ok(next as usize)
+	}
+	fn create(&self, name: &ByteStr, nodetype: node::NodeType) -> node::Result<node::InodeId> {
+		let is_dir = match nodetype
+			{
+			node::NodeType::File => false,
+			node::NodeType::Dir => true,
+			// exFAT has no symbolic links
+			node::NodeType::Symlink(_) => return Err(vfs::Error::PermissionDenied),
+			};
+		let name = try!(encode_name(name));
+		let _lh = self.fs.dir_lock.lock();
+		match self.find_set16(&name)
+		{
+		Ok(_) => return Err(vfs::Error::AlreadyExists),
+		Err(vfs::Error::NotFound) => {},
+		Err(e) => return Err(e),
+		}
+
+		// Directories always have a cluster, files get their first cluster when written
+		let mut chain = Chain {
+			first_cluster: 0,
+			no_fat_chain: false,
+			size: 0,
+			valid_size: 0,
+			};
+		if is_dir {
+			chain = Chain {
+				first_cluster: try!(self.fs.alloc_cluster(0)),
+				no_fat_chain: true,
+				size: self.fs.cluster_size as u64,
+				valid_size: self.fs.cluster_size as u64,
+				};
+		}
+		let file = on_disk::FileEnt {
+			secondary_count: 0,
+			set_checksum: 0,
+			attributes: if is_dir { on_disk::ATTR_DIRECTORY } else { on_disk::ATTR_ARCHIVE },
+			// TODO: Timestamps (needs a wall-clock source)
+			create_time: 0,
+			modified_time: 0,
+			accessed_time: 0,
+			create_10ms: 0,
+			modified_10ms: 0,
+			create_utc_ofs: 0,
+			modified_utc_ofs: 0,
+			accessed_utc_ofs: 0,
+			};
+		let res = if is_dir {
+				self.fs.zero_cluster(chain.first_cluster).map_err(From::from).and_then(|_| self.add_set(&name, &file, &chain))
+			}
+			else {
+				self.add_set(&name, &file, &chain)
+			};
+		match res
+		{
+		Ok(index) =>
+			if is_dir {
+				self.fs.register_dir(&self.state, index, chain, ent_metadata(&file, chain.size));
+				Ok( InodeRef::Dir(chain.first_cluster).to_id() )
+			}
+			else {
+				Ok( InodeRef::File { dir: self.first_cluster, index: index }.to_id() )
+			},
+		Err(e) => {
+			if let Err(e) = self.fs.truncate_chain(&mut chain, 0) {
+				log_error!("exFAT: Error freeing cluster {:#x}: {:?}", chain.first_cluster, e);
+			}
+			Err(e)
+			},
+		}
+	}
+	fn link(&self, _name: &ByteStr, _node: &dyn node::NodeBase) -> node::Result<()> {
+		// exFAT has no hard links
+		Err(vfs::Error::PermissionDenied)
+	}
+	fn unlink(&self, name: &ByteStr) -> node::Result<()> {
+		let _lh = self.fs.dir_lock.lock();
+		let set = try!(self.find_set(name));
+		let mut chain = set.chain();
+		if set.is_dir() {
+			let child = DirNode::new(self.fs.reborrow(), self.fs.register_dir(&self.state, set.index, chain, set.metadata()));
+			if ! try!(child.is_empty()) {
+				return Err(vfs::Error::DirectoryNotEmpty);
+			}
+		}
+		try!(self.remove_set(&set));
+		if set.is_dir() {
+			self.fs.dirs.lock().remove(&chain.first_cluster);
+			try!(self.fs.truncate_chain(&mut chain, 0));
+		}
+		else {
+			// Clusters of an open file are freed when it's closed
+			try!(self.fs.file_unlinked(set.inode(self.first_cluster), chain));
+		}
+		Ok( () )
+	}
+	fn rename(&self, src_name: &ByteStr, dst_dir: &dyn node::Dir, dst_name: &ByteStr) -> node::Result<()> {
+		let dst = match dst_dir.get_any().downcast_ref::<DirNode>()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::CrossFilesystem),
+			};
+		if &*self.fs as *const FilesystemInner != &*dst.fs as *const FilesystemInner {
+			return Err(vfs::Error::CrossFilesystem);
+		}
+		let dst_name = try!(encode_name(dst_name));
+		let _lh = self.fs.dir_lock.lock();
+
+		let src = try!(self.find_set(src_name));
+		match dst.find_set16(&dst_name)
+		{
+		// - Changing only the case of a name matches the source set, which is allowed
+		Ok(ref set) if self.first_cluster == dst.first_cluster && set.index == src.index => {},
+		Ok(_) => return Err(vfs::Error::AlreadyExists),
+		Err(vfs::Error::NotFound) => {},
+		Err(e) => return Err(e),
+		}
+
+		// Write the new set (keeping attributes, times, and data), then remove the original
+		// - The new set only used free entries, so the original's position is unchanged
+		// NOTE: Any vendor-defined secondary entries are dropped
+		let new_index = try!(dst.add_set(&dst_name, &src.file, &src.chain()));
+		try!(self.remove_set(&src));
+
+		if src.is_dir() {
+			// Directories are located through the recorded parent
+			// - The previous parent (this directory) is loaded, so its state is released when this node is
+			let state = self.fs.register_dir(&self.state, src.index, src.chain(), src.metadata());
+			let mut loc = state.loc.lock();
+			loc.parent = Some(dst.state.clone());
+			loc.index = new_index;
+		}
+		else {
+			// Files are identified by their set's location, so open files need to be told of the new location
+			self.fs.file_moved(src.inode(self.first_cluster), InodeRef::File { dir: dst.first_cluster, index: new_index }.to_id(), &dst.state);
+		}
+		Ok( () )
+	}
+}
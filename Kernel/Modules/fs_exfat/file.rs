@@ -0,0 +1,313 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/file.rs
+use kernel::prelude::*;
+use kernel::lib::mem::aref::ArefBorrow;
+use kernel::lib::mem::Arc;
+use kernel::vfs::{self, node};
+use super::{FilesystemInner,Chain,ClusterList};
+use super::dir::DirState;
+
+const ERROR_SHORTCHAIN: vfs::Error = vfs::Error::Unknown("Cluster chain terminated early");
+
+pub struct FileNode
+{
+	fs: ArefBorrow<FilesystemInner>,
+	state: Arc<FileState>,
+	metadata: node::Metadata,
+}
+
+/// State shared by all `FileNode`s for the same file
+pub struct FileState
+{
+	pub ent: ::kernel::sync::Mutex<FileEnt>,
+	/// Held locked while writing
+	pub data: ::kernel::sync::Mutex<Chain>,
+}
+/// Location of a file's entry set
+pub struct FileEnt
+{
+	/// Current inode number (changes when the file is renamed)
+	pub inode: node::InodeId,
+	/// State of the directory holding the entry set (held so it stays registered, released on the last close)
+	pub dir: Option<Arc<DirState>>,
+	/// Number of `FileNode`s for this file
+	pub users: usize,
+	/// The entry set has been removed, free the clusters on the last close
+	pub unlinked: bool,
+}
+
+impl FileState
+{
+	pub fn new(inode: node::InodeId, dir: Arc<DirState>, chain: Chain) -> FileState {
+		FileState {
+			ent: ::kernel::sync::Mutex::new(FileEnt {
+				inode: inode,
+				dir: Some(dir),
+				users: 1,
+				unlinked: false,
+				}),
+			data: ::kernel::sync::Mutex::new(chain),
+		}
+	}
+}
+
+impl FileNode
+{
+	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, inode: node::InodeId, dir: &Arc<DirState>, chain: Chain, metadata: node::Metadata) -> Box<FileNode> {
+		let state = fs.file_opened(inode, dir, chain);
+		Box::new(FileNode {
+			fs: fs,
+			state: state,
+			metadata: metadata,
+			})
+	}
+
+	/// Allocate clusters to cover `new_size` bytes, and set the size (the valid size is unchanged)
+	///
+	/// Either all required clusters are allocated, or none are.
+	fn reserve(&self, chain: &mut Chain, new_size: u64) -> node::Result<()> {
+		let cluster_size = self.fs.cluster_size as u64;
+		let have = self.fs.chain_clusters(chain);
+		let need = (new_size + cluster_size - 1) / cluster_size;
+		let mut last = if have > 0 { try!(self.fs.chain_cluster(chain, have - 1)) } else { 0 };
+		for n in have .. need
+		{
+			match self.fs.extend_chain(chain, last)
+			{
+			Ok(v) => last = v,
+			Err(e) => {
+				// Release the clusters allocated so far
+				let old_size = chain.size;
+				chain.size = n * cluster_size;
+				if let Err(e) = self.fs.truncate_chain(chain, have) {
+					log_error!("exFAT: Error freeing clusters: {:?}", e);
+				}
+				chain.size = old_size;
+				return Err(e);
+				},
+			}
+		}
+		chain.size = new_size;
+		Ok( () )
+	}
+	/// Release clusters past `new_size`, and set the size
+	fn release(&self, chain: &mut Chain, new_size: u64) -> node::Result<()> {
+		let cluster_size = self.fs.cluster_size as u64;
+		try!(self.fs.truncate_chain(chain, (new_size + cluster_size - 1) / cluster_size));
+		chain.size = new_size;
+		chain.valid_size = ::core::cmp::min(chain.valid_size, new_size);
+		Ok( () )
+	}
+	/// Write `len` bytes from `src` (or zeroes if `None`) at `ofs` to already allocated clusters
+	///
+	/// Returns a short count if an error occurs after data has been written.
+	fn write_data(&self, chain: &Chain, ofs: u64, len: usize, src: Option<&[u8]>) -> node::Result<usize> {
+		let zeroes = if src.is_none() { vec![0u8; self.fs.cluster_size] } else { Vec::new() };
+		let cluster_size = self.fs.cluster_size as u64;
+		let mut clusters = ClusterList::new(self.fs.reborrow(), chain);
+		for _ in 0 .. ofs / cluster_size {
+			clusters.next();
+		}
+		let mut in_ofs = (ofs % cluster_size) as usize;
+		let mut done = 0;
+		while done < len
+		{
+			let cluster = match clusters.next()
+				{
+				Some(v) => v,
+				None => return if done > 0 { Ok(done) } else { Err(ERROR_SHORTCHAIN) },
+				};
+			let count = ::core::cmp::min(len - done, self.fs.cluster_size - in_ofs);
+			let buf = match src
+				{
+				Some(s) => &s[done..][..count],
+				None => &zeroes[..count],
+				};
+			if let Err(e) = self.fs.write_cluster_data(cluster, in_ofs, buf) {
+				return if done > 0 { Ok(done) } else { Err(From::from(e)) };
+			}
+			done += count;
+			in_ofs = 0;
+		}
+		Ok(done)
+	}
+	/// Write data after zero-filling any uninitialised space before it, updating the valid size
+	fn write_valid(&self, chain: &mut Chain, ofs: u64, src: &[u8]) -> node::Result<usize> {
+		if ofs > chain.valid_size {
+			let vdl = chain.valid_size;
+			let gap = (ofs - vdl) as usize;
+			let count = try!(self.write_data(chain, vdl, gap, None));
+			chain.valid_size += count as u64;
+			if count < gap {
+				return Err(vfs::Error::BlockIoError);
+			}
+		}
+		let count = try!(self.write_data(chain, ofs, src.len(), Some(src)));
+		chain.valid_size = ::core::cmp::max(chain.valid_size, ofs + count as u64);
+		Ok(count)
+	}
+}
+impl ::core::ops::Drop for FileNode {
+	fn drop(&mut self) {
+		if self.fs.file_closed(&self.state) {
+			let mut chain = self.state.data.lock();
+			if let Err(e) = self.fs.truncate_chain(&mut chain, 0) {
+				log_error!("exFAT: Error freeing clusters of unlinked file {:#x}: {:?}", chain.first_cluster, e);
+			}
+		}
+	}
+}
+impl node::NodeBase for FileNode {
+	fn get_id(&self) -> node::InodeId {
+		self.state.ent.lock().inode
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok(node::Metadata {
+			size: self.state.data.lock().size,
+			..self.metadata.clone()
+			})
+	}
+}
+impl node::File for FileNode {
+	fn size(&self) -> u64 {
+		self.state.data.lock().size
+	}
+	fn truncate(&self, newsize: u64) -> node::Result<u64> {
+		let mut chain = self.state.data.lock();
+		if newsize > chain.size {
+			// Only allocate, the new space is beyond the valid size (so reads as zero)
+			let mut new_chain = *chain;
+			try!(self.reserve(&mut new_chain, newsize));
+			if let Err(e) = self.fs.save_file_chain(&self.state, &new_chain) {
+				let size = chain.size;
+				if let Err(e) = self.release(&mut new_chain, size) {
+					log_error!("exFAT: Error freeing clusters: {:?}", e);
+				}
+				return Err(e);
+			}
+			*chain = new_chain;
+		}
+		else if newsize < chain.size {
+			// Update the entry first, then release the clusters past the new end
+			let cluster_size = self.fs.cluster_size as u64;
+			let mut new_chain = Chain {
+				size: newsize,
+				valid_size: ::core::cmp::min(chain.valid_size, newsize),
+				..*chain
+				};
+			if newsize == 0 {
+				new_chain.first_cluster = 0;
+				new_chain.no_fat_chain = false;
+			}
+			try!(self.fs.save_file_chain(&self.state, &new_chain));
+			let res = self.fs.truncate_chain(&mut chain, (newsize + cluster_size - 1) / cluster_size);
+			*chain = new_chain;
+			try!(res);
+		}
+		Ok(chain.size)
+	}
+	fn clear(&self, ofs: u64, size: u64) -> node::Result<()> {
+		let chain = self.state.data.lock();
+		if ofs > chain.size {
+			return Err( vfs::Error::InvalidParameter );
+		}
+		// Data past the valid size already reads as zero
+		if ofs < chain.valid_size {
+			let len = ::core::cmp::min(size, chain.valid_size - ofs) as usize;
+			if try!(self.write_data(&chain, ofs, len, None)) < len {
+				return Err( vfs::Error::BlockIoError );
+			}
+		}
+		Ok( () )
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
+		let chain = *self.state.data.lock();
+		// Sanity check and bound parameters
+		if ofs > chain.size {
+			// out of range
+			return Err( vfs::Error::InvalidParameter );
+		}
+		let maxread = (chain.size - ofs) as usize;
+		let buf = if buf.len() > maxread { &mut buf[..maxread] } else { buf };
+		let read_length = buf.len();
+
+		// Only data before the valid size is read from disk, the rest is zero
+		let data_len = if ofs >= chain.valid_size { 0 } else { ::core::cmp::min(read_length as u64, chain.valid_size - ofs) as usize };
+		for b in buf[data_len..].iter_mut() {
+			*b = 0;
+		}
+
+		// Seek to correct position in the cluster chain
+		let cluster_size = self.fs.cluster_size;
+		let mut clusters = ClusterList::new(self.fs.reborrow(), &chain);
+		for _ in 0 .. (ofs / cluster_size as u64) {
+			clusters.next();
+		}
+		let mut in_ofs = (ofs % cluster_size as u64) as usize;
+		let mut done = 0;
+		while done < data_len
+		{
+			let dst = &mut buf[done..data_len];
+			if in_ofs == 0 && dst.len() >= cluster_size {
+				// Read whole clusters directly
+				let (cluster, count) = match clusters.next_extent( dst.len() / cluster_size )
+					{
+					Some(v) => v,
+					None => return Err(ERROR_SHORTCHAIN),
+					};
+				let bytes = count * cluster_size;
+				log_trace!("- Read cluster {}+{}", cluster, count);
+				try!(self.fs.read_clusters(cluster, &mut dst[..bytes]));
+				done += bytes;
+			}
+			else {
+				// Partial cluster, bounce through the cache
+				let cluster = match clusters.next()
+					{
+					Some(v) => v,
+					None => return Err(ERROR_SHORTCHAIN),
+					};
+				let c = try!(self.fs.load_cluster(cluster));
+				let bytes = ::core::cmp::min(cluster_size - in_ofs, dst.len());
+				dst[..bytes].clone_from_slice( &c[in_ofs..][..bytes] );
+				done += bytes;
+				in_ofs = 0;
+			}
+		}
+
+		Ok( read_length )
+	}
+	/// Write data to the file, can only grow the file if ofs==size
+	fn write(&self, ofs: u64, buf: &[u8]) -> node::Result<usize> {
+		let mut chain = self.state.data.lock();
+		if ofs > chain.size {
+			return Err( vfs::Error::InvalidParameter );
+		}
+		if buf.len() == 0 {
+			return Ok(0);
+		}
+		let end = ofs + buf.len() as u64;
+		let old_size = chain.size;
+		let mut new_chain = *chain;
+		if end > old_size {
+			try!(self.reserve(&mut new_chain, end));
+		}
+		let res = self.write_valid(&mut new_chain, ofs, buf);
+		// Give back any space reserved for data that wasn't written
+		let written_end = ofs + *res.as_ref().unwrap_or(&0) as u64;
+		if new_chain.size > old_size && written_end < new_chain.size {
+			let new_size = ::core::cmp::max(old_size, written_end);
+			if let Err(e) = self.release(&mut new_chain, new_size) {
+				log_error!("exFAT: Error freeing clusters: {:?}", e);
+			}
+		}
+		try!(self.fs.save_file_chain(&self.state, &new_chain));
+		*chain = new_chain;
+		res
+	}
+}
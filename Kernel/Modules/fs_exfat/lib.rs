@@ -0,0 +1,1052 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/lib.rs
+//! exFAT filesystem driver
+#![feature(linkage)]
+#![no_std]
+
+#[macro_use] extern crate kernel;
+use kernel::prelude::*;
+
+use kernel::vfs::{self, mount, node};
+use kernel::metadevs::storage::{self,VolumeHandle,SizePrinter};
+use kernel::lib::mem::aref::{ArefInner,ArefBorrow};
+use kernel::lib::mem::Arc;
+use kernel::lib::VecMap;
+
+extern crate utf16;
+extern crate blockcache;
+extern crate block_cache;
+
+module_define!{FS_EXFAT, [VFS], init}
+
+/// on-disk structures
+mod on_disk;
+/// Up-case table
+mod upcase;
+/// Directory IO
+mod dir;
+/// File IO
+mod file;
+
+/// Driver strucutre
+struct Driver;
+
+struct Filesystem
+{
+	inner: ArefInner<FilesystemInner>
+}
+impl ::core::ops::Deref for Filesystem {
+	type Target = FilesystemInner;
+	fn deref(&self) -> &FilesystemInner { &self.inner }
+}
+
+pub struct FilesystemInner
+{
+	vh: ::block_cache::CacheHandle,
+	/// Used to drop the VFS's cached names for directories whose state is released
+	mount_handle: mount::SelfHandle,
+
+	/// Bytes per sector (a multiple of the volume's block size)
+	sector_size: usize,
+	/// Sectors per cluster
+	spc: usize,
+	cluster_size: usize,
+	/// Number of clusters in the cluster heap (numbered from 2)
+	cluster_count: u32,
+	/// First sector of the FAT in use
+	fat_sector: u64,
+	/// First sector of the cluster heap
+	cluster_heap_sector: u64,
+
+	/// Clusters holding the allocation bitmap in use
+	bitmap_clusters: Vec<u32>,
+	upcase: upcase::UpcaseTable,
+
+	/// A cache of metadata clusters (i.e. directories)
+	metadata_block_cache: ::blockcache::BlockCache,
+	/// Serialises modifications to directory entries
+	dir_lock: ::kernel::sync::Mutex<()>,
+	/// Cluster allocation state (also serialises bitmap and FAT modifications)
+	alloc: ::kernel::sync::Mutex<AllocState>,
+	/// State of the volume dirty flag
+	volume_dirty: ::kernel::sync::Mutex<DirtyFlag>,
+	/// The root directory
+	root: Arc<dir::DirState>,
+	/// Directories seen by lookups (keyed on first cluster), used to locate their entries
+	///
+	/// exFAT has no `..` entries, so this is also the only way to find a directory's parent. Each directory's
+	/// state holds its parent's, and open files hold the state of their directory. Entries are released by
+	/// `release_dirs` once nothing holds them and their parent has no loaded nodes (which could look them up).
+	dirs: ::kernel::sync::Mutex<VecMap<u32, Arc<dir::DirState>>>,
+	/// Files currently loaded by the VFS (keyed on inode), so their state is shared and unlinking can defer freeing clusters
+	open_files: ::kernel::sync::Mutex<VecMap<node::InodeId, Arc<file::FileState>>>,
+}
+
+/// Cluster allocation state
+struct AllocState
+{
+	/// Cluster to start searching from
+	next_free: u32,
+}
+/// Volume dirty flag (set in the boot sector while the volume is being modified)
+struct DirtyFlag
+{
+	/// The flag is currently set on disk
+	set: bool,
+	/// The flag was already set when mounted, so is left set (the volume needs checking)
+	sticky: bool,
+}
+
+/// Location and size of a node's data (from the stream extension entry)
+#[derive(Copy,Clone,Debug)]
+pub struct Chain
+{
+	/// First cluster (zero if no clusters are allocated)
+	first_cluster: u32,
+	/// Clusters are contiguous, and not recorded in the FAT
+	no_fat_chain: bool,
+	/// Allocated size in bytes (`DataLength`)
+	size: u64,
+	/// Size of the initialised data (`ValidDataLength`), the rest reads as zeroes
+	valid_size: u64,
+}
+
+/// Decoded inode number
+///
+/// Directories are identified by their first cluster (which never changes). Files can have no clusters,
+/// so are identified by the location of their entry set (which changes if the file is renamed, the VFS
+/// re-keys its cached node using `get_id` after a rename).
+#[derive(Debug)]
+enum InodeRef
+{
+	Dir(u32),
+	File {
+		/// First cluster of the directory holding the entry set
+		dir: u32,
+		/// Entry index within the directory
+		index: u32,
+	},
+}
+
+/// Iterable cluster list
+enum ClusterList {
+	Range(::core::ops::Range<u32>),
+	Chained(ArefBorrow<FilesystemInner>, u32),
+}
+
+
+static S_DRIVER: Driver = Driver;
+
+fn init()
+{
+	let h = mount::DriverRegistration::new("exfat", &S_DRIVER);
+	// TODO: Remember the registration for unloading
+	::core::mem::forget(h);
+}
+
+impl mount::Driver for Driver
+{
+	fn detect(&self, vol: &VolumeHandle) -> vfs::Result<usize> {
+		if vol.block_size() < 512 {
+			return Ok(0);
+		}
+		let bs = {
+			let mut bs = vec![0u8; vol.block_size()];
+			try!( vol.read_blocks(0, &mut bs) );
+			on_disk::BootSector::read(&bs[..512])
+			};
+
+		if bs.fs_name == on_disk::FS_NAME && bs.boot_signature == 0xAA55 {
+			Ok(2)
+		}
+		else {
+			Ok(0)
+		}
+	}
+	fn mount(&self, vol: VolumeHandle, mounthandle: mount::SelfHandle, _options: &[&str]) -> vfs::Result<Box<dyn mount::Filesystem>> {
+		let vol = ::block_cache::CacheHandle::new(vol);
+
+		// Read the boot sector
+		let bs = {
+			let blk = try!(vol.get_block(0));
+			on_disk::BootSector::read(&blk.data()[..512])
+			};
+		if bs.fs_name != on_disk::FS_NAME {
+			return Err(vfs::Error::Unknown("exFAT: Bad filesystem name"));
+		}
+		if bs.fs_revision >> 8 != 1 {
+			log_notice!("exFAT: Unsupported revision {}.{:02}", bs.fs_revision >> 8, bs.fs_revision & 0xFF);
+			return Err(vfs::Error::Unknown("exFAT: Unsupported revision"));
+		}
+		if bs.bytes_per_sector_shift < 9 || bs.bytes_per_sector_shift > 12 {
+			return Err(vfs::Error::Unknown("exFAT: Invalid sector size"));
+		}
+		// Clusters are at most 32MiB
+		if bs.sectors_per_cluster_shift > 25 - bs.bytes_per_sector_shift {
+			return Err(vfs::Error::Unknown("exFAT: Invalid cluster size"));
+		}
+		let sector_size = 1 << bs.bytes_per_sector_shift;
+		// Sectors are accessed as a whole number of volume blocks
+		if sector_size < vol.block_size() {
+			return Err(vfs::Error::Unknown("exFAT: Sector size is smaller than the volume's block size"));
+		}
+		if bs.fat_count == 0 || bs.fat_count > 2 {
+			return Err(vfs::Error::Unknown("exFAT: Invalid FAT count"));
+		}
+		if bs.root_cluster < 2 || bs.root_cluster - 2 >= bs.cluster_count {
+			return Err(vfs::Error::Unknown("exFAT: Invalid root directory cluster"));
+		}
+		let spc = 1 << bs.sectors_per_cluster_shift;
+
+		log_debug!("exFAT {} clusters of {} bytes, Size {}", bs.cluster_count, spc * sector_size,
+			SizePrinter(bs.volume_length * sector_size as u64));
+		if bs.volume_flags & on_disk::VOLFLAG_DIRTY != 0 {
+			log_notice!("exFAT: Volume was not cleanly unmounted");
+		}
+
+		// The second FAT (and allocation bitmap) is only used by TexFAT, when selected by the volume flags
+		let active_fat = if bs.fat_count == 2 && bs.volume_flags & on_disk::VOLFLAG_ACTIVE_FAT != 0 { 1 } else { 0 };
+
+		let mut inner = FilesystemInner {
+			sector_size: sector_size,
+			spc: spc,
+			cluster_size: spc * sector_size,
+			cluster_count: bs.cluster_count,
+			fat_sector: bs.fat_offset as u64 + active_fat as u64 * bs.fat_length as u64,
+			cluster_heap_sector: bs.cluster_heap_offset as u64,
+
+			bitmap_clusters: Vec::new(),
+			upcase: upcase::UpcaseTable::new(&[]),
+
+			metadata_block_cache: ::blockcache::BlockCache::new(),
+			dir_lock: ::kernel::sync::Mutex::new(()),
+			alloc: ::kernel::sync::Mutex::new(AllocState { next_free: 2 }),
+			volume_dirty: ::kernel::sync::Mutex::new(DirtyFlag {
+				set: bs.volume_flags & on_disk::VOLFLAG_DIRTY != 0,
+				sticky: bs.volume_flags & on_disk::VOLFLAG_DIRTY != 0,
+				}),
+			root: Arc::new(dir::DirState::new_root(Chain {
+				first_cluster: bs.root_cluster,
+				no_fat_chain: false,
+				size: 0,
+				valid_size: 0,
+				})),
+			dirs: ::kernel::sync::Mutex::new(VecMap::new()),
+			open_files: ::kernel::sync::Mutex::new(VecMap::new()),
+
+			vh: vol,
+			mount_handle: mounthandle,
+			};
+		// The root directory has no entry, so its size comes from the length of its cluster chain
+		let root_size = try!(inner.chain_length(bs.root_cluster)) as u64 * inner.cluster_size as u64;
+		{
+			let mut chain = inner.root.chain.lock();
+			chain.size = root_size;
+			chain.valid_size = root_size;
+		}
+		try!(inner.load_root_metadata(active_fat));
+
+		Ok(Box::new(Filesystem {
+			// SAFE: Saving to a Box, so won't move
+			inner: unsafe { ArefInner::new(inner) },
+			}))
+	}
+}
+
+type Cluster = Arc<[u8]>;
+
+impl FilesystemInner
+{
+	/// Locate the allocation bitmap and up-case table (stored as entries in the root directory)
+	fn load_root_metadata(&mut self, active_fat: u8) -> vfs::Result<()>
+	{
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		let mut bitmap = None;
+		let mut upcase = None;
+		let mut cluster_data = vec![0u8; self.cluster_size];
+		let mut cluster = self.root.first_cluster;
+		'outer: loop
+		{
+			try!(self.read_clusters(cluster, &mut cluster_data));
+			for ent in cluster_data.chunks(32)
+			{
+				match ent[0]
+				{
+				on_disk::ENT_END => break 'outer,
+				// - Bit 0 of the flags selects which FAT the bitmap goes with
+				on_disk::ENT_BITMAP if ent[1] & 1 == active_fat => {
+					bitmap = Some( (LittleEndian::read_u32(&ent[20..]), LittleEndian::read_u64(&ent[24..])) );
+					},
+				on_disk::ENT_UPCASE => {
+					upcase = Some( (LittleEndian::read_u32(&ent[20..]), LittleEndian::read_u64(&ent[24..]), LittleEndian::read_u32(&ent[4..])) );
+					},
+				_ => {},
+				}
+			}
+			cluster = match try!(self.get_next_cluster(cluster))
+				{
+				Some(v) => v,
+				None => break,
+				};
+		}
+
+		let (bitmap_first, bitmap_len) = match bitmap
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::Unknown("exFAT: No allocation bitmap")),
+			};
+		if bitmap_len * 8 < self.cluster_count as u64 {
+			return Err(vfs::Error::Unknown("exFAT: Allocation bitmap is too small"));
+		}
+		let n_clusters = (bitmap_len + self.cluster_size as u64 - 1) / self.cluster_size as u64;
+		let mut cluster = try!(self.check_cluster(bitmap_first));
+		for i in 0 .. n_clusters
+		{
+			if i > 0 {
+				cluster = match try!(self.get_next_cluster(cluster))
+					{
+					Some(v) => v,
+					None => return Err(vfs::Error::InconsistentFilesystem),
+					};
+			}
+			self.bitmap_clusters.push(cluster);
+		}
+
+		let (upcase_first, upcase_len, upcase_checksum) = match upcase
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::Unknown("exFAT: No up-case table")),
+			};
+		// - The table is at most 128KiB (one entry for every UTF-16 code unit)
+		if upcase_len > 0x20000 + 4 {
+			return Err(vfs::Error::Unknown("exFAT: Up-case table is too large"));
+		}
+		let mut data = Vec::new();
+		let mut cluster = try!(self.check_cluster(upcase_first));
+		while (data.len() as u64) < upcase_len
+		{
+			if data.len() > 0 {
+				cluster = match try!(self.get_next_cluster(cluster))
+					{
+					Some(v) => v,
+					None => return Err(vfs::Error::InconsistentFilesystem),
+					};
+			}
+			try!(self.read_clusters(cluster, &mut cluster_data));
+			data.extend_from_slice(&cluster_data);
+		}
+		data.truncate(upcase_len as usize);
+		if on_disk::table_checksum(&data) != upcase_checksum {
+			log_warning!("exFAT: Up-case table checksum mismatch");
+		}
+		self.upcase = upcase::UpcaseTable::new(&data);
+		Ok( () )
+	}
+
+	/// Convert a sector number into a volume block number
+	fn sector_block(&self, sector: u64) -> u64 {
+		sector * (self.sector_size / self.vh.block_size()) as u64
+	}
+	/// Check that a cluster number (read from the volume) is within the cluster heap
+	fn check_cluster(&self, cluster: u32) -> vfs::Result<u32> {
+		if cluster >= 2 && cluster - 2 < self.cluster_count {
+			Ok(cluster)
+		}
+		else {
+			log_warning!("exFAT: Invalid cluster number {:#x}", cluster);
+			Err(vfs::Error::InconsistentFilesystem)
+		}
+	}
+	/// Check that a chain read from the volume only covers clusters within the cluster heap
+	fn chain_valid(&self, chain: &Chain) -> bool {
+		let count = self.chain_clusters(chain);
+		if chain.first_cluster == 0 {
+			count == 0 || chain.size == 0
+		}
+		else if count > self.cluster_count as u64 || self.check_cluster(chain.first_cluster).is_err() {
+			false
+		}
+		else {
+			// - Contiguous chains aren't checked by following the FAT, so must end within the heap
+			!chain.no_fat_chain || (chain.first_cluster - 2) as u64 + count <= self.cluster_count as u64
+		}
+	}
+	/// Get the first volume block of a cluster
+	fn cluster_block(&self, cluster: u32) -> u64 {
+		assert!(cluster >= 2);
+		assert!(cluster - 2 < self.cluster_count);
+		self.sector_block(self.cluster_heap_sector + (cluster - 2) as u64 * self.spc as u64)
+	}
+
+	fn read_clusters(&self, cluster: u32, dst: &mut [u8]) -> Result<(), storage::IoError> {
+		log_trace!("Filesystem::read_clusters({:#x}, {})", cluster, dst.len() / self.cluster_size);
+		assert_eq!(dst.len() % self.cluster_size, 0);
+		self.vh.read_blocks(self.cluster_block(cluster), dst)
+	}
+	fn write_clusters(&self, cluster: u32, src: &[u8]) -> Result<(), storage::IoError> {
+		log_trace!("Filesystem::write_clusters({:#x}, {})", cluster, src.len() / self.cluster_size);
+		assert_eq!(src.len() % self.cluster_size, 0);
+		try!(self.mark_dirty());
+		self.vh.write_blocks(self.cluster_block(cluster), src)
+	}
+
+	/// Load a metadata cluster (cached)
+	fn load_cluster(&self, cluster: u32) -> Result<Cluster, storage::IoError>
+	{
+		self.metadata_block_cache.get(
+			cluster,
+			|_| {
+				log_debug!("load_cluster: miss {}", cluster);
+				let mut buf: Cluster = Arc::from_iter( (0..self.cluster_size).map(|_| 0) );
+				try!(self.read_clusters( cluster, Arc::get_mut(&mut buf).unwrap() ));
+				Ok( buf )
+			})
+	}
+	/// Modify a metadata cluster, writing it back to disk
+	fn edit_cluster<F: FnOnce(&mut [u8])->R, R>(&self, cluster: u32, f: F) -> Result<R, storage::IoError>
+	{
+		let mut buf: Vec<u8> = Vec::from( &try!(self.load_cluster(cluster))[..] );
+		let rv = f(&mut buf);
+		let res = self.write_clusters(cluster, &buf);
+		// Force the next load to re-read (even on failure, the disk contents are unknown)
+		self.metadata_block_cache.invalidate(cluster);
+		try!(res);
+		Ok( rv )
+	}
+	/// Write a data cluster (or part of one), keeping the cluster cache consistent
+	fn write_cluster_data(&self, cluster: u32, ofs: usize, src: &[u8]) -> Result<(), storage::IoError> {
+		assert!(ofs + src.len() <= self.cluster_size);
+		if ofs == 0 && src.len() == self.cluster_size {
+			let res = self.write_clusters(cluster, src);
+			self.metadata_block_cache.invalidate(cluster);
+			res
+		}
+		else {
+			self.edit_cluster(cluster, |data| data[ofs..][..src.len()].clone_from_slice(src))
+		}
+	}
+	/// Fill a cluster with zeroes
+	fn zero_cluster(&self, cluster: u32) -> Result<(), storage::IoError> {
+		let zeroes = vec![0u8; self.cluster_size];
+		self.write_cluster_data(cluster, 0, &zeroes)
+	}
+
+	/// Set the volume dirty flag before the first modification
+	fn mark_dirty(&self) -> Result<(), storage::IoError> {
+		let mut lh = self.volume_dirty.lock();
+		if !lh.set {
+			try!(self.set_volume_flag(on_disk::VOLFLAG_DIRTY, true));
+			lh.set = true;
+		}
+		Ok( () )
+	}
+	/// Set or clear a flag in the boot sector's volume flags (which are excluded from the boot region checksum)
+	fn set_volume_flag(&self, flag: u16, value: bool) -> Result<(), storage::IoError> {
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		self.vh.edit(0, 1, |data| {
+			let flags = LittleEndian::read_u16(&data[on_disk::VOLFLAGS_OFS..]);
+			let flags = if value { flags | flag } else { flags & !flag };
+			LittleEndian::write_u16(&mut data[on_disk::VOLFLAGS_OFS..], flags);
+			})
+	}
+
+	/// Location of a cluster's FAT entry (volume block, and offset within the block)
+	fn fat_entry_loc(&self, cluster: u32) -> (u64, usize) {
+		let bs = self.vh.block_size();
+		let ofs = cluster as usize * 4;
+		(self.sector_block(self.fat_sector) + (ofs / bs) as u64, ofs % bs)
+	}
+	/// Read the raw FAT entry for a cluster
+	fn get_fat_entry(&self, cluster: u32) -> Result<u32, storage::IoError> {
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		let (blk_idx, ofs) = self.fat_entry_loc(cluster);
+		// NOTE: The returned handle can cover more than one block
+		let blk = try!(self.vh.get_block(blk_idx));
+		let start_ofs = (blk_idx - blk.index()) as usize * self.vh.block_size() + ofs;
+		Ok( LittleEndian::read_u32(&blk.data()[start_ofs..]) )
+	}
+	/// Set the FAT entry for a cluster. Must be called with the `alloc` lock held
+	fn set_fat_entry(&self, _lh: &mut AllocState, cluster: u32, value: u32) -> Result<(), storage::IoError> {
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		try!(self.mark_dirty());
+		let (blk_idx, ofs) = self.fat_entry_loc(cluster);
+		self.vh.edit(blk_idx, 1, |data| LittleEndian::write_u32(&mut data[ofs..], value))
+	}
+	/// Obtain the next cluster in a FAT chain
+	fn get_next_cluster(&self, cluster: u32) -> vfs::Result< Option<u32> > {
+		match try!(self.get_fat_entry(cluster))
+		{
+		on_disk::FAT_EOC => Ok(None),
+		v if v >= 2 && v - 2 < self.cluster_count => Ok(Some(v)),
+		v => {
+			log_warning!("exFAT: Invalid FAT entry for cluster {:#x}: {:#x}", cluster, v);
+			Err(vfs::Error::InconsistentFilesystem)
+			},
+		}
+	}
+	/// Count the clusters in a FAT chain
+	fn chain_length(&self, first_cluster: u32) -> vfs::Result<u32> {
+		let mut count = 1;
+		let mut cluster = first_cluster;
+		while let Some(next) = try!(self.get_next_cluster(cluster))
+		{
+			cluster = next;
+			count += 1;
+			if count > self.cluster_count {
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+		}
+		Ok(count)
+	}
+
+	/// Location of a cluster's allocation bitmap bit (volume block, and byte offset within the block)
+	fn bitmap_loc(&self, cluster: u32) -> (u64, usize) {
+		let bs = self.vh.block_size();
+		let byte = (cluster - 2) as usize / 8;
+		let ofs = byte % self.cluster_size;
+		(self.cluster_block(self.bitmap_clusters[byte / self.cluster_size]) + (ofs / bs) as u64, ofs % bs)
+	}
+	/// Find a free cluster, searching from `start` (and wrapping around)
+	fn find_free_cluster(&self, _lh: &mut AllocState, start: u32) -> Result<Option<u32>, storage::IoError> {
+		let bs = self.vh.block_size();
+		let mut cur = None;
+		for i in 0 .. self.cluster_count
+		{
+			let cluster = 2 + (start - 2 + i) % self.cluster_count;
+			let (blk_idx, ofs) = self.bitmap_loc(cluster);
+			if cur.as_ref().map(|&(idx, _)| idx) != Some(blk_idx) {
+				cur = Some( (blk_idx, try!(self.vh.get_block(blk_idx))) );
+			}
+			let blk = &cur.as_ref().unwrap().1;
+			let byte = blk.data()[(blk_idx - blk.index()) as usize * bs + ofs];
+			if byte & 1 << ((cluster - 2) % 8) == 0 {
+				return Ok(Some(cluster));
+			}
+		}
+		Ok(None)
+	}
+	/// Set or clear the allocation bitmap bits for a run of clusters. Must be called with the `alloc` lock held
+	fn set_bitmap(&self, _lh: &mut AllocState, first: u32, count: u32, value: bool) -> Result<(), storage::IoError> {
+		try!(self.mark_dirty());
+		let end = first + count;
+		let mut cluster = first;
+		while cluster < end
+		{
+			// Update all bits within the same block at once
+			let (blk_idx, _) = self.bitmap_loc(cluster);
+			try!(self.vh.edit(blk_idx, 1, |data| {
+				while cluster < end
+				{
+					let (b, ofs) = self.bitmap_loc(cluster);
+					if b != blk_idx {
+						break;
+					}
+					let bit = 1 << ((cluster - 2) % 8);
+					if value {
+						data[ofs] |= bit;
+					}
+					else {
+						data[ofs] &= !bit;
+					}
+					cluster += 1;
+				}
+				}));
+		}
+		Ok( () )
+	}
+
+	/// Allocate a cluster, preferring `hint` (to keep data contiguous)
+	fn alloc_cluster(&self, hint: u32) -> vfs::Result<u32> {
+		let mut lh = self.alloc.lock();
+		let end = self.cluster_count + 2;
+		let start = if hint >= 2 && hint < end {
+				hint
+			}
+			else if lh.next_free >= 2 && lh.next_free < end {
+				lh.next_free
+			}
+			else {
+				2
+			};
+		let cluster = match try!(self.find_free_cluster(&mut lh, start))
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::OutOfSpace),
+			};
+		log_trace!("alloc_cluster(hint={:#x}) = {:#x}", hint, cluster);
+		try!(self.set_bitmap(&mut lh, cluster, 1, true));
+		lh.next_free = cluster + 1;
+		Ok(cluster)
+	}
+
+	/// Number of clusters allocated to a chain
+	fn chain_clusters(&self, chain: &Chain) -> u64 {
+		// NOTE: The size comes from the volume, so could be close to the maximum
+		chain.size / self.cluster_size as u64 + if chain.size % self.cluster_size as u64 != 0 { 1 } else { 0 }
+	}
+	/// Get the `n`th cluster of a chain
+	fn chain_cluster(&self, chain: &Chain, n: u64) -> vfs::Result<u32> {
+		if chain.first_cluster == 0 || n >= self.chain_clusters(chain) {
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		if chain.no_fat_chain {
+			return Ok(chain.first_cluster + n as u32);
+		}
+		let mut cluster = chain.first_cluster;
+		for _ in 0 .. n
+		{
+			cluster = match try!(self.get_next_cluster(cluster))
+				{
+				Some(v) => v,
+				None => return Err(vfs::Error::InconsistentFilesystem),
+				};
+		}
+		Ok(cluster)
+	}
+	/// Append a cluster to a chain (whose last cluster is `last`), updating `chain` (but not its size)
+	///
+	/// Contiguous chains are converted to FAT chains if the next cluster isn't free.
+	fn extend_chain(&self, chain: &mut Chain, last: u32) -> vfs::Result<u32> {
+		let cluster = try!(self.alloc_cluster(if last != 0 { last + 1 } else { 0 }));
+		let res = if chain.first_cluster == 0 {
+				chain.first_cluster = cluster;
+				chain.no_fat_chain = true;
+				Ok( () )
+			}
+			else if chain.no_fat_chain && cluster == last + 1 {
+				Ok( () )
+			}
+			else {
+				let mut lh = self.alloc.lock();
+				(|| {
+					if chain.no_fat_chain {
+						// Record the existing clusters in the FAT
+						for c in chain.first_cluster .. last {
+							try!(self.set_fat_entry(&mut lh, c, c + 1));
+						}
+					}
+					// Mark as end-of-chain before linking, so the chain is never left pointing at a free cluster
+					try!(self.set_fat_entry(&mut lh, cluster, on_disk::FAT_EOC));
+					self.set_fat_entry(&mut lh, last, cluster)
+				})()
+			};
+		match res
+		{
+		Ok(_) => {
+			if chain.first_cluster != cluster && chain.no_fat_chain && cluster != last + 1 {
+				chain.no_fat_chain = false;
+			}
+			Ok(cluster)
+			},
+		Err(e) => {
+			let mut lh = self.alloc.lock();
+			if let Err(e) = self.set_bitmap(&mut lh, cluster, 1, false) {
+				log_error!("exFAT: Error freeing cluster {:#x}: {:?}", cluster, e);
+			}
+			Err(From::from(e))
+			},
+		}
+	}
+	/// Release all clusters after the first `keep` clusters of a chain, updating `chain` (but not its size)
+	fn truncate_chain(&self, chain: &mut Chain, keep: u64) -> vfs::Result<()> {
+		let count = self.chain_clusters(chain);
+		if chain.first_cluster == 0 || keep >= count {
+			return Ok( () );
+		}
+		if chain.no_fat_chain {
+			let mut lh = self.alloc.lock();
+			try!(self.set_bitmap(&mut lh, chain.first_cluster + keep as u32, (count - keep) as u32, false));
+		}
+		else {
+			// Find the first cluster to be released (terminating the chain before it)
+			let mut next = if keep == 0 {
+					chain.first_cluster
+				}
+				else {
+					let last = try!(self.chain_cluster(chain, keep - 1));
+					let next = try!(self.get_next_cluster(last));
+					let mut lh = self.alloc.lock();
+					try!(self.set_fat_entry(&mut lh, last, on_disk::FAT_EOC));
+					match next
+					{
+					Some(v) => v,
+					None => return Ok( () ),
+					}
+				};
+			loop
+			{
+				let following = try!(self.get_next_cluster(next));
+				{
+					let mut lh = self.alloc.lock();
+					try!(self.set_bitmap(&mut lh, next, 1, false));
+				}
+				next = match following
+					{
+					Some(v) => v,
+					None => break,
+					};
+			}
+		}
+		if keep == 0 {
+			chain.first_cluster = 0;
+			chain.no_fat_chain = false;
+		}
+		Ok( () )
+	}
+
+	/// Get the state of a directory (by its first cluster)
+	fn get_dir(&self, cluster: u32) -> Option<Arc<dir::DirState>> {
+		if cluster == self.root.first_cluster {
+			Some(self.root.clone())
+		}
+		else {
+			self.dirs.lock().get(&cluster).cloned()
+		}
+	}
+	/// Register a directory found by a lookup in `parent`, returning the existing state if already known
+	fn register_dir(&self, parent: &Arc<dir::DirState>, index: u32, chain: Chain, metadata: node::Metadata) -> Arc<dir::DirState> {
+		use kernel::lib::vec_map::Entry;
+		match self.dirs.lock().entry(chain.first_cluster)
+		{
+		Entry::Occupied(e) => e.into_mut().clone(),
+		Entry::Vacant(e) => e.insert(Arc::new(dir::DirState::new(parent.clone(), index, chain, metadata))).clone(),
+		}
+	}
+	/// Release the state of directories (by first cluster) that are no longer needed, and of their parents
+	///
+	/// A directory is kept while anything else holds its state (nodes, open files, or child directories) or
+	/// while its parent has a loaded node (the VFS can look it up, and then load it by inode, at any time).
+	///
+	/// `releasing` is the state of a directory whose `DirNode` is being dropped (so still holds a reference).
+	fn release_dirs(&self, mut candidates: Vec<u32>, releasing: Option<u32>) {
+		let mut lh = self.dirs.lock();
+		while let Some(cluster) = candidates.pop()
+		{
+			let unused = match lh.get(&cluster)
+				{
+				Some(s) => {
+					let users = Arc::strong_count(s) - 1 - if releasing == Some(cluster) { 1 } else { 0 };
+					users == 0 && !s.is_loaded() && !s.loc.lock().parent.as_ref().map_or(false, |p| p.is_loaded())
+					},
+				None => false,
+				};
+			if unused
+			{
+				let parent = lh.remove(&cluster).and_then(|s| s.loc.lock().parent.take());
+				if let Some(parent) = parent {
+					// Cached names in the parent would refer to a directory that can no longer be loaded by inode
+					// - Done with the map locked, so a new node for the parent can't look up the stale name first
+					self.mount_handle.invalidate_dir( InodeRef::Dir(parent.first_cluster).to_id() );
+					candidates.push(parent.first_cluster);
+				}
+			}
+		}
+	}
+	/// Record a new node for a directory (children are kept while it exists)
+	fn dir_node_added(&self, state: &dir::DirState) {
+		let _lh = self.dirs.lock();
+		state.nodes.fetch_add(1, ::core::sync::atomic::Ordering::Relaxed);
+	}
+	/// Record the release of a node for a directory, releasing it and its children if no longer needed
+	fn dir_node_removed(&self, state: &dir::DirState) {
+		let candidates = {
+			let lh = self.dirs.lock();
+			if state.nodes.fetch_sub(1, ::core::sync::atomic::Ordering::Relaxed) > 1 {
+				return ;
+			}
+			let mut rv: Vec<u32> = lh.iter()
+				.filter(|&(_, s)| s.loc.lock().parent.as_ref().map_or(false, |p| p.first_cluster == state.first_cluster))
+				.map(|(&c, _)| c)
+				.collect();
+			rv.push(state.first_cluster);
+			rv
+			};
+		self.release_dirs(candidates, Some(state.first_cluster));
+	}
+
+	/// Read a run of directory entries (can span clusters)
+	fn read_ents(&self, chain: &Chain, index: u32, dst: &mut [u8]) -> vfs::Result<()> {
+		let epc = (self.cluster_size / 32) as u32;
+		let mut done = 0;
+		while done < dst.len()
+		{
+			let idx = index + (done / 32) as u32;
+			let cluster = try!(self.load_cluster(try!(self.chain_cluster(chain, (idx / epc) as u64))));
+			let ofs = (idx % epc) as usize * 32;
+			let len = ::core::cmp::min(dst.len() - done, self.cluster_size - ofs);
+			dst[done..][..len].clone_from_slice(&cluster[ofs..][..len]);
+			done += len;
+		}
+		Ok( () )
+	}
+	/// Write a run of directory entries (can span clusters)
+	fn write_ents(&self, chain: &Chain, index: u32, src: &[u8]) -> vfs::Result<()> {
+		let epc = (self.cluster_size / 32) as u32;
+		let mut done = 0;
+		while done < src.len()
+		{
+			let idx = index + (done / 32) as u32;
+			let cluster = try!(self.chain_cluster(chain, (idx / epc) as u64));
+			let ofs = (idx % epc) as usize * 32;
+			let len = ::core::cmp::min(src.len() - done, self.cluster_size - ofs);
+			try!(self.edit_cluster(cluster, |data| data[ofs..][..len].clone_from_slice(&src[done..][..len])));
+			done += len;
+		}
+		Ok( () )
+	}
+	/// Read the entry set starting at `index` in a directory
+	fn read_ent_set(&self, chain: &Chain, index: u32) -> vfs::Result<Vec<u8>> {
+		let mut primary = [0u8; 32];
+		try!(self.read_ents(chain, index, &mut primary));
+		if primary[0] != on_disk::ENT_FILE || primary[1] < 2 {
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let mut set = vec![0u8; (1 + primary[1] as usize) * 32];
+		try!(self.read_ents(chain, index, &mut set));
+		if on_disk::set_checksum(&set) != on_disk::FileEnt::read(&set).set_checksum {
+			log_warning!("exFAT: Entry set checksum mismatch at index {} of {:#x}", index, chain.first_cluster);
+		}
+		Ok(set)
+	}
+	/// Modify the entry set at `index` in the directory starting at cluster `dir` (updating the checksum)
+	///
+	/// Must be called with the directory lock held
+	fn edit_ent_set<F: FnOnce(&mut [u8])>(&self, dir: u32, index: u32, f: F) -> vfs::Result<()> {
+		let dir = match self.get_dir(dir)
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::InconsistentFilesystem),
+			};
+		let chain = *dir.chain.lock();
+		let mut set = try!(self.read_ent_set(&chain, index));
+		f(&mut set);
+		on_disk::update_set_checksum(&mut set);
+		self.write_ents(&chain, index, &set)
+	}
+	/// Update the stream extension of the entry set at `index` in the directory `dir` to describe `chain`
+	///
+	/// Must be called with the directory lock held
+	fn save_chain(&self, dir: u32, index: u32, chain: &Chain) -> vfs::Result<()> {
+		self.edit_ent_set(dir, index, |set| {
+			let stream = &mut set[32..64];
+			let mut ent = on_disk::StreamEnt::read(stream);
+			ent.flags = (ent.flags & !on_disk::STREAM_NO_FAT_CHAIN)
+				| on_disk::STREAM_ALLOC_POSSIBLE
+				| if chain.no_fat_chain { on_disk::STREAM_NO_FAT_CHAIN } else { 0 };
+			ent.first_cluster = chain.first_cluster;
+			ent.data_length = chain.size;
+			ent.valid_data_length = chain.valid_size;
+			ent.write(stream);
+			})
+	}
+
+	/// Register a file loaded by the VFS, returning the state shared with other loads of the same file
+	fn file_opened(&self, inode: node::InodeId, dir: &Arc<dir::DirState>, chain: Chain) -> Arc<file::FileState> {
+		use kernel::lib::vec_map::Entry;
+		match self.open_files.lock().entry(inode)
+		{
+		Entry::Occupied(e) => {
+			let state = e.into_mut();
+			state.ent.lock().users += 1;
+			state.clone()
+			},
+		Entry::Vacant(e) => e.insert(Arc::new(file::FileState::new(inode, dir.clone(), chain))).clone(),
+		}
+	}
+	/// Release a file loaded by the VFS, returns `true` if this was the last user of an unlinked file (and its clusters should be freed)
+	fn file_closed(&self, state: &file::FileState) -> bool {
+		let (unlinked, dir) = {
+			let mut lh = self.open_files.lock();
+			let mut ent = state.ent.lock();
+			ent.users -= 1;
+			if ent.users > 0 {
+				return false;
+			}
+			lh.remove(&ent.inode);
+			(ent.unlinked, ent.dir.take())
+			};
+		// The directory holding the file may no longer be needed
+		if let Some(dir) = dir {
+			let cluster = dir.first_cluster;
+			drop(dir);
+			self.release_dirs(vec![cluster], None);
+		}
+		unlinked
+	}
+	/// Update the location of an open file after its entry set was moved to `new_dir`. Must be called with the directory lock held
+	fn file_moved(&self, old_inode: node::InodeId, new_inode: node::InodeId, new_dir: &Arc<dir::DirState>) {
+		let old_dir = {
+			let mut lh = self.open_files.lock();
+			match lh.remove(&old_inode)
+			{
+			Some(state) => {
+				let old_dir = {
+					let mut ent = state.ent.lock();
+					ent.inode = new_inode;
+					::core::mem::replace(&mut ent.dir, Some(new_dir.clone()))
+					};
+				lh.insert(new_inode, state);
+				old_dir
+				},
+			None => None,
+			}
+			};
+		if let Some(dir) = old_dir {
+			let cluster = dir.first_cluster;
+			drop(dir);
+			self.release_dirs(vec![cluster], None);
+		}
+	}
+	/// Free the clusters of an unlinked file (deferred until the last close if the file is open)
+	///
+	/// Must be called with the directory lock held
+	fn file_unlinked(&self, inode: node::InodeId, mut chain: Chain) -> vfs::Result<()> {
+		if let Some(state) = self.open_files.lock().get(&inode) {
+			// NOTE: The file stays registered until closed, so the entries aren't reused (which would reuse the inode number)
+			state.ent.lock().unlinked = true;
+			return Ok( () );
+		}
+		self.truncate_chain(&mut chain, 0)
+	}
+	/// Check if a directory entry is in use by an open file (including one that has been unlinked)
+	fn is_ent_open(&self, inode: node::InodeId) -> bool {
+		self.open_files.lock().get(&inode).is_some()
+	}
+	/// Update the stream extension of an open file (does nothing if the file has been unlinked)
+	fn save_file_chain(&self, state: &file::FileState, chain: &Chain) -> vfs::Result<()> {
+		let _lh = self.dir_lock.lock();
+		let inode = {
+			let ent = state.ent.lock();
+			if ent.unlinked {
+				return Ok( () );
+			}
+			ent.inode
+			};
+		match InodeRef::from(inode)
+		{
+		InodeRef::File { dir, index } => self.save_chain(dir, index, chain),
+		InodeRef::Dir(_) => Err(vfs::Error::InconsistentFilesystem),
+		}
+	}
+}
+
+impl mount::Filesystem for Filesystem
+{
+	fn flush(&self) -> vfs::Result<()> {
+		try!(self.vh.flush());
+		let mut lh = self.volume_dirty.lock();
+		if lh.set && !lh.sticky {
+			try!(self.set_volume_flag(on_disk::VOLFLAG_DIRTY, false));
+			lh.set = false;
+		}
+		Ok( () )
+	}
+	fn root_inode(&self) -> node::InodeId {
+		InodeRef::Dir(self.root.first_cluster).to_id()
+	}
+	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
+		match InodeRef::from(id)
+		{
+		InodeRef::Dir(cluster) => match self.get_dir(cluster)
+			{
+			Some(state) => Some(node::Node::Dir(dir::DirNode::new_boxed(self.inner.borrow(), state))),
+			None => {
+				log_notice!("exFAT: Directory {:#x} hasn't been seen by a lookup", cluster);
+				None
+				},
+			},
+		InodeRef::File { dir, index } => dir::DirNode::load_file(self.inner.borrow(), dir, index),
+		}
+	}
+}
+
+impl InodeRef
+{
+	fn to_id(&self) -> node::InodeId {
+		match *self
+		{
+		InodeRef::Dir(cluster) => cluster as u64,
+		InodeRef::File { dir, index } => {
+			// Directories are at most 256MiB, so have less than 2^24 entries
+			assert!(index < 1 << 24);
+			1 << 63 | (dir as u64) << 24 | index as u64
+			},
+		}
+	}
+}
+
+impl From<node::InodeId> for InodeRef {
+	fn from(v: node::InodeId) -> InodeRef {
+		if v >> 63 == 0 {
+			InodeRef::Dir(v as u32)
+		}
+		else {
+			InodeRef::File {
+				dir: (v >> 24) as u32,
+				index: (v & 0xFF_FFFF) as u32,
+			}
+		}
+	}
+}
+
+impl ClusterList {
+	pub fn new(fs: ArefBorrow<FilesystemInner>, chain: &Chain) -> ClusterList {
+		if chain.first_cluster == 0 {
+			ClusterList::Range(0 .. 0)
+		}
+		else if chain.no_fat_chain {
+			let count = fs.chain_clusters(chain) as u32;
+			ClusterList::Range(chain.first_cluster .. chain.first_cluster + count)
+		}
+		else {
+			ClusterList::Chained(fs, chain.first_cluster)
+		}
+	}
+
+	/// Returns an extent of at most `max_clusters` contigious clusters
+	pub fn next_extent(&mut self, max_clusters: usize) -> Option<(u32, usize)> {
+		match *self
+		{
+		ClusterList::Range(ref mut r) =>
+			if r.start == r.end {
+				None
+			}
+			else {
+				let count = ::core::cmp::min(max_clusters, (r.end - r.start) as usize);
+				let rv = r.start;
+				r.start += count as u32;
+				Some( (rv, count) )
+			},
+		ClusterList::Chained(ref fs, ref mut next) =>
+			if *next == 0 {
+				None
+			}
+			else {
+				let rv = *next;
+				let mut count = 0;
+				while *next != 0 && *next == rv + count as u32 && count < max_clusters
+				{
+					*next = match fs.get_next_cluster(*next)
+						{
+						Ok(Some(v)) => v,
+						Ok(None) => 0,
+						Err(e) => {
+							log_warning!("Error when reading cluster chain - {:?}", e);
+							return None;	// Inconsistency, terminate asap
+							},
+						};
+					count += 1;
+				}
+				Some( (rv, count) )
+			},
+		}
+	}
+}
+impl ::core::iter::Iterator for ClusterList {
+	type Item = u32;
+	fn next(&mut self) -> Option<u32> {
+		self.next_extent(1).map(|(c, _)| c)
+	}
+}
@@ -0,0 +1,255 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/on_disk.rs
+//! On-Disk structures and flags
+#[allow(unused_imports)]
+use kernel::prelude::*;
+use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+
+/// Value of `BootSector::fs_name`
+pub const FS_NAME: [u8; 8] = *b"EXFAT   ";
+
+/// Volume flag: Second FAT and allocation bitmap are active (TexFAT only)
+pub const VOLFLAG_ACTIVE_FAT: u16 = 0x0001;
+/// Volume flag: Volume is possibly inconsistent (set while being modified)
+pub const VOLFLAG_DIRTY: u16 = 0x0002;
+/// Byte offset of the volume flags in the boot sector
+pub const VOLFLAGS_OFS: usize = 106;
+
+/// FAT entry marking a bad cluster
+pub const FAT_BAD: u32 = 0xFFFF_FFF7;
+/// FAT entry marking the end of a chain
+pub const FAT_EOC: u32 = 0xFFFF_FFFF;
+
+/// Entry type flag: Entry is in use (cleared in deleted entries)
+pub const ENT_INUSE: u8 = 0x80;
+/// End of directory marker
+pub const ENT_END: u8 = 0x00;
+pub const ENT_BITMAP: u8 = 0x81;
+pub const ENT_UPCASE: u8 = 0x82;
+pub const ENT_LABEL: u8 = 0x83;
+pub const ENT_FILE: u8 = 0x85;
+pub const ENT_STREAM: u8 = 0xC0;
+pub const ENT_NAME: u8 = 0xC1;
+
+pub const ATTR_READONLY : u16 = 0x01;
+pub const ATTR_HIDDEN   : u16 = 0x02;
+pub const ATTR_SYSTEM   : u16 = 0x04;
+pub const ATTR_DIRECTORY: u16 = 0x10;
+pub const ATTR_ARCHIVE  : u16 = 0x20;
+
+/// Stream flag: Clusters are allocated (`first_cluster` is valid)
+pub const STREAM_ALLOC_POSSIBLE: u8 = 0x01;
+/// Stream flag: Clusters are contiguous, and the FAT isn't used
+pub const STREAM_NO_FAT_CHAIN: u8 = 0x02;
+
+/// Number of UTF-16 code units in each name entry
+pub const NAME_ENT_CHARS: usize = 15;
+/// Maximum length of a name (in UTF-16 code units)
+pub const MAX_NAME_LEN: usize = 255;
+
+pub struct BootSector
+{
+	pub _jump: [u8; 3],
+	pub fs_name: [u8; 8],
+	pub partition_offset: u64,
+	pub volume_length: u64,
+	pub fat_offset: u32,
+	pub fat_length: u32,
+	pub cluster_heap_offset: u32,
+	pub cluster_count: u32,
+	pub root_cluster: u32,
+	pub serial_number: u32,
+	pub fs_revision: u16,
+	pub volume_flags: u16,
+	pub bytes_per_sector_shift: u8,
+	pub sectors_per_cluster_shift: u8,
+	pub fat_count: u8,
+	pub _drive_select: u8,
+	pub percent_in_use: u8,
+	pub boot_signature: u16,
+}
+impl BootSector {
+	pub fn read(src: &[u8]) -> BootSector {
+		assert!(src.len() >= 512);
+		let mut jump = [0; 3];
+		jump.clone_from_slice(&src[0..3]);
+		let mut fs_name = [0; 8];
+		fs_name.clone_from_slice(&src[3..11]);
+		BootSector {
+			_jump: jump,
+			fs_name: fs_name,
+			partition_offset: LittleEndian::read_u64(&src[64..]),
+			volume_length: LittleEndian::read_u64(&src[72..]),
+			fat_offset: LittleEndian::read_u32(&src[80..]),
+			fat_length: LittleEndian::read_u32(&src[84..]),
+			cluster_heap_offset: LittleEndian::read_u32(&src[88..]),
+			cluster_count: LittleEndian::read_u32(&src[92..]),
+			root_cluster: LittleEndian::read_u32(&src[96..]),
+			serial_number: LittleEndian::read_u32(&src[100..]),
+			fs_revision: LittleEndian::read_u16(&src[104..]),
+			volume_flags: LittleEndian::read_u16(&src[VOLFLAGS_OFS..]),
+			bytes_per_sector_shift: src[108],
+			sectors_per_cluster_shift: src[109],
+			fat_count: src[110],
+			_drive_select: src[111],
+			percent_in_use: src[112],
+			boot_signature: LittleEndian::read_u16(&src[510..]),
+		}
+	}
+}
+
+/// File directory entry (primary entry of a file/directory's entry set)
+pub struct FileEnt
+{
+	pub secondary_count: u8,
+	pub set_checksum: u16,
+	pub attributes: u16,
+	pub create_time: u32,
+	pub modified_time: u32,
+	pub accessed_time: u32,
+	pub create_10ms: u8,
+	pub modified_10ms: u8,
+	pub create_utc_ofs: u8,
+	pub modified_utc_ofs: u8,
+	pub accessed_utc_ofs: u8,
+}
+impl FileEnt {
+	pub fn read(src: &[u8]) -> FileEnt {
+		FileEnt {
+			secondary_count: src[1],
+			set_checksum: LittleEndian::read_u16(&src[2..]),
+			attributes: LittleEndian::read_u16(&src[4..]),
+			create_time: LittleEndian::read_u32(&src[8..]),
+			modified_time: LittleEndian::read_u32(&src[12..]),
+			accessed_time: LittleEndian::read_u32(&src[16..]),
+			create_10ms: src[20],
+			modified_10ms: src[21],
+			create_utc_ofs: src[22],
+			modified_utc_ofs: src[23],
+			accessed_utc_ofs: src[24],
+		}
+	}
+	/// Serialise into a 32-byte directory entry (the checksum is filled by `set_checksum`)
+	pub fn write(&self, dst: &mut [u8]) {
+		assert!(dst.len() >= 32);
+		for b in dst[..32].iter_mut() {
+			*b = 0;
+		}
+		dst[0] = ENT_FILE;
+		dst[1] = self.secondary_count;
+		LittleEndian::write_u16(&mut dst[2..], self.set_checksum);
+		LittleEndian::write_u16(&mut dst[4..], self.attributes);
+		LittleEndian::write_u32(&mut dst[8..], self.create_time);
+		LittleEndian::write_u32(&mut dst[12..], self.modified_time);
+		LittleEndian::write_u32(&mut dst[16..], self.accessed_time);
+		dst[20] = self.create_10ms;
+		dst[21] = self.modified_10ms;
+		dst[22] = self.create_utc_ofs;
+		dst[23] = self.modified_utc_ofs;
+		dst[24] = self.accessed_utc_ofs;
+	}
+}
+
+/// Stream extension entry (first secondary entry, describes the data)
+pub struct StreamEnt
+{
+	pub flags: u8,
+	pub name_length: u8,
+	pub name_hash: u16,
+	pub valid_data_length: u64,
+	pub first_cluster: u32,
+	pub data_length: u64,
+}
+impl StreamEnt {
+	pub fn read(src: &[u8]) -> StreamEnt {
+		StreamEnt {
+			flags: src[1],
+			name_length: src[3],
+			name_hash: LittleEndian::read_u16(&src[4..]),
+			valid_data_length: LittleEndian::read_u64(&src[8..]),
+			first_cluster: LittleEndian::read_u32(&src[20..]),
+			data_length: LittleEndian::read_u64(&src[24..]),
+		}
+	}
+	/// Serialise into a 32-byte directory entry
+	pub fn write(&self, dst: &mut [u8]) {
+		assert!(dst.len() >= 32);
+		for b in dst[..32].iter_mut() {
+			*b = 0;
+		}
+		dst[0] = ENT_STREAM;
+		dst[1] = self.flags;
+		dst[3] = self.name_length;
+		LittleEndian::write_u16(&mut dst[4..], self.name_hash);
+		LittleEndian::write_u64(&mut dst[8..], self.valid_data_length);
+		LittleEndian::write_u32(&mut dst[20..], self.first_cluster);
+		LittleEndian::write_u64(&mut dst[24..], self.data_length);
+	}
+}
+
+/// Read the name characters from a file name entry
+pub fn read_name_ent(src: &[u8], dst: &mut [u16]) {
+	for (i, d) in dst.iter_mut().take(NAME_ENT_CHARS).enumerate() {
+		*d = LittleEndian::read_u16(&src[2 + i*2..]);
+	}
+}
+/// Serialise a file name entry (`chars` is at most 15 characters, the rest is zero-padded)
+pub fn write_name_ent(dst: &mut [u8], chars: &[u16]) {
+	assert!(dst.len() >= 32);
+	assert!(chars.len() <= NAME_ENT_CHARS);
+	for b in dst[..32].iter_mut() {
+		*b = 0;
+	}
+	dst[0] = ENT_NAME;
+	for (i, &c) in chars.iter().enumerate() {
+		LittleEndian::write_u16(&mut dst[2 + i*2..], c);
+	}
+}
+
+/// Checksum of an entry set (skipping the checksum field in the primary entry)
+pub fn set_checksum(ents: &[u8]) -> u16 {
+	ents.iter().enumerate()
+		.filter(|&(i,_)| i != 2 && i != 3)
+		.fold(0u16, |sum, (_,&b)| sum.rotate_right(1).wrapping_add(b as u16))
+}
+/// Update the checksum stored in an entry set
+pub fn update_set_checksum(ents: &mut [u8]) {
+	let sum = set_checksum(ents);
+	LittleEndian::write_u16(&mut ents[2..], sum);
+}
+/// Hash of an up-cased name, stored in the stream extension to speed up lookups
+pub fn name_hash(upcased_name: &[u16]) -> u16 {
+	upcased_name.iter()
+		.fold(0u16, |sum, &c| sum.rotate_right(1).wrapping_add(c & 0xFF).rotate_right(1).wrapping_add(c >> 8))
+}
+/// Checksum of the up-case table
+pub fn table_checksum(data: &[u8]) -> u32 {
+	data.iter().fold(0u32, |sum, &b| sum.rotate_right(1).wrapping_add(b as u32))
+}
+
+/// Convert an exFAT timestamp (DOS-style date and time, with an optional UTC offset) into a timestamp
+///
+/// A zero value (unset) gives a zero timestamp.
+pub fn timestamp_from_exfat(ts: u32, ms10: u8, utc_ofs: u8) -> ::kernel::time::Timestamp {
+	if ts == 0 {
+		return 0;
+	}
+	let (date, time) = ((ts >> 16) as u16, ts as u16);
+	let year = 1980 + (date >> 9) as i32;
+	let month = ((date >> 5) & 0xF) as u8;
+	let day = (date & 0x1F) as u8;
+	let hour = (time >> 11) as u8;
+	let minute = ((time >> 5) & 0x3F) as u8;
+	let second = ((time & 0x1F) * 2) as u8;
+	let rv = ::kernel::time::timestamp_from_date(year, month, day, hour, minute, second) + ms10 as i64 / 100;
+	// Bit 7 indicates that the offset (a signed count of 15 minute intervals) is valid, otherwise local time is treated as UTC
+	if utc_ofs & 0x80 != 0 {
+		let ofs = ((utc_ofs << 1) as i8 >> 1) as i64;
+		rv - ofs * 15 * 60
+	}
+	else {
+		rv
+	}
+}
@@ -0,0 +1,66 @@
+// "Tifflin" Kernel - exFAT Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_exfat/upcase.rs
+//! Up-case table (used for case-insensitive name comparisons and name hashes)
+use kernel::prelude::*;
+use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+
+pub struct UpcaseTable
+{
+	/// Mapping for the first `map.len()` code units, the rest map to themselves
+	map: Vec<u16>,
+}
+
+impl UpcaseTable
+{
+	/// Decompress the on-disk table
+	///
+	/// The table is a list of up-case values for each code unit in order, where `0xFFFF` followed by a count
+	/// indicates a run of code units that map to themselves.
+	pub fn new(data: &[u8]) -> UpcaseTable {
+		let mut map: Vec<u16> = Vec::new();
+		let mut values = data.chunks(2).filter(|v| v.len() == 2).map(|v| LittleEndian::read_u16(v));
+		while map.len() < 0x10000
+		{
+			match values.next()
+			{
+			None => break,
+			Some(0xFFFF) => match values.next()
+				{
+				Some(count) => for _ in 0 .. count {
+					let c = map.len();
+					if c == 0x10000 {
+						break;
+					}
+					map.push(c as u16);
+					},
+				// A trailing 0xFFFF is the mapping of 0xFFFF itself
+				None => map.push(0xFFFF),
+				},
+			Some(v) => map.push(v),
+			}
+		}
+		// Trim the identity mappings at the end
+		while map.len() > 0 && map[map.len()-1] as usize == map.len()-1 {
+			map.pop();
+		}
+		UpcaseTable {
+			map: map,
+		}
+	}
+
+	/// Up-case a single UTF-16 code unit
+	pub fn upcase(&self, c: u16) -> u16 {
+		match self.map.get(c as usize)
+		{
+		Some(&v) => v,
+		None => c,
+		}
+	}
+
+	/// Compare two names without regard to case
+	pub fn names_equal(&self, a: &[u16], b: &[u16]) -> bool {
+		a.len() == b.len() && Iterator::zip(a.iter(), b.iter()).all(|(&a,&b)| a == b || self.upcase(a) == self.upcase(b))
+	}
+}
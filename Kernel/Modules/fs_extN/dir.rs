@@ -3,6 +3,7 @@
 //
 // Modules/fs_extN/dir.rs
 //! Directory handling
+use kernel::prelude::*;
 use kernel::vfs;
 use kernel::lib::byte_str::ByteStr;
 
@@ -23,79 +24,179 @@ impl Dir
 	}
 
 
-	/// Returns (block_index, offset)
-	fn find_name(&self, name: &ByteStr) -> vfs::node::Result<(usize, usize, vfs::node::InodeId)>
+	pub fn inode(&self) -> &::inodes::Inode {
+		&self.inode
+	}
+
+
+	/// Locate the entry with the specified name
+	fn find_name(&self, name: &ByteStr) -> vfs::node::Result<EntPos>
 	{
+		if self.is_indexed()
+		{
+			match self.dx_find_name(name)
+			{
+			Ok(Some(v)) => return Ok(v),
+			Ok(None) => return Err(vfs::Error::NotFound),
+			Err(e) => {
+				log_notice!("Directory {}: Unusable index ({:?}), using a linear search", self.inode.get_id(), e);
+				},
+			}
+		}
 		// Linear search
-		// TODO: Later revisions have B+ trees
 		for (blk_index, vol_blk) in self.inode.blocks().enumerate()
 		{
-			let blk_data = try!(self.inode.fs.get_block(vol_blk));
-			
-			let mut offset = 0;
-			for ent in DirEnts(&blk_data)
-			{
-				if ent.d_rec_len == 0 {
-					return Err( vfs::Error::InconsistentFilesystem );
-				}
-				else if ent.d_inode == 0 {
-					return Err(vfs::Error::NotFound);
-				}
-				else if &ent.d_name == name.as_ref()
-				{
-					return Ok( (blk_index, offset, ent.d_inode as vfs::node::InodeId) );
-				}
-				else {
-					offset += ent.u32_len() * 4;
-				}
+			if let Some(v) = try!(self.find_in_block(blk_index as u32, vol_blk, name)) {
+				return Ok(v);
 			}
 		}
 		Err(vfs::Error::NotFound)
 	}
+	/// Search a single directory block for the specified name
+	fn find_in_block(&self, blk_index: u32, vol_blk: u32, name: &ByteStr) -> vfs::node::Result<Option<EntPos>>
+	{
+		let blk_data = try!(self.inode.fs.get_block(vol_blk));
+		
+		let mut offset = 0;
+		let mut prev_ofs = None;
+		for ent in DirEnts(&blk_data)
+		{
+			if ent.rec_len() == 0 {
+				return Err( vfs::Error::InconsistentFilesystem );
+			}
+			else if ent.d_inode != 0 && &ent.d_name == name.as_ref()
+			{
+				return Ok(Some(EntPos {
+					blk: blk_index,
+					ofs: offset,
+					prev_ofs: prev_ofs,
+					inode: ent.d_inode,
+					d_type: ent.d_type,
+					}));
+			}
+			else {
+				prev_ofs = Some(offset);
+				offset += ent.u32_len() * 4;
+			}
+		}
+		Ok(None)
+	}
 
 
+	/// Locate an entry with enough space to hold `name` (either unused, or with slack after its name)
 	fn find_free(&self, name: &ByteStr) -> vfs::node::Result<(u32, usize)>
 	{
 		assert!(name.len() <= 255);
-		// Linear search
-		// TODO: Later revisions have B+ trees
-		for (blk_index, vol_blk) in self.inode.blocks().enumerate()
+		let required = dirent_size(name.len());
+		if self.is_indexed()
 		{
-			let blk_data = try!(self.inode.fs.get_block(vol_blk));
-			
-			let mut offset = 0;
-			for ent in DirEnts(&blk_data)
+			match self.dx_find_free(name, required)
 			{
-				if ent.d_rec_len == 0 {
-					return Err( vfs::Error::InconsistentFilesystem );
-				}
-				else if ent.d_inode == 0 && (ent.d_rec_len - 8) <= name.len() as u16
+			Ok(v) => return Ok(v),
+			Err(e) => match e
 				{
-					// Free entry with sufficient space!
-					return Ok( (blk_index as u32, offset) );
-				}
-				else {
-					offset += ent.u32_len() * 4;
-				}
+				vfs::Error::InconsistentFilesystem | vfs::Error::Unknown(_) => {
+					// Entries added outside of the index can't be found through it, so remove the index (the blocks are
+					// still a valid linear directory without it)
+					log_notice!("Directory {}: Cannot insert via the index ({:?}), removing the index", self.inode.get_id(), e);
+					self.inode.clear_i_flags(::ondisk::EXT4_INDEX_FL);
+					try!(self.inode.flush());
+					},
+				_ => return Err(e),
+				},
+			}
+		}
+		// Linear search
+		for (blk_index, vol_blk) in self.inode.blocks().enumerate()
+		{
+			if let Some(ofs) = try!(self.find_space_in_block(vol_blk, required)) {
+				return Ok( (blk_index as u32, ofs) );
 			}
 		}
 
-		todo!("Dir::find_free - expand directory");
+		// No space in the existing blocks, add a block (holding a single unused entry) to the end of the directory
+		let blk_index = try!(self.append_block(|blk_data, bs| write_dirent(blk_data, 0, bs, 0, 0, b"")));
+		Ok( (blk_index, 0) )
+	}
+	/// Search a single directory block for an entry with `required` bytes of space
+	fn find_space_in_block(&self, vol_blk: u32, required: usize) -> vfs::node::Result<Option<usize>>
+	{
+		let blk_data = try!(self.inode.fs.get_block(vol_blk));
+		
+		let mut offset = 0;
+		for ent in DirEnts(&blk_data)
+		{
+			let rec_len = ent.rec_len();
+			if rec_len == 0 {
+				return Err( vfs::Error::InconsistentFilesystem );
+			}
+			else if ent.d_inode == 0 && rec_len >= required
+			{
+				// Free entry with sufficient space!
+				return Ok( Some(offset) );
+			}
+			else if ent.d_inode != 0 && rec_len >= dirent_size(ent.d_name.len()) + required
+			{
+				// Used entry with enough slack to be split
+				return Ok( Some(offset) );
+			}
+			else {
+				offset += ent.u32_len() * 4;
+			}
+		}
+		Ok(None)
+	}
+	/// Add a block (cleared, then filled by `init`) to the end of the directory, returning its index
+	fn append_block<F>(&self, init: F) -> vfs::node::Result<u32>
+	where
+		F: FnOnce(&mut [u32], usize) -> vfs::node::Result<()>
+	{
+		let blk_index = self.inode.max_blocks();
+		let (vol_blk, _) = try!(self.inode.get_or_alloc_block(blk_index));
+		let bs = self.inode.fs.fs_block_size;
+		try!(self.inode.fs.edit_block(vol_blk, |blk_data| {
+			for v in blk_data.iter_mut() {
+				*v = 0;
+			}
+			init(blk_data, bs)
+			}));
+		try!(self.inode.set_i_size(self.inode.i_size() + bs as u64));
+		try!(self.inode.flush());
+		Ok(blk_index)
 	}
 
-	fn add_dir_ent(&self, name: &ByteStr, inode: u32) -> Result<(), vfs::Error>
+	fn add_dir_ent(&self, name: &ByteStr, inode: u32, d_type: u8) -> Result<(), vfs::Error>
 	{
 		// 1. Find a suitable slot
 		let (blk, ofs) = try!(self.find_free(name));
 		// 2. Fill said slot
 		let vol_blk = try!( self.inode.blocks_from(blk as u32).next_or_err() );
 		self.inode.fs.edit_block(vol_blk, |blk_data| {
+				// - If the slot is in use, split off the unused tail
+				let ofs = match ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..])
+					{
+					None => return Err(vfs::Error::InconsistentFilesystem),
+					Some(ent) => if ent.d_inode == 0 {
+							ofs
+						}
+						else {
+							let used = dirent_size(ent.d_name.len());
+							let rem = ent.rec_len() - used;
+							ent.set_rec_len(used);
+							// Write the new entry's record length (the rest is filled below)
+							let new_ofs = ofs + used;
+							blk_data[new_ofs/4] = 0;
+							blk_data[new_ofs/4 + 1] = rem as u32;	// d_rec_len (and zero name length/type)
+							new_ofs
+						},
+					};
 				match ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..])
 				{
 				None => return Err(vfs::Error::InconsistentFilesystem),
 				Some(ent) => {
 					ent.d_name_len = name.len() as u8;
 					ent.d_inode = inode as u32;
+					ent.d_type = d_type;
 					},
 				}
 				// - Now that name length is longer, update the name
@@ -104,6 +205,538 @@ impl Dir
 				Ok( () )
 				})
 	}
+
+	/// Remove the entry at the specified location (merging its space into the previous entry)
+	fn remove_dir_ent(&self, pos: &EntPos) -> Result<(), vfs::Error>
+	{
+		let vol_blk = try!( self.inode.blocks_from(pos.blk).next_or_err() );
+		self.inode.fs.edit_block(vol_blk, |blk_data| {
+			let rec_len = match ::ondisk::DirEnt::new_mut(&mut blk_data[pos.ofs/4 ..])
+				{
+				None => return Err(vfs::Error::InconsistentFilesystem),
+				Some(ent) => {
+					ent.d_inode = 0;
+					ent.rec_len()
+					},
+				};
+			if let Some(prev_ofs) = pos.prev_ofs {
+				match ::ondisk::DirEnt::new_mut(&mut blk_data[prev_ofs/4 ..])
+				{
+				None => return Err(vfs::Error::InconsistentFilesystem),
+				Some(ent) => {
+					let len = ent.rec_len() + rec_len;
+					ent.set_rec_len(len);
+					},
+				}
+			}
+			Ok( () )
+			})
+	}
+}
+
+/// Directory index (htree) handling
+impl Dir
+{
+	/// Check if lookups and inserts should use the directory index
+	fn is_indexed(&self) -> bool
+	{
+		self.inode.fs.has_dir_index() && self.inode.i_flags() & ::ondisk::EXT4_INDEX_FL != 0
+	}
+
+	/// Locate an entry using the index, returns `Ok(None)` if the name isn't present
+	fn dx_find_name(&self, name: &ByteStr) -> vfs::node::Result<Option<EntPos>>
+	{
+		let mut path = try!(self.dx_probe(name));
+		let mut blk = path.leaf();
+		loop
+		{
+			let vol_blk = try!( self.inode.blocks_from(blk).next_or_err() );
+			if let Some(v) = try!(self.find_in_block(blk, vol_blk, name)) {
+				return Ok(Some(v));
+			}
+			// Names with colliding hashes can continue into the following leaves
+			blk = match try!(self.dx_next_leaf(&mut path))
+				{
+				Some(v) => v,
+				None => return Ok(None),
+				};
+		}
+	}
+
+	/// Locate space for a new entry in the leaf that its hash maps to (splitting the leaf if it's full)
+	fn dx_find_free(&self, name: &ByteStr, required: usize) -> vfs::node::Result<(u32, usize)>
+	{
+		let mut path = try!(self.dx_probe(name));
+		let leaf = path.leaf();
+		let vol_blk = try!( self.inode.blocks_from(leaf).next_or_err() );
+		if let Some(ofs) = try!(self.find_space_in_block(vol_blk, required)) {
+			return Ok( (leaf, ofs) );
+		}
+
+		try!(self.dx_make_room(&mut path));
+		let blk = try!(self.dx_split_leaf(&mut path));
+		let vol_blk = try!( self.inode.blocks_from(blk).next_or_err() );
+		match try!(self.find_space_in_block(vol_blk, required))
+		{
+		Some(ofs) => Ok( (blk, ofs) ),
+		None => Err( vfs::Error::Unknown("Split directory leaf is still full") ),
+		}
+	}
+
+	/// Hash a name using the specified (adjusted) hash version
+	fn dx_hash(&self, name: &[u8], version: u8) -> vfs::node::Result<u32>
+	{
+		match ::htree::name_hash(name, version, &self.inode.fs.dx_hash_seed())
+		{
+		Some(v) => Ok(v),
+		None => Err( vfs::Error::Unknown("Unknown directory hash version") ),
+		}
+	}
+
+	/// Walk the index from the root to the leaf that holds (or would hold) `name`
+	fn dx_probe(&self, name: &ByteStr) -> vfs::node::Result<DxPath>
+	{
+		let (version, levels) = {
+			let root = try!(self.inode.fs.get_block( try!(self.inode.blocks().next_or_err()) ));
+			let info = root[DX_ROOT_INFO + 1];
+			let (hash_version, info_length, levels) = (info as u8, (info >> 8) as u8, (info >> 16) as u8);
+			if root[DX_ROOT_INFO] != 0 || info_length != ::ondisk::DX_ROOT_INFO_LEN || levels >= self.inode.fs.dx_max_levels() {
+				return Err( vfs::Error::InconsistentFilesystem );
+			}
+			(self.inode.fs.dx_hash_version(hash_version), levels)
+			};
+		let hash = try!(self.dx_hash(name.as_ref(), version));
+
+		let mut frames = Vec::new();
+		let mut node = try!(self.dx_read_node(0, DX_ROOT_BASE));
+		loop
+		{
+			node.seek(hash);
+			let next = node.block();
+			frames.push(node);
+			if frames.len() > levels as usize {
+				break ;
+			}
+			node = try!(self.dx_read_node(next, DX_NODE_BASE));
+		}
+		Ok(DxPath {
+			version: version,
+			levels: levels,
+			hash: hash,
+			frames: frames,
+			})
+	}
+
+	/// Read (and sanity check) an index node
+	fn dx_read_node(&self, blk: u32, base: usize) -> vfs::node::Result<DxFrame>
+	{
+		let n_blocks = self.inode.max_blocks();
+		let data = try!(self.inode.fs.get_block( try!(self.inode.blocks_from(blk).next_or_err()) ));
+		let limit = (data[base] & 0xFFFF) as usize;
+		let count = (data[base] >> 16) as usize;
+		if count == 0 || count > limit || limit > (data.len() - base) / 2 {
+			return Err( vfs::Error::InconsistentFilesystem );
+		}
+		let mut entries: Vec<(u32,u32)> = Vec::with_capacity(count);
+		for i in 0 .. count
+		{
+			// - The first entry's hash overlaps the count/limit header, and is implicitly zero
+			let hash = if i == 0 { 0 } else { data[base + 2*i] };
+			let block = data[base + 2*i + 1] & DX_BLOCK_MASK;
+			// - Hashes must be sorted, and the blocks must be within the directory (and not the root)
+			if block == 0 || block >= n_blocks || entries.last().map(|e| e.0 > hash).unwrap_or(false) {
+				return Err( vfs::Error::InconsistentFilesystem );
+			}
+			entries.push( (hash, block) );
+		}
+		Ok(DxFrame {
+			blk: blk,
+			base: base,
+			limit: limit,
+			entries: entries,
+			pos: 0,
+			})
+	}
+
+	/// Advance to the next leaf if it could contain more entries with the same hash
+	fn dx_next_leaf(&self, path: &mut DxPath) -> vfs::node::Result<Option<u32>>
+	{
+		// Find the lowest level that has another entry
+		let mut lvl = path.frames.len();
+		loop
+		{
+			if lvl == 0 {
+				return Ok(None);
+			}
+			lvl -= 1;
+			if path.frames[lvl].pos + 1 < path.frames[lvl].entries.len() {
+				break ;
+			}
+		}
+		path.frames[lvl].pos += 1;
+		// The low bit of the hash is set when a run of colliding hashes was split, so compare without it
+		let next_hash = path.frames[lvl].entries[path.frames[lvl].pos].0;
+		if next_hash & !1 != path.hash {
+			return Ok(None);
+		}
+		// Re-load the lower levels, starting at their first entry
+		for i in lvl + 1 .. path.frames.len()
+		{
+			let blk = path.frames[i-1].block();
+			path.frames[i] = try!(self.dx_read_node(blk, DX_NODE_BASE));
+		}
+		Ok(Some(path.leaf()))
+	}
+
+	/// Write an index node's entries back to disk
+	fn dx_write_node(&self, frame: &DxFrame) -> vfs::node::Result<()>
+	{
+		let vol_blk = try!( self.inode.blocks_from(frame.blk).next_or_err() );
+		self.inode.fs.edit_block(vol_blk, |blk_data| {
+			write_dx_entries(blk_data, frame.base, frame.limit, &frame.entries);
+			Ok( () )
+			})
+	}
+	/// Add a new (non-root) index node to the end of the directory
+	fn dx_append_node(&self, entries: &[(u32,u32)]) -> vfs::node::Result<u32>
+	{
+		self.append_block(|blk_data, bs| {
+			// An unused entry spanning the whole block, so the node looks empty to a linear search
+			blk_data[1] = ::ondisk::rec_len_to_disk(bs) as u32;
+			write_dx_entries(blk_data, DX_NODE_BASE, dx_node_limit(bs), entries);
+			Ok( () )
+			})
+	}
+
+	/// Ensure that the lowest index node on `path` has space for another entry
+	///
+	/// Full nodes are split (with half of their entries moved to a new node), if all levels are full then a new level is
+	/// added below the root.
+	fn dx_make_room(&self, path: &mut DxPath) -> vfs::node::Result<()>
+	{
+		let n_full = path.frames.iter().rev().take_while(|f| f.entries.len() >= f.limit).count();
+		if n_full == 0 {
+			return Ok( () );
+		}
+		let mut first_full = path.frames.len() - n_full;
+		if first_full == 0
+		{
+			// Every level is full, move the root's entries into a new node
+			if path.levels + 1 >= self.inode.fs.dx_max_levels() {
+				return Err( vfs::Error::Unknown("Directory index is full") );
+			}
+			let new_blk = try!(self.dx_append_node(&path.frames[0].entries));
+			let node = DxFrame {
+				blk: new_blk,
+				base: DX_NODE_BASE,
+				limit: dx_node_limit(self.inode.fs.fs_block_size),
+				entries: ::core::mem::replace(&mut path.frames[0].entries, vec![(0, new_blk)]),
+				pos: path.frames[0].pos,
+				};
+			path.frames[0].pos = 0;
+			path.levels += 1;
+			try!(self.dx_write_node(&path.frames[0]));
+			let levels = path.levels;
+			try!(self.inode.fs.edit_block(try!(self.inode.blocks().next_or_err()), |blk_data| {
+				let info = &mut blk_data[DX_ROOT_INFO + 1];
+				*info = (*info & !0xFF_0000) | (levels as u32) << 16;
+				Ok( () )
+				}));
+			path.frames.insert(1, node);
+			// - The root and the new node now have space, split any full levels below them
+			first_full = 2;
+		}
+
+		// Split full nodes from the top down (so each split has space in its parent)
+		for i in first_full .. path.frames.len()
+		{
+			let split = path.frames[i].entries.len() / 2;
+			let upper = Vec::from(&path.frames[i].entries[split..]);
+			let new_blk = try!(self.dx_append_node(&upper));
+
+			let (parents, rest) = path.frames.split_at_mut(i);
+			let parent = parents.last_mut().unwrap();
+			let frame = &mut rest[0];
+			// - Add the new node to the parent before removing the moved entries from the original
+			let pos = parent.pos + 1;
+			parent.entries.insert(pos, (upper[0].0, new_blk));
+			frame.entries.truncate(split);
+			if frame.pos >= split {
+				parent.pos += 1;
+				try!(self.dx_write_node(parent));
+				try!(self.dx_write_node(frame));
+				// - The path now continues through the new node
+				*frame = DxFrame {
+					blk: new_blk,
+					base: DX_NODE_BASE,
+					limit: frame.limit,
+					entries: upper,
+					pos: frame.pos - split,
+					};
+			}
+			else {
+				try!(self.dx_write_node(parent));
+				try!(self.dx_write_node(frame));
+			}
+		}
+		Ok( () )
+	}
+
+	/// Split the leaf at the end of `path` (the lowest index node must have space), returning the leaf for `path.hash`
+	fn dx_split_leaf(&self, path: &mut DxPath) -> vfs::node::Result<u32>
+	{
+		let leaf = path.leaf();
+		let vol_blk = try!( self.inode.blocks_from(leaf).next_or_err() );
+
+		// Collect the leaf's entries, sorted by hash
+		let mut ents = Vec::new();
+		for ent in DirEnts(&*try!(self.inode.fs.get_block(vol_blk)))
+		{
+			if ent.rec_len() == 0 {
+				return Err( vfs::Error::InconsistentFilesystem );
+			}
+			if ent.d_inode != 0 {
+				ents.push(LeafEnt {
+					hash: try!(self.dx_hash(&ent.d_name, path.version)),
+					inode: ent.d_inode,
+					d_type: ent.d_type,
+					name: Vec::from(&ent.d_name[..]),
+					});
+			}
+		}
+		if ents.len() < 2 {
+			return Err( vfs::Error::Unknown("Directory leaf cannot be split") );
+		}
+		ents.sort_unstable_by_key(|e| e.hash);
+
+		// Split at the half-way point (by size)
+		let total: usize = ents.iter().map(|e| dirent_size(e.name.len())).sum();
+		let mut split = 0;
+		let mut size = 0;
+		while split < ents.len() && size < total / 2
+		{
+			size += dirent_size(ents[split].name.len());
+			split += 1;
+		}
+		let split = ::core::cmp::max(1, ::core::cmp::min(split, ents.len() - 1));
+		let mut split_hash = ents[split].hash;
+		if ents[split - 1].hash == split_hash {
+			// A run of colliding hashes spans the split, flag it so lookups continue into the new leaf
+			split_hash |= 1;
+		}
+
+		// Write the new leaf and add it to the index before removing the moved entries from the original
+		let new_blk = try!(self.append_block(|blk_data, bs| write_leaf(blk_data, bs, &ents[split..])));
+		{
+			let frame = path.frames.last_mut().unwrap();
+			let pos = frame.pos + 1;
+			frame.entries.insert(pos, (split_hash, new_blk));
+			try!(self.dx_write_node(frame));
+		}
+		let bs = self.inode.fs.fs_block_size;
+		try!(self.inode.fs.edit_block(vol_blk, |blk_data| write_leaf(blk_data, bs, &ents[..split])));
+
+		if path.hash >= split_hash {
+			path.frames.last_mut().unwrap().pos += 1;
+			Ok(new_blk)
+		}
+		else {
+			Ok(leaf)
+		}
+	}
+}
+
+/// Word offset of `dx_root_info` in the first block of an indexed directory (after the `.` and `..` entries)
+const DX_ROOT_INFO: usize = 6;
+/// Word offset of the count/limit header in the index root
+const DX_ROOT_BASE: usize = 8;
+/// Word offset of the count/limit header in other index nodes (after an unused entry spanning the block)
+const DX_NODE_BASE: usize = 2;
+/// Block number bits of an index entry (the rest are reserved)
+const DX_BLOCK_MASK: u32 = 0x0FFF_FFFF;
+
+/// Path through the directory index to a leaf
+struct DxPath
+{
+	/// Hash algorithm (adjusted for signedness)
+	version: u8,
+	/// Number of index levels below the root
+	levels: u8,
+	/// Hash of the name being located
+	hash: u32,
+	/// Index nodes, from the root down
+	frames: Vec<DxFrame>,
+}
+impl DxPath
+{
+	/// Directory block index of the current leaf
+	fn leaf(&self) -> u32 {
+		self.frames.last().unwrap().block()
+	}
+}
+/// A node of the directory index
+struct DxFrame
+{
+	/// Block index within the directory
+	blk: u32,
+	/// Word offset of the count/limit header
+	base: usize,
+	/// Maximum number of entries
+	limit: usize,
+	/// (hash, block) pairs, the first entry's hash is always zero
+	entries: Vec<(u32,u32)>,
+	/// Entry currently selected
+	pos: usize,
+}
+impl DxFrame
+{
+	/// Select the last entry with a hash not greater than `hash`
+	fn seek(&mut self, hash: u32) {
+		self.pos = self.entries[1..].iter().take_while(|e| e.0 <= hash).count();
+	}
+	/// Block index of the selected entry
+	fn block(&self) -> u32 {
+		self.entries[self.pos].1
+	}
+}
+/// Directory entry being moved by a leaf split
+struct LeafEnt
+{
+	hash: u32,
+	inode: u32,
+	d_type: u8,
+	name: Vec<u8>,
+}
+
+/// Maximum number of entries in a non-root index node
+fn dx_node_limit(block_size: usize) -> usize {
+	(block_size / 4 - DX_NODE_BASE) / 2
+}
+/// Fill the count/limit header and entries of an index node
+fn write_dx_entries(blk_data: &mut [u32], base: usize, limit: usize, entries: &[(u32,u32)])
+{
+	blk_data[base] = limit as u32 | (entries.len() as u32) << 16;
+	blk_data[base + 1] = entries[0].1;
+	for (i, &(hash, block)) in entries.iter().enumerate().skip(1)
+	{
+		blk_data[base + 2*i] = hash;
+		blk_data[base + 2*i + 1] = block;
+	}
+}
+/// Replace the contents of a leaf block with the passed entries (the last one covering the rest of the block)
+fn write_leaf(blk_data: &mut [u32], block_size: usize, ents: &[LeafEnt]) -> Result<(), vfs::Error>
+{
+	for v in blk_data.iter_mut() {
+		*v = 0;
+	}
+	let mut ofs = 0;
+	for (i, e) in ents.iter().enumerate()
+	{
+		let rec_len = if i == ents.len() - 1 { block_size - ofs } else { dirent_size(e.name.len()) };
+		try!(write_dirent(blk_data, ofs, rec_len, e.inode, e.d_type, &e.name));
+		ofs += rec_len;
+	}
+	Ok( () )
+}
+
+/// Location (and contents) of a directory entry
+struct EntPos
+{
+	/// Block index within the directory
+	blk: u32,
+	/// Byte offset of the entry within the block
+	ofs: usize,
+	/// Offset of the previous entry in the same block
+	prev_ofs: Option<usize>,
+	inode: u32,
+	d_type: u8,
+}
+
+/// Size of a directory entry holding a name of the specified length
+fn dirent_size(name_len: usize) -> usize {
+	(::ondisk::DIRENT_MIN_SIZE + name_len + 3) & !3
+}
+
+/// Fill a directory entry (in a cleared block)
+fn write_dirent(blk_data: &mut [u32], ofs: usize, rec_len: usize, inode: u32, d_type: u8, name: &[u8]) -> Result<(), vfs::Error>
+{
+	blk_data[ofs/4 + 1] = ::ondisk::rec_len_to_disk(rec_len) as u32;	// d_rec_len (and zero name length/type)
+	match ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..])
+	{
+	None => return Err(vfs::Error::InconsistentFilesystem),
+	Some(ent) => {
+		ent.d_inode = inode;
+		ent.d_name_len = name.len() as u8;
+		ent.d_type = d_type;
+		},
+	}
+	// - Now that name length is set, fill the name
+	::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..]).unwrap()
+		.d_name.clone_from_slice( name );
+	Ok( () )
+}
+
+/// Create the first block of a new directory (containing `.` and `..`)
+fn init_dir(inode: &::inodes::Inode, parent: u32) -> Result<(), vfs::Error>
+{
+	let bs = inode.fs.fs_block_size;
+	let d_type = inode.fs.dirent_type(::ondisk::S_IFDIR);
+	let self_id = inode.get_id() as u32;
+	let (vol_blk, _) = try!(inode.get_or_alloc_block(0));
+	try!(inode.fs.edit_block(vol_blk, |blk_data| {
+		for v in blk_data.iter_mut() {
+			*v = 0;
+		}
+		let dot_len = dirent_size(1);
+		try!(write_dirent(blk_data, 0, dot_len, self_id, d_type, b"."));
+		write_dirent(blk_data, dot_len, bs - dot_len, parent, d_type, b"..")
+		}));
+	inode.set_i_size(bs as u64)
+}
+
+/// Check if a directory contains anything other than `.` and `..`
+fn dir_is_empty(inode: &::inodes::Inode) -> Result<bool, vfs::Error>
+{
+	for vol_blk in inode.blocks()
+	{
+		let blk_data = try!(inode.fs.get_block(vol_blk));
+		for ent in DirEnts(&blk_data)
+		{
+			if ent.rec_len() == 0 {
+				return Err( vfs::Error::InconsistentFilesystem );
+			}
+			if ent.d_inode != 0 && &ent.d_name != b"." && &ent.d_name != b".." {
+				return Ok(false);
+			}
+		}
+	}
+	Ok(true)
+}
+
+/// Point the `..` entry of the directory `inode` at `parent`
+fn set_parent(inode: &::inodes::Inode, parent: u32) -> Result<(), vfs::Error>
+{
+	let vol_blk = try!( inode.blocks().next_or_err() );
+	inode.fs.edit_block(vol_blk, |blk_data| {
+		let mut offset = 0;
+		while offset < blk_data.len() * 4
+		{
+			let ent = match ::ondisk::DirEnt::new_mut(&mut blk_data[offset/4 ..])
+				{
+				Some(v) => v,
+				None => return Err(vfs::Error::InconsistentFilesystem),
+				};
+			if ent.rec_len() == 0 {
+				break;
+			}
+			if ent.d_inode != 0 && &ent.d_name == b".." {
+				ent.d_inode = parent;
+				return Ok( () );
+			}
+			offset += ent.u32_len() * 4;
+		}
+		Err(vfs::Error::InconsistentFilesystem)
+		})
 }
 
 impl vfs::node::NodeBase for Dir
@@ -114,6 +747,9 @@ impl vfs::node::NodeBase for Dir
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> vfs::node::Result<vfs::node::Metadata> {
+		Ok( self.inode.get_metadata() )
+	}
 }
 impl vfs::node::Dir for Dir
 {
@@ -123,8 +759,8 @@ impl vfs::node::Dir for Dir
 			Err(vfs::Error::NotFound)
 		}
 		else {
-			let (_, _, rv) = try!(self.find_name(name));
-			Ok( rv )
+			let pos = try!(self.find_name(name));
+			Ok( pos.inode as vfs::node::InodeId )
 		}
 	}
 	fn read(&self, start_ofs: usize, callback: &mut vfs::node::ReadDirCallback) -> vfs::Result<usize>
@@ -173,7 +809,7 @@ impl vfs::node::Dir for Dir
 			for ent in DirEnts(&data[cur_ofs / 4..])
 			{
 				log_debug!("ent = {:?}", ent);
-				if ent.d_rec_len == 0 {
+				if ent.rec_len() == 0 {
 					return Some(cur_ofs);
 				}
 
@@ -201,20 +837,51 @@ impl vfs::node::Dir for Dir
 		{
 			Err( vfs::Error::ReadOnlyFilesystem )
 		}
+		else if name == ""
+		{
+			Err(vfs::Error::InvalidParameter)
+		}
+		else if name.len() > 255
+		{
+			Err(vfs::Error::Unknown("Filename too long"))
+		}
 		else
 		{
 			let _lh = self.inode.write_lock();
 
-			let ino_id = try!( self.inode.fs.allocate_inode(self.inode.get_id() as u32, nodetype) );
-			match self.add_dir_ent(name, ino_id)
+			match self.find_name(name)
 			{
-			Ok(()) => Ok(ino_id as vfs::node::InodeId),
-			Err(e) => {
-				// Call with_inode to force the inode to be deallocated
-				let _ = self.inode.fs.with_inode(ino_id, |_| Ok(()));
-				Err(e)
-				},
+			Ok(_) => return Err( vfs::Error::AlreadyExists ),
+			Err(vfs::Error::NotFound) => {},
+			Err(e) => return Err(e),
 			}
+
+			let is_dir = match nodetype
+				{
+				vfs::node::NodeType::Dir => true,
+				_ => false,
+				};
+			let parent_id = self.inode.get_id() as u32;
+			let ino_id = try!( self.inode.fs.allocate_inode(parent_id, nodetype) );
+			// NOTE: If this fails, the new inode has no links and is released when the handle is dropped
+			try!(self.inode.fs.with_inode(ino_id, |ino| {
+				if is_dir {
+					try!(init_dir(ino, parent_id));
+				}
+				try!(self.add_dir_ent(name, ino_id, self.inode.fs.dirent_type(ino.i_mode_fmt())));
+				ino.inc_link_count();
+				if is_dir {
+					// - The directory's `.` entry
+					ino.inc_link_count();
+				}
+				ino.flush()
+				}));
+			if is_dir {
+				// The new directory's `..` entry
+				self.inode.inc_link_count();
+				try!(self.inode.flush());
+			}
+			Ok(ino_id as vfs::node::InodeId)
 		}
 	}
 	fn link(&self, name: &ByteStr, node: &dyn vfs::node::NodeBase) -> vfs::node::Result<()> {
@@ -234,16 +901,21 @@ impl vfs::node::Dir for Dir
 		{
 			let _lh = self.inode.write_lock();
 
+			match self.find_name(name)
+			{
+			Ok(_) => return Err( vfs::Error::AlreadyExists ),
+			Err(vfs::Error::NotFound) => {},
+			Err(e) => return Err(e),
+			}
+
 			// TODO: How can I be sure that the passed inode number is valid? (or that it stays valid)
 			let inode = node.get_id();
-			try!(self.add_dir_ent(name, inode as u32));
-			// 3. Update inode's link count
-			try!(self.inode.fs.with_inode(inode as u32, |ino| {
+			self.inode.fs.with_inode(inode as u32, |ino| {
+				try!(self.add_dir_ent(name, inode as u32, self.inode.fs.dirent_type(ino.i_mode_fmt())));
+				// Update inode's link count
 				ino.inc_link_count();
-				Ok( () )
-				}));
-			
-			todo!("link(name={:?}, inode={:?})", name, inode);
+				ino.flush()
+				})
 		}
 	}
 	fn unlink(&self, name: &ByteStr) -> vfs::node::Result<()> {
@@ -259,26 +931,96 @@ impl vfs::node::Dir for Dir
 		{
 			let _lh = self.inode.write_lock();
 
-			let (blk, ofs, _) = try!(self.find_name(name));
+			let pos = try!(self.find_name(name));
+			// NOTE: Once the last link is gone, the inode is released when its last handle is dropped
+			self.inode.fs.with_inode(pos.inode, |ino| {
+				let is_dir = ino.i_mode_fmt() == ::ondisk::S_IFDIR;
+				if is_dir && !try!(dir_is_empty(ino)) {
+					return Err( vfs::Error::DirectoryNotEmpty );
+				}
+				try!(self.remove_dir_ent(&pos));
+
+				// Decrement inode's reference count
+				ino.dec_link_count();
+				if is_dir {
+					// - Removing a directory also removes its `.` entry, and its `..` reference to this directory
+					ino.dec_link_count();
+					self.inode.dec_link_count();
+					try!(self.inode.flush());
+				}
+				ino.flush()
+				})
+		}
+	}
+	fn rename(&self, src_name: &ByteStr, dst_dir: &dyn vfs::node::Dir, dst_name: &ByteStr) -> vfs::node::Result<()> {
+		let dst = match dst_dir.get_any().downcast_ref::<Dir>()
+			{
+			Some(v) => v,
+			None => return Err( vfs::Error::CrossFilesystem ),
+			};
+		if &*self.inode.fs as *const _ != &*dst.inode.fs as *const _
+		{
+			Err( vfs::Error::CrossFilesystem )
+		}
+		else if self.inode.fs.is_readonly()
+		{
+			Err( vfs::Error::ReadOnlyFilesystem )
+		}
+		else if src_name == "" || dst_name == ""
+		{
+			Err( vfs::Error::InvalidParameter )
+		}
+		else if dst_name.len() > 255
+		{
+			Err( vfs::Error::InvalidParameter )
+		}
+		else
+		{
+			let src_id = self.inode.get_id();
+			let dst_id = dst.inode.get_id();
+			// Lock both directories, in inode order to avoid deadlocking against a reversed rename
+			let (_lh_src, _lh_dst);
+			if src_id == dst_id {
+				_lh_src = self.inode.write_lock();
+				_lh_dst = None;
+			}
+			else if src_id < dst_id {
+				_lh_src = self.inode.write_lock();
+				_lh_dst = Some(dst.inode.write_lock());
+			}
+			else {
+				_lh_dst = Some(dst.inode.write_lock());
+				_lh_src = self.inode.write_lock();
+			}
+
+			let pos = try!(self.find_name(src_name));
+			match dst.find_name(dst_name)
+			{
+			Ok(_) => return Err( vfs::Error::AlreadyExists ),
+			Err(vfs::Error::NotFound) => {},
+			Err(e) => return Err(e),
+			}
 
-			let vol_blk = try!( self.inode.blocks_from(blk as u32).next_or_err() );
+			// Add the new name before removing the old one, so a failure leaves the original intact
+			try!(dst.add_dir_ent(dst_name, pos.inode, pos.d_type));
+			// - Re-locate the source, adding the entry could have split the previous entry
+			let pos = try!(self.find_name(src_name));
+			try!(self.remove_dir_ent(&pos));
 
-			self.inode.fs.edit_block(vol_blk, |blk_data| {
-				match ::ondisk::DirEnt::new_mut(&mut blk_data[ofs/4 ..])
+			// Directories moved to a new parent need their `..` entry (and the parents' link counts) updated
+			if src_id != dst_id
+			{
+				let is_dir = try!(self.inode.fs.with_inode(pos.inode, |ino| Ok(ino.i_mode_fmt() == ::ondisk::S_IFDIR)));
+				if is_dir
 				{
-				None => return Err(vfs::Error::InconsistentFilesystem),
-				Some(ent) => {
-					// Clear name length
-					ent.d_name_len = 0;
-					// Decrement inode's reference count
-					try!(self.inode.fs.with_inode(ent.d_inode, |ino| {
-						ino.dec_link_count();
-						Ok( () )
-						}));
-					Ok( () )
-					},
+					try!(self.inode.fs.with_inode(pos.inode, |ino| set_parent(ino, dst_id as u32)));
+					self.inode.dec_link_count();
+					try!(self.inode.flush());
+					dst.inode.inc_link_count();
+					try!(dst.inode.flush());
 				}
-				})
+			}
+			Ok( () )
 		}
 	}
 }
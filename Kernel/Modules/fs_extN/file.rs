@@ -3,6 +3,7 @@
 //
 // Modules/fs_extN/file.rs
 //! Regular file
+use kernel::prelude::*;
 use kernel::vfs;
 
 pub struct File
@@ -20,9 +21,77 @@ impl File
 			}
 	}
 
+	pub fn inode(&self) -> &::inodes::Inode {
+		&self.inode
+	}
+
 	fn fs_block_size(&self) -> usize {
 		self.inode.fs.fs_block_size
 	}
+
+	/// Read a block (uncached), holes read as zero
+	fn get_block_data(&self, addr: u32) -> vfs::node::Result<Box<[u32]>> {
+		if addr == 0 {
+			Ok( vec![0u32; self.fs_block_size() / 4].into_boxed_slice() )
+		}
+		else {
+			self.inode.fs.get_block_uncached(addr)
+		}
+	}
+
+	/// Write `len` bytes from `src` (or zeroes if `None`) at `ofs`
+	///
+	/// Holes are allocated when writing data, but left alone when zeroing.
+	/// Returns a short count if an error occurs after data has been written.
+	fn write_data(&self, ofs: u64, len: usize, src: Option<&[u8]>) -> vfs::node::Result<usize>
+	{
+		let mut done = 0;
+		while done < len
+		{
+			let (blk_idx, blk_ofs) = ::kernel::lib::num::div_rem(ofs + done as u64, self.fs_block_size() as u64);
+			let blk_ofs = blk_ofs as usize;
+			let count = ::core::cmp::min(len - done, self.fs_block_size() - blk_ofs);
+			let res = match src
+				{
+				Some(src) => self.write_block(blk_idx as u32, blk_ofs, &src[done..][..count]),
+				None => self.zero_block(blk_idx as u32, blk_ofs, count),
+				};
+			if let Err(e) = res {
+				return if done > 0 { Ok(done) } else { Err(e) };
+			}
+			done += count;
+		}
+		Ok(done)
+	}
+	/// Write data within a single block (allocating the block if it's a hole)
+	fn write_block(&self, blk_idx: u32, blk_ofs: usize, data: &[u8]) -> vfs::node::Result<()>
+	{
+		let (addr, is_new) = try!(self.inode.get_or_alloc_block(blk_idx));
+		if data.len() == self.fs_block_size()
+		{
+			self.inode.fs.write_blocks(addr, data)
+		}
+		else
+		{
+			// Partial block: Read-modify-write (newly allocated blocks start as zero)
+			let mut blk_data = try!(self.get_block_data(if is_new { 0 } else { addr }));
+			::kernel::lib::as_byte_slice_mut(&mut blk_data[..])[blk_ofs ..][.. data.len()].clone_from_slice(data);
+			self.inode.fs.write_blocks(addr, ::kernel::lib::as_byte_slice(&blk_data[..]))
+		}
+	}
+	/// Zero part of a block (holes already read as zero)
+	fn zero_block(&self, blk_idx: u32, blk_ofs: usize, len: usize) -> vfs::node::Result<()>
+	{
+		let addr = try!(self.inode.get_block_addr(blk_idx));
+		if addr == 0 {
+			return Ok( () );
+		}
+		let mut blk_data = try!(self.get_block_data(if len == self.fs_block_size() { 0 } else { addr }));
+		for b in ::kernel::lib::as_byte_slice_mut(&mut blk_data[..])[blk_ofs ..][.. len].iter_mut() {
+			*b = 0;
+		}
+		self.inode.fs.write_blocks(addr, ::kernel::lib::as_byte_slice(&blk_data[..]))
+	}
 }
 
 impl vfs::node::NodeBase for File
@@ -33,6 +102,9 @@ impl vfs::node::NodeBase for File
 	fn get_any(&self) -> &dyn (::core::any::Any) {
 		self
 	}
+	fn get_metadata(&self) -> vfs::node::Result<vfs::node::Metadata> {
+		Ok( self.inode.get_metadata() )
+	}
 }
 impl vfs::node::File for File
 {
@@ -70,12 +142,13 @@ impl vfs::node::File for File
 		{
 			let partial_bytes = self.fs_block_size() - blk_ofs;
 			
-			let blk_data = try!(self.inode.fs.get_block_uncached( try!(blocks.next_or_err()) ));
-			let blk_data = ::kernel::lib::as_byte_slice(&blk_data[..]);
+			let blk_data = try!(self.get_block_data( try!(blocks.next_or_err()) ));
+			let blk_data = &::kernel::lib::as_byte_slice(&blk_data[..])[blk_ofs..];
 			if buf.len() <= partial_bytes
 			{
-				buf.clone_from_slice( blk_data );
-				read_bytes += buf.len();
+				let len = buf.len();
+				buf.clone_from_slice( &blk_data[..len] );
+				read_bytes += len;
 			}
 			else
 			{
@@ -91,7 +164,15 @@ impl vfs::node::File for File
 			let remain_blocks = (buf.len() - read_bytes)/self.fs_block_size();
 			let (blkid, count) = try!(blocks.next_extent_or_err( remain_blocks as u32 ));
 			let byte_count = count as usize * self.fs_block_size();
-			try!(self.inode.fs.read_blocks(blkid, &mut buf[read_bytes ..][.. byte_count]));
+			if blkid == 0 {
+				// Sparse file hole
+				for b in buf[read_bytes ..][.. byte_count].iter_mut() {
+					*b = 0;
+				}
+			}
+			else {
+				try!(self.inode.fs.read_blocks(blkid, &mut buf[read_bytes ..][.. byte_count]));
+			}
 			read_bytes += byte_count;
 		}
 
@@ -99,9 +180,10 @@ impl vfs::node::File for File
 		//log_trace!("remain {} (tail)", buf.len() - read_bytes);
 		if buf.len() - read_bytes > 0
 		{
-			let blk_data = try!(self.inode.fs.get_block_uncached( try!(blocks.next_or_err()) ));
+			let blk_data = try!(self.get_block_data( try!(blocks.next_or_err()) ));
 			let blk_data = ::kernel::lib::as_byte_slice(&blk_data[..]);
-			buf[read_bytes..].clone_from_slice(&blk_data);
+			let len = buf.len() - read_bytes;
+			buf[read_bytes..].clone_from_slice(&blk_data[..len]);
 			read_bytes = buf.len();
 		}
 
@@ -110,21 +192,35 @@ impl vfs::node::File for File
 	}
 
 	fn truncate(&self, newsize: u64) -> vfs::node::Result<u64> {
-		if newsize == 0
-		{
-			todo!("truncate - 0");
+		if self.inode.fs.is_readonly() {
+			return Err( vfs::Error::ReadOnlyFilesystem );
 		}
-		else if newsize == self.inode.i_size()
+		let _lh = self.inode.write_lock();
+		// Clamp to the largest supported size (the caller checks the returned size)
+		let newsize = ::core::cmp::min(newsize, self.inode.fs.max_file_size());
+		if newsize == self.inode.i_size()
 		{
 			Ok( newsize )
 		}
 		else if newsize < self.inode.i_size()
 		{
-			todo!("truncate - shrink");
+			let bs = self.fs_block_size() as u64;
+			try!(self.inode.set_i_size(newsize));
+			// Zero the tail of the new last block (so it reads as zero if the file is extended again)
+			let tail = (newsize % bs) as usize;
+			if tail != 0 {
+				try!(self.zero_block((newsize / bs) as u32, tail, bs as usize - tail));
+			}
+			try!(self.inode.free_blocks_from( ::kernel::lib::num::div_up(newsize, bs) as u32 ));
+			try!(self.inode.flush());
+			Ok( newsize )
 		}
 		else
 		{
-			todo!("truncate - grow");
+			// Leave the new space as a hole (anything past the old end of the last block is already zero)
+			try!(self.inode.set_i_size(newsize));
+			try!(self.inode.flush());
+			Ok( newsize )
 		}
 	}
 	fn clear(&self, ofs: u64, size: u64) -> vfs::node::Result<()> {
@@ -136,10 +232,11 @@ impl vfs::node::File for File
 			Err( vfs::Error::InvalidParameter )
 		}
 		else {
-			// 1. Leading partial
-			// 2. Inner
-			// 3. Trailing partial
-			todo!("clear");
+			let _lh = self.inode.write_lock();
+			if try!(self.write_data(ofs, size as usize, None)) < size as usize {
+				return Err( vfs::Error::BlockIoError );
+			}
+			Ok( () )
 		}
 	}
 	fn write(&self, ofs: u64, buf: &[u8]) -> vfs::Result<usize> {
@@ -147,44 +244,27 @@ impl vfs::node::File for File
 		{
 			Err( vfs::Error::ReadOnlyFilesystem )
 		}
-		else if ofs == self.inode.i_size()
+		else if ofs > self.inode.i_size()
 		{
-			todo!("write - extend");
-		}
-		else if ofs > self.inode.i_size() || buf.len() as u64 > self.inode.i_size() || ofs + buf.len() as u64 > self.inode.i_size() {
 			Err( vfs::Error::InvalidParameter )
 		}
-		else {
-			// NOTE: In this section, we're free to read-modify-write blocks without fear, as the VFS itself handles
-			//       the file "borrow checking". A file race is the userland's problem (if a SharedRW handle is used)
-			let (blk_idx, blk_ofs) = ::kernel::lib::num::div_rem(ofs, self.fs_block_size() as u64);
-			let mut blocks = self.inode.blocks_from(blk_idx as u32);
-			let mut written = 0;
-			// 1. Leading partial
-			let blk_ofs = blk_ofs as usize;
-			if blk_ofs > 0
-			{
-				todo!("write - mutate - leading partial {}", blk_ofs);
-				//written += blk_ofs;
-			}
-			// 2. Inner
-			while buf.len() - written >= self.fs_block_size()
-			{
-				let remain_blocks = (buf.len() - written)/self.fs_block_size();
-				let (blkid, count) = try!(blocks.next_extent_or_err( remain_blocks as u32 ));
-				let byte_count = count as usize * self.fs_block_size();
-				try!(self.inode.fs.write_blocks(blkid, &buf[written ..][.. byte_count]));
-				written += byte_count;
-			}
-			// 3. Trailing partial
-			let trailing_bytes = buf.len() - written;
-			if trailing_bytes > 0
-			{
-				todo!("write - mutate - trailing partial {}", trailing_bytes);
+		else
+		{
+			// NOTE: The VFS itself handles the file "borrow checking" (a file race is the userland's problem if a SharedRW
+			//       handle is used), the inode lock protects the block map.
+			let _lh = self.inode.write_lock();
+			let max_size = self.inode.fs.max_file_size();
+			let len = ::core::cmp::min(buf.len() as u64, max_size.saturating_sub(ofs)) as usize;
+			if len == 0 && buf.len() > 0 {
+				return Err( vfs::Error::FileTooLarge );
 			}
 
+			let written = try!(self.write_data(ofs, len, Some(&buf[..len])));
+			if ofs + written as u64 > self.inode.i_size() {
+				try!(self.inode.set_i_size(ofs + written as u64));
+			}
+			try!(self.inode.flush());
 			Ok( written )
 		}
 	}
 }
-
@@ -0,0 +1,243 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/htree.rs
+//! Directory index (htree) name hashes
+// NOTE: These must match the Linux implementation bit-for-bit (see fs/ext4/hash.c)
+
+pub const DX_HASH_LEGACY: u8 = 0;
+pub const DX_HASH_HALF_MD4: u8 = 1;
+pub const DX_HASH_TEA: u8 = 2;
+pub const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+pub const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+pub const DX_HASH_TEA_UNSIGNED: u8 = 5;
+
+/// Seed used when the superblock's hash seed is all zero
+const DEFAULT_SEED: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+
+/// Calculate the (major) hash of a name, returns `None` if the hash version is unknown
+///
+/// The lowest bit is always clear (it's used in index entries to flag a hash collision across blocks)
+pub fn name_hash(name: &[u8], version: u8, seed: &[u32; 4]) -> Option<u32>
+{
+	let mut buf = if seed.iter().any(|&v| v != 0) { *seed } else { DEFAULT_SEED };
+
+	let hash = match version
+		{
+		DX_HASH_LEGACY => dx_hack_hash(name, true),
+		DX_HASH_LEGACY_UNSIGNED => dx_hack_hash(name, false),
+		DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+			let signed = version == DX_HASH_HALF_MD4;
+			for (i, chunk) in name.chunks(32).enumerate()
+			{
+				let mut input = [0; 8];
+				str2hashbuf(chunk, name.len() - i * 32, &mut input, signed);
+				half_md4_transform(&mut buf, &input);
+			}
+			buf[1]
+			},
+		DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+			let signed = version == DX_HASH_TEA;
+			for (i, chunk) in name.chunks(16).enumerate()
+			{
+				let mut input = [0; 4];
+				str2hashbuf(chunk, name.len() - i * 16, &mut input, signed);
+				tea_transform(&mut buf, &input);
+			}
+			buf[0]
+			},
+		_ => return None,
+		};
+
+	let hash = hash & !1;
+	// The maximum value is reserved as an end-of-directory marker
+	Some( if hash == 0x7FFF_FFFF << 1 { (0x7FFF_FFFF - 1) << 1 } else { hash } )
+}
+
+/// Widen a name byte, as C would for `char` (signed) or `unsigned char`
+fn char_val(b: u8, signed: bool) -> u32 {
+	if signed {
+		b as i8 as i32 as u32
+	}
+	else {
+		b as u32
+	}
+}
+
+/// The original (pre half-MD4) hash
+fn dx_hack_hash(name: &[u8], signed: bool) -> u32
+{
+	let (mut hash0, mut hash1) = (0x12a3fe2du32, 0x37abe8f9u32);
+	for &b in name
+	{
+		let mut hash = hash1.wrapping_add( hash0 ^ char_val(b, signed).wrapping_mul(7152373) );
+		if hash & 0x8000_0000 != 0 {
+			hash = hash.wrapping_sub(0x7fff_ffff);
+		}
+		hash1 = hash0;
+		hash0 = hash;
+	}
+	hash0 << 1
+}
+
+/// Pack (up to `out.len()*4` bytes of) a name into words, padding with a value derived from the remaining length
+fn str2hashbuf(msg: &[u8], rem_len: usize, out: &mut [u32], signed: bool)
+{
+	let pad = {
+		let p = rem_len as u32 | (rem_len as u32) << 8;
+		p | p << 16
+		};
+	let mut val = pad;
+	let mut n = 0;
+	for (i, &b) in msg.iter().take(out.len() * 4).enumerate()
+	{
+		val = char_val(b, signed).wrapping_add(val << 8);
+		if i % 4 == 3 {
+			out[n] = val;
+			n += 1;
+			val = pad;
+		}
+	}
+	if n < out.len() {
+		out[n] = val;
+		n += 1;
+	}
+	for v in out[n..].iter_mut() {
+		*v = pad;
+	}
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8])
+{
+	const K1: u32 = 0;
+	const K2: u32 = 0o13240474631;
+	const K3: u32 = 0o15666365641;
+	fn f(x: u32, y: u32, z: u32) -> u32 { z ^ (x & (y ^ z)) }
+	fn g(x: u32, y: u32, z: u32) -> u32 { (x & y).wrapping_add((x ^ y) & z) }
+	fn h(x: u32, y: u32, z: u32) -> u32 { x ^ y ^ z }
+	fn round(fcn: fn(u32,u32,u32)->u32, a: &mut u32, b: u32, c: u32, d: u32, x: u32, s: u32) {
+		*a = a.wrapping_add(fcn(b, c, d)).wrapping_add(x).rotate_left(s);
+	}
+
+	let (mut a, mut b, mut c, mut d) = (buf[0], buf[1], buf[2], buf[3]);
+
+	// Round 1
+	round(f, &mut a, b, c, d, input[0].wrapping_add(K1),  3);
+	round(f, &mut d, a, b, c, input[1].wrapping_add(K1),  7);
+	round(f, &mut c, d, a, b, input[2].wrapping_add(K1), 11);
+	round(f, &mut b, c, d, a, input[3].wrapping_add(K1), 19);
+	round(f, &mut a, b, c, d, input[4].wrapping_add(K1),  3);
+	round(f, &mut d, a, b, c, input[5].wrapping_add(K1),  7);
+	round(f, &mut c, d, a, b, input[6].wrapping_add(K1), 11);
+	round(f, &mut b, c, d, a, input[7].wrapping_add(K1), 19);
+
+	// Round 2
+	round(g, &mut a, b, c, d, input[1].wrapping_add(K2),  3);
+	round(g, &mut d, a, b, c, input[3].wrapping_add(K2),  5);
+	round(g, &mut c, d, a, b, input[5].wrapping_add(K2),  9);
+	round(g, &mut b, c, d, a, input[7].wrapping_add(K2), 13);
+	round(g, &mut a, b, c, d, input[0].wrapping_add(K2),  3);
+	round(g, &mut d, a, b, c, input[2].wrapping_add(K2),  5);
+	round(g, &mut c, d, a, b, input[4].wrapping_add(K2),  9);
+	round(g, &mut b, c, d, a, input[6].wrapping_add(K2), 13);
+
+	// Round 3
+	round(h, &mut a, b, c, d, input[3].wrapping_add(K3),  3);
+	round(h, &mut d, a, b, c, input[7].wrapping_add(K3),  9);
+	round(h, &mut c, d, a, b, input[2].wrapping_add(K3), 11);
+	round(h, &mut b, c, d, a, input[6].wrapping_add(K3), 15);
+	round(h, &mut a, b, c, d, input[1].wrapping_add(K3),  3);
+	round(h, &mut d, a, b, c, input[5].wrapping_add(K3),  9);
+	round(h, &mut c, d, a, b, input[0].wrapping_add(K3), 11);
+	round(h, &mut b, c, d, a, input[4].wrapping_add(K3), 15);
+
+	buf[0] = buf[0].wrapping_add(a);
+	buf[1] = buf[1].wrapping_add(b);
+	buf[2] = buf[2].wrapping_add(c);
+	buf[3] = buf[3].wrapping_add(d);
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4])
+{
+	const DELTA: u32 = 0x9E3779B9;
+	let (mut b0, mut b1) = (buf[0], buf[1]);
+	let (a, b, c, d) = (input[0], input[1], input[2], input[3]);
+	let mut sum = 0u32;
+	for _ in 0 .. 16
+	{
+		sum = sum.wrapping_add(DELTA);
+		b0 = b0.wrapping_add( (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b) );
+		b1 = b1.wrapping_add( (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d) );
+	}
+	buf[0] = buf[0].wrapping_add(b0);
+	buf[1] = buf[1].wrapping_add(b1);
+}
+
+// Reference values are from Linux's fs/ext4/hash.c (as also used by e2fsprogs, e.g. `debugfs -R "dx_hash -h 1 hello"`)
+#[cfg(test)]
+const TEST_NAMES: [&'static [u8]; 5] = [
+	b"hello",
+	b"lost+found",
+	b"a_rather_long_file_name_that_spans_more_than_thirty_two_bytes.txt",
+	b"caf\xc3\xa9",
+	b"\xff\x80\x7f",
+	];
+#[cfg(test)]
+const TEST_SEED: [u32; 4] = [0x01234567, 0x89abcdef, 0xfedcba98, 0x76543210];
+
+#[cfg(test)]
+fn check_hashes(version: u8, seed: &[u32; 4], expected: &[u32; 5])
+{
+	for (name, &exp) in TEST_NAMES.iter().zip(expected.iter())
+	{
+		assert_eq!(name_hash(name, version, seed), Some(exp), "version {} name {:?}", version, name);
+	}
+}
+
+#[test]
+// The legacy hash ignores the seed, and only differs between signed/unsigned for non-ASCII names
+fn hash_legacy()
+{
+	let expected_signed = [0x32252546, 0x5e2aba24, 0xd5e726fe, 0x96ca5a2c, 0xb6a1b8cc];
+	check_hashes(DX_HASH_LEGACY, &[0; 4], &expected_signed);
+	check_hashes(DX_HASH_LEGACY, &TEST_SEED, &expected_signed);
+	check_hashes(DX_HASH_LEGACY_UNSIGNED, &[0; 4], &[0x32252546, 0x5e2aba24, 0xd5e726fe, 0x6dde4230, 0xb32a9ecc]);
+}
+#[test]
+fn hash_half_md4()
+{
+	check_hashes(DX_HASH_HALF_MD4, &[0; 4], &[0x1746da32, 0x591de422, 0x5d50b282, 0xfb9c5e5c, 0x337ff96a]);
+	check_hashes(DX_HASH_HALF_MD4_UNSIGNED, &[0; 4], &[0x1746da32, 0x591de422, 0x5d50b282, 0x9d72aed6, 0xf435ce8c]);
+	check_hashes(DX_HASH_HALF_MD4, &TEST_SEED, &[0x273316b8, 0xf489b8ec, 0xbebacc4c, 0x57eb1a0e, 0x6891284e]);
+	check_hashes(DX_HASH_HALF_MD4_UNSIGNED, &TEST_SEED, &[0x273316b8, 0xf489b8ec, 0xbebacc4c, 0x7b5ea528, 0x7b71da8c]);
+}
+#[test]
+fn hash_tea()
+{
+	check_hashes(DX_HASH_TEA, &[0; 4], &[0x6f5bb1a8, 0x2dbf9e80, 0xf7e2ee50, 0x105842ea, 0x6ba38152]);
+	check_hashes(DX_HASH_TEA_UNSIGNED, &[0; 4], &[0x6f5bb1a8, 0x2dbf9e80, 0xf7e2ee50, 0x6621f032, 0x4907e268]);
+	check_hashes(DX_HASH_TEA, &TEST_SEED, &[0x0cbb3f34, 0xbb0625d4, 0x7dfb2d16, 0xbd7a64ea, 0x520a170e]);
+	check_hashes(DX_HASH_TEA_UNSIGNED, &TEST_SEED, &[0x0cbb3f34, 0xbb0625d4, 0x7dfb2d16, 0xc27a533e, 0x5829a048]);
+}
+#[test]
+fn hash_unknown_version()
+{
+	assert_eq!(name_hash(b"hello", 6, &[0; 4]), None);
+}
+#[test]
+// Padding of a short name, and truncation of a long one
+fn hash_str2hashbuf()
+{
+	let mut out = [0; 8];
+	str2hashbuf(b"abcdefghij\xe9", 11, &mut out, true);
+	assert_eq!(out, [0x61626364, 0x65666768, 0x0b6969e9, 0x0b0b0b0b, 0x0b0b0b0b, 0x0b0b0b0b, 0x0b0b0b0b, 0x0b0b0b0b]);
+	str2hashbuf(b"abcdefghij\xe9", 11, &mut out, false);
+	assert_eq!(out, [0x61626364, 0x65666768, 0x0b696ae9, 0x0b0b0b0b, 0x0b0b0b0b, 0x0b0b0b0b, 0x0b0b0b0b, 0x0b0b0b0b]);
+
+	// - The padding uses the length of the rest of the name (not just this chunk)
+	let mut out = [0; 4];
+	str2hashbuf(b"abcdefghijklmnopqrst", 40, &mut out, true);
+	assert_eq!(out, [0x61626364, 0x65666768, 0x696a6b6c, 0x6d6e6f70]);
+	str2hashbuf(b"ab", 40, &mut out, true);
+	assert_eq!(out, [0x28286162, 0x28282828, 0x28282828, 0x28282828]);
+}
@@ -3,6 +3,7 @@
 //
 //! 
 use instance::InstancePtr;
+use kernel::prelude::*;
 use kernel::vfs;
 use core::sync::atomic::{AtomicBool,Ordering};
 
@@ -10,7 +11,9 @@ pub struct Inode
 {
 	pub fs: InstancePtr,
 	inode_idx: u32,
-	ondisk: ::ondisk::Inode,
+	/// Structural lock (held for writing while modifying the inode's data, e.g. directory entries)
+	lock: ::kernel::sync::RwLock<()>,
+	ondisk: ::kernel::sync::Mutex<::ondisk::Inode>,
 
 	is_dirty: AtomicBool,
 }
@@ -23,16 +26,27 @@ impl Inode
 		Ok(Inode {
 			fs: fs,
 			inode_idx: id,
-			ondisk: od,
+			lock: ::kernel::sync::RwLock::new(()),
+			ondisk: ::kernel::sync::Mutex::new(od),
 			is_dirty: AtomicBool::new(false),
 			})
 	}
 
 	pub fn dec_link_count(&self) {
-		todo!("Inode::dec_link_count");
+		let mut lh = self.ondisk.lock();
+		if lh.i_links_count == 0 {
+			log_warning!("Inode::dec_link_count - Inode {} already has no links", self.inode_idx);
+		}
+		else {
+			lh.i_links_count -= 1;
+		}
+		// NOTE: When the count reaches zero, the inode and its blocks are released once the last handle is dropped
+		self.is_dirty.store(true, Ordering::Relaxed);
 	}
 	pub fn inc_link_count(&self) {
-		todo!("Inode::inc_link_count");
+		let mut lh = self.ondisk.lock();
+		lh.i_links_count += 1;
+		self.is_dirty.store(true, Ordering::Relaxed);
 	}
 
 
@@ -40,16 +54,45 @@ impl Inode
 	{
 		if self.is_dirty.swap(false, Ordering::Relaxed)
 		{
-			try!(self.fs.write_inode(self.inode_idx, &self.ondisk));
+			try!(self.fs.write_inode(self.inode_idx, &self.ondisk.lock()));
 		}
 		Ok( () )
 	}
+
+	/// Free the blocks and inode number of an inode with no remaining links
+	fn release(&self) -> vfs::Result<()>
+	{
+		log_debug!("Releasing inode {}", self.inode_idx);
+		try!(self.free_blocks_from(0));
+		let is_dir = {
+			let mut od = self.ondisk.lock();
+			od.i_size = 0;
+			let is_dir = od.i_mode & ::ondisk::S_IFMT == ::ondisk::S_IFDIR;
+			// TODO: Set `i_dtime` (needs a wall-clock time source), until then a zero mode marks the inode as free
+			od.i_mode = 0;
+			is_dir
+			};
+		self.is_dirty.store(true, Ordering::Relaxed);
+		try!(self.flush());
+		self.fs.free_inode(self.inode_idx, is_dir)
+	}
 }
 
 impl Drop for Inode
 {
 	fn drop(&mut self)
 	{
+		let (links, fmt, dtime) = {
+			let od = self.ondisk.lock();
+			(od.i_links_count, od.i_mode & ::ondisk::S_IFMT, od.i_dtime)
+			};
+		// - A zero format or a deletion time means that the inode was already released
+		if links == 0 && fmt != 0 && dtime == 0 && !self.fs.is_readonly()
+		{
+			if let Err(e) = self.release() {
+				log_error!("Inode::drop - Error releasing inode {}: {:?}", self.inode_idx, e);
+			}
+		}
 		if self.is_dirty.load(Ordering::Relaxed)
 		{
 			log_warning!("Inode::drop - Dirty node being dropped, writing back and ignoring errors");
@@ -61,10 +104,65 @@ impl Drop for Inode
 impl Inode
 {
 	pub fn i_mode_fmt(&self) -> u16 {
-		self.ondisk.i_mode & ::ondisk::S_IFMT
+		self.ondisk.lock().i_mode & ::ondisk::S_IFMT
 	}
 	pub fn i_size(&self) -> u64 {
-		self.ondisk.i_size as u64
+		self.size_from(&self.ondisk.lock())
+	}
+	/// Set the size in bytes (the caller handles allocating/freeing blocks)
+	///
+	/// Returns `Error::FileTooLarge` if the size can't be stored (see `InstanceInner::max_file_size`)
+	pub fn set_i_size(&self, size: u64) -> vfs::Result<()> {
+		let mut od = self.ondisk.lock();
+		let is_reg = od.i_mode & ::ondisk::S_IFMT == ::ondisk::S_IFREG;
+		let max = if is_reg { self.fs.max_file_size() } else { ::core::u32::MAX as u64 };
+		if size > max {
+			return Err( vfs::Error::FileTooLarge );
+		}
+		od.i_size = size as u32;
+		if is_reg {
+			od.i_size_high = (size >> 32) as u32;
+		}
+		self.is_dirty.store(true, Ordering::Relaxed);
+		Ok( () )
+	}
+	/// Size from an on-disk inode (regular files always use the upper 32 bits, as Linux does even without FEAT_RO_COMPAT_LARGE_FILE)
+	fn size_from(&self, od: &::ondisk::Inode) -> u64 {
+		if od.i_mode & ::ondisk::S_IFMT == ::ondisk::S_IFREG {
+			od.i_size as u64 | (od.i_size_high as u64) << 32
+		}
+		else {
+			od.i_size as u64
+		}
+	}
+	pub fn i_links_count(&self) -> u16 {
+		self.ondisk.lock().i_links_count
+	}
+	pub fn i_flags(&self) -> u32 {
+		self.ondisk.lock().i_flags
+	}
+	/// Clear bits in the inode's flags (e.g. to drop a corrupted directory index)
+	pub fn clear_i_flags(&self, flags: u32) {
+		self.ondisk.lock().i_flags &= !flags;
+		self.is_dirty.store(true, Ordering::Relaxed);
+	}
+
+	/// Obtain the VFS metadata for this inode
+	pub fn get_metadata(&self) -> vfs::node::Metadata {
+		// Linux stores the upper 16 bits of the uid/gid in the second word of osd2
+		let od = self.ondisk.lock();
+		let uid_hi = od._osd2[1] & 0xFFFF;
+		let gid_hi = od._osd2[1] >> 16;
+		vfs::node::Metadata {
+			size: self.size_from(&od),
+			link_count: od.i_links_count as u32,
+			uid: od.i_uid as u32 | uid_hi << 16,
+			gid: od.i_gid as u32 | gid_hi << 16,
+			permissions: od.i_mode & !::ondisk::S_IFMT,
+			ctime: od.i_ctime as ::kernel::time::Timestamp,
+			mtime: od.i_mtime as ::kernel::time::Timestamp,
+			atime: od.i_atime as ::kernel::time::Timestamp,
+		}
 	}
 }
 
@@ -87,7 +185,7 @@ impl Inode
 impl Inode
 {
 	pub fn write_lock(&self) -> ::kernel::sync::rwlock::Write<()> {
-		todo!("Inode::write_lock");
+		self.lock.write()
 	}
 
 	pub fn get_extent_from_block(&self, block_idx: u32, max_blocks: u32) -> vfs::node::Result<(u32, u32)>
@@ -102,13 +200,24 @@ impl Inode
 		let di_base = si_base + u32_per_fs_block;
 		let ti_base = di_base + u32_per_fs_block*u32_per_fs_block;
 
+		{
+			let (i_block, i_flags) = {
+				let od = self.ondisk.lock();
+				(od.i_block, od.i_flags)
+				};
+			if i_flags & ::ondisk::EXT4_EXTENTS_FL != 0 {
+				let (fs_start, count) = try!(self.extent_lookup(&i_block, block_idx));
+				return Ok( (fs_start, ::core::cmp::min(count, max_blocks)) );
+			}
+		}
+
 		if block_idx < si_base
 		{
-			let fs_start = self.ondisk.i_block[block_idx as usize];
+			let fs_start = self.ondisk.lock().i_block[block_idx as usize];
 			let max_blocks = ::core::cmp::min( si_base - block_idx, max_blocks );
 			for num in 1 .. max_blocks
 			{
-				if fs_start + num != self.ondisk.i_block[(block_idx + num) as usize] {
+				if fs_start + num != self.ondisk.lock().i_block[(block_idx + num) as usize] {
 					return Ok( (fs_start, num) );
 				}
 			}
@@ -117,8 +226,13 @@ impl Inode
 		else if block_idx < di_base
 		{
 			let idx = block_idx - si_base;
+			let si_blk = self.ondisk.lock().i_block[SI_BLOCK];
+			if si_blk == 0 {
+				// Hole covering the entire indirect block
+				return Ok( (0, ::core::cmp::min(di_base - block_idx, max_blocks)) );
+			}
 			// TODO: Have locally a mutex-protected cached filesystem block (linked to a global cache manager)
-			let si_block = try!( self.fs.get_block( self.ondisk.i_block[SI_BLOCK] ) );
+			let si_block = try!( self.fs.get_block( si_blk ) );
 			
 			let fs_start = si_block[idx as usize];
 			let max_blocks = ::core::cmp::min( di_base - block_idx, max_blocks );
@@ -134,8 +248,15 @@ impl Inode
 		{
 			let idx = block_idx - di_base;
 			let (blk, idx) = (idx / u32_per_fs_block, idx % u32_per_fs_block);
-			let di_block = try!( self.fs.get_block( self.ondisk.i_block[DI_BLOCK] ) );
-			let di_block = try!( self.fs.get_block( di_block[blk as usize] ) );
+			let di_blk = self.ondisk.lock().i_block[DI_BLOCK];
+			if di_blk == 0 {
+				return Ok( (0, ::core::cmp::min(u32_per_fs_block - idx, max_blocks)) );
+			}
+			let di_blk = try!( self.fs.get_block( di_blk ) )[blk as usize];
+			if di_blk == 0 {
+				return Ok( (0, ::core::cmp::min(u32_per_fs_block - idx, max_blocks)) );
+			}
+			let di_block = try!( self.fs.get_block( di_blk ) );
 
 
 			let fs_start = di_block[idx as usize];
@@ -154,9 +275,18 @@ impl Inode
 			let idx = block_idx - ti_base;
 			let (blk, idx) = (idx / u32_per_fs_block, idx % u32_per_fs_block);
 			let (blk_o, blk_i) = (blk / u32_per_fs_block, blk % u32_per_fs_block);
-			let ti_block = try!( self.fs.get_block( self.ondisk.i_block[TI_BLOCK] ) );
-			let ti_block = try!( self.fs.get_block( ti_block[blk_o as usize] ) );
-			let ti_block = try!( self.fs.get_block( ti_block[blk_i as usize] ) );
+			let mut ti_blk = self.ondisk.lock().i_block[TI_BLOCK];
+			for &i in &[blk_o, blk_i]
+			{
+				if ti_blk == 0 {
+					return Ok( (0, ::core::cmp::min(u32_per_fs_block - idx, max_blocks)) );
+				}
+				ti_blk = try!( self.fs.get_block( ti_blk ) )[i as usize];
+			}
+			if ti_blk == 0 {
+				return Ok( (0, ::core::cmp::min(u32_per_fs_block - idx, max_blocks)) );
+			}
+			let ti_block = try!( self.fs.get_block( ti_blk ) );
 
 
 			let fs_start = ti_block[idx as usize];
@@ -171,7 +301,36 @@ impl Inode
 		}
 	}
 
+	/// Get the address of a block (zero for holes)
 	pub fn get_block_addr(&self, block_idx: u32) -> vfs::node::Result<u32>
+	{
+		let (i_block, i_flags) = {
+			let od = self.ondisk.lock();
+			(od.i_block, od.i_flags)
+			};
+		if i_flags & ::ondisk::EXT4_EXTENTS_FL != 0 {
+			Ok( try!(self.extent_lookup(&i_block, block_idx)).0 )
+		}
+		else {
+			self.lookup_block(&i_block, block_idx)
+		}
+	}
+	fn lookup_block(&self, i_block: &[u32; 15], block_idx: u32) -> vfs::node::Result<u32>
+	{
+		let (root, path, depth) = self.block_path(block_idx);
+		let mut addr = i_block[root];
+		for &idx in &path[.. depth]
+		{
+			if addr == 0 {
+				break;
+			}
+			// TODO: Have locally a mutex-protected cached filesystem block (linked to a global cache manager)
+			addr = try!(self.fs.get_block(addr))[idx as usize];
+		}
+		Ok( addr )
+	}
+	/// Returns the `i_block` slot for a block, the indexes within each level of indirect block, and the number of levels
+	fn block_path(&self, block_idx: u32) -> (usize, [u32; 3], usize)
 	{
 		let u32_per_fs_block = (self.fs.fs_block_size / ::core::mem::size_of::<u32>()) as u32;
 
@@ -182,35 +341,25 @@ impl Inode
 		if block_idx < si_base
 		{
 			// Direct block
-			Ok( self.ondisk.i_block[block_idx as usize] )
+			(block_idx as usize, [0; 3], 0)
 		}
 		else if block_idx < di_base
 		{
 			// Single-indirect block
-			let idx = block_idx - si_base;
-			// TODO: Have locally a mutex-protected cached filesystem block (linked to a global cache manager)
-			let si_block = try!( self.fs.get_block( self.ondisk.i_block[12] ) );
-			Ok( si_block[ idx as usize ] )
+			(12, [block_idx - si_base, 0, 0], 1)
 		}
 		else if block_idx < ti_base
 		{
 			// Double-indirect block
 			let idx = block_idx - di_base;
-			let (blk, idx) = (idx / u32_per_fs_block, idx % u32_per_fs_block);
-			let di_block = try!( self.fs.get_block( self.ondisk.i_block[13] ) );
-			let di_block = try!( self.fs.get_block( di_block[blk as usize] ) );
-			Ok( di_block[idx as usize] )
+			(13, [idx / u32_per_fs_block, idx % u32_per_fs_block, 0], 2)
 		}
 		else
 		{
 			// Triple-indirect block
 			let idx = block_idx - ti_base;
 			let (blk, idx) = (idx / u32_per_fs_block, idx % u32_per_fs_block);
-			let (blk_o, blk_i) = (blk / u32_per_fs_block, blk % u32_per_fs_block);
-			let ti_block = try!( self.fs.get_block( self.ondisk.i_block[14] ) );
-			let ti_block = try!( self.fs.get_block( ti_block[blk_o as usize] ) );
-			let ti_block = try!( self.fs.get_block( ti_block[blk_i as usize] ) );
-			Ok( ti_block[idx as usize] )
+			(14, [blk / u32_per_fs_block, blk % u32_per_fs_block, idx], 3)
 		}
 	}
 
@@ -232,6 +381,302 @@ impl Inode
 	}
 }
 
+/// Block allocation (the caller should hold the write lock)
+impl Inode
+{
+	/// Number of `i_blocks` units (512 bytes) in a filesystem block
+	fn sectors_per_block(&self) -> u32 {
+		(self.fs.fs_block_size / 512) as u32
+	}
+
+	/// Get the address of a block, allocating it (and any required indirect blocks) if it's a hole
+	///
+	/// Returns the address, and `true` if the block was newly allocated (so its contents are undefined)
+	pub fn get_or_alloc_block(&self, block_idx: u32) -> vfs::node::Result<(u32, bool)>
+	{
+		let mut od = self.ondisk.lock();
+		if od.i_flags & ::ondisk::EXT4_EXTENTS_FL != 0 {
+			// TODO: Extent tree modification (volumes using extents are currently mounted read-only)
+			return Err( vfs::Error::Unknown("TODO: Allocating blocks in extent-mapped inodes") );
+		}
+		let addr = try!(self.lookup_block(&od.i_block, block_idx));
+		if addr != 0 {
+			return Ok( (addr, false) );
+		}
+
+		// Try to place the block directly after the previous one
+		let prev = if block_idx > 0 { try!(self.lookup_block(&od.i_block, block_idx - 1)) } else { 0 };
+		let goal = if prev != 0 { prev + 1 } else { self.fs.inode_block_goal(self.inode_idx) };
+		let addr = try!(self.fs.allocate_block(goal));
+		od.i_blocks += self.sectors_per_block();
+		self.is_dirty.store(true, Ordering::Relaxed);
+		if let Err(e) = self.set_block_addr(&mut od, block_idx, addr) {
+			od.i_blocks -= self.sectors_per_block();
+			if let Err(e) = self.fs.free_blocks(addr, 1) {
+				log_error!("Inode::get_or_alloc_block - Error freeing block {}: {:?}", addr, e);
+			}
+			return Err(e);
+		}
+		Ok( (addr, true) )
+	}
+
+	/// Set a block address, allocating indirect blocks as required
+	fn set_block_addr(&self, od: &mut ::ondisk::Inode, block_idx: u32, addr: u32) -> vfs::node::Result<()>
+	{
+		let (root, path, depth) = self.block_path(block_idx);
+		if depth == 0 {
+			od.i_block[root] = addr;
+			return Ok( () );
+		}
+
+		if od.i_block[root] == 0 {
+			let new_blk = try!(self.alloc_indirect(od, addr));
+			od.i_block[root] = new_blk;
+		}
+		let mut blk = od.i_block[root];
+		for &idx in &path[.. depth - 1]
+		{
+			let next = try!(self.fs.get_block(blk))[idx as usize];
+			blk = if next != 0 {
+					next
+				}
+				else {
+					let new_blk = try!(self.alloc_indirect(od, addr));
+					try!(self.fs.edit_block(blk, |data| { data[idx as usize] = new_blk; Ok( () ) }));
+					new_blk
+				};
+		}
+		let idx = path[depth - 1];
+		self.fs.edit_block(blk, |data| { data[idx as usize] = addr; Ok( () ) })
+	}
+	/// Allocate a zeroed indirect block
+	fn alloc_indirect(&self, od: &mut ::ondisk::Inode, goal: u32) -> vfs::node::Result<u32>
+	{
+		let blk = try!(self.fs.allocate_block(goal));
+		// NOTE: Cleared via the cache, as indirect blocks are read using `get_block`
+		let res = self.fs.edit_block(blk, |data| {
+			for v in data.iter_mut() {
+				*v = 0;
+			}
+			Ok( () )
+			});
+		if let Err(e) = res {
+			if let Err(e) = self.fs.free_blocks(blk, 1) {
+				log_error!("Inode::alloc_indirect - Error freeing block {}: {:?}", blk, e);
+			}
+			return Err(e);
+		}
+		od.i_blocks += self.sectors_per_block();
+		Ok(blk)
+	}
+
+	/// Release all blocks from `first` onwards (along with indirect blocks that are no longer needed)
+	pub fn free_blocks_from(&self, first: u32) -> vfs::node::Result<()>
+	{
+		let mut od = self.ondisk.lock();
+		if od.i_flags & ::ondisk::EXT4_EXTENTS_FL != 0 {
+			// TODO: Extent tree modification (volumes using extents are currently mounted read-only)
+			return Err( vfs::Error::Unknown("TODO: Freeing blocks in extent-mapped inodes") );
+		}
+		let mut run = FreeRun { fs: &*self.fs, first: 0, count: 0, total: 0 };
+		let res = self.free_blocks_inner(&mut od, first, &mut run).and_then(|_| run.flush());
+		od.i_blocks = od.i_blocks.saturating_sub(run.total * self.sectors_per_block());
+		self.is_dirty.store(true, Ordering::Relaxed);
+		res
+	}
+	fn free_blocks_inner(&self, od: &mut ::ondisk::Inode, first: u32, run: &mut FreeRun) -> vfs::node::Result<()>
+	{
+		for i in first as usize .. 12
+		{
+			try!(run.push(od.i_block[i]));
+			od.i_block[i] = 0;
+		}
+
+		// Indirect blocks, each level covers `u32_per_fs_block` times as many blocks as the previous
+		let u32_per_fs_block = (self.fs.fs_block_size / ::core::mem::size_of::<u32>()) as u64;
+		let mut base = 12;
+		let mut span = u32_per_fs_block;
+		for (depth, root) in (12 .. 15).enumerate()
+		{
+			if (first as u64) < base + span && od.i_block[root] != 0
+			{
+				if try!(self.free_tree(od.i_block[root], depth as u32 + 1, base, first as u64, run)) {
+					od.i_block[root] = 0;
+				}
+			}
+			base += span;
+			span *= u32_per_fs_block;
+		}
+		Ok( () )
+	}
+	/// Free blocks at or after `first` referenced by the indirect block `blk` (which covers blocks from `base`)
+	///
+	/// Returns `true` if `blk` was freed too
+	fn free_tree(&self, blk: u32, depth: u32, base: u64, first: u64, run: &mut FreeRun) -> vfs::node::Result<bool>
+	{
+		let u32_per_fs_block = self.fs.fs_block_size / ::core::mem::size_of::<u32>();
+		// Number of blocks covered by each entry
+		let per_ent = (u32_per_fs_block as u64).pow(depth - 1);
+		// First entry that is entirely released
+		let (start, first_whole) = if first <= base {
+				(0, 0)
+			}
+			else {
+				let i = ((first - base) / per_ent) as usize;
+				(i, if base + i as u64 * per_ent >= first { i } else { i + 1 })
+			};
+
+		let entries: Vec<u32> = Vec::from( &try!(self.fs.get_block(blk))[..] );
+		for i in start .. u32_per_fs_block
+		{
+			if entries[i] == 0 {
+			}
+			else if depth == 1 {
+				try!(run.push(entries[i]));
+			}
+			else {
+				try!(self.free_tree(entries[i], depth - 1, base + i as u64 * per_ent, first, run));
+			}
+		}
+
+		if first <= base {
+			try!(run.push(blk));
+			Ok(true)
+		}
+		else {
+			try!(self.fs.edit_block(blk, |data| {
+				for v in data[first_whole ..].iter_mut() {
+					*v = 0;
+				}
+				Ok( () )
+				}));
+			Ok(false)
+		}
+	}
+}
+
+/// Extent trees [FEAT_INCOMPAT_EXTENTS]
+impl Inode
+{
+	/// Locate the extent containing `block_idx`
+	///
+	/// Returns the volume block and number of blocks from `block_idx` to the end of the extent.
+	/// Holes (and uninitialised extents) have a zero address.
+	fn extent_lookup(&self, i_block: &[u32; 15], block_idx: u32) -> vfs::node::Result<(u32, u32)>
+	{
+		// The root node is stored in `i_block`, the rest fill a filesystem block
+		let mut node: Vec<u32> = Vec::from(&i_block[..]);
+		// End of the range covered by the current node (from the parent's next entry)
+		let mut limit = ::core::u32::MAX;
+		let mut expected_depth = None;
+		loop
+		{
+			let hdr = *::ondisk::ExtentHeader::from_slice(&node[..3]);
+			let n_ents = hdr.eh_entries as usize;
+			if hdr.eh_magic != ::ondisk::EXTENT_MAGIC || 3 + n_ents * 3 > node.len() || expected_depth.map_or(false, |d| d != hdr.eh_depth)
+			{
+				log_error!("Inode {}: Bad extent node (magic={:#x}, entries={}, depth={})", self.inode_idx, hdr.eh_magic, hdr.eh_entries, hdr.eh_depth);
+				return Err( vfs::Error::InconsistentFilesystem );
+			}
+			let ents = node[3 ..][.. n_ents * 3].chunks(3);
+
+			if hdr.eh_depth == 0
+			{
+				// Leaf node, find the extent that covers the block
+				for ent in ents
+				{
+					let ext = ::ondisk::Extent::from_slice(ent);
+					if ext.ee_block > block_idx {
+						// Within a hole before this extent
+						limit = ext.ee_block;
+						break;
+					}
+					let (len, is_init) = if ext.ee_len > ::ondisk::EXTENT_MAX_INIT_LEN {
+							(ext.ee_len - ::ondisk::EXTENT_MAX_INIT_LEN, false)
+						}
+						else {
+							(ext.ee_len, true)
+						};
+					let ofs = block_idx - ext.ee_block;
+					if ofs < len as u32
+					{
+						if !is_init {
+							return Ok( (0, len as u32 - ofs) );
+						}
+						if ext.ee_start_hi != 0 {
+							return Err( vfs::Error::Unknown("TODO: extN block numbers over 32 bits") );
+						}
+						return Ok( (ext.ee_start_lo + ofs, len as u32 - ofs) );
+					}
+				}
+				return Ok( (0, limit - block_idx) );
+			}
+			else
+			{
+				// Interior node, descend into the last child starting at or before the block
+				let mut child = None;
+				for ent in ents
+				{
+					let idx = ::ondisk::ExtentIdx::from_slice(ent);
+					if idx.ei_block > block_idx {
+						limit = idx.ei_block;
+						break;
+					}
+					child = Some(*idx);
+				}
+				let child = match child
+					{
+					Some(v) => v,
+					None => return Ok( (0, limit - block_idx) ),
+					};
+				if child.ei_leaf_hi != 0 {
+					return Err( vfs::Error::Unknown("TODO: extN block numbers over 32 bits") );
+				}
+				expected_depth = Some(hdr.eh_depth - 1);
+				node = Vec::from( &try!(self.fs.get_block(child.ei_leaf_lo))[..] );
+			}
+		}
+	}
+}
+
+/// Accumulates runs of contiguous blocks to be freed
+struct FreeRun<'a>
+{
+	fs: &'a ::instance::InstanceInner,
+	first: u32,
+	count: u32,
+	/// Total number of blocks pushed
+	total: u32,
+}
+impl<'a> FreeRun<'a>
+{
+	fn push(&mut self, block: u32) -> vfs::node::Result<()>
+	{
+		if block == 0 {
+			return Ok( () );
+		}
+		if self.count > 0 && self.first + self.count == block {
+			self.count += 1;
+		}
+		else {
+			try!(self.flush());
+			self.first = block;
+			self.count = 1;
+		}
+		self.total += 1;
+		Ok( () )
+	}
+	fn flush(&mut self) -> vfs::node::Result<()>
+	{
+		if self.count > 0 {
+			let count = self.count;
+			self.count = 0;
+			try!(self.fs.free_blocks(self.first, count));
+		}
+		Ok( () )
+	}
+}
+
 /// Iterator over block numbers owned by an inode
 pub struct Blocks<'a>
 {
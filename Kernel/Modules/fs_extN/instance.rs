@@ -19,7 +19,18 @@ pub struct InstanceInner
 	pub fs_block_size: usize,
 
 	mount_handle: vfs::mount::SelfHandle,
+	/// Block group descriptors and free counts, locked while allocating
+	alloc: ::kernel::sync::Mutex<AllocState>,
+	/// Held while editing blocks larger than a page (which are edited as a copy)
+	large_block_lock: ::kernel::sync::Mutex<()>,
+}
+
+/// Allocation state (written back to the group descriptor table and superblock when changed)
+struct AllocState
+{
 	group_descriptors: Vec<::ondisk::GroupDesc>,
+	free_blocks: u32,
+	free_inodes: u32,
 }
 
 pub enum FeatureState
@@ -39,7 +50,8 @@ impl Instance
 			FeatureState::AllOk
 		}
 		else {
-			let unsupported_req = sb.ext.s_feature_incompat  & !::SUPPORTED_REQ_FEATURES;
+			let unsupported_req = sb.ext.s_feature_incompat  & !(::SUPPORTED_REQ_FEATURES | ::READONLY_REQ_FEATURES);
+			let readonly_req    = sb.ext.s_feature_incompat  & ::READONLY_REQ_FEATURES;
 			let unsupported_rdo = sb.ext.s_feature_ro_compat & !::SUPPORTED_RDO_FEATURES;
 			let unsupported_opt = sb.ext.s_feature_compat    & !::SUPPORTED_OPT_FEATURES;
 			if unsupported_req != 0 {
@@ -52,6 +64,11 @@ impl Instance
 				log_warning!("Volume `{}` uses incompatible read-write features (unsupported bits {:#x})", vol_name, unsupported_rdo);
 				FeatureState::ReadOnly( unsupported_rdo )
 			}
+			else if readonly_req != 0 {
+				// Can be read, but writing isn't implemented
+				log_warning!("Volume `{}` uses required features only supported read-only (bits {:#x})", vol_name, readonly_req);
+				FeatureState::ReadOnly( readonly_req )
+			}
 			else if unsupported_opt != 0 {
 				// Can read and write, but may confuse other systems
 				log_warning!("Volume `{}` uses incompatible optional features (unsupported bits {:#x})", vol_name, unsupported_opt);
@@ -65,6 +82,55 @@ impl Instance
 	}
 
 	pub fn new_boxed(vol: VolumeHandle, mount_handle: vfs::mount::SelfHandle) -> vfs::Result<Box<Instance>>
+	{
+		let mut inner = try!(InstanceInner::load(::block_cache::CacheHandle::new(vol), mount_handle));
+
+		// The volume wasn't cleanly unmounted, replay the journal before trusting the metadata
+		// - If that isn't possible, the volume is mounted read-only as-is (the VFS refuses an explicit `rw` mount)
+		if inner.needs_recovery()
+		{
+			if inner.is_readonly {
+				// Replaying writes to the volume, which isn't supported for this volume
+				log_warning!("Volume `{}` needs journal recovery, but can't be written, mounting read-only without replay (metadata may be inconsistent)",
+					inner.vol.name());
+			}
+			else {
+				// - The journal is read through its inode, so this needs a (temporary) instance
+				// - The superblock and group descriptors are then re-loaded, as they could have been replayed
+				// SAFE: Boxed, and only unwrapped after the borrow passed to `replay` is released
+				let tmp = Box::new(unsafe { ArefInner::new(inner) });
+				let res = ::journal::replay(tmp.borrow());
+				let InstanceInner { vol, mount_handle, .. } = ArefInner::into_inner(*tmp);
+				inner = try!(InstanceInner::load(vol, mount_handle));
+				match res
+				{
+				Ok( () ) => try!(inner.clear_recovery_flag()),
+				Err(e) => {
+					log_warning!("Volume `{}` needs journal recovery, but it failed ({:?}), mounting read-only (metadata may be inconsistent)",
+						inner.vol.name(), e);
+					inner.is_readonly = true;
+					},
+				}
+			}
+		}
+
+		// Writes aren't journaled, so a journaled volume is only mounted read-only
+		if inner.has_journal() && !inner.is_readonly {
+			log_notice!("Volume `{}` has a journal, which isn't supported for writing, mounting read-only", inner.vol.name());
+			inner.is_readonly = true;
+		}
+
+		// SAFE: Boxed instantly
+		unsafe {
+			Ok(Box::new(Instance(ArefInner::new( inner ))))
+		}
+	}
+}
+
+impl InstanceInner
+{
+	/// Read the superblock and group descriptors
+	fn load(vol: ::block_cache::CacheHandle, mount_handle: vfs::mount::SelfHandle) -> vfs::Result<InstanceInner>
 	{
 		let vol_bs = vol.block_size();
 
@@ -72,86 +138,65 @@ impl Instance
 		let superblock_idx = (1024 / vol_bs) as u64;
 		let superblock_ofs = (1024 % vol_bs) as usize;
 
-		let (superblock, first_block) = {
+		let superblock = {
 			let mut first_block: Vec<u32> = vec![0; ::core::cmp::max(1024, vol_bs)/4];
 			try!(vol.read_blocks(superblock_idx, ::kernel::lib::as_byte_slice_mut(&mut first_block[..])));
 			assert!(superblock_ofs % 4 == 0);
-			(
-				*::ondisk::Superblock::from_slice(&first_block[superblock_ofs/4 ..][..1024/4]),
-				first_block,
-				)
+			*::ondisk::Superblock::from_slice(&first_block[superblock_ofs/4 ..][..1024/4])
 			};
 
 
 		if superblock.data.s_magic != 0xEF53 {
 			return Err(vfs::Error::TypeMismatch);
 		}
-		let is_readonly = match Self::check_features(vol.name(), &superblock)
+		let is_readonly = match Instance::check_features(vol.name(), &superblock)
 			{
 			FeatureState::Incompatible(_) => return Err(vfs::Error::TypeMismatch),
 			FeatureState::ReadOnly(_) => true,
 			_ => false,
 			};
 
-		// - Limit block size to 1MB each
-		if superblock.data.s_log_block_size > 10 {
+		// - Limit block size to 64KiB (the largest allowed, directory record lengths can't describe larger blocks)
+		if superblock.data.s_log_block_size > 6 {
 			return Err(vfs::Error::Unknown("extN block size out of range"));
 		}
 
+		// NOTE: Both sizes are powers of two, so either can be larger (blocks bigger than a page, or smaller than a disk block,
+		//       are handled by `get_block` et al)
 		let fs_block_size = 1024 << superblock.data.s_log_block_size as usize;
-		if fs_block_size % vol_bs != 0 {
-			log_warning!("ExtN TODO: Handle filesystem block size smaller than disk block size?");
-			return Err(vfs::Error::InconsistentFilesystem);
+		// TODO: Block numbers are 32-bit internally
+		if superblock.data.s_rev_level > 0 && superblock.ext.s_feature_incompat & ::ondisk::FEAT_INCOMPAT_64BIT != 0 && superblock.ext.s_blocks_count_hi != 0 {
+			log_warning!("ExtN TODO: Handle volumes with more than 2^32 blocks");
+			return Err(vfs::Error::Unknown("extN volume too large"));
 		}
-		let num_groups = ::kernel::lib::num::div_up(superblock.data.s_blocks_count, superblock.data.s_blocks_per_group);
+		let num_groups = ::kernel::lib::num::div_up(superblock.data.s_blocks_count - superblock.data.s_first_data_block, superblock.data.s_blocks_per_group);
+		let desc_size = group_desc_size(&superblock);
 
 		// Read group descriptor table
-		// - This always resides immediately after the superblock
+		// - This always resides in the block after the superblock
 		let group_descs = {
-			use kernel::lib::{as_byte_slice_mut,as_byte_slice};
+			use kernel::lib::as_byte_slice_mut;
 			#[allow(non_snake_case)]
 			let GROUP_DESC_SIZE = ::core::mem::size_of::<::ondisk::GroupDesc>();
 
-			let groups_per_vol_block = vol_bs / GROUP_DESC_SIZE;
-
 			let mut gds: Vec<::ondisk::GroupDesc> = vec![Default::default(); num_groups as usize];
 
-			let (n_skip, mut block) = if vol_bs % (2*1024) == 0 {
-					// Volume block size is larger than the superblock
-					// - This means that at least 2048 bytes of the group descriptors are in the same block as the superblock
-					let n_shared = (vol_bs - 2*1024) / GROUP_DESC_SIZE;
+			let gdt_ofs = (superblock.data.s_first_data_block as u64 + 1) * fs_block_size as u64;
+			let (block, skip) = (gdt_ofs / vol_bs as u64, (gdt_ofs % vol_bs as u64) as usize);
+			let n_bytes = gds.len() * desc_size;
+			log_trace!("gdt_ofs={:#x}, block={}, skip={}, n_bytes={}", gdt_ofs, block, skip, n_bytes);
 
-					let src = as_byte_slice(&first_block[2*1024/4..]);
-					let count = ::core::cmp::min(n_shared, gds.len());
-					assert_eq!(src.len(), count * GROUP_DESC_SIZE);
-					as_byte_slice_mut(&mut gds[..count]).clone_from_slice( src );
-					(count, 1)
-				}
-				else {
-					// Volume BS <= superblock
-					(0, (2*1024 / vol_bs) as u64)
-				};
-
-			let rem_count = gds.len() - n_skip;
-			let tail_count = rem_count % groups_per_vol_block;
-			let body_count = rem_count - tail_count;
-			log_trace!("n_skip={}, block={}, rem_count={},  tail_count={}, body_count={}",
-				n_skip, block, rem_count,  tail_count, body_count);
-
-			if body_count > 0 
+			// Read covering volume blocks into a buffer, then populate from that
+			let mut buf: Vec<u8> = vec![0; ::kernel::lib::num::div_up(skip + n_bytes, vol_bs) * vol_bs];
+			try!(vol.read_blocks(block, &mut buf));
+			for (gd, src) in Iterator::zip( gds.iter_mut(), buf[skip ..][.. n_bytes].chunks(desc_size) )
 			{
-				try!(vol.read_blocks(block, as_byte_slice_mut(&mut gds[n_skip .. ][ .. body_count])));
-				block += (body_count / groups_per_vol_block) as u64;
-			}
-
-			if tail_count > 0
-			{
-				let ofs = n_skip + body_count;
-				// Read a single volume block into a buffer, then populate from that
-				let mut buf: Vec<u8> = vec![0; vol_bs];
-				try!(vol.read_blocks(block, &mut buf));
-				let n_bytes = (gds.len() - ofs) * GROUP_DESC_SIZE;
-				as_byte_slice_mut(&mut gds[ofs ..]).clone_from_slice( &buf[..n_bytes] );
+				as_byte_slice_mut(gd).clone_from_slice( &src[.. GROUP_DESC_SIZE] );
+				// [FEAT_INCOMPAT_64BIT] The upper halves of the bitmap and inode table addresses follow the base structure
+				if desc_size > GROUP_DESC_SIZE && src[GROUP_DESC_SIZE ..][.. 3*4].iter().any(|&b| b != 0) {
+					log_warning!("ExtN TODO: Handle group descriptors with block numbers over 32 bits");
+					return Err(vfs::Error::Unknown("extN volume too large"));
+				}
 			}
 
 			gds
@@ -163,19 +208,33 @@ impl Instance
 			log_debug!("{}: Group #{}: {:?}", vol.name(), i, gd);
 		}
 
-		let inner = InstanceInner {
+		Ok(InstanceInner {
 			is_readonly: is_readonly,
 			fs_block_size: fs_block_size,
 			superblock: superblock,
-			group_descriptors: group_descs,
+			alloc: ::kernel::sync::Mutex::new(AllocState {
+				group_descriptors: group_descs,
+				free_blocks: superblock.data.s_free_blocks_count,
+				free_inodes: superblock.data.s_free_inodes_count,
+				}),
+			large_block_lock: ::kernel::sync::Mutex::new( () ),
 			mount_handle: mount_handle,
-			vol: ::block_cache::CacheHandle::new(vol),
-			};
+			vol: vol,
+			})
+	}
 
-		// SAFE: Boxed instantly
-		unsafe {
-			Ok(Box::new(Instance(ArefInner::new( inner ))))
-		}
+	fn needs_recovery(&self) -> bool
+	{
+		self.superblock.data.s_rev_level > 0 && self.superblock.ext.s_feature_incompat & ::ondisk::FEAT_INCOMPAT_RECOVER != 0
+	}
+	/// Mark the volume as not needing journal recovery
+	fn clear_recovery_flag(&mut self) -> vfs::Result<()>
+	{
+		self.superblock.ext.s_feature_incompat &= !::ondisk::FEAT_INCOMPAT_RECOVER;
+		// `s_feature_incompat` is 96 bytes into the superblock
+		let flags = [self.superblock.ext.s_feature_incompat];
+		try!(self.write_volume_bytes(1024 + 96, ::kernel::lib::as_byte_slice(&flags)));
+		Ok( () )
 	}
 }
 
@@ -210,6 +269,20 @@ impl vfs::mount::Filesystem for Instance
 			},
 		}
 	}
+
+	fn flush(&self) -> vfs::Result<()> {
+		// NOTE: Inodes are written back when their nodes are released, this writes out the cached metadata blocks
+		Ok( try!(self.0.vol.flush()) )
+	}
+	fn set_readonly(&self, readonly: bool) -> vfs::Result<()> {
+		// Volumes with unsupported write features can't be made writable
+		if !readonly && self.0.is_readonly() {
+			Err(vfs::Error::ReadOnlyFilesystem)
+		}
+		else {
+			Ok( () )
+		}
+	}
 }
 
 impl InstanceInner
@@ -218,21 +291,125 @@ impl InstanceInner
 	{
 		self.is_readonly
 	}
+
+	/// Regular files can use the upper 32 bits of the size (FEAT_RO_COMPAT_LARGE_FILE)
+	pub fn has_large_files(&self) -> bool
+	{
+		self.superblock.data.s_rev_level > 0 && self.superblock.ext.s_feature_ro_compat & ::ondisk::FEAT_RO_COMPAT_LARGE_FILE != 0
+	}
+	/// Largest size a regular file can be written to
+	pub fn max_file_size(&self) -> u64
+	{
+		if !self.has_large_files() {
+			// Without large files, the size is treated as signed by other implementations
+			return 0x7FFF_FFFF;
+		}
+		let bs = self.fs_block_size as u64;
+		let n = bs / 4;
+		// Blocks addressable through the block map (direct, single, double, and triple indirect)
+		let mapped = 12 + n + n*n + n*n*n;
+		// `i_blocks` counts 512 byte sectors in 32 bits, including the indirect blocks
+		let indirect = 1 + (1 + n) + (1 + n + n*n);
+		let counted = ::core::u32::MAX as u64 / (bs / 512) - indirect;
+		::core::cmp::min(mapped, counted) * bs
+	}
+
+	/// Directory entry type for an inode format (only recorded if FEAT_INCOMPAT_FILETYPE is set)
+	pub fn dirent_type(&self, fmt: u16) -> u8
+	{
+		if self.superblock.data.s_rev_level == 0 || self.superblock.ext.s_feature_incompat & ::ondisk::FEAT_INCOMPAT_FILETYPE == 0 {
+			return 0;
+		}
+		match fmt
+		{
+		::ondisk::S_IFREG => 1,
+		::ondisk::S_IFDIR => 2,
+		::ondisk::S_IFCHR => 3,
+		::ondisk::S_IFBLK => 4,
+		::ondisk::S_IFIFO => 5,
+		::ondisk::S_IFSOCK => 6,
+		::ondisk::S_IFLNK => 7,
+		_ => 0,
+		}
+	}
+
+	/// Volume has a journal (possibly on another device)
+	pub fn has_journal(&self) -> bool
+	{
+		self.superblock.data.s_rev_level > 0 && self.superblock.ext.s_feature_compat & ::ondisk::FEAT_COMPAT_HAS_JOURNAL != 0
+	}
+	/// Inode number of the journal (zero if there's no journal, or it's on another device)
+	pub fn journal_inode(&self) -> u32
+	{
+		if self.has_journal() {
+			self.superblock.ext.s_journal_inum
+		}
+		else {
+			0
+		}
+	}
+
+	/// Directory indexes (htrees) are enabled on this volume
+	pub fn has_dir_index(&self) -> bool
+	{
+		self.superblock.data.s_rev_level > 0 && self.superblock.ext.s_feature_compat & ::ondisk::FEAT_COMPAT_DIR_INDEX != 0
+	}
+	/// Maximum number of index levels (including the root) in a directory index
+	pub fn dx_max_levels(&self) -> u8
+	{
+		if self.superblock.ext.s_feature_incompat & ::ondisk::FEAT_INCOMPAT_LARGEDIR != 0 { 3 } else { 2 }
+	}
+	/// Hash algorithm used for a directory index, adjusted for the signedness of `char` on the system that created it
+	pub fn dx_hash_version(&self, root_version: u8) -> u8
+	{
+		if root_version <= ::htree::DX_HASH_TEA && self.superblock.ext.s_flags & ::ondisk::EXT2_FLAGS_UNSIGNED_HASH != 0 {
+			root_version + 3
+		}
+		else {
+			root_version
+		}
+	}
+	pub fn dx_hash_seed(&self) -> [u32; 4]
+	{
+		self.superblock.ext.s_hash_seed
+	}
+}
+
+/// Size of each entry in the group descriptor table
+fn group_desc_size(sb: &::ondisk::Superblock) -> usize
+{
+	if sb.data.s_rev_level > 0 && sb.ext.s_feature_incompat & ::ondisk::FEAT_INCOMPAT_64BIT != 0 && sb.ext.s_desc_size as usize > ::core::mem::size_of::<::ondisk::GroupDesc>() {
+		sb.ext.s_desc_size as usize
+	}
+	else {
+		::core::mem::size_of::<::ondisk::GroupDesc>()
+	}
 }
 
-/// Structure representing a view into a BlockCache entry
-pub struct Block<'a>(::block_cache::CachedBlockHandle<'a>, u16,u16);
+/// Structure representing a view of a filesystem block
+pub enum Block<'a>
+{
+	/// Part of a BlockCache entry (offset and size in bytes)
+	Cached(::block_cache::CachedBlockHandle<'a>, usize, usize),
+	/// Copy of a block larger than a cache entry
+	Assembled(Box<[u32]>),
+}
 impl<'a> ::core::ops::Deref for Block<'a>
 {
 	type Target = [u32];
 	fn deref(&self) -> &[u32] {
-		let &Block(ref handle, ofs, size) = self;
-		// SAFE: Alignment should be good (but is checked anyway)
-		unsafe {
-			assert!(ofs as usize + size as usize <= handle.data().len());
-			assert!(ofs % 4 == 0);
-			assert!(&handle.data()[0] as *const _ as usize % 4 == 0);
-			::core::slice::from_raw_parts(&handle.data()[ofs as usize] as *const u8 as *const u32, (size / 4) as usize)
+		match self
+		{
+		&Block::Cached(ref handle, ofs, size) => {
+			// SAFE: Alignment should be good (but is checked anyway)
+			unsafe {
+				assert!(ofs + size <= handle.data().len());
+				assert!(ofs % 4 == 0);
+				assert!(&handle.data()[0] as *const _ as usize % 4 == 0);
+				::core::slice::from_raw_parts(&handle.data()[ofs] as *const u8 as *const u32, size / 4)
+			}
+			},
+		&Block::Assembled(ref data) => &data[..],
 		}
 	}
 }
@@ -242,17 +419,21 @@ impl InstanceInner
 	/// Obtain a block (using the block cache)
 	pub fn get_block(&self, block: u32) -> vfs::node::Result<Block>
 	{
+		log_trace!("get_block({})", block);
 		if self.fs_block_size > ::kernel::PAGE_SIZE {
-			// TODO: To handle extN blocks larger than the system's page size, we'd need to start packing multiple cache handles into
-			//       the `Block` structure
-			todo!("Handle extN block sizes > PAGE_SIZE - {} > {}", self.fs_block_size, ::kernel::PAGE_SIZE);
+			// Larger than a cache entry, copy each page of the block out of the cache
+			// - Locked so that a concurrent edit isn't seen half-written
+			let _lh = self.large_block_lock.lock();
+			let mut rv = vec![0u32; self.fs_block_size / 4].into_boxed_slice();
+			try!(self.read_large_block(block, ::kernel::lib::as_byte_slice_mut(&mut rv[..])));
+			Ok( Block::Assembled(rv) )
+		}
+		else {
+			let (vol_block, ofs) = self.block_pos(block);
+			let ch = try!(self.vol.get_block(vol_block));
+			let ofs = (vol_block - ch.index()) as usize * self.vol.block_size() + ofs;
+			Ok( Block::Cached(ch, ofs, self.fs_block_size) )
 		}
-		log_trace!("get_block({})", block);
-		let sector = block as u64 * self.vol_blocks_per_fs_block();
-
-		let ch = try!(self.vol.get_block(sector));
-		let ofs = (sector - ch.index()) as usize * self.vol.block_size();
-		Ok( Block(ch, ofs as u16, self.fs_block_size as u16) )
 	}
 
 	/// Edit a block in the cache using the provided closure
@@ -260,22 +441,35 @@ impl InstanceInner
 	where
 		F: FnOnce(&mut [u32]) -> vfs::node::Result<R>
 	{
+		log_trace!("edit_block({})", block);
 		if self.fs_block_size > ::kernel::PAGE_SIZE {
-			// TODO: To handle extN blocks larger than the system's page size, we'd need to start packing multiple cache handles into
-			//       the `Block` structure
-			todo!("Handle extN block sizes > PAGE_SIZE - {} > {}", self.fs_block_size, ::kernel::PAGE_SIZE);
+			// Larger than a cache entry, edit a copy then write each page back
+			// - Locked so that concurrent edits of a block don't overwrite each other's changes
+			let _lh = self.large_block_lock.lock();
+			let mut data = vec![0u32; self.fs_block_size / 4].into_boxed_slice();
+			try!(self.read_large_block(block, ::kernel::lib::as_byte_slice_mut(&mut data[..])));
+			let rv = f(&mut data[..]);
+			let (vol_block, _) = self.block_pos(block);
+			let blocks_per_page = self.vol.blocks_per_page();
+			for (i, page) in ::kernel::lib::as_byte_slice(&data[..]).chunks(::kernel::PAGE_SIZE).enumerate()
+			{
+				try!(self.vol.edit(vol_block + i as u64 * blocks_per_page, blocks_per_page as usize, |dst| dst.clone_from_slice(page)));
+			}
+			rv
+		}
+		else {
+			let (vol_block, ofs) = self.block_pos(block);
+			let count = ::core::cmp::max(1, self.fs_block_size / self.vol.block_size());
+			try!(self.vol.edit(vol_block, count, |data| {
+				let data = &mut data[ofs ..][.. self.fs_block_size];
+				// SAFE: Alignment checked, range valid
+				let slice_u32: &mut [u32] = unsafe {
+					assert!(&data[0] as *const _ as usize % 4 == 0);
+					::core::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u32, data.len() / 4)
+					};
+				f(slice_u32)
+				}))
 		}
-		log_trace!("get_block({})", block);
-		let sector = block as u64 * self.vol_blocks_per_fs_block();
-
-		try!(self.vol.edit(sector, self.vol_blocks_per_fs_block() as usize, |data| {
-			// SAFE: Alignment checked, range valid
-			let slice_u32: &mut [u32] = unsafe {
-				assert!(&data[0] as *const _ as usize % 4 == 0);
-				::core::slice::from_raw_parts_mut(data.as_mut_ptr() as *mut u32, data.len() / 4)
-				};
-			f(slice_u32)
-			}))
 	}
 
 	/// Obtain a block (uncached)
@@ -293,17 +487,57 @@ impl InstanceInner
 	/// Read a sequence of blocks into a user-provided buffer
 	pub fn read_blocks(&self, first_block: u32, data: &mut [u8]) -> vfs::node::Result<()>
 	{
-		try!( self.vol.read_blocks( first_block as u64 * self.vol_blocks_per_fs_block(), data) );
+		if self.fs_block_size < self.vol.block_size() {
+			// Blocks smaller than a disk block are read via the cache (which holds whole disk blocks)
+			for (i, dst) in data.chunks_mut(self.fs_block_size).enumerate()
+			{
+				let (vol_block, ofs) = self.block_pos(first_block + i as u32);
+				try!( self.vol.read_inner(vol_block, ofs, dst) );
+			}
+		}
+		else {
+			try!( self.vol.read_blocks( self.block_pos(first_block).0, data) );
+		}
 		Ok( () )
 	}
 
 	/// Write a sequence of blocks from a user-provided buffer
 	pub fn write_blocks(&self, first_block: u32, data: &[u8]) -> vfs::node::Result<()>
 	{
-		// TODO: Requires maybe interfacing with the cache used by get_block?
-		try!( self.vol.write_blocks( first_block as u64 * self.vol_blocks_per_fs_block(), data) );
+		if self.fs_block_size < self.vol.block_size() {
+			// Blocks smaller than a disk block are written via the cache, so the rest of the disk block is preserved (and the
+			// update is atomic with respect to other writers)
+			for (i, src) in data.chunks(self.fs_block_size).enumerate()
+			{
+				let (vol_block, ofs) = self.block_pos(first_block + i as u32);
+				try!( self.vol.edit(vol_block, 1, |dst| dst[ofs ..][.. src.len()].clone_from_slice(src)) );
+			}
+		}
+		else {
+			// NOTE: The block cache updates any cached copy, so `get_block` stays coherent
+			try!( self.vol.write_blocks( self.block_pos(first_block).0, data) );
+		}
+		Ok( () )
+	}
+
+	/// Read a block larger than a page through the cache
+	fn read_large_block(&self, block: u32, data: &mut [u8]) -> vfs::node::Result<()>
+	{
+		let (vol_block, _) = self.block_pos(block);
+		let blocks_per_page = self.vol.blocks_per_page();
+		for (i, dst) in data.chunks_mut(::kernel::PAGE_SIZE).enumerate()
+		{
+			let ch = try!(self.vol.get_block(vol_block + i as u64 * blocks_per_page));
+			dst.clone_from_slice( &ch.data()[.. dst.len()] );
+		}
 		Ok( () )
 	}
+	/// Get the volume block containing the start of a filesystem block, and the byte offset within it
+	fn block_pos(&self, block: u32) -> (u64, usize) {
+		let byte_ofs = block as u64 * self.fs_block_size as u64;
+		let vol_bs = self.vol.block_size() as u64;
+		(byte_ofs / vol_bs, (byte_ofs % vol_bs) as usize)
+	}
 }
 
 /// Inode lookup and save
@@ -320,11 +554,12 @@ impl InstanceInner
 	fn get_inode_pos(&self, inode_num: u32) -> (u64, usize) {
 		let (group, ofs) = self.get_inode_grp_id(inode_num);
 
-		let base_blk_id = self.group_descriptors[group as usize].bg_inode_table as u64 * self.vol_blocks_per_fs_block();
-		let ofs_bytes = (ofs as usize) * self.s_inode_size();
-		let (sub_blk_id, sub_blk_ofs) = (ofs_bytes / self.vol.block_size(), ofs_bytes % self.vol.block_size());
+		// NOTE: Must not be called with `alloc` held
+		let inode_table = self.alloc.lock().group_descriptors[group as usize].bg_inode_table;
+		let ofs_bytes = inode_table as u64 * self.fs_block_size as u64 + (ofs as usize * self.s_inode_size()) as u64;
+		let vol_bs = self.vol.block_size() as u64;
 
-		(base_blk_id + sub_blk_id as u64,  sub_blk_ofs as usize)
+		(ofs_bytes / vol_bs, (ofs_bytes % vol_bs) as usize)
 	}
 
 	/// Perform an operation with a temporary handle to an inode
@@ -337,30 +572,108 @@ impl InstanceInner
 		// - This prevents us from having to maintain our own node cache
 
 		let node = try!(self.mount_handle.get_node(inode_num as vfs::node::InodeId));
-		match node.get_any().downcast_ref()
-		{
-		Some(our_in) => fcn(our_in),
-		None => Err(vfs::Error::Unknown("BUG: Node wasn't an extN inode")),
+		let any = node.get_any();
+		if let Some(d) = any.downcast_ref::<::dir::Dir>() {
+			fcn(d.inode())
+		}
+		else if let Some(f) = any.downcast_ref::<::file::File>() {
+			fcn(f.inode())
+		}
+		else {
+			Err(vfs::Error::Unknown("BUG: Node wasn't an extN inode"))
 		}
 	}
 
 	/// Allocate a new inode number, possibly in the same block group as `parent_inode_num`.
-	pub fn allocate_inode(&self, parent_inode_num: u32, _nodetype: vfs::node::NodeType) -> vfs::node::Result< u32 >
+	///
+	/// The new inode has no links, so is released when its last handle is dropped unless it's added to a directory.
+	pub fn allocate_inode(&self, parent_inode_num: u32, nodetype: vfs::node::NodeType) -> vfs::node::Result< u32 >
 	{
-		let (grp, _idx) = self.get_inode_grp_id(parent_inode_num);
-		let gd = &self.group_descriptors[grp as usize];
-		if gd.bg_free_inodes_count > 0
-		{
-			todo!("InstanceInner::allocate_inode - Current BG");
-		}
-		else if self.superblock.data.s_free_inodes_count > 0
-		{
-			todo!("InstanceInner::allocate_inode - Search for any BG");
+		let mode = match nodetype
+			{
+			vfs::node::NodeType::File => ::ondisk::S_IFREG | 0o644,
+			vfs::node::NodeType::Dir => ::ondisk::S_IFDIR | 0o755,
+			// TODO: Symbolic links (`get_node_by_inode` doesn't handle them yet either)
+			vfs::node::NodeType::Symlink(_) => return Err(vfs::Error::Unknown("TODO: Symbolic links on extN")),
+			};
+		let is_dir = mode & ::ondisk::S_IFMT == ::ondisk::S_IFDIR;
+
+		let inode_num = {
+			let (parent_grp, _idx) = self.get_inode_grp_id(parent_inode_num);
+			let mut lh = self.alloc.lock();
+			if lh.free_inodes == 0 {
+				return Err(vfs::Error::OutOfSpace);
+			}
+			let n_groups = lh.group_descriptors.len() as u32;
+			let mut found = None;
+			// Check the parent's group first, then search the rest
+			for i in 0 .. n_groups
+			{
+				let grp = (parent_grp + i) % n_groups;
+				let gd = lh.group_descriptors[grp as usize];
+				if gd.bg_free_inodes_count == 0 {
+					continue ;
+				}
+				// - Inodes before `s_first_ino` are reserved
+				let first = if grp == 0 { self.s_first_ino() - 1 } else { 0 };
+				match try!(self.find_clear_bit(gd.bg_inode_bitmap, first, self.s_inodes_per_group(), first))
+				{
+				Some(bit) => {
+					try!(self.set_bitmap_bit(gd.bg_inode_bitmap, bit));
+					{
+						let gd = &mut lh.group_descriptors[grp as usize];
+						gd.bg_free_inodes_count -= 1;
+						if is_dir {
+							gd.bg_used_dirs_count += 1;
+						}
+					}
+					lh.free_inodes -= 1;
+					try!(self.save_group(&lh, grp));
+					found = Some(grp * self.s_inodes_per_group() + bit + 1);
+					break;
+					},
+				None => {
+					log_warning!("{}: Inode bitmap for group {} is full, but {} inodes are free", self.vol.name(), grp, gd.bg_free_inodes_count);
+					},
+				}
+			}
+			match found
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::OutOfSpace),
+			}
+			};
+		log_debug!("allocate_inode: {} ({:#o})", inode_num, mode);
+
+		// Initialise the inode (clearing any space past the base structure)
+		let (vol_block, blk_ofs) = self.get_inode_pos(inode_num);
+		try!( self.vol.write_inner(vol_block, blk_ofs, &vec![0u8; self.s_inode_size()]) );
+		let mut inode_data = ::ondisk::Inode::default();
+		inode_data.i_mode = mode;
+		// TODO: Set the timestamps (needs a wall-clock time source)
+		try!( self.write_inode(inode_num, &inode_data) );
+
+		Ok(inode_num)
+	}
+	/// Release an inode number (once its link count has reached zero and its blocks have been freed)
+	pub fn free_inode(&self, inode_num: u32, is_dir: bool) -> vfs::node::Result<()>
+	{
+		let (grp, bit) = self.get_inode_grp_id(inode_num);
+		let mut lh = self.alloc.lock();
+		let bitmap = lh.group_descriptors[grp as usize].bg_inode_bitmap;
+		if try!(self.clear_bitmap_bits(bitmap, bit, 1)) == 0 {
+			log_warning!("{}: Freeing inode {} which is already free", self.vol.name(), inode_num);
+			return Ok( () );
 		}
-		else
 		{
-			Err(vfs::Error::OutOfSpace)
+			let gd = &mut lh.group_descriptors[grp as usize];
+			gd.bg_free_inodes_count += 1;
+			if is_dir {
+				gd.bg_used_dirs_count -= 1;
+			}
 		}
+		lh.free_inodes += 1;
+		self.save_group(&lh, grp)
 	}
 
 	/// Read an inode descriptor from the disk
@@ -372,7 +685,7 @@ impl InstanceInner
 		let mut rv = ::ondisk::Inode::default();
 		{
 			// NOTE: Unused fields in the inode are zero
-			let slice = &mut ::kernel::lib::as_byte_slice_mut(&mut rv)[.. self.s_inode_size()];
+			let slice = &mut ::kernel::lib::as_byte_slice_mut(&mut rv)[.. self.inode_struct_size()];
 			try!( self.vol.read_inner(vol_block, blk_ofs, slice) );
 		}
 		log_trace!("- rv={:?}", rv);
@@ -383,22 +696,186 @@ impl InstanceInner
 	{
 		let (vol_block, blk_ofs) = self.get_inode_pos(inode_num);
 		
-		let slice = &::kernel::lib::as_byte_slice(inode_data)[.. self.s_inode_size()];
+		let slice = &::kernel::lib::as_byte_slice(inode_data)[.. self.inode_struct_size()];
 		try!( self.vol.write_inner(vol_block, blk_ofs, slice) );
 
 		Ok( () )
 	}
 }
 
+/// Block and bitmap allocation
+impl InstanceInner
+{
+	/// Allocate a block, preferably at or after `goal`
+	pub fn allocate_block(&self, goal: u32) -> vfs::node::Result<u32>
+	{
+		let first_data = self.s_first_data_block();
+		let (goal_grp, goal_bit) = if first_data <= goal && goal < self.superblock.data.s_blocks_count {
+				::kernel::lib::num::div_rem(goal - first_data, self.s_blocks_per_group())
+			}
+			else {
+				(0, 0)
+			};
+
+		let mut lh = self.alloc.lock();
+		if lh.free_blocks == 0 {
+			return Err(vfs::Error::OutOfSpace);
+		}
+		let n_groups = lh.group_descriptors.len() as u32;
+		for i in 0 .. n_groups
+		{
+			let grp = (goal_grp + i) % n_groups;
+			let gd = lh.group_descriptors[grp as usize];
+			if gd.bg_free_blocks_count == 0 {
+				continue ;
+			}
+			let start = if i == 0 { goal_bit } else { 0 };
+			match try!(self.find_clear_bit(gd.bg_block_bitmap, 0, self.group_block_count(grp), start))
+			{
+			Some(bit) => {
+				try!(self.set_bitmap_bit(gd.bg_block_bitmap, bit));
+				lh.group_descriptors[grp as usize].bg_free_blocks_count -= 1;
+				lh.free_blocks -= 1;
+				try!(self.save_group(&lh, grp));
+				return Ok(first_data + grp * self.s_blocks_per_group() + bit);
+				},
+			None => {
+				log_warning!("{}: Block bitmap for group {} is full, but {} blocks are free", self.vol.name(), grp, gd.bg_free_blocks_count);
+				},
+			}
+		}
+		Err(vfs::Error::OutOfSpace)
+	}
+	/// Release a run of blocks
+	pub fn free_blocks(&self, first: u32, count: u32) -> vfs::node::Result<()>
+	{
+		let first_data = self.s_first_data_block();
+		if first < first_data || first >= self.superblock.data.s_blocks_count || count > self.superblock.data.s_blocks_count - first {
+			log_error!("{}: Freeing out-of-range blocks {}+{}", self.vol.name(), first, count);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+
+		let mut lh = self.alloc.lock();
+		let mut block = first;
+		while block < first + count
+		{
+			let (grp, bit) = ::kernel::lib::num::div_rem(block - first_data, self.s_blocks_per_group());
+			let n = ::core::cmp::min(first + count - block, self.s_blocks_per_group() - bit);
+			let bitmap = lh.group_descriptors[grp as usize].bg_block_bitmap;
+			let n_freed = try!(self.clear_bitmap_bits(bitmap, bit, n));
+			if n_freed != n {
+				log_warning!("{}: Freeing blocks {}+{} - {} were already free", self.vol.name(), block, n, n - n_freed);
+			}
+			lh.group_descriptors[grp as usize].bg_free_blocks_count += n_freed as u16;
+			lh.free_blocks += n_freed;
+			try!(self.save_group(&lh, grp));
+			block += n;
+		}
+		Ok( () )
+	}
+	/// Allocation goal for the first block of an inode (the start of its block group)
+	pub fn inode_block_goal(&self, inode_num: u32) -> u32 {
+		let (grp, _) = self.get_inode_grp_id(inode_num);
+		self.s_first_data_block() + grp * self.s_blocks_per_group()
+	}
+
+	/// Search a bitmap block for a clear bit in `first .. count` (starting at `start` and wrapping)
+	fn find_clear_bit(&self, bitmap_block: u32, first: u32, count: u32, start: u32) -> vfs::node::Result<Option<u32>>
+	{
+		let bitmap = try!(self.get_block(bitmap_block));
+		let scan = |from: u32, to: u32| {
+			let mut bit = from;
+			while bit < to
+			{
+				let word = u32::from_le(bitmap[(bit / 32) as usize]);
+				if bit % 32 == 0 && word == !0 {
+					// Skip full words
+					bit += 32;
+				}
+				else if word & (1 << (bit % 32)) == 0 {
+					return Some(bit);
+				}
+				else {
+					bit += 1;
+				}
+			}
+			None
+			};
+		let start = if first <= start && start < count { start } else { first };
+		Ok( scan(start, count).or_else(|| scan(first, start)) )
+	}
+	/// Set a bit in a bitmap block
+	fn set_bitmap_bit(&self, bitmap_block: u32, bit: u32) -> vfs::node::Result<()>
+	{
+		self.edit_block(bitmap_block, |data| {
+			data[(bit / 32) as usize] |= (1u32 << (bit % 32)).to_le();
+			Ok( () )
+			})
+	}
+	/// Clear a run of bits in a bitmap block, returning the number that were set
+	fn clear_bitmap_bits(&self, bitmap_block: u32, first: u32, count: u32) -> vfs::node::Result<u32>
+	{
+		self.edit_block(bitmap_block, |data| {
+			let mut n_cleared = 0;
+			for bit in first .. first + count
+			{
+				let mask = (1u32 << (bit % 32)).to_le();
+				let word = &mut data[(bit / 32) as usize];
+				if *word & mask != 0 {
+					*word &= !mask;
+					n_cleared += 1;
+				}
+			}
+			Ok(n_cleared)
+			})
+	}
+
+	/// Write a group descriptor and the superblock's free counts back to the (cached) disk
+	fn save_group(&self, state: &AllocState, grp: u32) -> vfs::node::Result<()>
+	{
+		// NOTE: Only the base structure is written, the upper halves (64-bit volumes) aren't changed
+		let gd_size = group_desc_size(&self.superblock) as u64;
+		let gd_ofs = (self.s_first_data_block() as u64 + 1) * self.fs_block_size as u64 + grp as u64 * gd_size;
+		try!(self.write_volume_bytes(gd_ofs, ::kernel::lib::as_byte_slice(&state.group_descriptors[grp as usize])));
+		// `s_free_blocks_count` and `s_free_inodes_count` are adjacent, 12 bytes into the superblock
+		let counts = [state.free_blocks, state.free_inodes];
+		try!(self.write_volume_bytes(1024 + 12, ::kernel::lib::as_byte_slice(&counts)));
+		Ok( () )
+	}
+	/// Update bytes via the metadata cache (must not span a volume block)
+	fn write_volume_bytes(&self, ofs: u64, data: &[u8]) -> vfs::node::Result<()>
+	{
+		let vol_bs = self.vol.block_size() as u64;
+		try!( self.vol.write_inner(ofs / vol_bs, (ofs % vol_bs) as usize, data) );
+		Ok( () )
+	}
+}
+
 /// Superblock parameters
 impl InstanceInner
 {
-	fn s_inodes_per_group(&self) -> u32 {
-		self.superblock.data.s_inodes_per_group
+	fn s_blocks_per_group(&self) -> u32 {
+		self.superblock.data.s_blocks_per_group
+	}
+	fn s_first_data_block(&self) -> u32 {
+		self.superblock.data.s_first_data_block
+	}
+	/// Number of blocks in a group (the last group can be short)
+	fn group_block_count(&self, grp: u32) -> u32 {
+		let first = self.s_first_data_block() + grp * self.s_blocks_per_group();
+		::core::cmp::min(self.s_blocks_per_group(), self.superblock.data.s_blocks_count - first)
+	}
+	fn s_first_ino(&self) -> u32 {
+		if self.superblock.data.s_rev_level > 0 {
+			self.superblock.ext.s_first_ino
+		}
+		else {
+			11
+		}
 	}
 
-	fn vol_blocks_per_fs_block(&self) -> u64 {
-		(self.fs_block_size / self.vol.block_size()) as u64
+	fn s_inodes_per_group(&self) -> u32 {
+		self.superblock.data.s_inodes_per_group
 	}
 
 	fn s_inode_size(&self) -> usize {
@@ -409,6 +886,10 @@ impl InstanceInner
 			128
 		}
 	}
+	/// Number of bytes of the on-disk inode covered by `::ondisk::Inode`
+	fn inode_struct_size(&self) -> usize {
+		::core::cmp::min(self.s_inode_size(), ::core::mem::size_of::<::ondisk::Inode>())
+	}
 }
 
 
@@ -0,0 +1,471 @@
+// "Tifflin" Kernel - ext2/3/4 Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_extN/journal.rs
+//! JBD2 journal recovery (replaying committed transactions at mount time)
+// NOTE: All journal structures are big-endian
+use kernel::prelude::*;
+use kernel::vfs;
+use kernel::lib::VecMap;
+use kernel::lib::byteorder::{ByteOrder,BigEndian};
+use instance::InstancePtr;
+
+const JBD2_MAGIC: u32 = 0xC03B3998;
+
+const BLOCKTYPE_DESCRIPTOR: u32 = 1;
+const BLOCKTYPE_COMMIT: u32 = 2;
+const BLOCKTYPE_SUPERBLOCK_V1: u32 = 3;
+const BLOCKTYPE_SUPERBLOCK_V2: u32 = 4;
+const BLOCKTYPE_REVOKE: u32 = 5;
+
+const FEAT_COMPAT_CHECKSUM: u32 = 0x1;
+
+const FEAT_INCOMPAT_REVOKE: u32 = 0x1;
+const FEAT_INCOMPAT_64BIT: u32 = 0x2;
+const FEAT_INCOMPAT_CSUM_V2: u32 = 0x8;
+const FEAT_INCOMPAT_CSUM_V3: u32 = 0x10;
+/// Incompatible journal features that recovery handles
+// NOTE: Asynchronous commits (0x4) aren't supported, recovery stops at the first transaction that fails checksum verification.
+const SUPPORTED_INCOMPAT: u32 = FEAT_INCOMPAT_REVOKE | FEAT_INCOMPAT_64BIT | FEAT_INCOMPAT_CSUM_V2 | FEAT_INCOMPAT_CSUM_V3;
+
+const TAG_FLAG_ESCAPE: u32 = 0x1;	// The block's first word matched the magic, and was zeroed
+const TAG_FLAG_SAME_UUID: u32 = 0x2;	// No UUID follows the tag
+const TAG_FLAG_LAST_TAG: u32 = 0x8;	// Last tag in the descriptor
+
+/// Commit block checksum type for `FEAT_COMPAT_CHECKSUM` (CRC32 of the transaction's blocks)
+const CHKSUM_TYPE_CRC32: u8 = 1;
+/// Byte offset of the checksum in a commit block
+const COMMIT_CHKSUM: usize = 0x10;
+
+/// Byte offsets of journal superblock fields
+const JSB_BLOCKSIZE: usize = 0x0C;
+const JSB_MAXLEN: usize = 0x10;
+const JSB_FIRST: usize = 0x14;
+const JSB_SEQUENCE: usize = 0x18;
+const JSB_START: usize = 0x1C;
+const JSB_FEATURE_COMPAT: usize = 0x24;
+const JSB_FEATURE_INCOMPAT: usize = 0x28;
+const JSB_UUID: usize = 0x30;
+const JSB_CHECKSUM: usize = 0xFC;
+const JSB_SIZE: usize = 0x400;
+
+/// Replay the journal of a volume that wasn't cleanly unmounted
+///
+/// The journal is marked as empty once replayed.
+/// Returns an error if the journal can't be recovered, in which case the metadata may be inconsistent.
+pub fn replay(fs: InstancePtr) -> vfs::Result<()>
+{
+	let journal = try!(Journal::open(fs));
+	if journal.start == 0 {
+		log_log!("Journal is empty, nothing to replay");
+	}
+	else {
+		let mut revoked = VecMap::new();
+		// Pass 1: Find the end of the log (the first transaction without a commit block)
+		let end_seq = try!(journal.do_pass(Pass::Scan, journal.sequence, &mut revoked));
+		log_notice!("Recovering journal transactions {} to {}", journal.sequence, end_seq);
+		// Pass 2: Collect revoked blocks (which must not be overwritten by older transactions)
+		try!(journal.do_pass(Pass::Revoke(end_seq), journal.sequence, &mut revoked));
+		// Pass 3: Copy the logged blocks to their final locations
+		try!(journal.do_pass(Pass::Replay(end_seq), journal.sequence, &mut revoked));
+		try!(journal.mark_empty(end_seq));
+	}
+	Ok( () )
+}
+
+#[derive(Copy,Clone)]
+enum Pass
+{
+	Scan,
+	/// Collect revoke records from transactions before the specified sequence number
+	Revoke(u32),
+	/// Replay transactions before the specified sequence number
+	Replay(u32),
+}
+
+struct Journal
+{
+	fs: InstancePtr,
+	inode: ::inodes::Inode,
+	/// First block of the log (after the journal superblock)
+	first: u32,
+	/// Total number of blocks in the journal
+	maxlen: u32,
+	/// Sequence number of the first transaction in the log
+	sequence: u32,
+	/// Block containing the first transaction (zero if the journal is empty)
+	start: u32,
+	feature_compat: u32,
+	feature_incompat: u32,
+	/// Initial value for CSUM_V2/V3 checksums (CRC32-C of the journal UUID)
+	csum_seed: u32,
+}
+
+impl Journal
+{
+	fn open(fs: InstancePtr) -> vfs::Result<Journal>
+	{
+		let inode_num = fs.journal_inode();
+		if inode_num == 0 {
+			return Err( vfs::Error::Unknown("Journal is missing, or on an external device") );
+		}
+		let inode = try!(::inodes::Inode::from_id(fs.reborrow(), inode_num));
+
+		let (blocksize, maxlen, first, sequence, start, feature_compat, feature_incompat, csum_seed) = {
+			let jsb = try!(read_journal_block(&fs, &inode, 0));
+			let b = ::kernel::lib::as_byte_slice(&jsb[..]);
+			if BigEndian::read_u32(&b[0..]) != JBD2_MAGIC {
+				return Err( vfs::Error::InconsistentFilesystem );
+			}
+			let (feature_compat, feature_incompat) = match BigEndian::read_u32(&b[4..])
+				{
+				BLOCKTYPE_SUPERBLOCK_V1 => (0, 0),
+				BLOCKTYPE_SUPERBLOCK_V2 => (BigEndian::read_u32(&b[JSB_FEATURE_COMPAT..]), BigEndian::read_u32(&b[JSB_FEATURE_INCOMPAT..])),
+				_ => return Err( vfs::Error::InconsistentFilesystem ),
+				};
+			(
+				BigEndian::read_u32(&b[JSB_BLOCKSIZE..]),
+				BigEndian::read_u32(&b[JSB_MAXLEN..]),
+				BigEndian::read_u32(&b[JSB_FIRST..]),
+				BigEndian::read_u32(&b[JSB_SEQUENCE..]),
+				BigEndian::read_u32(&b[JSB_START..]),
+				feature_compat,
+				feature_incompat,
+				crc32c(!0, &b[JSB_UUID..][..16]),
+			)
+			};
+		log_debug!("Journal: inode {}, blocksize={}, maxlen={}, first={}, sequence={}, start={}, features={:#x}/{:#x}",
+			inode_num, blocksize, maxlen, first, sequence, start, feature_compat, feature_incompat);
+
+		if feature_incompat & !SUPPORTED_INCOMPAT != 0 {
+			log_warning!("Journal uses unsupported features {:#x}", feature_incompat & !SUPPORTED_INCOMPAT);
+			return Err( vfs::Error::Unknown("Journal uses unsupported features") );
+		}
+		if blocksize as usize != fs.fs_block_size || maxlen > inode.max_blocks() || first == 0 || first >= maxlen {
+			return Err( vfs::Error::InconsistentFilesystem );
+		}
+		if start != 0 && (start < first || start >= maxlen) {
+			return Err( vfs::Error::InconsistentFilesystem );
+		}
+
+		Ok(Journal {
+			fs: fs,
+			inode: inode,
+			first: first,
+			maxlen: maxlen,
+			sequence: sequence,
+			start: start,
+			feature_compat: feature_compat,
+			feature_incompat: feature_incompat,
+			csum_seed: csum_seed,
+			})
+	}
+
+	/// Size of a block tag in a descriptor block (excluding the optional UUID)
+	fn tag_bytes(&self) -> usize {
+		if self.feature_incompat & FEAT_INCOMPAT_CSUM_V3 != 0 {
+			16
+		}
+		else {
+			let base = if self.feature_incompat & FEAT_INCOMPAT_CSUM_V2 != 0 { 12 + 2 } else { 12 };
+			if self.feature_incompat & FEAT_INCOMPAT_64BIT != 0 { base } else { base - 4 }
+		}
+	}
+	/// Journal blocks are checksummed using CRC32-C (CSUM_V2/V3)
+	fn has_csum_v2v3(&self) -> bool {
+		self.feature_incompat & (FEAT_INCOMPAT_CSUM_V2 | FEAT_INCOMPAT_CSUM_V3) != 0
+	}
+	/// Commit blocks hold a CRC32 of the transaction's blocks (the original checksum feature)
+	fn has_csum_v1(&self) -> bool {
+		self.feature_compat & FEAT_COMPAT_CHECKSUM != 0
+	}
+	/// Size of the checksum tail at the end of descriptor and revoke blocks
+	fn tail_bytes(&self) -> usize {
+		if self.has_csum_v2v3() { 4 } else { 0 }
+	}
+	/// Check the checksum of a block, stored (big-endian) at `ofs` and calculated with that field zeroed
+	fn block_csum_valid(&self, b: &[u8], ofs: usize) -> bool {
+		let crc = crc32c(self.csum_seed, &b[.. ofs]);
+		let crc = crc32c(crc, &[0; 4]);
+		let crc = crc32c(crc, &b[ofs + 4 ..]);
+		crc == BigEndian::read_u32(&b[ofs..])
+	}
+	/// Check the checksum tail of a descriptor or revoke block
+	fn tail_csum_valid(&self, b: &[u8]) -> bool {
+		!self.has_csum_v2v3() || self.block_csum_valid(b, b.len() - 4)
+	}
+	/// Check a data block against the checksum from its descriptor tag
+	fn tag_csum_valid(&self, seq: u32, tag_csum: u32, data: &[u8]) -> bool {
+		if !self.has_csum_v2v3() {
+			return true;
+		}
+		let mut seq_bytes = [0; 4];
+		BigEndian::write_u32(&mut seq_bytes, seq);
+		let crc = crc32c(crc32c(self.csum_seed, &seq_bytes), data);
+		if self.feature_incompat & FEAT_INCOMPAT_CSUM_V3 != 0 {
+			crc == tag_csum
+		}
+		else {
+			// - CSUM_V2 tags only hold the low 16 bits
+			crc & 0xFFFF == tag_csum
+		}
+	}
+	/// Check a commit block, given the running CRC32 of the transaction's blocks
+	fn commit_csum_valid(&self, b: &[u8], txn_crc: u32) -> bool {
+		if self.has_csum_v2v3() && !self.block_csum_valid(b, COMMIT_CHKSUM) {
+			return false;
+		}
+		if self.has_csum_v1() {
+			let (ty, size, sum) = (b[12], b[13], BigEndian::read_u32(&b[COMMIT_CHKSUM..]));
+			// - A zeroed checksum means that the checksum wasn't recorded
+			let valid = (ty == CHKSUM_TYPE_CRC32 && size == 4 && sum == txn_crc) || (ty == 0 && size == 0 && sum == 0);
+			if !valid {
+				return false;
+			}
+		}
+		true
+	}
+	/// Advance a log position, wrapping back to the first log block
+	fn next_pos(&self, pos: u32) -> u32 {
+		if pos + 1 >= self.maxlen { self.first } else { pos + 1 }
+	}
+
+	/// Walk the log from the start, returning the sequence number of the first incomplete (or missing) transaction
+	///
+	/// The scan pass also treats a transaction that fails checksum verification as the end of the log (it may have been
+	/// torn by a crash).
+	fn do_pass(&self, pass: Pass, start_seq: u32, revoked: &mut VecMap<u32,u32>) -> vfs::Result<u32>
+	{
+		let mut seq = start_seq;
+		let mut pos = self.start;
+		// Checksum state for the current transaction (only tracked by the scan pass)
+		let mut txn_crc = !0;
+		let mut txn_valid = true;
+		// Number of log blocks visited (a valid log can't cover the journal more than once)
+		let mut n_walked = 0;
+		loop
+		{
+			if n_walked > self.maxlen {
+				return Err( vfs::Error::InconsistentFilesystem );
+			}
+			match pass
+			{
+			Pass::Scan => {},
+			Pass::Revoke(end) | Pass::Replay(end) => if seq == end { break ; },
+			}
+
+			let blk = try!(read_journal_block(&self.fs, &self.inode, pos));
+			let b = ::kernel::lib::as_byte_slice(&blk[..]);
+			// Anything other than a block from the expected transaction marks the end of the log
+			if BigEndian::read_u32(&b[0..]) != JBD2_MAGIC || BigEndian::read_u32(&b[8..]) != seq {
+				break ;
+			}
+			pos = self.next_pos(pos);
+			n_walked += 1;
+
+			match BigEndian::read_u32(&b[4..])
+			{
+			BLOCKTYPE_DESCRIPTOR => {
+				if let Pass::Scan = pass
+				{
+					txn_valid &= self.tail_csum_valid(b);
+					if self.has_csum_v1() {
+						txn_crc = crc32_be(txn_crc, b);
+					}
+				}
+				// Each tag describes one of the following data blocks
+				for (block, flags, tag_csum) in try!(self.parse_tags(b))
+				{
+					match pass
+					{
+					Pass::Scan => if self.has_csum_v1() || self.has_csum_v2v3() {
+						let data = try!(read_journal_block(&self.fs, &self.inode, pos));
+						let data = ::kernel::lib::as_byte_slice(&data[..]);
+						if self.has_csum_v1() {
+							txn_crc = crc32_be(txn_crc, data);
+						}
+						txn_valid &= self.tag_csum_valid(seq, tag_csum, data);
+						},
+					Pass::Revoke(_) => {},
+					Pass::Replay(_) => {
+						let is_revoked = match revoked.get(&block)
+							{
+							Some(&rseq) => !seq_after(seq, rseq),
+							None => false,
+							};
+						if !is_revoked
+						{
+							let mut data = try!(read_journal_block(&self.fs, &self.inode, pos));
+							if flags & TAG_FLAG_ESCAPE != 0 {
+								data[0] = JBD2_MAGIC.to_be();
+							}
+							log_trace!("Replay block {} (transaction {}) from journal block {}", block, seq, pos);
+							try!(self.fs.write_blocks(block, ::kernel::lib::as_byte_slice(&data[..])));
+						}
+						},
+					}
+					pos = self.next_pos(pos);
+					n_walked += 1;
+				}
+				},
+			BLOCKTYPE_COMMIT => {
+				if let Pass::Scan = pass
+				{
+					if !(txn_valid && self.commit_csum_valid(b, txn_crc)) {
+						log_warning!("Journal: Transaction {} failed checksum verification, ending the log there", seq);
+						break ;
+					}
+					txn_crc = !0;
+					txn_valid = true;
+				}
+				seq = seq.wrapping_add(1);
+				},
+			BLOCKTYPE_REVOKE => {
+				if let Pass::Scan = pass
+				{
+					txn_valid &= self.tail_csum_valid(b);
+				}
+				if let Pass::Revoke(_) = pass
+				{
+					for block in try!(self.parse_revoke(b))
+					{
+						// Keep the newest revocation of each block
+						let newer = match revoked.get(&block)
+							{
+							Some(&rseq) => seq_after(seq, rseq),
+							None => true,
+							};
+						if newer {
+							revoked.insert(block, seq);
+						}
+					}
+				}
+				},
+			v @ _ => {
+				log_warning!("Journal: Unexpected block type {} in transaction {}", v, seq);
+				break ;
+				},
+			}
+		}
+		Ok(seq)
+	}
+
+	/// Obtain the (block, flags, checksum) tuples from a descriptor block
+	fn parse_tags(&self, b: &[u8]) -> vfs::Result<Vec<(u32,u32,u32)>>
+	{
+		let tag_bytes = self.tag_bytes();
+		let end = b.len() - self.tail_bytes();
+		let is_tag3 = self.feature_incompat & FEAT_INCOMPAT_CSUM_V3 != 0;
+		let is_64bit = self.feature_incompat & FEAT_INCOMPAT_64BIT != 0;
+		let mut rv = Vec::new();
+		let mut ofs = 12;
+		while ofs + tag_bytes <= end
+		{
+			let tag = &b[ofs..];
+			let block = BigEndian::read_u32(&tag[0..]);
+			let flags = if is_tag3 { BigEndian::read_u32(&tag[4..]) } else { BigEndian::read_u16(&tag[6..]) as u32 };
+			let block_hi = if is_tag3 || is_64bit { BigEndian::read_u32(&tag[8..]) } else { 0 };
+			let csum = if is_tag3 { BigEndian::read_u32(&tag[12..]) } else { BigEndian::read_u16(&tag[4..]) as u32 };
+			if block_hi != 0 {
+				log_warning!("Journal: Logged block over 32 bits ({:#x}:{:08x})", block_hi, block);
+				return Err( vfs::Error::Unknown("extN volume too large") );
+			}
+			rv.push( (block, flags, csum) );
+
+			ofs += tag_bytes;
+			if flags & TAG_FLAG_SAME_UUID == 0 {
+				ofs += 16;
+			}
+			if flags & TAG_FLAG_LAST_TAG != 0 {
+				break ;
+			}
+		}
+		Ok(rv)
+	}
+
+	/// Obtain the block numbers from a revoke block
+	fn parse_revoke(&self, b: &[u8]) -> vfs::Result<Vec<u32>>
+	{
+		let count = BigEndian::read_u32(&b[12..]) as usize;
+		if count < 16 || count > b.len() - self.tail_bytes() {
+			return Err( vfs::Error::InconsistentFilesystem );
+		}
+		let mut rv = Vec::new();
+		if self.feature_incompat & FEAT_INCOMPAT_64BIT != 0 {
+			for r in b[16 .. count].chunks(8) {
+				// - Blocks over 32 bits can't be logged (see `parse_tags`), so can be ignored here
+				if r.len() == 8 && BigEndian::read_u32(&r[0..]) == 0 {
+					rv.push( BigEndian::read_u32(&r[4..]) );
+				}
+			}
+		}
+		else {
+			for r in b[16 .. count].chunks(4) {
+				if r.len() == 4 {
+					rv.push( BigEndian::read_u32(r) );
+				}
+			}
+		}
+		Ok(rv)
+	}
+
+	/// Update the journal superblock to indicate that there's nothing left to replay
+	fn mark_empty(&self, next_seq: u32) -> vfs::Result<()>
+	{
+		let addr = try!(self.inode.get_block_addr(0));
+		let mut jsb = try!(read_journal_block(&self.fs, &self.inode, 0));
+		{
+			let b = ::kernel::lib::as_byte_slice_mut(&mut jsb[..]);
+			BigEndian::write_u32(&mut b[JSB_SEQUENCE..], next_seq);
+			BigEndian::write_u32(&mut b[JSB_START..], 0);
+			if self.feature_incompat & (FEAT_INCOMPAT_CSUM_V2 | FEAT_INCOMPAT_CSUM_V3) != 0 {
+				BigEndian::write_u32(&mut b[JSB_CHECKSUM..], 0);
+				let csum = crc32c(!0, &b[..JSB_SIZE]);
+				BigEndian::write_u32(&mut b[JSB_CHECKSUM..], csum);
+			}
+		}
+		try!(self.fs.write_blocks(addr, ::kernel::lib::as_byte_slice(&jsb[..])));
+		Ok( () )
+	}
+}
+
+/// Read a block of the journal (uncached, as the log is only read once)
+fn read_journal_block(fs: &InstancePtr, inode: &::inodes::Inode, idx: u32) -> vfs::Result<Box<[u32]>>
+{
+	match try!(inode.get_block_addr(idx))
+	{
+	0 => Err( vfs::Error::InconsistentFilesystem ),
+	addr => fs.get_block_uncached(addr),
+	}
+}
+
+/// Compare transaction sequence numbers (which wrap)
+fn seq_after(a: u32, b: u32) -> bool {
+	(a.wrapping_sub(b) as i32) > 0
+}
+
+/// Big-endian CRC32 (as used by `FEAT_COMPAT_CHECKSUM`), without the final inversion
+fn crc32_be(mut crc: u32, data: &[u8]) -> u32
+{
+	for &b in data
+	{
+		crc ^= (b as u32) << 24;
+		for _ in 0 .. 8 {
+			crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04C11DB7 } else { crc << 1 };
+		}
+	}
+	crc
+}
+
+/// CRC32-C (Castagnoli), without the final inversion (as used by jbd2)
+fn crc32c(mut crc: u32, data: &[u8]) -> u32
+{
+	for &b in data
+	{
+		crc ^= b as u32;
+		for _ in 0 .. 8 {
+			crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F63B78 } else { crc >> 1 };
+		}
+	}
+	crc
+}
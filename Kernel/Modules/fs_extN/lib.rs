@@ -20,10 +20,12 @@ module_define!{FS_EXTN, [VFS], init}
 
 mod ondisk;
 mod inodes;
+mod htree;
 
 mod dir;
 mod file;
 mod instance;
+mod journal;
 
 fn init()
 {
@@ -34,14 +36,24 @@ fn init()
 const SUPPORTED_OPT_FEATURES: u32 = 0
 	| ::ondisk::FEAT_COMPAT_EXT_ATTR	// Extended attributes
 	| ::ondisk::FEAT_COMPAT_RESIZE_INODE	// Extra space was allocated for resizing the filesystem
+	| ::ondisk::FEAT_COMPAT_DIR_INDEX	// Directories can have a hashed index (htree), which is kept valid (or removed) on insert
+	| ::ondisk::FEAT_COMPAT_HAS_JOURNAL	// Metadata journal, replayed at mount (writes aren't journaled, so the volume is mounted read-only)
 	;
 /// Read-only features: Missing features stop write support
 const SUPPORTED_RDO_FEATURES: u32 = 0
 	| ::ondisk::FEAT_RO_COMPAT_SPARSE_SUPER	// Enables storing SB backups at group 0, 3^n, 5^n, and 7^n
+	| ::ondisk::FEAT_RO_COMPAT_LARGE_FILE	// Regular files can exceed 2GiB (upper 32 bits of the size in `i_size_high`)
 	;
 /// Required Features: Missing features prevent mounting
 const SUPPORTED_REQ_FEATURES: u32 = 0
 	| ::ondisk::FEAT_INCOMPAT_FILETYPE	// DirEnt.d_name_len restricted to 1 byte and extra byte used for file type
+	| ::ondisk::FEAT_INCOMPAT_FLEX_BG	// Bitmaps and inode tables can be outside their block group
+	| ::ondisk::FEAT_INCOMPAT_RECOVER	// The journal needs replaying (done at mount, or the volume is mounted read-only)
+	;
+/// Required Features that are only supported for reading: Volumes using these are mounted read-only
+const READONLY_REQ_FEATURES: u32 = 0
+	| ::ondisk::FEAT_INCOMPAT_EXTENTS	// Inodes can use extent trees instead of block maps
+	| ::ondisk::FEAT_INCOMPAT_64BIT	// 64-bit block numbers, and larger group descriptors
 	;
 
 static S_DRIVER: Driver = Driver;
@@ -79,7 +91,7 @@ impl vfs::mount::Driver for Driver
 			Ok(0)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, mounthandle: vfs::mount::SelfHandle) -> vfs::Result<Box<dyn vfs::mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, mounthandle: vfs::mount::SelfHandle, _options: &[&str]) -> vfs::Result<Box<dyn vfs::mount::Filesystem>> {
 		Ok( try!(instance::Instance::new_boxed(vol, mounthandle)) )
 	}
 }
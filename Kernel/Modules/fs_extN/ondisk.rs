@@ -245,12 +245,15 @@ pub const FEAT_COMPAT_IMAGIC_INODES: u32 = 1 << 1;	// ?
 pub const FEAT_COMPAT_HAS_JOURNAL  : u32 = 1 << 2;
 pub const FEAT_COMPAT_EXT_ATTR     : u32 = 1 << 3;	// Extended attributes
 pub const FEAT_COMPAT_RESIZE_INODE : u32 = 1 << 4;	// Reserved GDT blocks for expansion
-pub const FEAT_COMPAT_DIR_INDEX    : u32 = 1 << 5;	// Directory indicies [?]
+pub const FEAT_COMPAT_DIR_INDEX    : u32 = 1 << 5;	// Hashed directory indexes (htree)
 pub const FEAT_COMPAT_LAZY_BG      : u32 = 1 << 6;
 pub const FEAT_COMPAT_EXCLUDE_INODE: u32 = 1 << 7;
 pub const FEAT_COMPAT_EXCLUDE_BITMAP:u32 = 1 << 8;
 pub const FEAT_COMPAT_SPARSE_SUPER2: u32 = 1 << 9;
 
+pub const EXT2_FLAGS_SIGNED_HASH  : u32 = 0x1;	// s_flags: Directory hashes treat names as signed `char`s
+pub const EXT2_FLAGS_UNSIGNED_HASH: u32 = 0x2;	// s_flags: Directory hashes treat names as unsigned `char`s
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct Inode
@@ -270,7 +273,7 @@ pub struct Inode
 	pub i_block: [u32; 15],	// Pointers to blocks
 	pub i_version: u32,	// File version (for NFS)
 	pub i_file_acl: u32,	// File ACL
-	pub i_dir_acl: u32,	// Directory ACL / Extended File Size
+	pub i_size_high: u32,	// Upper 32 bits of the size for regular files (`i_dir_acl` for directories on old revisions)
 	pub i_faddr: u32,	// Fragment address
 	pub _osd2: [u32; 3],	// OS Dependent #2 (Typically fragment info)
 }
@@ -317,7 +320,51 @@ pub const S_IROTH: u16 =  0o004;	// Global Read
 pub const S_IWOTH: u16 =  0o002;	// Global Write
 pub const S_IXOTH: u16 =  0o001;	// Global Execute
 
-pub const EXT4_INDEX_FL: u16 = 0x1000;	// i_flags: Directory uses a hashed btree
+pub const EXT4_INDEX_FL: u32 = 0x1000;	// i_flags: Directory uses a hashed btree
+pub const EXT4_EXTENTS_FL: u32 = 0x80000;	// i_flags: Inode uses an extent tree (`i_block` holds the root node)
+
+/// Length of the `dx_root_info` structure (in the first block of an indexed directory)
+pub const DX_ROOT_INFO_LEN: u8 = 8;
+
+/// Extent tree node header [FEAT_INCOMPAT_EXTENTS]
+#[repr(C)]
+pub struct ExtentHeader
+{
+	pub eh_magic: u16,	// Magic number (EXTENT_MAGIC)
+	pub eh_entries: u16,	// Number of valid entries following the header
+	pub eh_max: u16,	// Capacity of the node (in entries)
+	pub eh_depth: u16,	// Depth of the tree below this node (zero for leaf nodes)
+	pub eh_generation: u32,
+}
+pod_impls!{ ExtentHeader }
+def_from_slice!{ ExtentHeader }
+pub const EXTENT_MAGIC: u16 = 0xF30A;
+
+/// Extent tree interior node entry
+#[repr(C)]
+pub struct ExtentIdx
+{
+	pub ei_block: u32,	// First file block covered by this child
+	pub ei_leaf_lo: u32,	// Block containing the child node
+	pub ei_leaf_hi: u16,
+	pub ei_unused: u16,
+}
+pod_impls!{ ExtentIdx }
+def_from_slice!{ ExtentIdx }
+
+/// Extent tree leaf node entry
+#[repr(C)]
+pub struct Extent
+{
+	pub ee_block: u32,	// First file block covered
+	pub ee_len: u16,	// Number of blocks (values above EXTENT_MAX_INIT_LEN are uninitialised extents)
+	pub ee_start_hi: u16,
+	pub ee_start_lo: u32,	// First volume block
+}
+pod_impls!{ Extent }
+def_from_slice!{ Extent }
+/// Maximum length of an initialised extent, longer lengths mark preallocated space that reads as zero
+pub const EXTENT_MAX_INIT_LEN: u16 = 32768;
 
 #[repr(C)]
 pub struct GroupDesc
@@ -361,6 +408,16 @@ pub struct DirEnt
 }
 pub const DIRENT_MIN_SIZE: usize = 8;
 
+/// Decode `d_rec_len` (a record covering a whole 64KiB block is stored as 65535, or zero)
+pub fn rec_len_from_disk(v: u16) -> usize {
+	if v == 0xFFFF || v == 0 { 0x10000 } else { v as usize }
+}
+/// Encode a record length for `d_rec_len`
+pub fn rec_len_to_disk(len: usize) -> u16 {
+	assert!(len <= 0x10000);
+	if len == 0x10000 { 0xFFFF } else { len as u16 }
+}
+
 //pod_impls!{ DirEnt }
 
 impl DirEnt
@@ -378,7 +435,7 @@ impl DirEnt
 		// SAFE: 0 name length is valid
 		let rv0: &DirEnt = unsafe { &*Self::new_raw(buf, 0) };
 
-		let rec_len = rv0.d_rec_len as usize;
+		let rec_len = rv0.rec_len();
 		let name_len = rv0.d_name_len as usize;
 
 		if rec_len > buf.len() * 4 {
@@ -407,9 +464,17 @@ impl DirEnt
 	}
 
 
+	/// Record length in bytes
+	pub fn rec_len(&self) -> usize {
+		rec_len_from_disk(self.d_rec_len)
+	}
+	pub fn set_rec_len(&mut self, len: usize) {
+		self.d_rec_len = rec_len_to_disk(len);
+	}
+
 	/// Returns the number of 32-bit integers this entry takes up
 	pub fn u32_len(&self) -> usize {
-		(self.d_rec_len as usize + 3) / 4
+		(self.rec_len() + 3) / 4
 	}
 }
 
@@ -17,6 +17,8 @@ pub struct DirNode
 	fs: ArefBorrow<::FilesystemInner>,
 	start_cluster: u32,
 	// - Uses the cluster chain
+	/// Metadata from the parent's directory entry (default for the root)
+	metadata: node::Metadata,
 }
 impl_fmt! {
 	Debug(self, f) for DirNode {
@@ -29,20 +31,39 @@ impl DirNode {
 		DirNode {
 			fs: fs,
 			start_cluster: start_cluster,
+			metadata: Default::default(),
 		}
 	}
 	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, start_cluster: u32) -> Box<DirNode> {
 		Box::new(Self::new(fs, start_cluster))
 	}
+	fn new_boxed_with_metadata(fs: ArefBorrow<FilesystemInner>, start_cluster: u32, metadata: node::Metadata) -> Box<DirNode> {
+		Box::new(DirNode {
+			metadata: metadata,
+			..Self::new(fs, start_cluster)
+			})
+	}
 }
 
 impl node::NodeBase for DirNode {
 	fn get_id(&self) -> node::InodeId {
-		todo!("DirNode::get_id")
+		super::InodeRef::Dir(self.start_cluster).to_id()
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		let size = if self.is_fixed_root() {
+				self.fs.root_sector_count as u64 * self.fs.sector_size as u64
+			}
+			else {
+				self.clusters().count() as u64 * self.fs.cluster_size as u64
+			};
+		Ok(node::Metadata {
+			size: size,
+			..self.metadata.clone()
+			})
+	}
 }
 
 impl DirNode {
@@ -59,45 +80,70 @@ impl DirNode {
 		}
 	}
 
-	/// Locate a node in this directory by its first data cluster
-	pub fn find_node(&self, ent_cluster: u32) -> Option<node::Node>
+	/// Load a (non-root) directory by its first cluster, using its `..` entry to locate the parent's entry for it
+	pub fn load_dir(fs: ArefBorrow<FilesystemInner>, cluster: u32) -> Option<node::Node>
 	{
-		match self.find_ent_by_cluster(ent_cluster)
-		{
-		None => None,
-		Some(e) =>
-			if e.attributes & on_disk::ATTR_DIRECTORY != 0 {
-				Some(node::Node::Dir(DirNode::new_boxed(self.fs.reborrow(), ent_cluster)))
-			}
-			else if e.attributes & on_disk::ATTR_VOLUMEID != 0 {
-				None
-			}
-			else {
-				Some(node::Node::File(FileNode::new_boxed(
-					self.fs.reborrow(), self.start_cluster, ent_cluster, e.size
-					)))
-			},
-		}
-	}
-	
-	fn find_ent_by_cluster(&self, ent_cluster: u32) -> Option<DirEntShort> {
-		log_trace!("find_ent_by_cluster(self={:?}, ent_cluster={})", self, ent_cluster);
-		for c in self.clusters()
+		let parent = match fs.load_cluster(cluster)
+			{
+			Ok(data) => match DirEnts::new(&data).nth(1)
+				{
+				Some(DirEnt::Short(ref e)) if e.name() == ".." => if e.cluster == 0 { fs.root_first_cluster } else { e.cluster },
+				_ => {
+					log_notice!("load_dir: Cluster {:#x} doesn't start with `.` and `..`", cluster);
+					return None;
+					},
+				},
+			Err(e) => {
+				log_error!("load_dir: Error reading cluster {:#x}: {:?}", cluster, e);
+				return None;
+				},
+			};
+		let parent = DirNode::new(fs.reborrow(), parent);
+		for c in parent.clusters()
 		{
-			let cluster = match self.fs.load_cluster(c) {
+			let data = match fs.load_cluster(c) {
 				Ok(v) => v,
 				Err(_) => return None,
 				};
-			for ent in DirEnts::new(&cluster) {
-				if let DirEnt::Short(e) = ent {
-					if e.cluster == ent_cluster {
-						return Some(e);
-					}
+			for ent in DirEnts::new(&data) {
+				match ent
+				{
+				DirEnt::End => return None,
+				DirEnt::Short(ref e) if e.cluster == cluster && e.attributes & on_disk::ATTR_DIRECTORY != 0 && e.name() != "." && e.name() != ".." =>
+					return Some(node::Node::Dir(DirNode::new_boxed_with_metadata(fs.reborrow(), cluster, e.metadata()))),
+				_ => {},
 				}
 			}
 		}
 		None
 	}
+	/// Load a file from its short entry (entry `index` of `cluster`)
+	pub fn load_file(fs: ArefBorrow<FilesystemInner>, cluster: u32, index: usize) -> Option<node::Node>
+	{
+		let data = match fs.load_cluster(cluster)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("load_file: Error reading cluster {:#x}: {:?}", cluster, e);
+				return None;
+				},
+			};
+		if (index + 1) * 32 > data.len() {
+			return None;
+		}
+		match DirEnts::new(&data[index*32..][..32]).next()
+		{
+		Some(DirEnt::Short(e)) =>
+			if e.attributes & on_disk::ATTR_DIRECTORY != 0 {
+				None
+			}
+			else {
+				let inode = e.inode(&fs, cluster, index);
+				Some(node::Node::File(FileNode::new_boxed(fs, inode, e.cluster, e.size, e.metadata())))
+			},
+		_ => None,
+		}
+	}
 }
 
 /// Iterator over directory entries
@@ -121,9 +167,9 @@ struct DirEntShort {
 	cluster: u32,
 	size: u32,
 	attributes: u8,
-	//creation_time: ::kernel::time::Timestamp,
-	//modified_time: ::kernel::time::Timestamp,
-	//accessed_time: ::kernel::time::Timestamp,
+	creation_time: ::kernel::time::Timestamp,
+	modified_time: ::kernel::time::Timestamp,
+	accessed_time: ::kernel::time::Timestamp,
 }
 impl_fmt! {
 	Debug(self,f) for DirEntShort {
@@ -231,6 +277,9 @@ impl<'a> ::core::iter::Iterator for DirEnts<'a> {
 					cluster: (ent.cluster as u32) | (ent.cluster_hi as u32) << 16,
 					size: ent.size,
 					attributes: ent.attribs,
+					creation_time: on_disk::timestamp_from_fat(ent.creation_date, ent.creation_time) + ent.creation_ds as i64 / 100,
+					modified_time: on_disk::timestamp_from_fat(ent.modified_date, ent.modified_time),
+					accessed_time: on_disk::timestamp_from_fat(ent.accessed_date, 0),
 					}) )
 			}
 		}
@@ -240,8 +289,30 @@ impl DirEntShort {
 	fn name(&self) -> &ByteStr {
 		ByteStr::new( (&self.name).split(|&e|e==0).next().unwrap() )
 	}
-	fn inode(&self, parent_dir: u32) -> node::InodeId {
-		super::InodeRef::new(self.cluster, parent_dir).to_id()
+	/// Inode number for this entry (located at entry `index` of `cluster`)
+	fn inode(&self, fs: &FilesystemInner, cluster: u32, index: usize) -> node::InodeId {
+		if self.attributes & on_disk::ATTR_DIRECTORY != 0 {
+			// - `..` entries refer to the root as cluster 0
+			super::InodeRef::Dir(if self.cluster == 0 { fs.root_first_cluster } else { self.cluster }).to_id()
+		}
+		else {
+			super::InodeRef::File { cluster: cluster, index: index as u16 }.to_id()
+		}
+	}
+	fn metadata(&self) -> node::Metadata {
+		// FAT has no ownership or permissions, only a read-only flag
+		let mut permissions = if self.attributes & on_disk::ATTR_DIRECTORY != 0 { 0o755 } else { 0o644 };
+		if self.attributes & on_disk::ATTR_READONLY != 0 {
+			permissions &= !0o222;
+		}
+		node::Metadata {
+			size: self.size as u64,
+			permissions: permissions,
+			ctime: self.creation_time,
+			mtime: self.modified_time,
+			atime: self.accessed_time,
+			..Default::default()
+		}
 	}
 }
 
@@ -294,6 +365,441 @@ impl LFN {
 	}
 }
 
+/// Location of a named entry within a directory
+struct EntPos
+{
+	/// Index of the first entry (the start of the LFN entries, if present)
+	first: usize,
+	/// Index of the short entry
+	short: usize,
+	/// Inode number of the node
+	inode: node::InodeId,
+	/// Raw contents of the short entry
+	raw: [u8; 32],
+}
+
+/// Directory modification
+impl DirNode {
+	fn ents_per_cluster(&self) -> usize {
+		self.fs.cluster_size / 32
+	}
+	/// Maximum number of entries (only limited for the FAT12/16 root)
+	fn max_ents(&self) -> Option<usize> {
+		if self.is_fixed_root() {
+			Some(self.fs.root_sector_count as usize * self.fs.sector_size / 32)
+		}
+		else {
+			None
+		}
+	}
+
+	/// Locate an entry by name, returning the range of entries used
+	fn find_ent_pos(&self, name: &ByteStr) -> node::Result<EntPos> {
+		self.find_ent_pos_by(|e, lfn| e.name() == name || lfn.name() == name)
+	}
+	/// Locate an entry with a name that matches ignoring case (i.e. one that a new `name` would clash with)
+	fn find_ent_pos_nocase(&self, name: &ByteStr) -> node::Result<EntPos> {
+		self.find_ent_pos_by(|e, lfn| {
+			name_eq_nocase(e.name().as_bytes().iter().cloned(), name)
+				|| (lfn.is_valid() && name_eq_nocase(lfn.name().wtf8(), name))
+			})
+	}
+	fn find_ent_pos_by<F: Fn(&DirEntShort, &LFN)->bool>(&self, is_match: F) -> node::Result<EntPos> {
+		let epc = self.ents_per_cluster();
+		let mut lfn = LFN::new();
+		let mut lfn_start = 0;
+		for (ci, c) in self.clusters().enumerate()
+		{
+			let cluster = try!(self.fs.load_cluster(c));
+			for (i, ent) in DirEnts::new(&cluster).enumerate()
+			{
+				let idx = ci * epc + i;
+				match ent {
+				DirEnt::End => return Err(vfs::Error::NotFound),
+				DirEnt::Short(e) => {
+					if is_match(&e, &lfn) {
+						let mut raw = [0; 32];
+						raw.clone_from_slice(&cluster[i*32..][..32]);
+						return Ok(EntPos {
+							first: if lfn.is_valid() { lfn_start } else { idx },
+							short: idx,
+							inode: e.inode(&self.fs, c, i),
+							raw: raw,
+							});
+					}
+					lfn.clear();
+					},
+				DirEnt::Long(e) => {
+					if e.id & 0x40 != 0 {
+						lfn_start = idx;
+					}
+					lfn.add(&e)
+					},
+				DirEnt::Empty => {
+					lfn.clear();
+					},
+				}
+			}
+		}
+		Err(vfs::Error::NotFound)
+	}
+
+	/// Check if a short name is already used in this directory
+	fn short_name_exists(&self, short_name: &[u8; 11]) -> node::Result<bool> {
+		let limit = self.max_ents().unwrap_or(!0);
+		let epc = self.ents_per_cluster();
+		for (ci, c) in self.clusters().enumerate()
+		{
+			let cluster = try!(self.fs.load_cluster(c));
+			for (i, ent) in cluster.chunks(32).enumerate()
+			{
+				if ci * epc + i >= limit || ent[0] == 0 {
+					return Ok(false);
+				}
+				if ent[0] != 0xE5 && ent[11] != on_disk::ATTR_LFN && &ent[..11] == &short_name[..] {
+					return Ok(true);
+				}
+			}
+		}
+		Ok(false)
+	}
+
+	/// Find a run of `count` unused entries, extending the directory if required
+	fn find_free_run(&self, count: usize) -> node::Result<usize> {
+		let limit = self.max_ents().unwrap_or(!0);
+		let epc = self.ents_per_cluster();
+		let (mut run_start, mut run_len) = (0, 0);
+		let (mut n_clusters, mut last_cluster) = (0, 0);
+		for (ci, c) in self.clusters().enumerate()
+		{
+			n_clusters = ci + 1;
+			last_cluster = c;
+			let cluster = try!(self.fs.load_cluster(c));
+			for (i, ent) in cluster.chunks(32).enumerate()
+			{
+				let idx = ci * epc + i;
+				if idx >= limit {
+					break;
+				}
+				// NOTE: Volume ID entries show up as `Empty` from `DirEnts`, so check the raw bytes
+				// - Entries of unlinked files that are still open are kept, so their inode number isn't reused
+				if (ent[0] == 0 || ent[0] == 0xE5) && !self.fs.is_ent_open(super::InodeRef::File { cluster: c, index: i as u16 }.to_id()) {
+					if run_len == 0 {
+						run_start = idx;
+					}
+					run_len += 1;
+					if run_len == count {
+						return Ok(run_start);
+					}
+				}
+				else {
+					run_len = 0;
+				}
+			}
+		}
+		// The FAT12/16 root directory has a fixed size
+		if self.max_ents().is_some() {
+			return Err(vfs::Error::OutOfSpace);
+		}
+		if n_clusters == 0 {
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+
+		// Append zeroed clusters (linked only once cleared, so readers never see stale data)
+		while run_len < count
+		{
+			let c = try!(self.fs.alloc_cluster(0));
+			let res = self.fs.zero_cluster(c).and_then(|_| self.fs.link_cluster(last_cluster, c));
+			if let Err(e) = res {
+				if let Err(e) = self.fs.free_chain(c) {
+					log_error!("FAT: Error freeing cluster {:#x}: {:?}", c, e);
+				}
+				return Err(From::from(e));
+			}
+			if run_len == 0 {
+				run_start = n_clusters * epc;
+			}
+			run_len += epc;
+			n_clusters += 1;
+			last_cluster = c;
+		}
+		Ok(run_start)
+	}
+
+	/// Inode number for a file with its short entry at `idx`
+	fn file_inode(&self, idx: usize) -> node::Result<node::InodeId> {
+		let epc = self.ents_per_cluster();
+		match self.clusters().nth(idx / epc)
+		{
+		Some(c) => Ok( super::InodeRef::File { cluster: c, index: (idx % epc) as u16 }.to_id() ),
+		None => Err(vfs::Error::InconsistentFilesystem),
+		}
+	}
+
+	/// Check if the directory has no entries (other than `.` and `..`)
+	fn is_empty(&self) -> node::Result<bool> {
+		for c in self.clusters()
+		{
+			let cluster = try!(self.fs.load_cluster(c));
+			for ent in DirEnts::new(&cluster)
+			{
+				match ent
+				{
+				DirEnt::End => return Ok(true),
+				DirEnt::Short(e) => if e.name() != "." && e.name() != ".." {
+					return Ok(false);
+					},
+				_ => {},
+				}
+			}
+		}
+		Ok(true)
+	}
+
+	/// Modify the entries `first .. first+count`, `f` is passed the index relative to `first`
+	fn edit_ents<F: FnMut(usize, &mut [u8])>(&self, first: usize, count: usize, mut f: F) -> node::Result<()> {
+		let epc = self.ents_per_cluster();
+		let end = first + count;
+		for (ci, c) in self.clusters().enumerate()
+		{
+			let (c_start, c_end) = (ci * epc, (ci + 1) * epc);
+			if c_end <= first {
+				continue ;
+			}
+			if c_start >= end {
+				break ;
+			}
+			try!(self.fs.edit_cluster(c, |data| {
+				for i in ::core::cmp::max(first, c_start) .. ::core::cmp::min(end, c_end)
+				{
+					f(i - first, &mut data[(i - c_start)*32..][..32]);
+				}
+				}));
+		}
+		Ok( () )
+	}
+
+	/// Generate the short name (and lower-case flags) for a new entry, along with the long name if one is needed
+	fn make_name(&self, name: &ByteStr) -> node::Result<([u8; 11], u8, Option<Vec<u16>>)> {
+		let bytes = name.as_bytes();
+		if ! is_valid_name(bytes) {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let s = match ::core::str::from_utf8(bytes)
+			{
+			Ok(v) => v,
+			Err(_) => return Err(vfs::Error::InvalidParameter),
+			};
+		if let Some((short_name, lcase)) = exact_short_name(bytes) {
+			if ! try!(self.short_name_exists(&short_name)) {
+				return Ok( (short_name, lcase, None) );
+			}
+		}
+
+		let lfn: Vec<u16> = s.encode_utf16().collect();
+		if lfn.len() > 255 {
+			return Err(vfs::Error::InvalidParameter);
+		}
+
+		// Generate a short name of the form `BASIS~N.EXT`
+		let (base, ext) = split_ext(bytes);
+		let mut basis = [b' '; 11];
+		let mut basis_len = 0;
+		for &c in base {
+			if basis_len == 8 {
+				break;
+			}
+			if c == b' ' || c == b'.' {
+				continue ;
+			}
+			basis[basis_len] = to_short_char(c);
+			basis_len += 1;
+		}
+		let mut ext_len = 0;
+		for &c in ext {
+			if ext_len == 3 {
+				break;
+			}
+			if c == b' ' {
+				continue ;
+			}
+			basis[8 + ext_len] = to_short_char(c);
+			ext_len += 1;
+		}
+		for n in 1 .. 1_000_000u32
+		{
+			let mut tail = [0u8; 7];
+			let mut tail_len = 0;
+			let mut v = n;
+			while v > 0 {
+				tail[tail_len] = b'0' + (v % 10) as u8;
+				v /= 10;
+				tail_len += 1;
+			}
+			let keep = ::core::cmp::min(basis_len, 8 - 1 - tail_len);
+			let mut short_name = basis;
+			for i in keep .. 8 {
+				short_name[i] = b' ';
+			}
+			short_name[keep] = b'~';
+			for i in 0 .. tail_len {
+				short_name[keep + 1 + i] = tail[tail_len - 1 - i];
+			}
+			if ! try!(self.short_name_exists(&short_name)) {
+				return Ok( (short_name, 0, Some(lfn)) );
+			}
+		}
+		Err(vfs::Error::Unknown("FAT: Unable to generate a unique short name"))
+	}
+
+	/// Add the entries for a node named `name`: LFN entries (if required), then `short_ent` with the generated short name
+	///
+	/// Must be called with the directory lock held. Returns the indexes of the first entry and the short entry.
+	fn add_ents(&self, name: &ByteStr, short_ent: &[u8; 32]) -> node::Result<(usize, usize)> {
+		let (short_name, lcase, lfn) = try!(self.make_name(name));
+		let lfn_count = lfn.as_ref().map(|v| (v.len() + 12) / 13).unwrap_or(0);
+		let checksum = short_name_checksum(&short_name);
+		let dst_first = try!(self.find_free_run(lfn_count + 1));
+		try!(self.edit_ents(dst_first, lfn_count + 1, |i, ent| {
+			if i < lfn_count {
+				// LFN entries are stored last-first
+				let seq = lfn_count - i;
+				let mut chars = [0xFFFFu16; 13];
+				{
+					let lfn = lfn.as_ref().unwrap();
+					let part = &lfn[(seq-1)*13 ..];
+					let part = &part[.. ::core::cmp::min(13, part.len())];
+					chars[..part.len()].clone_from_slice(part);
+					if part.len() < 13 {
+						chars[part.len()] = 0;
+					}
+				}
+				on_disk::DirEntLong {
+					id: seq as u8 | if i == 0 { 0x40 } else { 0 },
+					name1: [chars[0], chars[1], chars[2], chars[3], chars[4]],
+					attrib: on_disk::ATTR_LFN,
+					ty: 0,
+					checksum: checksum,
+					name2: [chars[5], chars[6], chars[7], chars[8], chars[9], chars[10]],
+					first_cluster: 0,
+					name3: [chars[11], chars[12]],
+					}.write(ent);
+			}
+			else {
+				ent.clone_from_slice(short_ent);
+				ent[..11].clone_from_slice(&short_name);
+				ent[12] = (ent[12] & !(on_disk::CASE_LOWER_BASE|on_disk::CASE_LOWER_EXT)) | lcase;
+			}
+			}));
+		Ok( (dst_first, dst_first + lfn_count) )
+	}
+
+	/// Initialise the cluster of a new node (if it has one), and add its entries
+	fn init_node(&self, name: &ByteStr, cluster: u32, is_dir: bool) -> node::Result<node::InodeId> {
+		let mut ent = [0u8; 32];
+		if is_dir {
+			// `.` and `..` entries (the root is always referred to as cluster 0)
+			let parent = if self.start_cluster == self.fs.root_first_cluster { 0 } else { self.start_cluster };
+			let mut data = vec![0u8; self.fs.cluster_size];
+			new_short_ent(*b".          ", on_disk::ATTR_DIRECTORY, cluster).write(&mut data[0..]);
+			new_short_ent(*b"..         ", on_disk::ATTR_DIRECTORY, parent).write(&mut data[32..]);
+			try!(self.fs.write_cluster_data(cluster, 0, &data));
+			new_short_ent([b' '; 11], on_disk::ATTR_DIRECTORY, cluster).write(&mut ent);
+			try!(self.add_ents(name, &ent));
+			Ok( super::InodeRef::Dir(cluster).to_id() )
+		}
+		else {
+			new_short_ent([b' '; 11], on_disk::ATTR_ARCHIVE, cluster).write(&mut ent);
+			let (_, short) = try!(self.add_ents(name, &ent));
+			self.file_inode(short)
+		}
+	}
+}
+
+/// First cluster from a raw short entry
+fn raw_ent_cluster(ent: &[u8]) -> u32 {
+	(ent[26] as u32 | (ent[27] as u32) << 8) | (ent[20] as u32 | (ent[21] as u32) << 8) << 16
+}
+/// Short entry for a new node (with all timestamps set to the current time)
+fn new_short_ent(name: [u8; 11], attribs: u8, cluster: u32) -> on_disk::DirEnt {
+	let (date, time) = on_disk::timestamp_to_fat(::kernel::time::now());
+	on_disk::DirEnt {
+		name: name,
+		attribs: attribs,
+		lcase: 0,
+		creation_ds: 0,
+		creation_time: time,
+		creation_date: date,
+		accessed_date: date,
+		cluster_hi: (cluster >> 16) as u16,
+		modified_time: time,
+		modified_date: date,
+		cluster: cluster as u16,
+		size: 0,
+	}
+}
+/// Check that a name can be stored (no characters reserved by FAT, and no trailing dot or space)
+fn is_valid_name(name: &[u8]) -> bool {
+	match name.last()
+	{
+	None | Some(&b'.') | Some(&b' ') => false,
+	_ => name.iter().all(|&c| c >= 0x20 && !b"\"*/:<>?\\|".contains(&c)),
+	}
+}
+/// Split a name into base and extension (at the last dot, ignoring a leading dot)
+fn split_ext(name: &[u8]) -> (&[u8], &[u8]) {
+	match name.iter().rposition(|&c| c == b'.')
+	{
+	Some(p) if p > 0 => (&name[..p], &name[p+1..]),
+	_ => (name, &[]),
+	}
+}
+/// Convert a character into one valid in a short name (replacing invalid characters with '_')
+fn to_short_char(c: u8) -> u8 {
+	match c.to_ascii_uppercase()
+	{
+	c @ b'A' ..= b'Z' | c @ b'0' ..= b'9' => c,
+	c @ b'!' | c @ b'#' | c @ b'$' | c @ b'%' | c @ b'&' | c @ b'\'' | c @ b'(' | c @ b')'
+		| c @ b'-' | c @ b'@' | c @ b'^' | c @ b'_' | c @ b'`' | c @ b'{' | c @ b'}' | c @ b'~' => c,
+	_ => b'_',
+	}
+}
+/// If the name can be represented exactly by a short entry, return the short name and lower-case flags
+fn exact_short_name(name: &[u8]) -> Option<([u8; 11], u8)> {
+	let (base, ext) = split_ext(name);
+	if base.len() == 0 || base.len() > 8 || ext.len() > 3 || (ext.len() == 0 && name.last() == Some(&b'.')) {
+		return None;
+	}
+	// Returns Some(is_lower) if the characters are all valid and of a single case
+	fn check_part(part: &[u8], dst: &mut [u8]) -> Option<bool> {
+		let (mut has_lower, mut has_upper) = (false, false);
+		for (d, &c) in Iterator::zip(dst.iter_mut(), part.iter()) {
+			let uc = c.to_ascii_uppercase();
+			if to_short_char(uc) != uc {
+				return None;
+			}
+			has_lower |= c.is_ascii_lowercase();
+			has_upper |= c.is_ascii_uppercase();
+			*d = uc;
+		}
+		if has_lower && has_upper { None } else { Some(has_lower) }
+	}
+	let mut short_name = [b' '; 11];
+	let lower_base = check_part(base, &mut short_name[..8])?;
+	let lower_ext = check_part(ext, &mut short_name[8..])?;
+	let lcase = (if lower_base { on_disk::CASE_LOWER_BASE } else { 0 })
+		| (if lower_ext { on_disk::CASE_LOWER_EXT } else { 0 });
+	Some( (short_name, lcase) )
+}
+/// Compare names ignoring (ASCII) case, as FAT doesn't allow names that only differ by case
+fn name_eq_nocase<I: Iterator<Item=u8>>(a: I, b: &ByteStr) -> bool {
+	a.map(|c| c.to_ascii_lowercase()).eq( b.as_bytes().iter().map(|c| c.to_ascii_lowercase()) )
+}
+/// Checksum of a short name, stored in the associated LFN entries
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+	short_name.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
 impl node::Dir for DirNode {
 	fn lookup(&self, name: &ByteStr) -> node::Result<node::InodeId> {
 		// For each cluster in the directory, iterate
@@ -301,13 +807,13 @@ impl node::Dir for DirNode {
 		for c in self.clusters()
 		{
 			let cluster = try!(self.fs.load_cluster(c));
-			for ent in DirEnts::new(&cluster)
+			for (i, ent) in DirEnts::new(&cluster).enumerate()
 			{
 				match ent {
 				DirEnt::End => return Err(vfs::Error::NotFound),
 				DirEnt::Short(e) => {
 					if e.name() == name || lfn.name() == name {
-						return Ok( e.inode(self.start_cluster) );
+						return Ok( e.inode(&self.fs, c, i) );
 					}
 					lfn.clear();
 					},
@@ -327,10 +833,12 @@ impl node::Dir for DirNode {
 		
 		let mut lfn = LFN::new();
 		let mut cur_ofs = ofs;
-		for c in self.clusters().skip(cluster_idx)
+		for (ci, c) in self.clusters().skip(cluster_idx).enumerate()
 		{
 			let cluster = try!(self.fs.load_cluster(c));
-			for ent in DirEnts::new(&cluster).skip(c_ofs)
+			// - Only the first cluster starts part-way through
+			let skip = if ci == 0 { c_ofs } else { 0 };
+			for (i, ent) in DirEnts::new(&cluster).enumerate().skip(skip)
 			{
 				cur_ofs += 1;
 				match ent
@@ -340,7 +848,7 @@ impl node::Dir for DirNode {
 					return Ok(cur_ofs - 1);
 					},
 				DirEnt::Short(e) => {
-					let inode = e.inode(self.start_cluster);
+					let inode = e.inode(&self.fs, c, i);
 					let cont = if lfn.is_valid() {
 							callback(inode, &mut lfn.name().wtf8())
 						}
@@ -363,16 +871,246 @@ impl node::Dir for DirNode {
 		Ok( cur_ofs )
 	}
 	fn create(&self, name: &ByteStr, nodetype: node::NodeType) -> node::Result<node::InodeId> {
-		// File cluster for the dir's data
-		let new_cluster = self.fs.alloc_cluster( self.start_cluster )?;
-		// Add entries to the end of the dir
-		todo!("DirNode::create('{:?}', {:?}): dir_cluster={:#x}", name, nodetype, new_cluster);
+		let is_dir = match nodetype
+			{
+			node::NodeType::File => false,
+			node::NodeType::Dir => true,
+			// FAT has no symbolic links
+			node::NodeType::Symlink(_) => return Err(vfs::Error::PermissionDenied),
+			};
+		let _lh = self.fs.dir_lock.lock();
+		match self.find_ent_pos_nocase(name)
+		{
+		Ok(_) => return Err(vfs::Error::AlreadyExists),
+		Err(vfs::Error::NotFound) => {},
+		Err(e) => return Err(e),
+		}
+
+		// Directories always have a cluster (for `.` and `..`), files get their first cluster when written
+		let cluster = if is_dir { try!(self.fs.alloc_cluster(0)) } else { 0 };
+		match self.init_node(name, cluster, is_dir)
+		{
+		Ok(inode) => Ok(inode),
+		Err(e) => {
+			if cluster != 0 {
+				if let Err(e) = self.fs.free_chain(cluster) {
+					log_error!("FAT: Error freeing cluster {:#x}: {:?}", cluster, e);
+				}
+			}
+			Err(e)
+			},
+		}
 	}
-	fn link(&self, name: &ByteStr, node: &dyn node::NodeBase) -> node::Result<()> {
-		todo!("DirNode::link('{:?}', {:#x})", name, node.get_id());
+	fn link(&self, _name: &ByteStr, _node: &dyn node::NodeBase) -> node::Result<()> {
+		// FAT has no hard links
+		Err(vfs::Error::PermissionDenied)
 	}
 	fn unlink(&self, name: &ByteStr) -> node::Result<()> {
-		todo!("DirNode::unlink('{:?}')", name);
+		let _lh = self.fs.dir_lock.lock();
+		let pos = try!(self.find_ent_pos(name));
+		let cluster = raw_ent_cluster(&pos.raw);
+		if pos.raw[11] & on_disk::ATTR_DIRECTORY != 0 {
+			if ! try!(DirNode::new(self.fs.reborrow(), cluster).is_empty()) {
+				return Err(vfs::Error::DirectoryNotEmpty);
+			}
+		}
+		try!(self.edit_ents(pos.first, pos.short - pos.first + 1, |_, ent| ent[0] = 0xE5));
+		if pos.raw[11] & on_disk::ATTR_DIRECTORY != 0 {
+			if cluster != 0 {
+				try!(self.fs.free_chain(cluster));
+			}
+		}
+		else {
+			// Clusters of an open file are freed when it's closed
+			try!(self.fs.file_unlinked(pos.inode, cluster));
+		}
+		Ok( () )
+	}
+	fn rename(&self, src_name: &ByteStr, dst_dir: &dyn node::Dir, dst_name: &ByteStr) -> node::Result<()> {
+		let dst = match dst_dir.get_any().downcast_ref::<DirNode>()
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::CrossFilesystem),
+			};
+		if &*self.fs as *const FilesystemInner != &*dst.fs as *const FilesystemInner {
+			return Err(vfs::Error::CrossFilesystem);
+		}
+		let _lh = self.fs.dir_lock.lock();
+
+		let src = try!(self.find_ent_pos(src_name));
+		match dst.find_ent_pos_nocase(dst_name)
+		{
+		// - Changing only the case of a name clashes with the source entry, which is allowed
+		Ok(ref pos) if self.start_cluster == dst.start_cluster && pos.short == src.short => {},
+		Ok(_) => return Err(vfs::Error::AlreadyExists),
+		Err(vfs::Error::NotFound) => {},
+		Err(e) => return Err(e),
+		}
+
+		// Write the new entries (LFN entries, then a copy of the short entry)
+		// - The new name is written before the old one is removed, so a failure never leaves the node with no name.
+		let (new_first, new_short) = try!(dst.add_ents(dst_name, &src.raw));
+
+		// Remove the original entries (positions are unchanged, the new entries only used free slots)
+		// - On failure, release the new entries again so the node is left under its original name.
+		if let Err(e) = self.edit_ents(src.first, src.short - src.first + 1, |_, ent| ent[0] = 0xE5)
+		{
+			if let Err(e2) = dst.edit_ents(new_first, new_short - new_first + 1, |_, ent| ent[0] = 0xE5) {
+				log_error!("FAT: Rename failed ({:?}) and the new entry couldn't be removed ({:?}), node now has two names", e, e2);
+			}
+			return Err(e);
+		}
+		let new_inode = try!(dst.file_inode(new_short));
+
+		// Files are identified by their entry's location, so open files need to be told of the new location
+		if src.raw[11] & on_disk::ATTR_DIRECTORY == 0 {
+			self.fs.file_moved(src.inode, new_inode);
+		}
+
+		// A directory moved to a new parent needs its `..` entry updated
+		if src.raw[11] & on_disk::ATTR_DIRECTORY != 0 && self.start_cluster != dst.start_cluster
+		{
+			let cluster = raw_ent_cluster(&src.raw);
+			// - The root is always referred to as cluster 0
+			let parent = if dst.start_cluster == self.fs.root_first_cluster { 0 } else { dst.start_cluster };
+			let moved = DirNode::new(self.fs.reborrow(), cluster);
+			try!(moved.edit_ents(1, 1, |_, ent| {
+				ent[26] = (parent >> 0) as u8;
+				ent[27] = (parent >> 8) as u8;
+				ent[20] = (parent >> 16) as u8;
+				ent[21] = (parent >> 24) as u8;
+				}));
+		}
+		Ok( () )
 	}
 }
 
+
+#[cfg(test)]
+fn test_short_ent(name: &[u8; 11], lcase: u8, cluster: u32) -> on_disk::DirEnt {
+	on_disk::DirEnt {
+		name: *name,
+		attribs: on_disk::ATTR_ARCHIVE,
+		lcase: lcase,
+		creation_ds: 0,
+		creation_time: 0,
+		creation_date: 0,
+		accessed_date: 0,
+		cluster_hi: (cluster >> 16) as u16,
+		modified_time: 0,
+		modified_date: 0,
+		cluster: cluster as u16,
+		size: 0,
+	}
+}
+
+#[test]
+// Reference values from the algorithm in Microsoft's FAT specification
+fn fat_short_name_checksum()
+{
+	assert_eq!(short_name_checksum(b"README  TXT"), 0x73);
+	assert_eq!(short_name_checksum(b"LONGFI~1TXT"), 0xD4);
+	assert_eq!(short_name_checksum(b"FOO        "), 0x88);
+}
+#[test]
+fn fat_exact_short_name()
+{
+	assert_eq!(exact_short_name(b"README.TXT"), Some( (*b"README  TXT", 0) ));
+	assert_eq!(exact_short_name(b"readme.txt"), Some( (*b"README  TXT", on_disk::CASE_LOWER_BASE|on_disk::CASE_LOWER_EXT) ));
+	assert_eq!(exact_short_name(b"README.txt"), Some( (*b"README  TXT", on_disk::CASE_LOWER_EXT) ));
+	assert_eq!(exact_short_name(b"FOO"), Some( (*b"FOO        ", 0) ));
+	// - Mixed case, too long, multiple dots, leading dot, and characters not allowed in short names
+	assert_eq!(exact_short_name(b"ReadMe.txt"), None);
+	assert_eq!(exact_short_name(b"TOOLONGNAME.TXT"), None);
+	assert_eq!(exact_short_name(b"A.TEXT"), None);
+	assert_eq!(exact_short_name(b"A.B.C"), None);
+	assert_eq!(exact_short_name(b".PROFILE"), None);
+	assert_eq!(exact_short_name(b"A+B"), None);
+}
+#[test]
+fn fat_valid_names()
+{
+	assert!(is_valid_name(b"Long File Name.txt"));
+	assert!(is_valid_name(b".profile"));
+	assert!( !is_valid_name(b"") );
+	assert!( !is_valid_name(b"name.") );
+	assert!( !is_valid_name(b"name ") );
+	assert!( !is_valid_name(b"a?b") );
+	assert!( !is_valid_name(b"a\x01b") );
+}
+#[test]
+// Short names are decoded with the NT lower-case flags applied
+fn fat_short_ent_decode()
+{
+	let mut raw = [0u8; 2*32];
+	test_short_ent(b"README  TXT", on_disk::CASE_LOWER_BASE|on_disk::CASE_LOWER_EXT, 0x12345).write(&mut raw[0..]);
+	test_short_ent(b"NOEXT      ", 0, 3).write(&mut raw[32..]);
+	let mut it = DirEnts::new(&raw);
+	match it.next()
+	{
+	Some(DirEnt::Short(e)) => {
+		assert_eq!(e.name().as_bytes(), b"readme.txt");
+		assert_eq!(e.cluster, 0x12345);
+		},
+	_ => panic!("Expected a short entry"),
+	}
+	match it.next()
+	{
+	Some(DirEnt::Short(e)) => assert_eq!(e.name().as_bytes(), b"NOEXT"),
+	_ => panic!("Expected a short entry"),
+	}
+	assert!(it.next().is_none());
+}
+#[test]
+// Long names are stored last part first (the first entry flagged with 0x40), followed by the short entry
+fn fat_lfn_decode()
+{
+	let name: Vec<u16> = "Long File Name.txt".encode_utf16().collect();
+	let mut raw = [0u8; 3*32];
+	for (i, seq) in [2usize, 1].iter().cloned().enumerate()
+	{
+		let mut chars = [0xFFFFu16; 13];
+		let part = &name[(seq-1)*13 ..];
+		let part = &part[.. ::core::cmp::min(13, part.len())];
+		chars[..part.len()].clone_from_slice(part);
+		if part.len() < 13 {
+			chars[part.len()] = 0;
+		}
+		on_disk::DirEntLong {
+			id: seq as u8 | if i == 0 { 0x40 } else { 0 },
+			name1: [chars[0], chars[1], chars[2], chars[3], chars[4]],
+			attrib: on_disk::ATTR_LFN,
+			ty: 0,
+			checksum: short_name_checksum(b"LONGFI~1TXT"),
+			name2: [chars[5], chars[6], chars[7], chars[8], chars[9], chars[10]],
+			first_cluster: 0,
+			name3: [chars[11], chars[12]],
+			}.write(&mut raw[i*32..]);
+	}
+	test_short_ent(b"LONGFI~1TXT", 0, 5).write(&mut raw[2*32..]);
+
+	let mut lfn = LFN::new();
+	let mut short = None;
+	for ent in DirEnts::new(&raw)
+	{
+		match ent
+		{
+		DirEnt::Long(ref e) => lfn.add(e),
+		DirEnt::Short(e) => short = Some(e),
+		_ => panic!("Unexpected entry type"),
+		}
+	}
+	assert!(lfn.is_valid());
+	assert_eq!(lfn.as_slice(), &name[..]);
+	assert_eq!(short.expect("No short entry").name().as_bytes(), b"LONGFI~1.TXT");
+
+	// - A sequence that doesn't start with the flagged last part is discarded
+	let mut lfn = LFN::new();
+	for ent in DirEnts::new(&raw[32..])
+	{
+		if let DirEnt::Long(ref e) = ent {
+			lfn.add(e);
+		}
+	}
+	assert!( !lfn.is_valid() );
+}
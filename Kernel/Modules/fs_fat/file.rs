@@ -6,61 +6,245 @@ use kernel::prelude::*;
 use kernel::lib::mem::aref::ArefBorrow;
 use kernel::vfs::{self, node};
 use super::FilesystemInner;
+use kernel::lib::mem::Arc;
 
 const ERROR_SHORTCHAIN: vfs::Error = vfs::Error::Unknown("Cluster chain terminated early");
 
 pub struct FileNode
 {
 	fs: ArefBorrow<FilesystemInner>,
-	//parent_dir: u32,
+	state: Arc<FileState>,
+	metadata: node::Metadata,
+}
+
+/// State shared by all `FileNode`s for the same file
+pub struct FileState
+{
+	pub ent: ::kernel::sync::Mutex<FileEnt>,
+	/// Held locked while writing
+	pub data: ::kernel::sync::Mutex<FileData>,
+}
+/// Location of a file's directory entry
+pub struct FileEnt
+{
+	/// Current inode number (changes when the file is renamed)
+	pub inode: node::InodeId,
+	/// Number of `FileNode`s for this file
+	pub users: usize,
+	/// The directory entry has been removed, free the clusters on the last close
+	pub unlinked: bool,
+}
+pub struct FileData
+{
+	/// First cluster of the data (zero if the file has no clusters)
 	first_cluster: u32,
 	size: u32,
 }
 
+impl FileState
+{
+	pub fn new(inode: node::InodeId, first_cluster: u32, size: u32) -> FileState {
+		FileState {
+			ent: ::kernel::sync::Mutex::new(FileEnt {
+				inode: inode,
+				users: 1,
+				unlinked: false,
+				}),
+			data: ::kernel::sync::Mutex::new(FileData {
+				first_cluster: first_cluster,
+				size: size,
+				}),
+		}
+	}
+}
+
 impl FileNode
 {
-	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, _parent: u32, first_cluster: u32, size: u32) -> Box<FileNode> {	
+	pub fn new_boxed(fs: ArefBorrow<FilesystemInner>, inode: node::InodeId, first_cluster: u32, size: u32, metadata: node::Metadata) -> Box<FileNode> {	
+		let state = fs.file_opened(inode, first_cluster, size);
 		Box::new(FileNode {
 			fs: fs,
-			//parent_dir: parent,
-			first_cluster: first_cluster,
-			size: size,
+			state: state,
+			metadata: metadata,
 			})
 	}
+
+	/// Get the cluster after `cluster`, allocating a new one if the chain ends there
+	fn next_cluster(&self, cluster: u32) -> node::Result<u32> {
+		match try!(self.fs.get_next_cluster(cluster))
+		{
+		Some(v) => Ok(v),
+		None => self.fs.alloc_cluster(cluster),
+		}
+	}
+	/// Allocate the first cluster of an empty file
+	fn alloc_first_cluster(&self, data: &mut FileData) -> node::Result<u32> {
+		let cluster = try!(self.fs.alloc_cluster(0));
+		if let Err(e) = self.set_first_cluster(data, cluster) {
+			if let Err(e) = self.fs.free_chain(cluster) {
+				log_error!("FAT: Error freeing cluster {:#x}: {:?}", cluster, e);
+			}
+			return Err(e);
+		}
+		Ok(cluster)
+	}
+	/// Write `len` bytes from `src` (or zeroes if `None`) at `ofs`, extending the cluster chain as required
+	///
+	/// The size is not updated. Returns a short count if an error occurs after data has been written.
+	fn write_data(&self, data: &mut FileData, ofs: u64, len: usize, src: Option<&[u8]>) -> node::Result<usize> {
+		let zeroes = if src.is_none() { vec![0u8; self.fs.cluster_size] } else { Vec::new() };
+		let cluster_size = self.fs.cluster_size as u64;
+		let mut cluster = if data.first_cluster == 0 {
+				try!(self.alloc_first_cluster(data))
+			}
+			else {
+				data.first_cluster
+			};
+		for _ in 0 .. ofs / cluster_size {
+			cluster = try!(self.next_cluster(cluster));
+		}
+		let mut in_ofs = (ofs % cluster_size) as usize;
+		let mut done = 0;
+		loop
+		{
+			let count = ::core::cmp::min(len - done, self.fs.cluster_size - in_ofs);
+			let buf = match src
+				{
+				Some(s) => &s[done..][..count],
+				None => &zeroes[..count],
+				};
+			if let Err(e) = self.fs.write_cluster_data(cluster, in_ofs, buf) {
+				return if done > 0 { Ok(done) } else { Err(From::from(e)) };
+			}
+			done += count;
+			if done == len {
+				return Ok(done);
+			}
+			in_ofs = 0;
+			cluster = match self.next_cluster(cluster)
+				{
+				Ok(v) => v,
+				Err(e) => return if done > 0 { Ok(done) } else { Err(e) },
+				};
+		}
+	}
+	/// Update the size stored in the directory entry (and the cached size)
+	fn set_size(&self, data: &mut FileData, new_size: u32) -> node::Result<()> {
+		try!(self.fs.edit_file_ent(&self.state, |ent| {
+			ent[28..32].clone_from_slice(&[new_size as u8, (new_size >> 8) as u8, (new_size >> 16) as u8, (new_size >> 24) as u8]);
+			}));
+		data.size = new_size;
+		Ok( () )
+	}
+	/// Update the first cluster stored in the directory entry (and the cached value)
+	fn set_first_cluster(&self, data: &mut FileData, cluster: u32) -> node::Result<()> {
+		try!(self.fs.edit_file_ent(&self.state, |ent| {
+			ent[26] = (cluster >> 0) as u8;
+			ent[27] = (cluster >> 8) as u8;
+			ent[20] = (cluster >> 16) as u8;
+			ent[21] = (cluster >> 24) as u8;
+			}));
+		data.first_cluster = cluster;
+		Ok( () )
+	}
+}
+impl ::core::ops::Drop for FileNode {
+	fn drop(&mut self) {
+		if self.fs.file_closed(&self.state) {
+			let first_cluster = self.state.data.lock().first_cluster;
+			if first_cluster != 0 {
+				if let Err(e) = self.fs.free_chain(first_cluster) {
+					log_error!("FAT: Error freeing clusters of unlinked file {:#x}: {:?}", first_cluster, e);
+				}
+			}
+		}
+	}
 }
 impl node::NodeBase for FileNode {
 	fn get_id(&self) -> node::InodeId {
-		todo!("FileNode::get_id")
+		self.state.ent.lock().inode
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok(node::Metadata {
+			size: self.state.data.lock().size as u64,
+			..self.metadata.clone()
+			})
+	}
 }
 impl node::File for FileNode {
 	fn size(&self) -> u64 {
-		self.size as u64
+		self.state.data.lock().size as u64
 	}
 	fn truncate(&self, newsize: u64) -> node::Result<u64> {
-		todo!("FileNode::truncate({:#x})", newsize);
+		let mut data = self.state.data.lock();
+		// FAT sizes are 32-bit, so clamp (the caller checks the returned size)
+		let newsize = ::core::cmp::min(newsize, !0u32 as u64) as u32;
+		if newsize > data.size {
+			// Extend and zero-fill (stopping early if space runs out)
+			let old_size = data.size;
+			let added = try!(self.write_data(&mut *data, old_size as u64, (newsize - old_size) as usize, None));
+			try!(self.set_size(&mut *data, old_size + added as u32));
+		}
+		else if newsize < data.size {
+			// Update the entry first, then release the clusters past the new end
+			try!(self.set_size(&mut *data, newsize));
+			if data.first_cluster == 0 {
+				// No clusters to release
+			}
+			else if newsize == 0 {
+				let first_cluster = data.first_cluster;
+				try!(self.set_first_cluster(&mut *data, 0));
+				try!(self.fs.free_chain(first_cluster));
+			}
+			else {
+				let cluster_size = self.fs.cluster_size as u64;
+				let keep = (newsize as u64 + cluster_size - 1) / cluster_size;
+				let mut cluster = data.first_cluster;
+				for _ in 1 .. keep {
+					cluster = match try!(self.fs.get_next_cluster(cluster))
+						{
+						Some(v) => v,
+						None => return Err(ERROR_SHORTCHAIN),
+						};
+				}
+				try!(self.fs.truncate_chain(cluster));
+			}
+		}
+		Ok(data.size as u64)
 	}
 	fn clear(&self, ofs: u64, size: u64) -> node::Result<()> {
-		todo!("FileNode::clear({:#x}+{:#x}", ofs, size);
+		let mut data = self.state.data.lock();
+		if ofs > data.size as u64 {
+			return Err( vfs::Error::InvalidParameter );
+		}
+		let len = ::core::cmp::min(size, data.size as u64 - ofs) as usize;
+		if len > 0 && try!(self.write_data(&mut *data, ofs, len, None)) < len {
+			return Err( vfs::Error::OutOfSpace );
+		}
+		Ok( () )
 	}
 	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
+		let (first_cluster, size) = {
+			let data = self.state.data.lock();
+			(data.first_cluster, data.size)
+			};
 		// Sanity check and bound parameters
-		if ofs > self.size as u64 {
+		if ofs > size as u64 {
 			// out of range
 			return Err( vfs::Error::InvalidParameter );
 		}
-		if ofs == self.size as u64 {
+		if ofs == size as u64 {
 			return Ok(0);
 		}
-		let maxread = (self.size as u64 - ofs) as usize;
+		let maxread = (size as u64 - ofs) as usize;
 		let buf = if buf.len() > maxread { &mut buf[..maxread] } else { buf };
 		let read_length = buf.len();
 		
 		// Seek to correct position in the cluster chain
-		let mut clusters = super::ClusterList::chained(self.fs.reborrow(), self.first_cluster);
+		let mut clusters = super::ClusterList::chained(self.fs.reborrow(), first_cluster);
 		for _ in 0 .. (ofs/self.fs.cluster_size as u64) {
 			clusters.next();
 		}
@@ -150,7 +334,22 @@ impl node::File for FileNode {
 	}
 	/// Write data to the file, can only grow the file if ofs==size
 	fn write(&self, ofs: u64, buf: &[u8]) -> node::Result<usize> {
-		todo!("FileNode::write({:#x}, {:p})", ofs, ::kernel::lib::SlicePtr(buf));
+		let mut data = self.state.data.lock();
+		if ofs > data.size as u64 {
+			return Err( vfs::Error::InvalidParameter );
+		}
+		// FAT sizes are 32-bit
+		let max_len = (!0u32 as u64 - ofs) as usize;
+		let buf = if buf.len() > max_len { &buf[..max_len] } else { buf };
+		if buf.len() == 0 {
+			return Ok(0);
+		}
+		let count = try!(self.write_data(&mut *data, ofs, buf.len(), Some(buf)));
+		let end = ofs + count as u64;
+		if end > data.size as u64 {
+			try!(self.set_size(&mut *data, end as u32));
+		}
+		Ok(count)
 	}
 }
 
@@ -26,9 +26,10 @@ const FAT32_MIN_CLUSTERS: usize = 65525;
 /// FAT Legacy (pre 32) root cluster base. Just has to be above the max cluster num for FAT16
 const FATL_ROOT_CLUSTER: u32 = 0x00FF0000;
 
-const FAT12_EOC: u16 = 0x0FFF;
-const FAT16_EOC: u16 = 0xFFFF;
-const FAT32_EOC: u32 = 0x00FFFFFF;
+/// FSInfo sector signatures (at offsets 0, 484 and 508)
+const FSINFO_SIG_LEAD: u32 = 0x41615252;
+const FSINFO_SIG_STRUCT: u32 = 0x61417272;
+const FSINFO_SIG_TRAIL: u32 = 0xAA550000;
 
 /// on-disk structures
 mod on_disk;
@@ -44,6 +45,26 @@ enum Size
 	Fat16,
 	Fat32,
 }
+impl Size
+{
+	/// Mask of the used bits in a FAT entry (also the value written as end-of-chain)
+	fn entry_mask(&self) -> u32 {
+		match *self
+		{
+		Size::Fat12 => 0x0FFF,
+		Size::Fat16 => 0xFFFF,
+		Size::Fat32 => 0x0FFF_FFFF,	// Top 4 bits are reserved
+		}
+	}
+	/// Entries with this value or above terminate a chain
+	fn min_eoc(&self) -> u32 {
+		self.entry_mask() & !7
+	}
+	/// Marker for a bad cluster
+	fn bad_cluster(&self) -> u32 {
+		self.entry_mask() - 8
+	}
+}
 
 /// Driver strucutre
 struct Driver;
@@ -64,11 +85,19 @@ pub struct FilesystemInner
 	vh: ::block_cache::CacheHandle,
 	ty: Size,
 	
+	/// Bytes per FAT sector (a multiple of the volume's block size)
+	sector_size: usize,
 	spc: usize,
 	cluster_size: usize,
 	/// Total number of data clusters
 	cluster_count: usize,
 	first_fat_sector: usize,
+	/// Sectors per FAT
+	fat_size: usize,
+	/// Number of FAT copies
+	fat_count: usize,
+	/// Single FAT in use (FAT32 can disable mirroring, all copies are updated otherwise)
+	active_fat: Option<usize>,
 	first_data_sector: usize,
 	
 	root_first_cluster: u32,
@@ -78,15 +107,42 @@ pub struct FilesystemInner
 	// XXX: Should really use the above line for this, but BlockCache exists
 	/// A cache of metadata clusters (i.e. directories)
 	metadata_block_cache: ::blockcache::BlockCache,
+	/// Serialises modifications to directory entries
+	dir_lock: ::kernel::sync::Mutex<()>,
+	/// Cluster allocation state (also serialises FAT modifications)
+	alloc: ::kernel::sync::Mutex<AllocState>,
+	/// Files currently loaded by the VFS (keyed on inode), so their state is shared and unlinking can defer freeing clusters
+	open_files: ::kernel::sync::Mutex<::kernel::lib::VecMap<node::InodeId, Arc<file::FileState>>>,
+}
+
+/// Free cluster tracking, loaded from (and saved to) the FAT32 FSInfo sector
+struct AllocState
+{
+	/// Sector number of the FSInfo sector (FAT32 only)
+	fsinfo_sector: Option<u64>,
+	/// Number of free clusters (if known)
+	free_count: Option<u32>,
+	/// Cluster to start searching from
+	next_free: u32,
+	/// The FSInfo sector needs to be written
+	dirty: bool,
 }
 
-/// Inodes IDs destrucure into two 28-bit cluster IDs, and a 16-bit dir offset
+/// Decoded inode number
+///
+/// Directories are identified by their first cluster (which never changes, and is what `..` entries
+/// refer to). Files can have no clusters, so are identified by the location of their short entry
+/// (which changes if the file is renamed, the VFS re-keys its cached node using `get_id` after a rename).
 #[derive(Debug)]
-struct InodeRef
+enum InodeRef
 {
-	dir_first_cluster: u32,
-	dir_offset: u16,
-	first_cluster: u32,
+	Dir(u32),
+	File {
+		/// Directory cluster holding the entry
+		cluster: u32,
+		/// Entry index within the cluster
+		index: u16,
+	},
 }
 
 /// Iterable cluster list
@@ -108,24 +164,38 @@ fn init()
 impl mount::Driver for Driver
 {
 	fn detect(&self, vol: &VolumeHandle) -> vfs::Result<usize> {
+		if vol.block_size() < 512 {
+			return Ok(0);
+		}
 		let bs = {
-			let mut bs = [0u8; 512];
+			let mut bs = vec![0u8; vol.block_size()];
 			try!( vol.read_blocks(0, &mut bs) );
-			on_disk::BootSect::read(&bs)
+			on_disk::BootSect::read(&bs[..512])
 			};
 		
 		let bps = bs.common().bps;
 		let spc = bs.common().spc;
 		let media_desc = bs.common().media_descriptor;
 		
-		if bps == 0 || spc == 0 || media_desc < 0xf0 {
+		if !is_valid_sector_size(bps) || spc == 0 || media_desc < 0xf0 {
 			Ok(0)
 		}
 		else {
 			Ok(1)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle) -> vfs::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle, options: &[&str]) -> vfs::Result<Box<dyn mount::Filesystem>> {
+		for opt in options
+		{
+			if opt.starts_with("codepage=") {
+				// TODO: Support OEM codepages (short names are currently only handled as ASCII)
+				log_notice!("FAT: Codepages are not supported ('{}')", opt);
+				return Err(vfs::Error::InvalidParameter);
+			}
+			else {
+				log_notice!("FAT: Unknown option '{}'", opt);
+			}
+		}
 		let vol = ::block_cache::CacheHandle::new(vol);
 
 		// Read the bootsector
@@ -134,8 +204,12 @@ impl mount::Driver for Driver
 			on_disk::BootSect::read(&mut &blk.data()[..512])
 			};
 		let bs_c = bs.common();
-		if bs_c.bps != 512 {
-			return Err(vfs::Error::Unknown("TODO: non 512-byte sector FAT"))
+		if !is_valid_sector_size(bs_c.bps) {
+			return Err(vfs::Error::Unknown("FAT: Invalid sector size"));
+		}
+		// Sectors are accessed as a whole number of volume blocks
+		if (bs_c.bps as usize) < vol.block_size() || bs_c.bps as usize % vol.block_size() != 0 {
+			return Err(vfs::Error::Unknown("FAT: Sector size is smaller than the volume's block size"));
 		}
 		if bs_c.fat_count == 0 {
 			return Err(vfs::Error::Unknown("FAT Count is 0"));
@@ -165,7 +239,7 @@ impl mount::Driver for Driver
 		let first_data_sector = bs_c.reserved_sect_count as usize
 			+ fat_size + spare_fat_sectors
 			+ root_dir_sectors;
-		let cluster_count = (total_sectors - first_data_sector - spare_fat_sectors) / spc;
+		let cluster_count = (total_sectors - first_data_sector) / spc;
 		
 		// Determine the FAT type
 		let fat_type = if cluster_count < FAT16_MIN_CLUSTERS {
@@ -179,15 +253,32 @@ impl mount::Driver for Driver
 			};
 		log_debug!("{:?} {} sectors, Size {}", fat_type, total_sectors,
 			SizePrinter((total_sectors*bs_c.bps as usize) as u64));
+
+		// Bit 7 of the FAT32 extended flags disables mirroring, bits 0-3 then select the active FAT
+		let active_fat = match bs.info32()
+			{
+			Some(i) if is!(fat_type, Size::Fat32) && i.ext_flags & 0x80 != 0 => Some( (i.ext_flags & 0xF) as usize ),
+			_ => None,
+			};
+		let alloc = match bs.info32()
+			{
+			Some(i) if is!(fat_type, Size::Fat32) && i.fs_info != 0 && i.fs_info < bs_c.reserved_sect_count => try!(AllocState::load(&vol, i.fs_info as u64 * (bps / vol.block_size()) as u64)),
+			_ => AllocState::new(None),
+			};
+		log_debug!("Free clusters: {:?}, next free hint {:#x}", alloc.free_count, alloc.next_free);
 		
 		Ok(Box::new(Filesystem {
 			// SAFE: Saving to a Box, so won't move
 			inner: unsafe { ArefInner::new(FilesystemInner {
 				ty: fat_type,
+				sector_size: bps,
 				spc: spc,
-				cluster_size: spc * vol.block_size(),
+				cluster_size: spc * bps,
 				cluster_count: cluster_count,
 				first_fat_sector: bs_c.reserved_sect_count as usize,
+				fat_size: fat_size,
+				fat_count: bs_c.fat_count as usize,
+				active_fat: active_fat,
 				first_data_sector: first_data_sector,
 				root_first_cluster: match fat_type {
 					Size::Fat32 => bs.info32().unwrap().root_cluster,
@@ -196,6 +287,9 @@ impl mount::Driver for Driver
 				root_sector_count: root_dir_sectors as u32,
 				
 				metadata_block_cache: ::blockcache::BlockCache::new(),
+				dir_lock: ::kernel::sync::Mutex::new(()),
+				alloc: ::kernel::sync::Mutex::new(alloc),
+				open_files: ::kernel::sync::Mutex::new(::kernel::lib::VecMap::new()),
 
 				vh: vol,
 				}) },
@@ -205,6 +299,11 @@ impl mount::Driver for Driver
 
 type Cluster = Arc<[u8]>;
 
+/// FAT sectors are a power of two between 512 and 4096 bytes
+fn is_valid_sector_size(bps: u16) -> bool {
+	bps >= 512 && bps <= 4096 && bps.is_power_of_two()
+}
+
 impl FilesystemInner
 {
 	/// Load a cluster from disk
@@ -216,24 +315,47 @@ impl FilesystemInner
 		log_trace!("Filesystem::read_clusters({:#x}, {})", cluster, dst.len() / self.cluster_size);
 		assert_eq!(dst.len() % self.cluster_size, 0);
 		// For now, just read the bytes, screw caching
-		let sector = if !is!(self.ty, Size::Fat32) && cluster >= FATL_ROOT_CLUSTER {
-				// Root directory (for FAT12/16, where it was not a normal file)
-				let rc = cluster - FATL_ROOT_CLUSTER;
-				assert!( (rc as u64 * self.spc as u64) < self.root_sector_count as u64);
-				(self.first_data_sector - self.root_sector_count as usize) as u64
-				+ (rc * self.spc as u32) as u64
+		let sector = self.cluster_to_sector(cluster);
+		log_debug!("read_clusters: cluster = {:#x}, sector = 0x{:x}", cluster, sector);
+		try!(self.vh.read_blocks(self.sector_block(sector), dst));
+		//::kernel::logging::hex_dump("FAT Cluster", &buf);
+		Ok( () )
+	}
+	fn write_clusters(&self, cluster: u32, src: &[u8]) -> Result<(), storage::IoError> {
+		log_trace!("Filesystem::write_clusters({:#x}, {})", cluster, src.len() / self.cluster_size);
+		assert_eq!(src.len() % self.cluster_size, 0);
+		let sector = self.cluster_to_sector(cluster);
+		let src = if !is!(self.ty, Size::Fat32) && cluster >= FATL_ROOT_CLUSTER {
+				// Don't write past the end of the legacy root directory (it may not be a whole number of clusters)
+				let root_end = (self.first_data_sector as u64 - sector) as usize * self.sector_size;
+				&src[.. ::core::cmp::min(src.len(), root_end)]
 			}
 			else {
-				// Anything else
-				assert!(cluster >= 2);
-				assert!(cluster - 2 < self.cluster_count as u32);
-				self.first_data_sector as u64 + (cluster as u64 - 2) * self.spc as u64
+				src
 			};
-		log_debug!("read_clusters: cluster = {:#x}, sector = 0x{:x}", cluster, sector);
-		try!(self.vh.read_blocks(sector, dst));
-		//::kernel::logging::hex_dump("FAT Cluster", &buf);
+		try!(self.vh.write_blocks(self.sector_block(sector), src));
 		Ok( () )
 	}
+	/// Convert a FAT sector number into a volume block number
+	fn sector_block(&self, sector: u64) -> u64 {
+		sector * (self.sector_size / self.vh.block_size()) as u64
+	}
+	/// Get the first sector of a cluster
+	fn cluster_to_sector(&self, cluster: u32) -> u64 {
+		if !is!(self.ty, Size::Fat32) && cluster >= FATL_ROOT_CLUSTER {
+			// Root directory (for FAT12/16, where it was not a normal file)
+			let rc = cluster - FATL_ROOT_CLUSTER;
+			assert!( (rc as u64 * self.spc as u64) < self.root_sector_count as u64);
+			(self.first_data_sector - self.root_sector_count as usize) as u64
+			+ (rc * self.spc as u32) as u64
+		}
+		else {
+			// Anything else
+			assert!(cluster >= 2);
+			assert!(cluster - 2 < self.cluster_count as u32);
+			self.first_data_sector as u64 + (cluster as u64 - 2) * self.spc as u64
+		}
+	}
 
 	// TODO: Locking/Cache
 	// - Should this function lock the cluster somehow to prevent accidental overlap?
@@ -244,122 +366,415 @@ impl FilesystemInner
 			cluster,
 			|_| {
 				log_debug!("load_cluster: miss {}", cluster);
-				let mut buf: Cluster = Arc::from_iter( (0..self.spc * self.vh.block_size()).map(|_| 0) );
+				let mut buf: Cluster = Arc::from_iter( (0..self.cluster_size).map(|_| 0) );
 				try!(self.read_cluster( cluster, Arc::get_mut(&mut buf).unwrap() ));
 				Ok( buf )
 			})
 	}
+	/// Modify a metadata cluster, writing it back to disk
+	fn edit_cluster<F: FnOnce(&mut [u8])->R, R>(&self, cluster: u32, f: F) -> Result<R, storage::IoError>
+	{
+		let mut buf: Vec<u8> = Vec::from( &try!(self.load_cluster(cluster))[..] );
+		let rv = f(&mut buf);
+		let res = self.write_clusters(cluster, &buf);
+		// Force the next load to re-read (even on failure, the disk contents are unknown)
+		self.metadata_block_cache.invalidate(cluster);
+		try!(res);
+		Ok( rv )
+	}
 	
-	/// Obtain the next cluster in a chain
-	fn get_next_cluster(&self, cluster: u32) -> Result< Option<u32>, storage::IoError > {
-		use kernel::lib::byteorder::{ReadBytesExt,LittleEndian};
-		// - Determine what sector contains the requested FAT entry
-		let bs = self.vh.block_size();
-		let (fat_sector,ofs) = match self.ty
-			{
-			Size::Fat12 => {
-				let cps = bs / 3 * 2;	// 2 per 3 bytes
-				(cluster as usize / cps, (cluster as usize % cps) / 2 * 3 )
-				},
-			Size::Fat16 => {
-				let cps = bs / 2;
-				(cluster as usize / cps, (cluster as usize % cps) * 2)
-				},
-			Size::Fat32 => {
-				let cps = bs / 4;
-				(cluster as usize / cps, (cluster as usize % cps) * 2)
-				},
-			};
+	/// Write a data cluster (or part of one), keeping the cluster cache consistent
+	fn write_cluster_data(&self, cluster: u32, ofs: usize, src: &[u8]) -> Result<(), storage::IoError> {
+		assert!(ofs + src.len() <= self.cluster_size);
+		if ofs == 0 && src.len() == self.cluster_size {
+			let res = self.write_clusters(cluster, src);
+			self.metadata_block_cache.invalidate(cluster);
+			res
+		}
+		else {
+			self.edit_cluster(cluster, |data| data[ofs..][..src.len()].clone_from_slice(src))
+		}
+	}
+	/// Fill a cluster with zeroes
+	fn zero_cluster(&self, cluster: u32) -> Result<(), storage::IoError> {
+		let zeroes = vec![0u8; self.cluster_size];
+		self.write_cluster_data(cluster, 0, &zeroes)
+	}
 
-		// - Read a single sector from the FAT
-		let sector_idx = (self.first_fat_sector + fat_sector) as u64;
-		let sector_data_blk = try!(self.vh.get_block( sector_idx ));
-		let start_ofs = (sector_idx - sector_data_blk.index()) as usize * bs;
-		let sector_data = &sector_data_blk.data()[start_ofs .. ];
-		//log_debug!("Sector {} accessed via cached block at sector {}", sector_idx, sector_data_blk.index());
-		//::kernel::logging::hex_dump("FAT FAT", &sector_data[..bs]);
+	/// Byte offset of a cluster's entry within the FAT
+	fn fat_entry_ofs(&self, cluster: u32) -> usize {
+		match self.ty
+		{
+		Size::Fat12 => cluster as usize + cluster as usize / 2,	// 1.5 bytes per entry
+		Size::Fat16 => cluster as usize * 2,
+		Size::Fat32 => cluster as usize * 4,
+		}
+	}
+	/// Read bytes from a FAT (the range can span blocks, for FAT12)
+	fn read_fat_bytes(&self, fat_idx: usize, ofs: usize, dst: &mut [u8]) -> Result<(), storage::IoError> {
+		let bs = self.vh.block_size();
+		let mut done = 0;
+		while done < dst.len()
+		{
+			let pos = ofs + done;
+			let blk_idx = self.sector_block((self.first_fat_sector + fat_idx * self.fat_size) as u64) + (pos / bs) as u64;
+			let len = ::core::cmp::min(dst.len() - done, bs - pos % bs);
+			// NOTE: The returned handle can cover more than one block
+			let blk = try!(self.vh.get_block(blk_idx));
+			let start_ofs = (blk_idx - blk.index()) as usize * bs + pos % bs;
+			dst[done..][..len].clone_from_slice( &blk.data()[start_ofs..][..len] );
+			done += len;
+		}
+		Ok( () )
+	}
+	/// Write bytes to a FAT (the range can span blocks, for FAT12)
+	fn write_fat_bytes(&self, fat_idx: usize, ofs: usize, src: &[u8]) -> Result<(), storage::IoError> {
+		let bs = self.vh.block_size();
+		let mut done = 0;
+		while done < src.len()
+		{
+			let pos = ofs + done;
+			let blk_idx = self.sector_block((self.first_fat_sector + fat_idx * self.fat_size) as u64) + (pos / bs) as u64;
+			let len = ::core::cmp::min(src.len() - done, bs - pos % bs);
+			try!(self.vh.edit(blk_idx, 1, |data| data[pos % bs..][..len].clone_from_slice(&src[done..][..len])));
+			done += len;
+		}
+		Ok( () )
+	}
 
-		// - Extract the entry
+	/// Read the raw FAT entry for a cluster
+	fn get_fat_entry(&self, cluster: u32) -> Result<u32, storage::IoError> {
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		let ofs = self.fat_entry_ofs(cluster);
+		let mut buf = [0u8; 4];
 		Ok(match self.ty
 		{
 		Size::Fat12 => {
-			// FAT12 has special handling because it packs 2 entries into 24 bytes
-			let val = {
-				let v24 = (&sector_data[ofs..]).read_uint::<LittleEndian>(3).unwrap();
-				if cluster % 2 == 0 { v24 & 0xFFF } else { v24 >> 12 }
-				} as u16;
-			if val == 0 { return Err(storage::IoError::Unknown("FAT: Zero FAT entry")); }
-			if val == FAT12_EOC { None } else { Some(val as u32) }
+			// FAT12 has special handling because it packs 2 entries into 3 bytes
+			try!(self.read_fat_bytes(self.active_fat.unwrap_or(0), ofs, &mut buf[..2]));
+			let v16 = LittleEndian::read_u16(&buf) as u32;
+			if cluster % 2 == 0 { v16 & 0xFFF } else { v16 >> 4 }
 			},
 		Size::Fat16 => {
-			let val = (&sector_data[ofs..]).read_u16::<LittleEndian>().unwrap();
-			if val == 0 { return Err(storage::IoError::Unknown("FAT: Zero FAT entry")); }
-			if val == FAT16_EOC { None } else { Some(val as u32) }
+			try!(self.read_fat_bytes(self.active_fat.unwrap_or(0), ofs, &mut buf[..2]));
+			LittleEndian::read_u16(&buf) as u32
 			},
 		Size::Fat32 => {
-			let val = (&sector_data[ofs..]).read_u32::<LittleEndian>().unwrap();
-			if val == 0 { return Err(storage::IoError::Unknown("FAT: Zero FAT entry")); }
-			if val == FAT32_EOC { None } else { Some(val as u32) }
+			try!(self.read_fat_bytes(self.active_fat.unwrap_or(0), ofs, &mut buf[..4]));
+			LittleEndian::read_u32(&buf) & 0x0FFF_FFFF
 			},
 		})
 	}
+	/// Set the FAT entry for a cluster (on every FAT in use). Must be called with the `alloc` lock held
+	fn set_fat_entry(&self, _lh: &mut AllocState, cluster: u32, value: u32) -> Result<(), storage::IoError> {
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		assert!(value <= self.ty.entry_mask());
+		let ofs = self.fat_entry_ofs(cluster);
+		let fats = match self.active_fat
+			{
+			Some(i) => i .. i+1,
+			None => 0 .. self.fat_count,
+			};
+		for fat_idx in fats
+		{
+			let mut buf = [0u8; 4];
+			let len = match self.ty
+				{
+				Size::Fat12 => {
+					// Merge with the neighbouring entry's nibble
+					try!(self.read_fat_bytes(fat_idx, ofs, &mut buf[..2]));
+					let v16 = LittleEndian::read_u16(&buf);
+					let v16 = if cluster % 2 == 0 {
+							(v16 & 0xF000) | value as u16
+						}
+						else {
+							(v16 & 0x000F) | (value as u16) << 4
+						};
+					LittleEndian::write_u16(&mut buf, v16);
+					2
+					},
+				Size::Fat16 => {
+					LittleEndian::write_u16(&mut buf, value as u16);
+					2
+					},
+				Size::Fat32 => {
+					// Preserve the reserved top bits
+					try!(self.read_fat_bytes(fat_idx, ofs, &mut buf[..4]));
+					let v32 = (LittleEndian::read_u32(&buf) & 0xF000_0000) | value;
+					LittleEndian::write_u32(&mut buf, v32);
+					4
+					},
+				};
+			try!(self.write_fat_bytes(fat_idx, ofs, &buf[..len]));
+		}
+		Ok( () )
+	}
 
-	fn alloc_cluster(&self, prev_cluster: u32) -> Result< u32, storage::IoError > {
-		todo!("alloc_cluster(prev={:#x})", prev_cluster)
+	/// Obtain the next cluster in a chain
+	fn get_next_cluster(&self, cluster: u32) -> Result< Option<u32>, storage::IoError > {
+		let val = try!(self.get_fat_entry(cluster));
+		if val == 0 {
+			Err(storage::IoError::Unknown("FAT: Zero FAT entry"))
+		}
+		else if val >= self.ty.min_eoc() {
+			Ok(None)
+		}
+		else if val == self.ty.bad_cluster() || val < 2 || val - 2 >= self.cluster_count as u32 {
+			Err(storage::IoError::Unknown("FAT: Invalid FAT entry"))
+		}
+		else {
+			Ok(Some(val))
+		}
+	}
+
+	/// Allocate a cluster, and append it to the chain ending at `prev_cluster` (if non-zero)
+	fn alloc_cluster(&self, prev_cluster: u32) -> vfs::Result<u32> {
+		let mut lh = self.alloc.lock();
+		// NOTE: FSInfo's free count is only a hint (and can be stale), so a count of zero still scans the FAT
+		// Start searching after the previous cluster (to keep files contiguous), or from the hint
+		let end = self.cluster_count as u32 + 2;
+		let start = if prev_cluster >= 2 && prev_cluster + 1 < end {
+				prev_cluster + 1
+			}
+			else if lh.next_free >= 2 && lh.next_free < end {
+				lh.next_free
+			}
+			else {
+				2
+			};
+		let mut cluster = start;
+		while try!(self.get_fat_entry(cluster)) != 0
+		{
+			cluster += 1;
+			if cluster == end {
+				cluster = 2;
+			}
+			if cluster == start {
+				lh.free_count = Some(0);
+				lh.dirty = true;
+				return Err(vfs::Error::OutOfSpace);
+			}
+		}
+		log_trace!("alloc_cluster(prev={:#x}) = {:#x}", prev_cluster, cluster);
+
+		// Mark as end-of-chain before linking, so the chain is never left pointing at a free cluster
+		try!(self.set_fat_entry(&mut lh, cluster, self.ty.entry_mask()));
+		if prev_cluster != 0 {
+			try!(self.set_fat_entry(&mut lh, prev_cluster, cluster));
+		}
+		lh.next_free = cluster + 1;
+		// - A stale count of zero becomes unknown
+		lh.free_count = lh.free_count.and_then(|v| v.checked_sub(1));
+		lh.dirty = true;
+		Ok(cluster)
+	}
+	/// Append the (allocated) cluster `next` to the chain ending at `prev`
+	fn link_cluster(&self, prev: u32, next: u32) -> Result<(), storage::IoError> {
+		let mut lh = self.alloc.lock();
+		self.set_fat_entry(&mut lh, prev, next)
+	}
+	/// Mark `cluster` as the end of its chain, and free all following clusters
+	fn truncate_chain(&self, cluster: u32) -> vfs::Result<()> {
+		let next = try!(self.get_next_cluster(cluster));
+		{
+			let mut lh = self.alloc.lock();
+			try!(self.set_fat_entry(&mut lh, cluster, self.ty.entry_mask()));
+		}
+		match next
+		{
+		Some(c) => self.free_chain(c),
+		None => Ok( () ),
+		}
+	}
+	/// Free every cluster in the chain starting at `first_cluster`
+	fn free_chain(&self, first_cluster: u32) -> vfs::Result<()> {
+		let mut lh = self.alloc.lock();
+		let mut cluster = first_cluster;
+		loop
+		{
+			let next = try!(self.get_next_cluster(cluster));
+			try!(self.set_fat_entry(&mut lh, cluster, 0));
+			lh.free_count = lh.free_count.map(|v| v + 1);
+			lh.dirty = true;
+			match next
+			{
+			Some(c) => cluster = c,
+			None => break,
+			}
+		}
+		Ok( () )
+	}
+
+	/// Register a file loaded by the VFS, returning the state shared with other loads of the same file
+	fn file_opened(&self, inode: node::InodeId, first_cluster: u32, size: u32) -> Arc<file::FileState> {
+		use kernel::lib::vec_map::Entry;
+		match self.open_files.lock().entry(inode)
+		{
+		Entry::Occupied(e) => {
+			let state = e.into_mut();
+			state.ent.lock().users += 1;
+			state.clone()
+			},
+		Entry::Vacant(e) => e.insert(Arc::new(file::FileState::new(inode, first_cluster, size))).clone(),
+		}
+	}
+	/// Release a file loaded by the VFS, returns `true` if this was the last user of an unlinked file (and its clusters should be freed)
+	fn file_closed(&self, state: &file::FileState) -> bool {
+		let mut lh = self.open_files.lock();
+		let mut ent = state.ent.lock();
+		ent.users -= 1;
+		if ent.users > 0 {
+			return false;
+		}
+		lh.remove(&ent.inode);
+		ent.unlinked
+	}
+	/// Update the location of an open file after its entry was moved. Must be called with the directory lock held
+	fn file_moved(&self, old_inode: node::InodeId, new_inode: node::InodeId) {
+		let mut lh = self.open_files.lock();
+		if let Some(state) = lh.remove(&old_inode) {
+			state.ent.lock().inode = new_inode;
+			lh.insert(new_inode, state);
+		}
+	}
+	/// Free the clusters of an unlinked file (deferred until the last close if the file is open)
+	///
+	/// Must be called with the directory lock held
+	fn file_unlinked(&self, inode: node::InodeId, first_cluster: u32) -> vfs::Result<()> {
+		if let Some(state) = self.open_files.lock().get(&inode) {
+			// NOTE: The file stays registered until closed, so the entry isn't reused (which would reuse the inode number)
+			state.ent.lock().unlinked = true;
+			return Ok( () );
+		}
+		if first_cluster != 0 {
+			self.free_chain(first_cluster)
+		}
+		else {
+			Ok( () )
+		}
+	}
+	/// Check if a directory entry is in use by an open file (including one that has been unlinked)
+	fn is_ent_open(&self, inode: node::InodeId) -> bool {
+		self.open_files.lock().get(&inode).is_some()
+	}
+	/// Modify the (raw) short entry for an open file (does nothing if the file has been unlinked)
+	fn edit_file_ent<F: FnOnce(&mut [u8])>(&self, state: &file::FileState, f: F) -> vfs::Result<()> {
+		let _lh = self.dir_lock.lock();
+		let inode = {
+			let ent = state.ent.lock();
+			if ent.unlinked {
+				return Ok( () );
+			}
+			ent.inode
+			};
+		match InodeRef::from(inode)
+		{
+		InodeRef::File { cluster, index } => {
+			try!(self.edit_cluster(cluster, |data| f(&mut data[index as usize * 32..][..32])));
+			Ok( () )
+			},
+		InodeRef::Dir(_) => Err(vfs::Error::InconsistentFilesystem),
+		}
+	}
+}
+
+impl AllocState
+{
+	fn new(fsinfo_sector: Option<u64>) -> AllocState {
+		AllocState {
+			fsinfo_sector: fsinfo_sector,
+			free_count: None,
+			next_free: 2,
+			dirty: false,
+		}
+	}
+	/// Load the free cluster count and hint from the FSInfo sector (ignoring an invalid sector)
+	fn load(vh: &::block_cache::CacheHandle, sector: u64) -> vfs::Result<AllocState> {
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		let blk = try!(vh.get_block(sector));
+		let data = &blk.data()[(sector - blk.index()) as usize * vh.block_size() ..][..512];
+		if LittleEndian::read_u32(&data[0..]) != FSINFO_SIG_LEAD || LittleEndian::read_u32(&data[484..]) != FSINFO_SIG_STRUCT || LittleEndian::read_u32(&data[508..]) != FSINFO_SIG_TRAIL {
+			log_notice!("FSInfo sector {} has bad signatures, ignoring", sector);
+			return Ok(AllocState::new(None));
+		}
+		let mut rv = AllocState::new(Some(sector));
+		// !0 indicates an unknown value
+		match LittleEndian::read_u32(&data[488..])
+		{
+		0xFFFF_FFFF => {},
+		v => rv.free_count = Some(v),
+		}
+		match LittleEndian::read_u32(&data[492..])
+		{
+		0xFFFF_FFFF => {},
+		v => rv.next_free = v,
+		}
+		Ok(rv)
+	}
+	/// Write the free cluster count and hint back to the FSInfo sector
+	fn save(&mut self, vh: &::block_cache::CacheHandle) -> vfs::Result<()> {
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		if let Some(sector) = self.fsinfo_sector {
+			let free_count = self.free_count.unwrap_or(0xFFFF_FFFF);
+			let next_free = self.next_free;
+			try!(vh.edit(sector, 1, |data| {
+				LittleEndian::write_u32(&mut data[488..], free_count);
+				LittleEndian::write_u32(&mut data[492..], next_free);
+				}));
+		}
+		self.dirty = false;
+		Ok( () )
 	}
 }
 
 impl mount::Filesystem for Filesystem
 {
+	fn flush(&self) -> vfs::Result<()> {
+		let mut lh = self.alloc.lock();
+		if lh.dirty {
+			try!(lh.save(&self.vh));
+		}
+		Ok( () )
+	}
 	fn root_inode(&self) -> node::InodeId {
-		(InodeRef {
-			first_cluster: self.root_first_cluster,
-			dir_first_cluster: 0,
-			dir_offset: 0,
-			}).to_id()
+		InodeRef::Dir(self.root_first_cluster).to_id()
 	}
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
-		let r = InodeRef::from(id);
-		if r.first_cluster == self.root_first_cluster {
-			Some(node::Node::Dir(dir::DirNode::new_boxed(self.inner.borrow(), r.first_cluster)))
-		}
-		else {
-			// Reading from the directory starting at r.dir_first_cluster
-			// locate the file with cluster equal to r.first_cluster.
-			// And use that to create the node
-			let dn = dir::DirNode::new(self.inner.borrow(), r.dir_first_cluster);
-			dn.find_node(r.first_cluster)
+		match InodeRef::from(id)
+		{
+		InodeRef::Dir(cluster) =>
+			if cluster == self.root_first_cluster {
+				Some(node::Node::Dir(dir::DirNode::new_boxed(self.inner.borrow(), cluster)))
+			}
+			else {
+				dir::DirNode::load_dir(self.inner.borrow(), cluster)
+			},
+		InodeRef::File { cluster, index } => dir::DirNode::load_file(self.inner.borrow(), cluster, index as usize),
 		}
 	}
 }
 
 impl InodeRef
 {
-	fn new(c: u32, dir_c: u32) -> InodeRef {
-		assert!(c     <= 0x00FF_FFFF);
-		assert!(dir_c <= 0x00FF_FFFF);
-		InodeRef {
-			first_cluster: c,
-			dir_first_cluster: dir_c,
-			dir_offset: 0,
-		}
-	}
 	fn to_id(&self) -> node::InodeId {
-		assert!(self.first_cluster <= 0x00FF_FFFF);
-		assert!(self.dir_first_cluster <= 0x00FF_FFFF);
-		//assert!(v.dir_offset <= 0xFFFF);
-		(self.first_cluster as u64)
-		| (self.dir_first_cluster as u64) << 24
-		| (self.dir_offset as u64) << 48
+		match *self
+		{
+		InodeRef::Dir(cluster) => cluster as u64,
+		InodeRef::File { cluster, index } => 1 << 63 | (cluster as u64) << 16 | index as u64,
+		}
 	}
 }
 
 impl From<node::InodeId> for InodeRef {
 	fn from(v: node::InodeId) -> InodeRef {
-		InodeRef {
-			first_cluster: (v & 0x00FF_FFFF) as u32,
-			dir_first_cluster: ((v >> 24) & 0x00FF_FFFF) as u32,
-			dir_offset: (v >> 48) as u16,
+		if v >> 63 == 0 {
+			InodeRef::Dir(v as u32)
+		}
+		else {
+			InodeRef::File {
+				cluster: (v >> 16) as u32,
+				index: v as u16,
+			}
 		}
 	}
 }
@@ -12,7 +12,6 @@ pub const ATTR_SYSTEM   : u8 = 0x04;	// System File
 pub const ATTR_VOLUMEID : u8 = 0x08;	// Volume ID (Deprecated)
 pub const ATTR_DIRECTORY: u8 = 0x10;	// Directory
 pub const ATTR_LFN: u8 = ATTR_READONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUMEID;
-#[allow(dead_code)]
 pub const ATTR_ARCHIVE  : u8 = 0x20;	// Flag set by user
 
 pub const CASE_LOWER_BASE: u8 = 0x08;	// Linux (maybe NT) flag
@@ -226,6 +225,23 @@ impl DirEnt {
 			size: read_u32(src),
 		}
 	}
+	/// Serialise into a 32-byte directory entry
+	pub fn write(&self, dst: &mut [u8]) {
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		assert!(dst.len() >= 32);
+		dst[..11].clone_from_slice(&self.name);
+		dst[11] = self.attribs;
+		dst[12] = self.lcase;
+		dst[13] = self.creation_ds;
+		LittleEndian::write_u16(&mut dst[14..], self.creation_time);
+		LittleEndian::write_u16(&mut dst[16..], self.creation_date);
+		LittleEndian::write_u16(&mut dst[18..], self.accessed_date);
+		LittleEndian::write_u16(&mut dst[20..], self.cluster_hi);
+		LittleEndian::write_u16(&mut dst[22..], self.modified_time);
+		LittleEndian::write_u16(&mut dst[24..], self.modified_date);
+		LittleEndian::write_u16(&mut dst[26..], self.cluster);
+		LittleEndian::write_u32(&mut dst[28..], self.size);
+	}
 }
 #[derive(Debug)]
 pub struct DirEntLong
@@ -252,5 +268,97 @@ impl DirEntLong {
 			name3: read_arr16(src),
 		}
 	}
+	/// Serialise into a 32-byte directory entry
+	pub fn write(&self, dst: &mut [u8]) {
+		use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+		assert!(dst.len() >= 32);
+		dst[0] = self.id;
+		for (i,&c) in self.name1.iter().enumerate() {
+			LittleEndian::write_u16(&mut dst[1 + i*2..], c);
+		}
+		dst[11] = self.attrib;
+		dst[12] = self.ty;
+		dst[13] = self.checksum;
+		for (i,&c) in self.name2.iter().enumerate() {
+			LittleEndian::write_u16(&mut dst[14 + i*2..], c);
+		}
+		LittleEndian::write_u16(&mut dst[26..], self.first_cluster);
+		for (i,&c) in self.name3.iter().enumerate() {
+			LittleEndian::write_u16(&mut dst[28 + i*2..], c);
+		}
+	}
+}
+
+
+/// Convert a FAT date and time (local time, treated as UTC) into a timestamp
+///
+/// A zero date (unset) gives a zero timestamp.
+pub fn timestamp_from_fat(date: u16, time: u16) -> ::kernel::time::Timestamp {
+	if date == 0 {
+		return 0;
+	}
+	let year = 1980 + (date >> 9) as i32;
+	let month = ((date >> 5) & 0xF) as u8;
+	let day = (date & 0x1F) as u8;
+	let hour = (time >> 11) as u8;
+	let minute = ((time >> 5) & 0x3F) as u8;
+	let second = ((time & 0x1F) * 2) as u8;
+	::kernel::time::timestamp_from_date(year, month, day, hour, minute, second)
+}
+/// Convert a timestamp into a FAT (date, time) pair, clamped to the representable years (1980-2107)
+pub fn timestamp_to_fat(ts: ::kernel::time::Timestamp) -> (u16, u16) {
+	let (year, month, day, hour, minute, second) = ::kernel::time::date_from_timestamp(ts);
+	if year < 1980 {
+		// 1980-01-01 00:00:00
+		return ( (1 << 5) | 1, 0 );
+	}
+	if year > 1980 + 127 {
+		// 2107-12-31 23:59:58
+		return ( (127 << 9) | (12 << 5) | 31, (23 << 11) | (59 << 5) | 29 );
+	}
+	let date = ((year - 1980) as u16) << 9 | (month as u16) << 5 | day as u16;
+	let time = (hour as u16) << 11 | (minute as u16) << 5 | (second as u16 / 2);
+	(date, time)
 }
 
+#[test]
+fn fat_timestamps()
+{
+	// 2021-03-14 15:09:26
+	assert_eq!(timestamp_from_fat(0x526E, 0x792D), 1615734566);
+	assert_eq!(timestamp_from_fat(0, 0x792D), 0);
+	assert_eq!(timestamp_to_fat(1615734566), (0x526E, 0x792D));
+	// - Two second resolution (rounds down)
+	assert_eq!(timestamp_to_fat(1615734567), (0x526E, 0x792D));
+	// - Clamped to the representable range (1980-01-01 to 2107-12-31 23:59:58)
+	assert_eq!(timestamp_to_fat(0), (0x0021, 0x0000));
+	assert_eq!(timestamp_to_fat(315532800), (0x0021, 0x0000));
+	assert_eq!(timestamp_to_fat(4354819198 + 86400), (0xFF9F, 0xBF7D));
+	assert_eq!(timestamp_from_fat(0xFF9F, 0xBF7D), 4354819198);
+}
+#[test]
+fn fat_lfn_entry_roundtrip()
+{
+	let ent = DirEntLong {
+		id: 0x41,
+		name1: [0x61, 0x62, 0x63, 0x64, 0x65],
+		attrib: ATTR_LFN,
+		ty: 0,
+		checksum: 0xD4,
+		name2: [0x66, 0x67, 0x68, 0x69, 0x6A, 0x6B],
+		first_cluster: 0,
+		name3: [0x0000, 0xFFFF],
+		};
+	let mut raw = [0u8; 32];
+	ent.write(&mut raw);
+	assert_eq!(&raw[..4], &[0x41, 0x61,0x00, 0x62]);
+	assert_eq!(raw[11], ATTR_LFN);
+	assert_eq!(raw[13], 0xD4);
+	assert_eq!(&raw[28..], &[0x00,0x00, 0xFF,0xFF]);
+	let ent2 = DirEntLong::read(&mut &raw[..]);
+	assert_eq!(ent2.id, ent.id);
+	assert_eq!(ent2.checksum, ent.checksum);
+	assert_eq!(ent2.name1, ent.name1);
+	assert_eq!(ent2.name2, ent.name2);
+	assert_eq!(ent2.name3, ent.name3);
+}
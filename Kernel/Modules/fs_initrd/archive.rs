@@ -0,0 +1,453 @@
+// "Tifflin" Kernel - Initial Ramdisk Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_initrd/archive.rs
+//! Archive (tar and newc cpio) parsing
+use kernel::prelude::*;
+use kernel::vfs;
+use kernel::lib::byte_str::ByteStr;
+use kernel::metadevs::storage::VolumeHandle;
+use super::read_bytes;
+
+/// Maximum length of a path or link target (from extension headers or cpio)
+const MAX_NAME_LEN: u64 = 4096;
+/// Maximum size of a pax extended header
+const MAX_PAX_LEN: u64 = 64 * 1024;
+
+const TAR_BLOCK: u64 = 512;
+const CPIO_HDR_LEN: usize = 110;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+#[derive(Copy,Clone,Debug)]
+pub enum Format
+{
+	/// POSIX ustar (and GNU/v7) tar
+	Tar,
+	/// SVR4 "newc" cpio (with or without checksums)
+	Cpio,
+}
+
+pub enum Kind
+{
+	File,
+	Dir,
+	Symlink(Vec<u8>),
+	/// Hard link to an earlier entry (tar)
+	HardLink(Vec<u8>),
+}
+
+/// A single archive member
+pub struct Entry
+{
+	pub path: Vec<u8>,
+	pub kind: Kind,
+	/// UNIX mode (only the permission bits are used)
+	pub mode: u32,
+	pub uid: u32,
+	pub gid: u32,
+	pub mtime: i64,
+	/// Offset of the member's data in the archive
+	pub data_ofs: u64,
+	pub size: u64,
+	/// Identifies other names for the same file (cpio inode number, for files with more than one link)
+	pub link_key: Option<u32>,
+}
+
+/// Check the first 512 bytes of a volume for an archive header
+pub fn detect(hdr: &[u8]) -> Option<Format> {
+	if &hdr[..6] == b"070701" || &hdr[..6] == b"070702" {
+		Some(Format::Cpio)
+	}
+	else if hdr[0] != 0 && tar_checksum_valid(hdr) {
+		Some(Format::Tar)
+	}
+	else {
+		None
+	}
+}
+
+/// Enumerate all entries in the archive
+pub fn parse(vh: &VolumeHandle, format: Format, cb: &mut dyn FnMut(Entry)) -> vfs::Result<()> {
+	let vol_size = vh.block_count() * vh.block_size() as u64;
+	match format
+	{
+	Format::Tar => parse_tar(vh, vol_size, cb),
+	Format::Cpio => parse_cpio(vh, vol_size, cb),
+	}
+}
+
+fn tar_checksum_valid(hdr: &[u8]) -> bool {
+	let expected = match parse_octal(&hdr[148..156])
+		{
+		Some(v) => v,
+		None => return false,
+		};
+	// The checksum is calculated with the checksum field set to spaces
+	let sum = hdr[..512].iter().enumerate()
+		.map(|(i,&b)| if 148 <= i && i < 156 { b' ' as u64 } else { b as u64 })
+		.sum::<u64>();
+	sum == expected
+}
+
+/// Parse a NUL/space terminated octal field
+fn parse_octal(field: &[u8]) -> Option<u64> {
+	let mut rv = 0u64;
+	let mut seen = false;
+	for &b in field
+	{
+		match b
+		{
+		b'0' ..= b'7' => {
+			rv = rv.checked_mul(8)? + (b - b'0') as u64;
+			seen = true;
+			},
+		b' ' if !seen => {},
+		b' ' | b'\0' => break,
+		_ => return None,
+		}
+	}
+	if seen { Some(rv) } else { None }
+}
+/// Parse a numeric tar field (octal, or GNU base-256 if the high bit of the first byte is set)
+fn parse_tar_number(field: &[u8]) -> Option<u64> {
+	if field[0] & 0x80 != 0 {
+		let mut rv = (field[0] & 0x3F) as u64;
+		for &b in &field[1..]
+		{
+			rv = rv.checked_mul(256)? + b as u64;
+		}
+		Some(rv)
+	}
+	else {
+		parse_octal(field)
+	}
+}
+
+/// Get a NUL-terminated string from a fixed-length field
+fn c_field(field: &[u8]) -> &[u8] {
+	match field.iter().position(|&b| b == 0)
+	{
+	Some(l) => &field[..l],
+	None => field,
+	}
+}
+
+/// Read a variable-length member (long name, link target) from the archive
+fn read_string(vh: &VolumeHandle, ofs: u64, len: u64) -> vfs::Result<Vec<u8>> {
+	if len > MAX_NAME_LEN {
+		log_notice!("initrd: Name at {:#x} is too long ({} bytes)", ofs, len);
+		return Err(vfs::Error::InconsistentFilesystem);
+	}
+	let mut rv = vec![0u8; len as usize];
+	try!(read_bytes(vh, ofs, &mut rv));
+	let l = c_field(&rv).len();
+	rv.truncate(l);
+	Ok(rv)
+}
+
+fn parse_tar(vh: &VolumeHandle, vol_size: u64, cb: &mut dyn FnMut(Entry)) -> vfs::Result<()> {
+	let mut hdr = [0u8; TAR_BLOCK as usize];
+	// Values from GNU long name headers and pax extended headers, applied to the next entry
+	let mut long_name = None;
+	let mut long_link = None;
+	let mut pax_size = None;
+	let mut ofs = 0;
+	while ofs + TAR_BLOCK <= vol_size
+	{
+		try!(read_bytes(vh, ofs, &mut hdr));
+		// End of archive is marked by zeroed blocks
+		if hdr.iter().all(|&b| b == 0) {
+			break;
+		}
+		if !tar_checksum_valid(&hdr) {
+			log_warning!("initrd: Bad tar header checksum at {:#x}", ofs);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let data_ofs = ofs + TAR_BLOCK;
+		let size = match pax_size.take()
+			{
+			Some(v) => v,
+			None => match parse_tar_number(&hdr[124..136])
+				{
+				Some(v) => v,
+				None => {
+					log_warning!("initrd: Bad size in tar header at {:#x}", ofs);
+					return Err(vfs::Error::InconsistentFilesystem);
+					},
+				},
+			};
+		if size > vol_size - data_ofs {
+			log_warning!("initrd: Entry at {:#x} extends past the end of the volume ({} bytes)", ofs, size);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let next = match size.checked_add(TAR_BLOCK - 1).and_then(|v| data_ofs.checked_add(v / TAR_BLOCK * TAR_BLOCK))
+			{
+			Some(v) => v,
+			None => return Err(vfs::Error::InconsistentFilesystem),
+			};
+
+		match hdr[156]
+		{
+		// GNU long name/link
+		b'L' => long_name = Some( try!(read_string(vh, data_ofs, size)) ),
+		b'K' => long_link = Some( try!(read_string(vh, data_ofs, size)) ),
+		// pax extended header (for the next entry)
+		b'x' => {
+			if size > MAX_PAX_LEN {
+				log_notice!("initrd: pax header at {:#x} too large ({} bytes)", ofs, size);
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			let mut data = vec![0u8; size as usize];
+			try!(read_bytes(vh, data_ofs, &mut data));
+			for (key, value) in PaxRecords(&data)
+			{
+				match key
+				{
+				b"path" => long_name = Some(Vec::from(value)),
+				b"linkpath" => long_link = Some(Vec::from(value)),
+				b"size" => pax_size = ::core::str::from_utf8(value).ok().and_then(|v| v.parse().ok()),
+				_ => {},
+				}
+			}
+			},
+		// pax global header
+		b'g' => {},
+		ty => {
+			let path = match long_name.take()
+				{
+				Some(v) => v,
+				None => {
+					let name = c_field(&hdr[0..100]);
+					let prefix = c_field(&hdr[345..500]);
+					// Only ustar has the prefix field (GNU uses that space for other data)
+					if &hdr[257..263] == b"ustar\0" && prefix.len() > 0 {
+						let mut v = Vec::from(prefix);
+						v.push(b'/');
+						v.extend_from_slice(name);
+						v
+					}
+					else {
+						Vec::from(name)
+					}
+					},
+				};
+			let link = match long_link.take()
+				{
+				Some(v) => v,
+				None => Vec::from(c_field(&hdr[157..257])),
+				};
+			let kind = match ty
+				{
+				b'0' | b'\0' | b'7' => Some(Kind::File),
+				b'1' => Some(Kind::HardLink(link)),
+				b'2' => Some(Kind::Symlink(link)),
+				b'5' => Some(Kind::Dir),
+				_ => {
+					log_notice!("initrd: Ignoring '{:?}' with unsupported tar type {:?}", ByteStr::new(&path), ty as char);
+					None
+					},
+				};
+			if let Some(kind) = kind {
+				cb(Entry {
+					path: path,
+					kind: kind,
+					mode: parse_octal(&hdr[100..108]).unwrap_or(0o644) as u32,
+					uid: parse_tar_number(&hdr[108..116]).unwrap_or(0) as u32,
+					gid: parse_tar_number(&hdr[116..124]).unwrap_or(0) as u32,
+					mtime: parse_tar_number(&hdr[136..148]).unwrap_or(0) as i64,
+					data_ofs: data_ofs,
+					// Only regular files have data, hard links may have a size recorded
+					size: if ty == b'1' || ty == b'2' || ty == b'5' { 0 } else { size },
+					link_key: None,
+					});
+			}
+			},
+		}
+		ofs = next;
+	}
+	Ok( () )
+}
+
+/// Iterator over `<length> <key>=<value>\n` pax records
+struct PaxRecords<'a>(&'a [u8]);
+impl<'a> Iterator for PaxRecords<'a>
+{
+	type Item = (&'a [u8], &'a [u8]);
+	fn next(&mut self) -> Option<Self::Item> {
+		let space = self.0.iter().position(|&b| b == b' ')?;
+		let len: usize = ::core::str::from_utf8(&self.0[..space]).ok()?.parse().ok()?;
+		if len <= space + 1 || len > self.0.len() || self.0[len-1] != b'\n' {
+			return None;
+		}
+		let rec = &self.0[space+1 .. len-1];
+		self.0 = &self.0[len..];
+		let eq = rec.iter().position(|&b| b == b'=')?;
+		Some( (&rec[..eq], &rec[eq+1..]) )
+	}
+}
+
+/// Parse an 8-character hexadecimal cpio field
+fn parse_hex(field: &[u8]) -> Option<u32> {
+	u32::from_str_radix(::core::str::from_utf8(field).ok()?, 16).ok()
+}
+
+fn parse_cpio(vh: &VolumeHandle, vol_size: u64, cb: &mut dyn FnMut(Entry)) -> vfs::Result<()> {
+	let align4 = |v: u64| (v + 3) & !3;
+	let mut hdr = [0u8; CPIO_HDR_LEN];
+	let mut ofs = 0;
+	loop
+	{
+		if ofs + CPIO_HDR_LEN as u64 > vol_size {
+			log_warning!("initrd: cpio archive is missing the trailer");
+			break;
+		}
+		try!(read_bytes(vh, ofs, &mut hdr));
+		if &hdr[..6] != b"070701" && &hdr[..6] != b"070702" {
+			log_warning!("initrd: Bad cpio header magic at {:#x}", ofs);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let mut fields = [0u32; 13];
+		for (i, f) in fields.iter_mut().enumerate()
+		{
+			*f = match parse_hex(&hdr[6 + i*8..][..8])
+				{
+				Some(v) => v,
+				None => {
+					log_warning!("initrd: Bad cpio header field {} at {:#x}", i, ofs);
+					return Err(vfs::Error::InconsistentFilesystem);
+					},
+				};
+		}
+		let [ino, mode, uid, gid, nlink, mtime, size, _devmajor, _devminor, _rdevmajor, _rdevminor, namesize, _check] = fields;
+
+		let path = try!(read_string(vh, ofs + CPIO_HDR_LEN as u64, namesize as u64));
+		let data_ofs = align4(ofs + CPIO_HDR_LEN as u64 + namesize as u64);
+		let size = size as u64;
+		ofs = align4(data_ofs + size);
+
+		if &path[..] == b"TRAILER!!!" {
+			break;
+		}
+		let kind = match mode & S_IFMT
+			{
+			S_IFREG => Kind::File,
+			S_IFDIR => Kind::Dir,
+			S_IFLNK => Kind::Symlink( try!(read_string(vh, data_ofs, size)) ),
+			v => {
+				log_notice!("initrd: Ignoring '{:?}' with unsupported cpio type {:#o}", ByteStr::new(&path), v);
+				continue ;
+				},
+			};
+		let is_file = match kind { Kind::File => true, _ => false };
+		cb(Entry {
+			path: path,
+			kind: kind,
+			mode: mode,
+			uid: uid,
+			gid: gid,
+			mtime: mtime as i64,
+			data_ofs: data_ofs,
+			size: if is_file { size } else { 0 },
+			// Hard-linked files share an inode number, with the data only stored on the last one
+			link_key: if is_file && nlink > 1 { Some(ino) } else { None },
+			});
+	}
+	Ok( () )
+}
+
+// Reference headers are from Python's `tarfile` (ustar, GNU and pax formats) and the newc format used by `cpio -H newc`
+#[cfg(test)]
+fn test_tar_header() -> [u8; 512] {
+	let mut hdr = [0u8; 512];
+	{
+		let mut set = |ofs: usize, val: &[u8]| hdr[ofs .. ofs + val.len()].copy_from_slice(val);
+		set(0, b"hello.txt");
+		set(100, b"0000644\0");
+		set(108, b"0001750\0");
+		set(116, b"0001750\0");
+		set(124, b"00000000014\0");
+		set(136, b"14023423446\0");
+		set(148, b"007677\0 ");
+		set(156, b"0");
+		set(257, b"ustar\0");
+		set(263, b"00");
+	}
+	hdr
+}
+
+#[test]
+fn tar_header()
+{
+	let mut hdr = test_tar_header();
+	assert!(tar_checksum_valid(&hdr));
+	match detect(&hdr)
+	{
+	Some(Format::Tar) => {},
+	_ => panic!("ustar header not detected"),
+	}
+	assert_eq!(c_field(&hdr[0..100]), b"hello.txt");
+	assert_eq!(parse_tar_number(&hdr[100..108]), Some(0o644));
+	assert_eq!(parse_tar_number(&hdr[108..116]), Some(1000));
+	assert_eq!(parse_tar_number(&hdr[124..136]), Some(12));
+	assert_eq!(parse_tar_number(&hdr[136..148]), Some(1615734566));
+
+	// - Any change invalidates the checksum
+	hdr[0] = b'j';
+	assert!( !tar_checksum_valid(&hdr) );
+	assert!(detect(&hdr).is_none());
+}
+#[test]
+fn tar_numbers()
+{
+	// Leading spaces, and either terminator
+	assert_eq!(parse_octal(b"  755 \0"), Some(0o755));
+	assert_eq!(parse_octal(b"0000755\0"), Some(0o755));
+	assert_eq!(parse_octal(b"12345670123"), Some(0o12345670123));
+	// Empty, or not octal
+	assert_eq!(parse_octal(b"\0\0\0\0\0\0\0\0"), None);
+	assert_eq!(parse_octal(b"       \0"), None);
+	assert_eq!(parse_octal(b"0000789\0"), None);
+	// GNU base-256 (8GiB + 5, which doesn't fit in 11 octal digits)
+	assert_eq!(parse_tar_number(b"\x80\0\0\0\0\0\0\x02\0\0\0\x05"), Some(8 * 1024*1024*1024 + 5));
+}
+#[test]
+fn tar_pax_records()
+{
+	let mut it = PaxRecords(b"14 comment=hi\n20 mtime=1615734566\n");
+	assert_eq!(it.next(), Some( (&b"comment"[..], &b"hi"[..]) ));
+	assert_eq!(it.next(), Some( (&b"mtime"[..], &b"1615734566"[..]) ));
+	assert_eq!(it.next(), None);
+	// - Length past the end, length not matching the newline, and a missing '='
+	assert_eq!(PaxRecords(b"99 path=x\n").next(), None);
+	assert_eq!(PaxRecords(b"11 path=x\n").next(), None);
+	assert_eq!(PaxRecords(b"9 pathx\n").next(), None);
+}
+#[test]
+fn cpio_header()
+{
+	let mut hdr = [0u8; 512];
+	hdr[..CPIO_HDR_LEN].copy_from_slice(b"070701\
+		00000002000081A4000003E8000003E800000001604E27260000000C\
+		00000000000000000000000000000000\
+		0000000A00000000");
+	match detect(&hdr)
+	{
+	Some(Format::Cpio) => {},
+	_ => panic!("cpio header not detected"),
+	}
+	hdr[5] = b'2';
+	match detect(&hdr)
+	{
+	Some(Format::Cpio) => {},
+	_ => panic!("cpio (with checksums) header not detected"),
+	}
+	assert_eq!(parse_hex(&hdr[14..22]), Some(0o100644));
+	assert_eq!(parse_hex(&hdr[46..54]), Some(1615734566));
+	assert_eq!(parse_hex(&hdr[54..62]), Some(12));
+	assert_eq!(parse_hex(&hdr[94..102]), Some(10));
+	assert_eq!(parse_hex(b"0000000G"), None);
+}
@@ -0,0 +1,443 @@
+// "Tifflin" Kernel - Initial Ramdisk Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_initrd/lib.rs
+//! Read-only filesystem over a tar or cpio archive (e.g. an initial ramdisk provided by the bootloader)
+#![feature(linkage)]
+#![no_std]
+use kernel::prelude::*;
+
+use kernel::vfs::{self, mount, node};
+use kernel::metadevs::storage::VolumeHandle;
+use kernel::lib::mem::aref::{ArefInner,ArefBorrow};
+use kernel::lib::byte_str::{ByteStr,ByteString};
+use kernel::lib::VecMap;
+
+#[macro_use]
+extern crate kernel;
+
+module_define!{FS_INITRD, [VFS], init}
+
+mod archive;
+
+struct Driver;
+static S_DRIVER: Driver = Driver;
+
+struct Instance(ArefInner<InstanceInner>);
+impl ::core::ops::Deref for Instance {
+	type Target = InstanceInner;
+	fn deref(&self) -> &InstanceInner { &self.0 }
+}
+
+struct InstanceInner
+{
+	vh: VolumeHandle,
+	/// Directory tree built from the archive when mounted (index is the inode number)
+	nodes: Vec<Node>,
+}
+
+struct Node
+{
+	metadata: node::Metadata,
+	kind: NodeKind,
+}
+enum NodeKind
+{
+	Dir {
+		parent: usize,
+		ents: VecMap<ByteString,usize>,
+	},
+	File {
+		/// Offset of the data in the archive
+		data_ofs: u64,
+	},
+	Symlink(ByteString),
+}
+
+fn init()
+{
+	let h = mount::DriverRegistration::new("initrd", &S_DRIVER);
+	::core::mem::forget(h);
+}
+
+impl mount::Driver for Driver
+{
+	fn detect(&self, vol: &VolumeHandle) -> vfs::Result<usize> {
+		let mut hdr = [0u8; 512];
+		if vol.block_count() * (vol.block_size() as u64) < hdr.len() as u64 {
+			return Ok(0);
+		}
+		try!(read_bytes(vol, 0, &mut hdr));
+		match archive::detect(&hdr)
+		{
+		Some(_) => Ok(1),
+		None => Ok(0),
+		}
+	}
+	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle, _options: &[&str]) -> vfs::Result<Box<dyn mount::Filesystem>> {
+		let mut hdr = [0u8; 512];
+		try!(read_bytes(&vol, 0, &mut hdr));
+		let format = match archive::detect(&hdr)
+			{
+			Some(v) => v,
+			None => return Err( vfs::Error::Unknown("Not a tar or cpio archive") ),
+			};
+
+		let mut tree = TreeBuilder::new();
+		try!(archive::parse(&vol, format, &mut |ent| tree.add(ent)));
+		log_log!("initrd: Mounted {:?} archive with {} nodes", format, tree.nodes.len());
+
+		Ok(Box::new( Instance(
+			// SAFE: ArefInner must not change addresses, but because you can't move out of a boxed trait, we're good
+			unsafe { ArefInner::new( InstanceInner {
+				vh: vol,
+				nodes: tree.nodes,
+				}) }
+			) ))
+	}
+}
+
+/// Read an arbitrary byte range from a volume
+fn read_bytes(vh: &VolumeHandle, ofs: u64, dst: &mut [u8]) -> vfs::Result<()> {
+	let bs = vh.block_size();
+	let mut blk = ofs / bs as u64;
+	let mut done = 0;
+	// Leading partial block
+	let start_ofs = (ofs % bs as u64) as usize;
+	if start_ofs != 0 || dst.len() < bs {
+		let mut buf = vec![0u8; bs];
+		try!(vh.read_blocks(blk, &mut buf));
+		let len = ::core::cmp::min(bs - start_ofs, dst.len());
+		dst[..len].clone_from_slice(&buf[start_ofs..][..len]);
+		done += len;
+		blk += 1;
+	}
+	// Whole blocks are read directly
+	let whole = (dst.len() - done) / bs * bs;
+	if whole > 0 {
+		try!(vh.read_blocks(blk, &mut dst[done..][..whole]));
+		done += whole;
+		blk += (whole / bs) as u64;
+	}
+	// Trailing partial block
+	if done < dst.len() {
+		let mut buf = vec![0u8; bs];
+		try!(vh.read_blocks(blk, &mut buf));
+		let len = dst.len() - done;
+		dst[done..].clone_from_slice(&buf[..len]);
+	}
+	Ok( () )
+}
+
+/// Constructs the node list from archive entries
+struct TreeBuilder
+{
+	nodes: Vec<Node>,
+	/// cpio inode numbers of hard-linked files
+	links: VecMap<u32,usize>,
+}
+impl TreeBuilder
+{
+	fn new() -> TreeBuilder {
+		TreeBuilder {
+			nodes: vec![ Node { metadata: dir_metadata(), kind: NodeKind::Dir { parent: 0, ents: VecMap::new() } } ],
+			links: VecMap::new(),
+		}
+	}
+
+	fn add(&mut self, ent: archive::Entry) {
+		// Split into components, ignoring empty and `.` components (and any leading `/`)
+		let mut components: Vec<&[u8]> = ent.path.split(|&b| b == b'/').filter(|c| c.len() > 0 && *c != b".").collect();
+		if components.iter().any(|c| *c == b"..") {
+			log_notice!("initrd: Ignoring entry with `..` in the path - {:?}", ByteStr::new(&ent.path));
+			return ;
+		}
+		let metadata = node::Metadata {
+			size: ent.size,
+			link_count: 1,
+			uid: ent.uid,
+			gid: ent.gid,
+			permissions: (ent.mode & 0o7777) as u16,
+			ctime: ent.mtime,
+			mtime: ent.mtime,
+			atime: ent.mtime,
+			};
+		let name = match components.pop()
+			{
+			Some(v) => ByteString::from(v),
+			// The root itself (e.g. `./`)
+			None => {
+				if let archive::Kind::Dir = ent.kind {
+					self.nodes[0].metadata = metadata;
+				}
+				return ;
+				},
+			};
+
+		// Locate (creating as needed) the parent directory
+		let mut dir = 0;
+		for c in components
+		{
+			dir = match self.get_or_create_dir(dir, ByteStr::new(c))
+				{
+				Some(v) => v,
+				None => {
+					log_notice!("initrd: Ignoring {:?}, {:?} isn't a directory", ByteStr::new(&ent.path), ByteStr::new(c));
+					return ;
+					},
+				};
+		}
+
+		let inode = match ent.kind
+			{
+			archive::Kind::Dir => {
+				match self.lookup(dir, &name)
+				{
+				Some(i) => if let NodeKind::Dir { .. } = self.nodes[i].kind {
+						// Already created implicitly, just update the metadata
+						self.nodes[i].metadata = metadata;
+						return ;
+					},
+				None => {},
+				}
+				self.push(metadata, NodeKind::Dir { parent: dir, ents: VecMap::new() })
+				},
+			archive::Kind::File => {
+				let existing = match ent.link_key
+					{
+					Some(k) => self.links.get(&k).cloned(),
+					None => None,
+					};
+				match existing
+				{
+				Some(i) => {
+					// Another name for an existing file, the last entry carries the data
+					if ent.size > 0 {
+						self.nodes[i].metadata.size = ent.size;
+						self.nodes[i].kind = NodeKind::File { data_ofs: ent.data_ofs };
+					}
+					self.nodes[i].metadata.link_count += 1;
+					i
+					},
+				None => {
+					let i = self.push(metadata, NodeKind::File { data_ofs: ent.data_ofs });
+					if let Some(k) = ent.link_key {
+						self.links.insert(k, i);
+					}
+					i
+					},
+				}
+				},
+			archive::Kind::Symlink(target) => {
+				let mut metadata = metadata;
+				metadata.size = target.len() as u64;
+				self.push(metadata, NodeKind::Symlink(ByteString::from(target)))
+				},
+			archive::Kind::HardLink(target) => {
+				match self.resolve(&target)
+				{
+				Some(i) if self.nodes[i].is_dir() => {
+					log_notice!("initrd: Ignoring hard link {:?} to directory {:?}", ByteStr::new(&ent.path), ByteStr::new(&target));
+					return ;
+					},
+				Some(i) => {
+					self.nodes[i].metadata.link_count += 1;
+					i
+					},
+				None => {
+					log_notice!("initrd: Ignoring hard link {:?}, target {:?} not found", ByteStr::new(&ent.path), ByteStr::new(&target));
+					return ;
+					},
+				}
+				},
+			};
+
+		// Later entries replace earlier ones (as when extracting)
+		match self.nodes[dir].kind
+		{
+		NodeKind::Dir { ref mut ents, .. } => { ents.insert(name, inode); },
+		_ => unreachable!(),
+		}
+	}
+
+	fn push(&mut self, metadata: node::Metadata, kind: NodeKind) -> usize {
+		self.nodes.push(Node { metadata: metadata, kind: kind });
+		self.nodes.len() - 1
+	}
+	fn lookup(&self, dir: usize, name: &ByteStr) -> Option<usize> {
+		match self.nodes[dir].kind
+		{
+		NodeKind::Dir { ref ents, .. } => ents.get(name).cloned(),
+		_ => None,
+		}
+	}
+	fn get_or_create_dir(&mut self, dir: usize, name: &ByteStr) -> Option<usize> {
+		match self.lookup(dir, name)
+		{
+		Some(i) => if self.nodes[i].is_dir() { Some(i) } else { None },
+		None => {
+			let i = self.push(dir_metadata(), NodeKind::Dir { parent: dir, ents: VecMap::new() });
+			match self.nodes[dir].kind
+			{
+			NodeKind::Dir { ref mut ents, .. } => { ents.insert(ByteString::from(name), i); },
+			_ => unreachable!(),
+			}
+			Some(i)
+			},
+		}
+	}
+	/// Resolve an archive path (without following symlinks)
+	fn resolve(&self, path: &[u8]) -> Option<usize> {
+		let mut cur = 0;
+		for c in path.split(|&b| b == b'/').filter(|c| c.len() > 0 && *c != b".")
+		{
+			cur = self.lookup(cur, ByteStr::new(c))?;
+		}
+		Some(cur)
+	}
+}
+
+fn dir_metadata() -> node::Metadata {
+	node::Metadata { permissions: 0o755, ..Default::default() }
+}
+
+impl Node
+{
+	fn is_dir(&self) -> bool {
+		match self.kind
+		{
+		NodeKind::Dir { .. } => true,
+		_ => false,
+		}
+	}
+}
+
+impl mount::Filesystem for Instance
+{
+	fn root_inode(&self) -> node::InodeId {
+		0
+	}
+	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
+		let n = match self.nodes.get(id as usize)
+			{
+			Some(v) => v,
+			None => {
+				log_log!("initrd: get_node_by_inode - Inode {} out of range", id);
+				return None;
+				},
+			};
+		let nr = Box::new(NodeRef(self.0.borrow(), id as usize));
+		Some(match n.kind
+			{
+			NodeKind::Dir { .. } => node::Node::Dir(nr),
+			NodeKind::File { .. } => node::Node::File(nr),
+			NodeKind::Symlink(_) => node::Node::Symlink(nr),
+			})
+	}
+}
+
+struct NodeRef(ArefBorrow<InstanceInner>, usize);
+impl NodeRef
+{
+	fn node(&self) -> &Node {
+		&self.0.nodes[self.1]
+	}
+}
+impl node::NodeBase for NodeRef {
+	fn get_id(&self) -> node::InodeId {
+		self.1 as node::InodeId
+	}
+	fn get_any(&self) -> &dyn (::core::any::Any) {
+		self
+	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.node().metadata.clone() )
+	}
+}
+impl node::Dir for NodeRef {
+	fn lookup(&self, name: &ByteStr) -> node::Result<node::InodeId> {
+		match self.node().kind
+		{
+		NodeKind::Dir { parent, ref ents } =>
+			if name == "." {
+				Ok(self.1 as node::InodeId)
+			}
+			else if name == ".." {
+				Ok(parent as node::InodeId)
+			}
+			else {
+				match ents.get(name)
+				{
+				Some(&v) => Ok(v as node::InodeId),
+				None => Err(vfs::Error::NotFound),
+				}
+			},
+		_ => Err(vfs::Error::TypeMismatch),
+		}
+	}
+	fn read(&self, start_ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize> {
+		let ents = match self.node().kind
+			{
+			NodeKind::Dir { ref ents, .. } => ents,
+			_ => return Err(vfs::Error::TypeMismatch),
+			};
+		let mut count = 0;
+		for (name, &inode) in ents.iter().skip(start_ofs)
+		{
+			count += 1;
+			if ! callback(inode as node::InodeId, &mut name.as_bytes().iter().cloned()) {
+				break ;
+			}
+		}
+		Ok(start_ofs + count)
+	}
+	fn create(&self, _name: &ByteStr, _nodetype: node::NodeType) -> node::Result<node::InodeId> {
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+	fn link(&self, _name: &ByteStr, _node: &dyn node::NodeBase) -> node::Result<()> {
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+	fn unlink(&self, _name: &ByteStr) -> node::Result<()> {
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+	fn rename(&self, _src_name: &ByteStr, _dst_dir: &dyn node::Dir, _dst_name: &ByteStr) -> node::Result<()> {
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+}
+impl node::File for NodeRef {
+	fn size(&self) -> u64 {
+		self.node().metadata.size
+	}
+	fn truncate(&self, _newsize: u64) -> node::Result<u64> {
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+	fn clear(&self, _ofs: u64, _size: u64) -> node::Result<()> {
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
+		let data_ofs = match self.node().kind
+			{
+			NodeKind::File { data_ofs } => data_ofs,
+			_ => return Err(vfs::Error::TypeMismatch),
+			};
+		let size = self.size();
+		if ofs > size {
+			return Err( vfs::Error::InvalidParameter );
+		}
+		let len = ::core::cmp::min(buf.len() as u64, size - ofs) as usize;
+		try!(read_bytes(&self.0.vh, data_ofs + ofs, &mut buf[..len]));
+		Ok(len)
+	}
+	fn write(&self, _ofs: u64, _buf: &[u8]) -> node::Result<usize> {
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+}
+impl node::Symlink for NodeRef {
+	fn read(&self) -> ByteString {
+		match self.node().kind
+		{
+		NodeKind::Symlink(ref target) => target.clone(),
+		_ => ByteString::new(),
+		}
+	}
+}
@@ -9,13 +9,15 @@ use kernel::prelude::*;
 use kernel::vfs::{self, mount, node};
 use kernel::metadevs::storage::{self,VolumeHandle};
 use kernel::lib::mem::aref::{ArefInner,ArefBorrow};
-use kernel::lib::byteorder::{ByteOrder,LittleEndian};
-use kernel::lib::byte_str::ByteStr;
+use kernel::lib::byteorder::{ByteOrder,LittleEndian,BigEndian};
+use kernel::lib::byte_str::{ByteStr,ByteString};
+use utf16::Str16;
 
 #[macro_use]
 extern crate kernel;
 
 extern crate block_cache;
+extern crate utf16;
 
 module_define!{FS_ISO9660, [VFS], init}
 
@@ -36,8 +38,12 @@ struct InstanceInner
 	lb_size: usize,
 	root_lba: u32,
 	root_size: u32,
+	/// Volume size in logical blocks (from the PVD), used to check locations read from the media
+	vol_blocks: u32,
 
 	susp_len_skip: Option<u8>,
+	/// Directory tree is from a Joliet SVD (names are UCS-2)
+	joliet: bool,
 }
 
 fn init()
@@ -63,16 +69,18 @@ impl mount::Driver for Driver
 			Ok(0)
 		}
 	}
-	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle) -> vfs::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle, _options: &[&str]) -> vfs::Result<Box<dyn mount::Filesystem>> {
 		// For this to work properly, the block size must evenly divide 2048
 		if 2048 % vol.block_size() != 0 {
 			return Err( vfs::Error::Unknown("Can't mount ISO9660 with sector size not a factor of 2048"/*, vol.block_size()*/) );
 		}
 		let scale = 2048 / vol.block_size();
 		
-		// Search the start of the disk for the primary volume descriptor
+		// Search the start of the disk for the primary volume descriptor (and a Joliet SVD)
 		// - TODO: Limit the number of sectors searched.
 		let mut block = vec![0u8; 2048];
+		let mut pvd = None;
+		let mut joliet_svd = None;
 		for sector in 16 .. 
 		{
 			try!(vol.read_blocks((sector*scale) as u64, &mut block));
@@ -80,24 +88,36 @@ impl mount::Driver for Driver
 				return Err( vfs::Error::Unknown("Invalid volume descriptor present") );
 			}
 			else if block[0] == 255 {
-				return Err( vfs::Error::Unknown("Can't find ISO9660 primary volume descriptor") );
+				// Volume descriptor set terminator
+				break ;
 			}
 			else if block[0] == 0x01 {
-				// Found it!
-				break ;
+				if pvd.is_none() {
+					pvd = Some(block.clone());
+				}
+			}
+			else if block[0] == 0x02 && is_joliet_escape(&block[88..][..32]) {
+				if joliet_svd.is_none() {
+					joliet_svd = Some(block.clone());
+				}
 			}
 			else {
 				// Try the next one
 			}
 		}
+		let block = match pvd
+			{
+			Some(v) => v,
+			None => return Err( vfs::Error::Unknown("Can't find ISO9660 primary volume descriptor") ),
+			};
 		//::kernel::logging::hex_dump("ISO966 PVD", &block);
 		
 		// Obtain the logical block size (different from medium sector size)
 		let lb_size = LittleEndian::read_u16(&block[128..]);
+		let vol_blocks = LittleEndian::read_u32(&block[80..]);
 		// Extract the root directory entry
 		// - We want the LBA and byte length
-		let root_lba  = LittleEndian::read_u32(&block[156+ 2..]);
-		let root_size = LittleEndian::read_u32(&block[156+10..]);
+		let (root_lba, root_size) = root_extent(&block);
 		
 		log_debug!("lb_size = {}, root = {:#x} + {:#x} bytes", lb_size, root_lba, root_size);
 	
@@ -107,7 +127,9 @@ impl mount::Driver for Driver
 			lb_size: lb_size as usize,
 			root_lba: root_lba,
 			root_size: root_size,
+			vol_blocks: vol_blocks,
 			susp_len_skip: None,
+			joliet: false,
 			};
 
 		// Determine if SUSP is in use (used for RockRidge extensions)
@@ -125,55 +147,111 @@ impl mount::Driver for Driver
 				None
 			}
 			};
+
+		// Rock Ridge names are preferred, but fall back to the Joliet tree if it's absent
+		if let Some(svd) = joliet_svd {
+			if inner.susp_len_skip.is_some() {
+				log_debug!("Joliet SVD present, but using Rock Ridge names");
+			}
+			else {
+				let (root_lba, root_size) = root_extent(&svd);
+				log_debug!("Using Joliet SVD, root = {:#x} + {:#x} bytes", root_lba, root_size);
+				inner.root_lba = root_lba;
+				inner.root_size = root_size;
+				inner.joliet = true;
+			}
+		}
 		
 		// SAFE: Stored in a box, and not moved out.
 		Ok( Box::new( Instance(unsafe { ArefInner::new( inner ) }) ) )
 	}
 }
 
+/// Check a SVD's escape sequences field for one of the Joliet UCS-2 levels
+fn is_joliet_escape(esc: &[u8]) -> bool {
+	match &esc[..3]
+	{
+	b"%/@" | b"%/C" | b"%/E" => true,
+	_ => false,
+	}
+}
+/// Get the root directory extent (LBA and byte length) from a PVD/SVD
+fn root_extent(vd: &[u8]) -> (u32, u32) {
+	(LittleEndian::read_u32(&vd[156+ 2..]), LittleEndian::read_u32(&vd[156+10..]))
+}
+
 impl mount::Filesystem for Instance
 {
 	fn root_inode(&self) -> node::InodeId {
 		0 as node::InodeId
 	}
 	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
-		if id == 0 {
-			Some(Dir::new_node(self.0.borrow(), self.root_lba, self.root_size) )
-		}
-		else {
-			// Look up (or read) parent directory to obtain the info
-			let (sector, ofs) = ::kernel::lib::num::div_rem(id as u64, self.lb_size as u64);
-			let blk = match self.get_sector(sector as u32)
-				{
-				Ok(v) => v,
-				Err(_) => return None,
-				};
-			let mut it = DirSector::new(&self.0, blk, ofs as usize);
-			let ent = match it.next()
-				{
-				Ok(Some(v)) => v,
-				Ok(None) => return None,
-				Err(_) => return None,
-				};
-			if ent.name.len() == 0 {
-				None
+		let rv = if id == 0 {
+				self.dir_from_dot(0, self.root_lba).map(Some)
 			}
 			else {
-				if ent.flags & (1 << 7) != 0 {
-					// Multi-extent file!
-					None
-				}
-				else if ent.flags & (1 << 1) != 0 {
-					Some(Dir::new_node(self.0.borrow(), ent.start, ent.size))
-				}
-				else if ent.flags & 0x64 != 0 {
-					None
-				}
-				else {
-					Some(File::new_node(self.0.borrow(), ent.start, ent.size))
+				// Read the directory entry (the inode number is its location on disk)
+				let (sector, ofs) = ::kernel::lib::num::div_rem(id as u64, self.lb_size as u64);
+				match self.get_sector(sector as u32)
+				{
+				Ok(blk) => {
+					let mut it = DirSector::new(&self.0, blk, ofs as usize);
+					let rv = match it.next()
+						{
+						Ok(Some(ent)) => self.node_from_dirent(id, ent),
+						Ok(None) => Ok(None),
+						Err(e) => Err(e),
+						};
+					rv
+					},
+				Err(e) => Err(From::from(e)),
 				}
-			}
+			};
+		match rv
+		{
+		Ok(v) => v,
+		Err(e) => {
+			log_notice!("Error reading ISO9660 inode {:#x}: {:?}", id, e);
+			None
+			},
+		}
+	}
+}
+impl Instance
+{
+	/// Create a directory node using the directory's own `.` entry
+	fn dir_from_dot(&self, id: node::InodeId, lba: u32) -> node::Result<node::Node> {
+		let (start, size, metadata) = try!(self.dot_entry(lba));
+		Ok( Dir::new_node(self.0.borrow(), id, start, size, metadata) )
+	}
+	fn node_from_dirent(&self, id: node::InodeId, mut ent: DirEnt) -> node::Result<Option<node::Node>> {
+		Ok(if ent.ident.len() == 0 {
+			None
+		}
+		else if ent.flags & (1 << 7) != 0 {
+			// Multi-extent file!
+			None
+		}
+		else if let Some(lba) = ent.rr.child_link {
+			// Rock Ridge relocated directory, the real entry is the `.` of the target
+			Some( try!(self.dir_from_dot(id, try!(self.check_lba(lba)))) )
+		}
+		else if ent.flags & (1 << 1) != 0 {
+			Some(Dir::new_node(self.0.borrow(), id, ent.start, ent.size, ent.metadata(true)))
+		}
+		else if ent.flags & 0x64 != 0 {
+			None
+		}
+		else if let Some(target) = ent.rr.symlink.take() {
+			Some(Symlink::new_node(id, target, ent.metadata(false)))
+		}
+		else if let Some(mode) = ent.rr.mode.filter(|m| m & S_IFMT != S_IFREG) {
+			log_notice!("Unsupported Rock Ridge file type {:#o} (device {:?})", mode & S_IFMT, ent.rr.device);
+			None
 		}
+		else {
+			Some(File::new_node(self.0.borrow(), id, ent.start, ent.size, ent.metadata(false)))
+		})
 	}
 }
 struct Sector<'a>(::block_cache::CachedBlockHandle<'a>,u16,u16);
@@ -203,33 +281,127 @@ impl InstanceInner
 		let ofs = (sector as u64 - blk.index()) as usize * self.vh.block_size();
 		Ok( Sector(blk, ofs as u16, self.vh.block_size() as u16) )
 	}
+
+	/// Check that a LBA read from the media (e.g. a Rock Ridge link) is within the volume
+	fn check_lba(&self, lba: u32) -> node::Result<u32> {
+		if lba == 0 || lba >= self.vol_blocks {
+			log_warning!("LBA {:#x} out of range (volume is {:#x} blocks)", lba, self.vol_blocks);
+			Err(vfs::Error::InconsistentFilesystem)
+		}
+		else {
+			Ok(lba)
+		}
+	}
+	/// Get the inode number for the directory entry at `ofs` in `sector`
+	fn dirent_inode(&self, sector: u32, ofs: usize) -> node::InodeId {
+		sector as u64 * self.lb_size as u64 + ofs as u64
+	}
+	/// Read the extent and metadata from the `.` entry of the directory starting at `lba`
+	fn dot_entry(&self, lba: u32) -> node::Result<(u32, u32, node::Metadata)> {
+		let mut it = DirSector::new(self, try!(self.get_sector(lba)), 0);
+		let rv = match try!(it.next())
+			{
+			Some(ent) => if ent.ident == b"\0" {
+					Ok( (ent.start, ent.size, ent.metadata(true)) )
+				}
+				else {
+					log_warning!("Directory at {:#x} doesn't start with a `.` entry", lba);
+					Err(vfs::Error::InconsistentFilesystem)
+				},
+			None => Err(vfs::Error::InconsistentFilesystem),
+			};
+		rv
+	}
+	/// Get the first LBA of the parent of the directory at `lba`
+	fn parent_lba(&self, lba: u32) -> node::Result<u32> {
+		let mut it = DirSector::new(self, try!(self.get_sector(lba)), 0);
+		try!(it.next());
+		let rv = match try!(it.next())
+			{
+			// A relocated directory's `..` has a PL entry with its logical parent
+			Some(ent) => if ent.ident == b"\x01" {
+					match ent.rr.parent_link
+					{
+					Some(v) => self.check_lba(v),
+					None => Ok(ent.start),
+					}
+				}
+				else {
+					log_warning!("Directory at {:#x} doesn't have `..` as the second entry", lba);
+					Err(vfs::Error::InconsistentFilesystem)
+				},
+			None => Err(vfs::Error::InconsistentFilesystem),
+			};
+		rv
+	}
+	/// Get the inode number of the parent of the directory at `lba`
+	///
+	/// This is the entry in the grandparent (or the root), so is the same ID as returned by a lookup.
+	fn parent_inode(&self, lba: u32) -> node::Result<node::InodeId> {
+		if lba == self.root_lba {
+			return Ok(0);
+		}
+		let parent = try!(self.parent_lba(lba));
+		if parent == self.root_lba {
+			return Ok(0);
+		}
+		let (gp_lba, gp_size, _) = try!(self.dot_entry( try!(self.parent_lba(parent)) ));
+		for sector in 0 .. ::kernel::lib::num::div_up(gp_size, self.lb_size as u32)
+		{
+			let sector = gp_lba + sector;
+			let mut it = DirSector::new(self, try!(self.get_sector(sector)), 0);
+			while let Some(ent) = try!(it.next())
+			{
+				if ent.is_special() || ent.rr.relocated {
+					continue ;
+				}
+				let is_match = match ent.rr.child_link
+					{
+					Some(v) => v == parent,
+					None => ent.flags & (1 << 1) != 0 && ent.start == parent,
+					};
+				if is_match {
+					return Ok( self.dirent_inode(sector, ent.this_ofs) );
+				}
+			}
+		}
+		log_warning!("Can't find the entry for directory {:#x} in its parent {:#x}", parent, gp_lba);
+		Err(vfs::Error::InconsistentFilesystem)
+	}
 }
 
 // --------------------------------------------------------------------
 struct File
 {
 	fs: ArefBorrow<InstanceInner>,
+	id: node::InodeId,
 	first_lba: u32,
 	size: u32,
+	metadata: node::Metadata,
 }
 impl File
 {
-	fn new_node(fs: ArefBorrow<InstanceInner>, first_lba: u32, size: u32) -> node::Node {
+	fn new_node(fs: ArefBorrow<InstanceInner>, id: node::InodeId, first_lba: u32, size: u32, metadata: node::Metadata) -> node::Node {
 		node::Node::File( Box::new( File {
 			fs: fs,
+			id: id,
 			first_lba: first_lba,
 			size: size,
+			metadata: metadata,
 			} ) )
 	}
 }
 impl node::NodeBase for File
 {
 	fn get_id(&self) -> node::InodeId {
-		todo!("File::get_id")
+		self.id
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.metadata.clone() )
+	}
 }
 impl node::File for File
 {
@@ -298,42 +470,53 @@ impl node::File for File
 struct Dir
 {
 	fs: ArefBorrow<InstanceInner>,
+	id: node::InodeId,
 	first_lba: u32,
 	size: u32,
+	metadata: node::Metadata,
 }
 impl Dir
 {
-	fn new_node(fs: ArefBorrow<InstanceInner>, first_lba: u32, size: u32) -> node::Node {
+	fn new_node(fs: ArefBorrow<InstanceInner>, id: node::InodeId, first_lba: u32, size: u32, metadata: node::Metadata) -> node::Node {
 		node::Node::Dir( Box::new( Dir {
 			fs: fs,
+			id: id,
 			first_lba: first_lba,
 			size: size,
+			metadata: metadata,
 			} ) )
 	}
 }
 impl node::NodeBase for Dir
 {
 	fn get_id(&self) -> node::InodeId {
-		todo!("Dir::get_id")
+		self.id
 	}
 	fn get_any(&self) -> &dyn core::any::Any {
 		self
 	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.metadata.clone() )
+	}
 }
 impl node::Dir for Dir
 {
 	fn lookup(&self, name: &ByteStr) -> node::Result<node::InodeId>
 	{
+		if name == ".." {
+			return self.fs.parent_inode(self.first_lba);
+		}
+
 		for sector in 0 .. ::kernel::lib::num::div_up(self.size, self.fs.lb_size as u32)
 		{
 			let mut it = DirSector::new(&self.fs, try!(self.fs.get_sector(self.first_lba + sector)), 0); 
 
 			while let Some(ent) = try!(it.next())
 			{
-				if ent.name == name.as_bytes()
+				// Relocated directories are accessed via the CL entry in their logical parent
+				if ! ent.is_special() && ! ent.rr.relocated && ent.name_matches(name)
 				{
-					let inode = (self.first_lba + sector) as u64 * self.fs.lb_size as u64 + ent.this_ofs as u64;
-					return Ok( inode );
+					return Ok( self.fs.dirent_inode(self.first_lba + sector, ent.this_ofs) );
 				}
 			}
 		}
@@ -354,11 +537,28 @@ impl node::Dir for Dir
 
 			while let Some(ent) = try!(it.next())
 			{
-				if ent.name.len() > 0 && ent.name != b"\0" && ent.name != b"\x01"
+				if ! ent.is_special() && ! ent.rr.relocated
 				{
 					log_debug!("ent = {:?}", ent);
-					let inode = (self.first_lba + sector) as u64 * self.fs.lb_size as u64 + ent.this_ofs as u64;
-					if ! callback(inode, &mut ent.name.iter().cloned()) {
+					let inode = self.fs.dirent_inode(self.first_lba + sector, ent.this_ofs);
+					let cont = if let Some(ref name) = ent.rr.name {
+							callback(inode, &mut name.iter().cloned())
+						}
+						else if ent.ucs2 {
+							let name16 = ent.name16();
+							match Str16::new(&name16)
+							{
+							Some(s) => callback(inode, &mut s.wtf8()),
+							None => {
+								log_notice!("Invalid UCS-2 name in Joliet directory entry: {:?}", ent);
+								true
+								},
+							}
+						}
+						else {
+							callback(inode, &mut ent.ident.iter().cloned())
+						};
+					if ! cont {
 						return Ok( sector as usize * self.fs.lb_size + ent.next_ofs );
 					}
 				}
@@ -383,6 +583,47 @@ impl node::Dir for Dir
 		// ISO9660 is readonly
 		Err( vfs::Error::ReadOnlyFilesystem )
 	}
+	fn rename(&self, _src_name: &ByteStr, _dst_dir: &dyn node::Dir, _dst_name: &ByteStr) -> node::Result<()> {
+		// ISO9660 is readonly
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+}
+
+// --------------------------------------------------------------------
+/// Rock Ridge symbolic link
+struct Symlink
+{
+	id: node::InodeId,
+	target: Vec<u8>,
+	metadata: node::Metadata,
+}
+impl Symlink
+{
+	fn new_node(id: node::InodeId, target: Vec<u8>, metadata: node::Metadata) -> node::Node {
+		node::Node::Symlink( Box::new( Symlink {
+			id: id,
+			target: target,
+			metadata: metadata,
+			} ) )
+	}
+}
+impl node::NodeBase for Symlink
+{
+	fn get_id(&self) -> node::InodeId {
+		self.id
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.metadata.clone() )
+	}
+}
+impl node::Symlink for Symlink
+{
+	fn read(&self) -> ByteString {
+		ByteString::from( ByteStr::new(&self.target) )
+	}
 }
 
 
@@ -395,19 +636,74 @@ struct DirEnt<'a>
 	flags: u8,
 	start: u32,
 	size: u32,
-	name: &'a [u8],
+	time: ::kernel::time::Timestamp,
+	/// File identifier (raw from the directory record)
+	ident: &'a [u8],
+	/// `ident` is big-endian UCS-2 (from a Joliet directory)
+	ucs2: bool,
 	sys_use: &'a [u8],
+	rr: RockRidge,
 }
 impl<'a> ::core::fmt::Debug for DirEnt<'a> {
 	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
-		write!(f, "DirEnt {{ start: {:#x}, size: {:#x}, name: {:?} }}",
-			self.start, self.size, ByteStr::new(self.name)
-			)
+		try!(write!(f, "DirEnt {{ start: {:#x}, size: {:#x}, name: ", self.start, self.size));
+		if let Some(ref name) = self.rr.name {
+			try!(write!(f, "{:?}", ByteStr::new(name)));
+		}
+		else if self.ucs2 {
+			match Str16::new(&self.name16())
+			{
+			Some(s) => try!(write!(f, "{:?}", s)),
+			None => try!(write!(f, "{:?}", ByteStr::new(self.ident))),
+			}
+		}
+		else {
+			try!(write!(f, "{:?}", ByteStr::new(self.ident)));
+		}
+		write!(f, " }}")
 	}
 }
 
 impl<'a> DirEnt<'a>
 {
+	/// Padding, or the `.`/`..` entries
+	fn is_special(&self) -> bool {
+		self.ident.len() == 0 || self.ident == b"\0" || self.ident == b"\x01"
+	}
+	/// Decode a UCS-2 identifier into code units
+	fn name16(&self) -> Vec<u16> {
+		(0 .. self.ident.len() / 2).map(|i| BigEndian::read_u16(&self.ident[i*2..])).collect()
+	}
+	/// Compare this entry's name against a VFS name
+	fn name_matches(&self, name: &ByteStr) -> bool {
+		if let Some(ref v) = self.rr.name {
+			&v[..] == name.as_bytes()
+		}
+		else if self.ucs2 {
+			match Str16::new(&self.name16())
+			{
+			Some(s) => *s == *name,
+			None => false,
+			}
+		}
+		else {
+			self.ident == name.as_bytes()
+		}
+	}
+	/// Node metadata, using Rock Ridge information where present
+	fn metadata(&self, is_dir: bool) -> node::Metadata {
+		let default_perms = if is_dir { 0o555 } else { 0o444 };
+		node::Metadata {
+			size: self.size as u64,
+			link_count: self.rr.n_links.unwrap_or(1),
+			uid: self.rr.uid,
+			gid: self.rr.gid,
+			permissions: self.rr.mode.map(|m| (m & 0o7777) as u16).unwrap_or(default_perms),
+			ctime: self.rr.ctime.unwrap_or(self.time),
+			mtime: self.rr.mtime.unwrap_or(self.time),
+			atime: self.rr.atime.unwrap_or(self.time),
+			}
+	}
 }
 
 struct DirSector<'a> {
@@ -463,20 +759,22 @@ impl<'a> DirSector<'a>
 
 				let mut name = &ent[33..][..namelen];
 
+				let mut rr = RockRidge::default();
 				if let Some(skip) = self.fs.susp_len_skip {
 					let skip = skip as usize;
 					if su.len() < skip {
 						log_warning!("System use area smaller than SUSP skip value");
 						return Err(vfs::Error::InconsistentFilesystem);
 					}
+					try!(rr.parse(self.fs, &su[skip..]));
+				}
 
-					for ent in SuspIterator(&su[skip..])
-					{
-						//log_trace!("ent={:?}", ent);
-						// TODO: Need to handle this _FAR_ better
-						if let SuspItem::AlternateName(0, new_name) = ent {
-							name = new_name;
-						}
+				// Joliet names are UCS-2, except for the single-byte `.` and `..` identifiers
+				let ucs2 = self.fs.joliet && namelen > 1;
+				if ucs2 {
+					// - Strip the `;1` version suffix
+					if let Some(p) = name.chunks(2).position(|c| c == b"\0;") {
+						name = &name[.. p*2];
 					}
 				}
 
@@ -486,14 +784,224 @@ impl<'a> DirSector<'a>
 					flags: ent[25],
 					start: LittleEndian::read_u32(&ent[2..]),
 					size: LittleEndian::read_u32(&ent[10..]),
-					name: name,
+					time: recording_time(&ent[18..25]),
+					ident: name,
+					ucs2: ucs2,
 					sys_use: su,
+					rr: rr,
 					}))
 			}
 		}
 	}
 }
 
+// POSIX file type bits (from the Rock Ridge PX entry)
+const S_IFMT : u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+
+// NM entry flags
+const NM_CURRENT: u8 = 0x02;
+const NM_PARENT : u8 = 0x04;
+// SL component flags
+const SL_CONTINUE: u8 = 0x01;
+const SL_CURRENT : u8 = 0x02;
+const SL_PARENT  : u8 = 0x04;
+const SL_ROOT    : u8 = 0x08;
+const SL_VOLROOT : u8 = 0x10;
+
+/// Maximum number of SUSP continuation areas followed for one entry
+const MAX_CONTINUATIONS: usize = 16;
+
+/// Rock Ridge information for a directory entry
+#[derive(Default)]
+struct RockRidge
+{
+	/// Alternate name (NM)
+	name: Option<Vec<u8>>,
+	/// POSIX attributes (PX)
+	mode: Option<u32>,
+	n_links: Option<u32>,
+	uid: u32,
+	gid: u32,
+	/// Timestamps (TF)
+	ctime: Option<::kernel::time::Timestamp>,
+	mtime: Option<::kernel::time::Timestamp>,
+	atime: Option<::kernel::time::Timestamp>,
+	/// Symbolic link target (SL)
+	symlink: Option<Vec<u8>>,
+	/// A separator is needed before the next SL component
+	sl_sep: bool,
+	/// Location of a relocated directory (CL)
+	child_link: Option<u32>,
+	/// Logical parent of a relocated directory (PL, on its `..` entry)
+	parent_link: Option<u32>,
+	/// This is a relocated directory, hidden from its physical parent (RE)
+	relocated: bool,
+	/// Device number (PN)
+	device: Option<(u32,u32)>,
+}
+impl RockRidge
+{
+	/// Parse a SUSP area, and any continuation areas
+	fn parse(&mut self, fs: &InstanceInner, su: &[u8]) -> node::Result<()> {
+		let mut cont = self.parse_area(su);
+		let mut n_followed = 0;
+		while let Some( (lba, ofs, len) ) = cont
+		{
+			n_followed += 1;
+			if n_followed > MAX_CONTINUATIONS {
+				log_warning!("Too many SUSP continuation areas");
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			let sector = try!(fs.get_sector( try!(fs.check_lba(lba)) ));
+			if ofs as usize > sector.len() || len as usize > sector.len() - ofs as usize {
+				log_warning!("SUSP continuation area {:#x}+{}+{} overruns the sector", lba, ofs, len);
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			cont = self.parse_area(&sector[ofs as usize ..][.. len as usize]);
+		}
+		Ok( () )
+	}
+	/// Parse a single SUSP area, returning the continuation (if any)
+	fn parse_area(&mut self, data: &[u8]) -> Option<(u32, u32, u32)> {
+		let mut cont = None;
+		for ent in SuspIterator(data)
+		{
+			match ent
+			{
+			SuspItem::ContinuationEntry(lba, ofs, len) => cont = Some( (lba, ofs, len) ),
+			SuspItem::PosixMode { mode, n_links, uid, gid, .. } => {
+				self.mode = Some(mode);
+				self.n_links = Some(n_links);
+				self.uid = uid;
+				self.gid = gid;
+				},
+			SuspItem::AlternateName(flags, name) => {
+				// Names can be split over multiple entries (with the CONTINUE flag set on all but the last)
+				if flags & (NM_CURRENT|NM_PARENT) == 0 {
+					self.name.get_or_insert_with(Vec::new).extend_from_slice(name);
+				}
+				},
+			SuspItem::Symlink { components, .. } => self.add_symlink_components(components),
+			SuspItem::Timestamps { flags, data } => self.set_times(flags, data),
+			SuspItem::ChildLink(lba) => self.child_link = Some(lba),
+			SuspItem::ParentLink(lba) => self.parent_link = Some(lba),
+			SuspItem::Relocated => self.relocated = true,
+			SuspItem::DeviceNumber { high, low } => self.device = Some( (high, low) ),
+			_ => {},
+			}
+		}
+		cont
+	}
+
+	/// Append SL components to the symlink target
+	fn add_symlink_components(&mut self, mut data: &[u8]) {
+		let target = self.symlink.get_or_insert_with(Vec::new);
+		while data.len() >= 2
+		{
+			let flags = data[0];
+			let len = data[1] as usize;
+			if 2 + len > data.len() {
+				log_notice!("SL component overruns the entry");
+				break ;
+			}
+			let content = &data[2..][..len];
+			data = &data[2 + len..];
+
+			if flags & (SL_ROOT|SL_VOLROOT) != 0 {
+				target.clear();
+				target.push(b'/');
+				self.sl_sep = false;
+				continue ;
+			}
+			if self.sl_sep {
+				target.push(b'/');
+			}
+			if flags & SL_CURRENT != 0 {
+				target.push(b'.');
+			}
+			else if flags & SL_PARENT != 0 {
+				target.extend_from_slice(b"..");
+			}
+			else {
+				target.extend_from_slice(content);
+			}
+			// A component with CONTINUE set is joined to the next one
+			self.sl_sep = flags & SL_CONTINUE == 0;
+		}
+	}
+
+	/// Decode a TF entry
+	fn set_times(&mut self, flags: u8, mut data: &[u8]) {
+		// Bit 7 selects the 17-byte format, otherwise the 7-byte directory record format is used
+		let long_form = flags & 0x80 != 0;
+		let len = if long_form { 17 } else { 7 };
+		let mut creation = None;
+		let mut attributes = None;
+		// Stamps are present in bit order: creation, modify, access, attributes, backup, expiration, effective
+		for bit in 0 .. 7
+		{
+			if flags & (1 << bit) == 0 {
+				continue ;
+			}
+			if data.len() < len {
+				log_notice!("TF entry too short for flags {:#x}", flags);
+				break ;
+			}
+			let ts = if long_form { long_time(&data[..len]) } else { recording_time(&data[..len]) };
+			data = &data[len..];
+			if ts == 0 {
+				continue ;
+			}
+			match bit
+			{
+			0 => creation = Some(ts),
+			1 => self.mtime = Some(ts),
+			2 => self.atime = Some(ts),
+			3 => attributes = Some(ts),
+			_ => {},
+			}
+		}
+		// ctime is the attribute change time, falling back to creation
+		if let Some(ts) = attributes.or(creation) {
+			self.ctime = Some(ts);
+		}
+	}
+}
+
+/// Decode a directory record's recording date/time (7 bytes)
+fn recording_time(d: &[u8]) -> ::kernel::time::Timestamp {
+	if d[1] == 0 || d[2] == 0 {
+		// Unset
+		return 0;
+	}
+	let ts = ::kernel::time::timestamp_from_date(1900 + d[0] as i32, d[1], d[2], d[3], d[4], d[5]);
+	// Last byte is the offset from GMT in 15 minute intervals
+	ts - (d[6] as i8) as i64 * 15 * 60
+}
+/// Decode a 17-byte (volume descriptor format) date/time
+fn long_time(d: &[u8]) -> ::kernel::time::Timestamp {
+	// "YYYYMMDDHHMMSScc" in ASCII, followed by the GMT offset
+	let mut v = [0u32; 6];
+	for (i, dst) in v.iter_mut().enumerate()
+	{
+		let digits = if i == 0 { &d[0..4] } else { &d[2 + i*2 ..][..2] };
+		for &c in digits
+		{
+			if c < b'0' || c > b'9' {
+				return 0;
+			}
+			*dst = *dst * 10 + (c - b'0') as u32;
+		}
+	}
+	if v[1] == 0 || v[2] == 0 {
+		// Unset
+		return 0;
+	}
+	let ts = ::kernel::time::timestamp_from_date(v[0] as i32, v[1] as u8, v[2] as u8, v[3] as u8, v[4] as u8, v[5] as u8);
+	ts - (d[16] as i8) as i64 * 15 * 60
+}
+
 struct SuspIterator<'a>(&'a [u8]);
 
 #[derive(Debug)]
@@ -515,10 +1023,21 @@ enum SuspItem<'a>
 		serial_number: u32,
 		},
 	AlternateName(u8, &'a [u8]),
+	Symlink {
+		flags: u8,
+		components: &'a [u8],
+		},
 	Timestamps {
 		flags: u8,
 		data: &'a [u8],
 		},
+	DeviceNumber {
+		high: u32,
+		low: u32,
+		},
+	ChildLink(u32),
+	ParentLink(u32),
+	Relocated,
 
 	Unknown([u8; 2], u8, &'a[u8]),
 }
@@ -583,7 +1102,29 @@ impl<'a> Iterator for SuspIterator<'a>
 						data: &data[1..],
 						}
 					},
-				//b"SL" => SuspItem::Symlink(),
+				b"SL" => {
+					if data.len() < 1 { return None; }
+					SuspItem::Symlink {
+						flags: data[0],
+						components: &data[1..],
+						}
+					},
+				b"PN" => {
+					if data.len() < 2*8 { return None; }
+					SuspItem::DeviceNumber {
+						high: LittleEndian::read_u32(&data[0..]),
+						low:  LittleEndian::read_u32(&data[8..]),
+						}
+					},
+				b"CL" => {
+					if data.len() < 8 { return None; }
+					SuspItem::ChildLink( LittleEndian::read_u32(&data[0..]) )
+					},
+				b"PL" => {
+					if data.len() < 8 { return None; }
+					SuspItem::ParentLink( LittleEndian::read_u32(&data[0..]) )
+					},
+				b"RE" => SuspItem::Relocated,
 				b"NM" => {
 					if data.len() < 1 { return None; }
 					SuspItem::AlternateName(data[0], &data[1..])
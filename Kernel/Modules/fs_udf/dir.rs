@@ -0,0 +1,123 @@
+// "Tifflin" Kernel - UDF Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_udf/dir.rs
+use kernel::prelude::*;
+use kernel::lib::mem::aref::ArefBorrow;
+use kernel::vfs::{self, node};
+use kernel::lib::byte_str::ByteStr;
+use super::InstanceInner;
+use super::icb::Icb;
+use ondisk::{self, Fid};
+
+/// Maximum size of a directory (they're read in full when loaded)
+const MAX_DIR_SIZE: u64 = 16 << 20;
+
+pub struct DirNode
+{
+	id: node::InodeId,
+	metadata: node::Metadata,
+	/// Directory contents (file identifier descriptors), the filesystem is read-only so this is loaded once
+	data: Vec<u8>,
+}
+
+impl DirNode
+{
+	pub fn new_boxed(fs: ArefBorrow<InstanceInner>, id: node::InodeId, icb: Icb) -> vfs::Result<Box<DirNode>> {
+		if icb.size > MAX_DIR_SIZE {
+			log_notice!("UDF: Directory {:#x} is too large ({} bytes)", id, icb.size);
+			return Err( vfs::Error::Unknown("UDF: Directory too large") );
+		}
+		let mut data = vec![0u8; icb.size as usize];
+		try!(icb.data.read(&fs, 0, &mut data));
+		Ok(Box::new(DirNode {
+			id: id,
+			metadata: icb.metadata,
+			data: data,
+			}))
+	}
+
+	/// Parse the FID at `ofs`, returning it and the offset of the next
+	fn next_fid(&self, ofs: usize) -> vfs::Result<Option<(Fid, usize)>> {
+		if ofs >= self.data.len() {
+			return Ok(None);
+		}
+		match Fid::read(&self.data[ofs..])
+		{
+		Some(fid) => {
+			let next = ofs + fid.size;
+			Ok(Some( (fid, next) ))
+			},
+		None => {
+			log_warning!("UDF: Bad FID at offset {:#x} in directory {:#x}", ofs, self.id);
+			Err(vfs::Error::InconsistentFilesystem)
+			},
+		}
+	}
+}
+impl node::NodeBase for DirNode {
+	fn get_id(&self) -> node::InodeId {
+		self.id
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.metadata.clone() )
+	}
+}
+impl node::Dir for DirNode {
+	fn lookup(&self, name: &ByteStr) -> node::Result<node::InodeId> {
+		let mut ofs = 0;
+		while let Some((fid, next)) = try!(self.next_fid(ofs))
+		{
+			ofs = next;
+			if fid.characteristics & ondisk::FID_DELETED != 0 {
+				continue ;
+			}
+			if fid.characteristics & ondisk::FID_PARENT != 0 {
+				if name == ".." {
+					return Ok( super::addr_to_inode(fid.icb.location) );
+				}
+				continue ;
+			}
+			match ondisk::decode_dchars(fid.name)
+			{
+			Some(ref v) if &v[..] == name.as_bytes() => return Ok( super::addr_to_inode(fid.icb.location) ),
+			_ => {},
+			}
+		}
+		Err(vfs::Error::NotFound)
+	}
+	fn read(&self, start_ofs: usize, callback: &mut node::ReadDirCallback) -> node::Result<usize> {
+		let mut ofs = start_ofs;
+		while let Some((fid, next)) = try!(self.next_fid(ofs))
+		{
+			ofs = next;
+			if fid.characteristics & (ondisk::FID_DELETED|ondisk::FID_PARENT) != 0 {
+				continue ;
+			}
+			match ondisk::decode_dchars(fid.name)
+			{
+			Some(name) => if ! callback(super::addr_to_inode(fid.icb.location), &mut name.iter().cloned()) {
+				return Ok(ofs);
+				},
+			None => log_notice!("UDF: Undecodable name in directory {:#x}: {:?}", self.id, fid.name),
+			}
+		}
+		Ok(ofs)
+	}
+
+	fn create(&self, _name: &ByteStr, _nodetype: node::NodeType) -> node::Result<node::InodeId> {
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+	fn link(&self, _name: &ByteStr, _node: &dyn node::NodeBase) -> node::Result<()> {
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+	fn unlink(&self, _name: &ByteStr) -> node::Result<()> {
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+	fn rename(&self, _src_name: &ByteStr, _dst_dir: &dyn node::Dir, _dst_name: &ByteStr) -> node::Result<()> {
+		Err( vfs::Error::ReadOnlyFilesystem )
+	}
+}
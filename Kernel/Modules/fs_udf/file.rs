@@ -0,0 +1,147 @@
+// "Tifflin" Kernel - UDF Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_udf/file.rs
+use kernel::prelude::*;
+use kernel::lib::mem::aref::ArefBorrow;
+use kernel::vfs::{self, node};
+use kernel::lib::byte_str::{ByteStr,ByteString};
+use super::InstanceInner;
+use super::icb::Icb;
+use ondisk;
+
+/// Maximum size of a symbolic link's path component list
+const MAX_SYMLINK_SIZE: u64 = 4096;
+
+pub struct FileNode
+{
+	fs: ArefBorrow<InstanceInner>,
+	id: node::InodeId,
+	icb: Icb,
+}
+impl FileNode
+{
+	pub fn new_boxed(fs: ArefBorrow<InstanceInner>, id: node::InodeId, icb: Icb) -> Box<FileNode> {
+		Box::new(FileNode {
+			fs: fs,
+			id: id,
+			icb: icb,
+			})
+	}
+}
+impl node::NodeBase for FileNode {
+	fn get_id(&self) -> node::InodeId {
+		self.id
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.icb.metadata.clone() )
+	}
+}
+impl node::File for FileNode {
+	fn size(&self) -> u64 {
+		self.icb.size
+	}
+	fn truncate(&self, _newsize: u64) -> node::Result<u64> {
+		Err(vfs::Error::ReadOnlyFilesystem)
+	}
+	fn clear(&self, _ofs: u64, _size: u64) -> node::Result<()> {
+		Err(vfs::Error::ReadOnlyFilesystem)
+	}
+	fn read(&self, ofs: u64, buf: &mut [u8]) -> node::Result<usize> {
+		if ofs > self.icb.size {
+			return Err(vfs::Error::InvalidParameter);
+		}
+		let len = ::core::cmp::min(buf.len() as u64, self.icb.size - ofs) as usize;
+		try!(self.icb.data.read(&self.fs, ofs, &mut buf[..len]));
+		Ok(len)
+	}
+	fn write(&self, _ofs: u64, _buf: &[u8]) -> node::Result<usize> {
+		Err(vfs::Error::ReadOnlyFilesystem)
+	}
+}
+
+pub struct SymlinkNode
+{
+	id: node::InodeId,
+	metadata: node::Metadata,
+	target: Vec<u8>,
+}
+impl SymlinkNode
+{
+	pub fn new_boxed(fs: &InstanceInner, id: node::InodeId, icb: Icb) -> vfs::Result<Box<SymlinkNode>> {
+		if icb.size > MAX_SYMLINK_SIZE {
+			log_notice!("UDF: Symbolic link {:#x} is too large ({} bytes)", id, icb.size);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let mut data = vec![0u8; icb.size as usize];
+		try!(icb.data.read(fs, 0, &mut data));
+
+		// The data is a list of path components (ECMA-167 4/14.16)
+		let mut target = Vec::new();
+		let mut ofs = 0;
+		while ofs + 4 <= data.len()
+		{
+			let ty = data[ofs];
+			let len = data[ofs+1] as usize;
+			if ofs + 4 + len > data.len() {
+				log_notice!("UDF: Symbolic link {:#x} component overruns the data", id);
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			let ident = &data[ofs + 4..][..len];
+			ofs += 4 + len;
+
+			let component = match ty
+				{
+				// Root (of the implementation, or this volume)
+				1 | 2 => {
+					target.clear();
+					target.push(b'/');
+					continue ;
+					},
+				3 => Vec::from(&b".."[..]),
+				4 => Vec::from(&b"."[..]),
+				5 => match ondisk::decode_dchars(ident)
+					{
+					Some(v) => v,
+					None => {
+						log_notice!("UDF: Symbolic link {:#x} has an undecodable component", id);
+						return Err(vfs::Error::InconsistentFilesystem);
+						},
+					},
+				_ => {
+					log_notice!("UDF: Symbolic link {:#x} has unknown component type {}", id, ty);
+					return Err(vfs::Error::InconsistentFilesystem);
+					},
+				};
+			if target.len() > 0 && target.last() != Some(&b'/') {
+				target.push(b'/');
+			}
+			target.extend_from_slice(&component);
+		}
+
+		Ok(Box::new(SymlinkNode {
+			id: id,
+			metadata: icb.metadata,
+			target: target,
+			}))
+	}
+}
+impl node::NodeBase for SymlinkNode {
+	fn get_id(&self) -> node::InodeId {
+		self.id
+	}
+	fn get_any(&self) -> &dyn core::any::Any {
+		self
+	}
+	fn get_metadata(&self) -> node::Result<node::Metadata> {
+		Ok( self.metadata.clone() )
+	}
+}
+impl node::Symlink for SymlinkNode {
+	fn read(&self) -> ByteString {
+		ByteString::from( ByteStr::new(&self.target) )
+	}
+}
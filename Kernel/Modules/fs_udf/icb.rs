@@ -0,0 +1,250 @@
+// "Tifflin" Kernel - UDF Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_udf/icb.rs
+//! File entries (ICBs) and allocation descriptors
+use kernel::prelude::*;
+use kernel::vfs::{self, node};
+use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+use super::InstanceInner;
+use ondisk::{self, Tag, LbAddr};
+
+/// Maximum number of allocation extent descriptors followed for one file
+const MAX_AED_CHAIN: usize = 4096;
+
+/// A loaded file entry
+pub struct Icb
+{
+	pub file_type: u8,
+	/// Information length (size of the data)
+	pub size: u64,
+	pub metadata: node::Metadata,
+	pub data: Data,
+}
+/// Location of a file's data
+pub enum Data
+{
+	/// Stored in the file entry's allocation descriptor area
+	Embedded(Vec<u8>),
+	Extents(Vec<Extent>),
+}
+#[derive(Debug,Copy,Clone)]
+pub struct Extent
+{
+	/// Length in bytes
+	pub length: u32,
+	/// Extent is recorded (otherwise it reads as zeroes)
+	pub recorded: bool,
+	pub location: LbAddr,
+}
+
+impl Icb
+{
+	/// Load a file entry or extended file entry
+	pub fn load(fs: &InstanceInner, addr: LbAddr) -> vfs::Result<Icb> {
+		let mut buf = vec![0u8; fs.lb_size];
+		try!(fs.read_block(addr, &mut buf));
+		let tag = match Tag::read(&buf)
+			{
+			Some(v) => v,
+			None => {
+				log_notice!("UDF: Bad tag on ICB {:?}", addr);
+				return Err(vfs::Error::InconsistentFilesystem);
+				},
+			};
+		// The two entry types have the same fields (EFE adds some), at different offsets
+		let is_efe = match tag.ident
+			{
+			ondisk::TAG_FE => false,
+			ondisk::TAG_EFE => true,
+			v => {
+				log_notice!("UDF: ICB {:?} has unexpected tag {}", addr, v);
+				return Err(vfs::Error::InconsistentFilesystem);
+				},
+			};
+		if tag.location != addr.block || !tag.check_crc(&buf) {
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+
+		// ICB tag
+		let strategy = LittleEndian::read_u16(&buf[20..]);
+		let file_type = buf[27];
+		let flags = LittleEndian::read_u16(&buf[34..]);
+		// - Strategy 4096 (used by some writers) also records the entry directly
+		if strategy != 4 && strategy != 4096 {
+			log_notice!("UDF: ICB {:?} uses unsupported strategy {}", addr, strategy);
+			return Err( vfs::Error::Unknown("UDF: Unsupported ICB strategy") );
+		}
+
+		let (ea_len_ofs, hdr_len) = if is_efe { (208, 216) } else { (168, 176) };
+		let ea_len = LittleEndian::read_u32(&buf[ea_len_ofs..]) as usize;
+		let ad_len = LittleEndian::read_u32(&buf[ea_len_ofs + 4..]) as usize;
+		if hdr_len + ea_len + ad_len > buf.len() {
+			log_notice!("UDF: ICB {:?} EA/AD lengths ({}+{}) overrun the block", addr, ea_len, ad_len);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let ads = &buf[hdr_len + ea_len ..][.. ad_len];
+
+		let size = LittleEndian::read_u64(&buf[56..]);
+		let (atime_ofs, mtime_ofs, ctime_ofs) = if is_efe { (80, 92, 116) } else { (72, 84, 96) };
+		// - 0xFFFFFFFF is an invalid (unset) ID
+		let id = |v: u32| if v == !0 { 0 } else { v };
+		let metadata = node::Metadata {
+			size: size,
+			link_count: LittleEndian::read_u16(&buf[48..]) as u32,
+			uid: id( LittleEndian::read_u32(&buf[36..]) ),
+			gid: id( LittleEndian::read_u32(&buf[40..]) ),
+			permissions: ondisk::unix_permissions(LittleEndian::read_u32(&buf[44..]), flags),
+			ctime: ondisk::timestamp(&buf[ctime_ofs..]),
+			mtime: ondisk::timestamp(&buf[mtime_ofs..]),
+			atime: ondisk::timestamp(&buf[atime_ofs..]),
+			};
+
+		let data = match flags & ondisk::ICB_FLAG_AD_MASK
+			{
+			ondisk::AD_EMBEDDED => {
+				if size > ads.len() as u64 {
+					log_notice!("UDF: ICB {:?} embedded data size {} > {}", addr, size, ads.len());
+					return Err(vfs::Error::InconsistentFilesystem);
+				}
+				Data::Embedded( Vec::from(&ads[..size as usize]) )
+				},
+			ad_type => Data::Extents( try!(read_ads(fs, ad_type, ads, addr.partition)) ),
+			};
+
+		Ok(Icb {
+			file_type: file_type,
+			size: size,
+			metadata: metadata,
+			data: data,
+			})
+	}
+}
+
+/// Decode allocation descriptors (following any allocation extent descriptors)
+fn read_ads(fs: &InstanceInner, ad_type: u16, ads: &[u8], partition: u16) -> vfs::Result<Vec<Extent>> {
+	let ad_size = match ad_type
+		{
+		ondisk::AD_SHORT => ondisk::ShortAd::SIZE,
+		ondisk::AD_LONG => ondisk::LongAd::SIZE,
+		ondisk::AD_EXTENDED => return Err( vfs::Error::Unknown("UDF: Extended allocation descriptors aren't supported") ),
+		_ => return Err(vfs::Error::InconsistentFilesystem),
+		};
+
+	let mut rv = Vec::new();
+	let mut cur = Vec::from(ads);
+	// Short descriptors are in the same partition as the descriptor area
+	let mut partition = partition;
+	let mut n_aeds = 0;
+	loop
+	{
+		let mut next = None;
+		for ad in cur.chunks(ad_size)
+		{
+			if ad.len() < ad_size {
+				break ;
+			}
+			let (length, location) = if ad_type == ondisk::AD_SHORT {
+					let v = ondisk::ShortAd::read(ad);
+					(v.length, LbAddr { block: v.position, partition: partition })
+				}
+				else {
+					let v = ondisk::LongAd::read(ad);
+					(v.length, v.location)
+				};
+			let len = length & ondisk::EXT_LEN_MASK;
+			// A zero length ends the descriptors
+			if len == 0 {
+				break ;
+			}
+			match length >> 30
+			{
+			ondisk::EXT_NEXT_ADS => {
+				next = Some(location);
+				break ;
+				},
+			ondisk::EXT_RECORDED => rv.push(Extent { length: len, recorded: true, location: location }),
+			_ => rv.push(Extent { length: len, recorded: false, location: location }),
+			}
+		}
+
+		let loc = match next
+			{
+			Some(v) => v,
+			None => break,
+			};
+		n_aeds += 1;
+		if n_aeds > MAX_AED_CHAIN {
+			log_notice!("UDF: Too many allocation extent descriptors");
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let mut blk = vec![0u8; fs.lb_size];
+		try!(fs.read_block(loc, &mut blk));
+		if Tag::read_checked(&blk, ondisk::TAG_AED, loc.block).is_none() {
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let len = LittleEndian::read_u32(&blk[20..]) as usize;
+		if 24 + len > blk.len() {
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		cur = Vec::from(&blk[24..][..len]);
+		partition = loc.partition;
+	}
+	Ok(rv)
+}
+
+impl Data
+{
+	/// Get the extent list (fails for embedded data)
+	pub fn extents(&self) -> vfs::Result<&[Extent]> {
+		match *self
+		{
+		Data::Embedded(_) => Err(vfs::Error::InconsistentFilesystem),
+		Data::Extents(ref v) => Ok(v),
+		}
+	}
+	/// Read data, anything past the recorded data reads as zero
+	pub fn read(&self, fs: &InstanceInner, ofs: u64, dst: &mut [u8]) -> vfs::Result<()> {
+		let done = match *self
+			{
+			Data::Embedded(ref data) => {
+				let bytes = if ofs >= data.len() as u64 { 0 } else { ::core::cmp::min(data.len() - ofs as usize, dst.len()) };
+				if bytes > 0 {
+					dst[..bytes].clone_from_slice( &data[ofs as usize..][..bytes] );
+				}
+				bytes
+				},
+			Data::Extents(ref extents) => {
+				let mut pos = 0;
+				let mut done = 0;
+				for e in extents
+				{
+					if done == dst.len() {
+						break;
+					}
+					let end = pos + e.length as u64;
+					let cur = ofs + done as u64;
+					if cur < end {
+						let bytes = ::core::cmp::min((end - cur) as usize, dst.len() - done);
+						let dst = &mut dst[done..][..bytes];
+						if e.recorded {
+							try!(fs.read_extent(e.location, cur - pos, dst));
+						}
+						else {
+							for b in dst.iter_mut() {
+								*b = 0;
+							}
+						}
+						done += bytes;
+					}
+					pos = end;
+				}
+				done
+				},
+			};
+		for b in dst[done..].iter_mut() {
+			*b = 0;
+		}
+		Ok( () )
+	}
+}
@@ -0,0 +1,590 @@
+// "Tifflin" Kernel - UDF Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_udf/lib.rs
+//! UDF (Universal Disk Format) filesystem driver, read-only
+#![feature(linkage)]
+#![no_std]
+
+#[macro_use] extern crate kernel;
+use kernel::prelude::*;
+
+use kernel::vfs::{self, mount, node};
+use kernel::metadevs::storage::VolumeHandle;
+use kernel::lib::mem::aref::ArefInner;
+use kernel::lib::byteorder::{ByteOrder,LittleEndian};
+use ondisk::{Tag,LbAddr};
+
+extern crate utf16;
+extern crate block_cache;
+
+module_define!{FS_UDF, [VFS], init}
+
+/// on-disk structures
+mod ondisk;
+/// File entries (ICBs) and allocation descriptors
+mod icb;
+/// Directory nodes
+mod dir;
+/// File and symbolic link nodes
+mod file;
+
+/// Maximum number of volume recognition sequence descriptors checked
+const MAX_VRS_LEN: usize = 64;
+/// Maximum number of volume descriptor pointers followed
+const MAX_VDS_EXTENTS: usize = 16;
+
+/// Driver strucutre
+struct Driver;
+static S_DRIVER: Driver = Driver;
+
+struct Instance(ArefInner<InstanceInner>);
+impl ::core::ops::Deref for Instance {
+	type Target = InstanceInner;
+	fn deref(&self) -> &InstanceInner { &self.0 }
+}
+
+pub struct InstanceInner
+{
+	vh: ::block_cache::CacheHandle,
+	/// Logical block (and sector) size
+	lb_size: usize,
+	/// Volume blocks per sector
+	scale: u64,
+	/// Partitions, indexed by partition reference number (the index of the map in the LVD)
+	partitions: Vec<Partition>,
+	/// ICB of the root directory
+	root: LbAddr,
+}
+
+/// Mapping from partition blocks to sectors
+enum Partition
+{
+	/// Type 1 map, blocks are contiguous from `start`
+	Physical {
+		start: u32,
+		length: u32,
+	},
+	/// Sparable partition (rewritable media), packets can be relocated by the sparing table
+	Sparable {
+		start: u32,
+		length: u32,
+		packet_len: u32,
+		/// Relocated packets (original block, new sector)
+		table: Vec<(u32,u32)>,
+	},
+	/// Metadata partition (UDF 2.50), blocks are the contents of the metadata file
+	Metadata {
+		/// Partition holding the metadata file
+		underlying: u16,
+		/// Extents of the metadata file (first block, block count)
+		extents: Vec<(u32,u32)>,
+	},
+}
+
+/// The interesting parts of the volume descriptor sequence
+struct Vds
+{
+	lvd: ondisk::LogicalVolumeDesc,
+	/// Raw LVD (the partition maps are variable length)
+	lvd_raw: Vec<u8>,
+	pds: Vec<ondisk::PartitionDesc>,
+}
+
+fn init()
+{
+	let h = mount::DriverRegistration::new("udf", &S_DRIVER);
+	// TODO: Remember the registration for unloading?
+	::core::mem::forget(h);
+}
+
+impl mount::Driver for Driver
+{
+	fn detect(&self, vol: &VolumeHandle) -> vfs::Result<usize> {
+		// Prefer UDF over ISO9660 on bridge discs (ISO9660 returns 1)
+		if try!(has_nsr_descriptor(vol)) {
+			Ok(2)
+		}
+		else {
+			Ok(0)
+		}
+	}
+	fn mount(&self, vol: VolumeHandle, _mounthandle: mount::SelfHandle, _options: &[&str]) -> vfs::Result<Box<dyn mount::Filesystem>> {
+		if ! try!(has_nsr_descriptor(&vol)) {
+			return Err( vfs::Error::Unknown("UDF: No NSR descriptor in the volume recognition sequence") );
+		}
+		// Locate the anchor (which also determines the sector size)
+		let (sector_size, avdp) = match try!(find_anchor(&vol))
+			{
+			Some(v) => v,
+			None => return Err( vfs::Error::Unknown("UDF: Can't find the anchor volume descriptor pointer") ),
+			};
+		let scale = (sector_size / vol.block_size()) as u64;
+
+		let mut inner = InstanceInner {
+			vh: ::block_cache::CacheHandle::new(vol),
+			lb_size: sector_size,
+			scale: scale,
+			partitions: Vec::new(),
+			root: LbAddr { block: 0, partition: 0 },
+			};
+
+		// Read the volume descriptor sequence (falling back to the reserve copy)
+		let vds = match inner.read_vds(avdp.main_vds)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_notice!("UDF: Main volume descriptor sequence unusable ({:?}), trying the reserve", e);
+				try!(inner.read_vds(avdp.reserve_vds))
+				},
+			};
+		if ! vds.lvd.is_udf {
+			return Err( vfs::Error::Unknown("UDF: Logical volume isn't UDF compliant") );
+		}
+		if vds.lvd.udf_revision > 0x0260 {
+			log_notice!("UDF: Revision {:x} is newer than supported, some files may be inaccessible", vds.lvd.udf_revision);
+		}
+		if vds.lvd.block_size as usize != sector_size {
+			log_notice!("UDF: Logical block size {} != sector size {}", vds.lvd.block_size, sector_size);
+			return Err( vfs::Error::Unknown("UDF: Logical block size doesn't match the sector size") );
+		}
+		try!(inner.load_partitions(&vds));
+
+		// Read the file set descriptor to get the root directory
+		let fsd_addr = vds.lvd.fsd.location;
+		let mut buf = vec![0u8; inner.lb_size];
+		try!(inner.read_block(fsd_addr, &mut buf));
+		if Tag::read_checked(&buf, ondisk::TAG_FSD, fsd_addr.block).is_none() {
+			return Err( vfs::Error::Unknown("UDF: Invalid file set descriptor") );
+		}
+		inner.root = ondisk::LongAd::read(&buf[400..]).location;
+
+		log_debug!("UDF revision {:x}, {} byte blocks, {} partitions, root = {:?}",
+			vds.lvd.udf_revision, inner.lb_size, inner.partitions.len(), inner.root);
+
+		// SAFE: Stored in a box, and not moved out.
+		Ok( Box::new( Instance(unsafe { ArefInner::new( inner ) }) ) )
+	}
+}
+
+/// Check the volume recognition sequence for a NSR (UDF) descriptor
+fn has_nsr_descriptor(vol: &VolumeHandle) -> vfs::Result<bool> {
+	let bs = vol.block_size();
+	// Descriptors are 2048 bytes, or a sector if sectors are larger
+	let desc_size = ::core::cmp::max(2048, bs);
+	if 32*1024 % bs != 0 {
+		return Ok(false);
+	}
+	let mut buf = vec![0u8; desc_size];
+	let mut in_extended_area = false;
+	for i in 0 .. MAX_VRS_LEN
+	{
+		let ofs = 32*1024 + i * desc_size;
+		try!(vol.read_blocks((ofs / bs) as u64, &mut buf));
+		match &buf[1..6]
+		{
+		b"BEA01" => in_extended_area = true,
+		b"TEA01" => break,
+		b"NSR02" | b"NSR03" => if in_extended_area {
+			return Ok(true);
+			},
+		b"CD001" | b"CDW02" | b"BOOT2" => {},
+		// End of the sequence
+		_ => break,
+		}
+	}
+	Ok(false)
+}
+
+/// Search for an anchor volume descriptor pointer, trying likely sector sizes
+fn find_anchor(vol: &VolumeHandle) -> vfs::Result<Option<(usize, ondisk::Avdp)>> {
+	let vol_bs = vol.block_size();
+	let candidates = [vol_bs, 2048, 512, 1024, 4096];
+	for (i, &size) in candidates.iter().enumerate()
+	{
+		if candidates[..i].contains(&size) || size < vol_bs || size % vol_bs != 0 {
+			continue ;
+		}
+		let scale = (size / vol_bs) as u64;
+		let n_sectors = vol.block_count() / scale;
+		let mut buf = vec![0u8; size];
+		// - The anchor is at sector 256, and optionally the last sector and 256 sectors before it
+		for &sector in &[ondisk::AVDP_SECTOR, n_sectors.wrapping_sub(1), n_sectors.wrapping_sub(1 + ondisk::AVDP_SECTOR)]
+		{
+			if sector >= n_sectors {
+				continue ;
+			}
+			// Read errors are ignored (the end of an optical disc might not be readable)
+			if vol.read_blocks(sector * scale, &mut buf).is_err() {
+				continue ;
+			}
+			if Tag::read_checked(&buf, ondisk::TAG_AVDP, sector as u32).is_some() {
+				return Ok(Some( (size, ondisk::Avdp::read(&buf)) ));
+			}
+		}
+	}
+	Ok(None)
+}
+
+impl mount::Filesystem for Instance
+{
+	fn root_inode(&self) -> node::InodeId {
+		addr_to_inode(self.root)
+	}
+	fn get_node_by_inode(&self, id: node::InodeId) -> Option<node::Node> {
+		let icb = match icb::Icb::load(&self.0, inode_to_addr(id))
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_notice!("UDF: Unable to load inode {:#x}: {:?}", id, e);
+				return None;
+				},
+			};
+		let rv = match icb.file_type
+			{
+			ondisk::FT_DIRECTORY => dir::DirNode::new_boxed(self.0.borrow(), id, icb).map(|v| node::Node::Dir(v)),
+			ondisk::FT_FILE => Ok( node::Node::File(file::FileNode::new_boxed(self.0.borrow(), id, icb)) ),
+			ondisk::FT_SYMLINK => file::SymlinkNode::new_boxed(&self.0, id, icb).map(|v| node::Node::Symlink(v)),
+			t => {
+				log_notice!("UDF: Inode {:#x} has unsupported file type {}", id, t);
+				return None;
+				},
+			};
+		match rv
+		{
+		Ok(v) => Some(v),
+		Err(e) => {
+			log_notice!("UDF: Unable to load inode {:#x}: {:?}", id, e);
+			None
+			},
+		}
+	}
+}
+
+/// Inode numbers are the address of the node's ICB
+fn addr_to_inode(addr: LbAddr) -> node::InodeId {
+	(addr.partition as u64) << 32 | addr.block as u64
+}
+fn inode_to_addr(id: node::InodeId) -> LbAddr {
+	LbAddr {
+		block: id as u32,
+		partition: (id >> 32) as u16,
+	}
+}
+
+impl InstanceInner
+{
+	/// Read a volume descriptor sequence
+	fn read_vds(&self, extent: ondisk::ExtentAd) -> vfs::Result<Vds> {
+		let mut buf = vec![0u8; self.lb_size];
+		let mut lvd: Option<Vds> = None;
+		let mut pds: Vec<ondisk::PartitionDesc> = Vec::new();
+		let mut extent = extent;
+		let mut n_extents = 0;
+		'outer: loop
+		{
+			for i in 0 .. extent.length / self.lb_size as u32
+			{
+				let sector = extent.location + i;
+				try!(self.read_sector(sector as u64, &mut buf));
+				let ident = match Tag::read(&buf)
+					{
+					Some(t) => t.ident,
+					None => {
+						log_notice!("UDF: Bad descriptor tag at sector {:#x}", sector);
+						return Err(vfs::Error::InconsistentFilesystem);
+						},
+					};
+				// An unrecorded sector ends the sequence (like a terminating descriptor)
+				if ident == 0 {
+					break 'outer;
+				}
+				if Tag::read_checked(&buf, ident, sector).is_none() {
+					return Err(vfs::Error::InconsistentFilesystem);
+				}
+				match ident
+				{
+				ondisk::TAG_PD => {
+					// Use the descriptor with the highest sequence number
+					let pd = ondisk::PartitionDesc::read(&buf);
+					match pds.iter().position(|v| v.number == pd.number)
+					{
+					Some(i) => if pd.vds_seq >= pds[i].vds_seq {
+						pds[i] = pd;
+						},
+					None => pds.push(pd),
+					}
+					},
+				ondisk::TAG_LVD => {
+					let d = ondisk::LogicalVolumeDesc::read(&buf);
+					if lvd.as_ref().map_or(true, |v| d.vds_seq >= v.lvd.vds_seq) {
+						lvd = Some(Vds { lvd: d, lvd_raw: buf.clone(), pds: Vec::new() });
+					}
+					},
+				ondisk::TAG_VDP => {
+					n_extents += 1;
+					if n_extents > MAX_VDS_EXTENTS {
+						log_notice!("UDF: Too many volume descriptor pointers");
+						return Err(vfs::Error::InconsistentFilesystem);
+					}
+					extent = ondisk::ExtentAd::read(&buf[20..]);
+					continue 'outer;
+					},
+				ondisk::TAG_TD => break 'outer,
+				// Primary/implementation use/unallocated space descriptors aren't needed
+				_ => {},
+				}
+			}
+			break ;
+		}
+
+		match lvd
+		{
+		Some(mut v) => {
+			v.pds = pds;
+			Ok(v)
+			},
+		None => {
+			log_notice!("UDF: No logical volume descriptor");
+			Err(vfs::Error::InconsistentFilesystem)
+			},
+		}
+	}
+
+	/// Populate the partition list from the LVD's partition maps
+	fn load_partitions(&mut self, vds: &Vds) -> vfs::Result<()> {
+		let table_len = vds.lvd.map_table_len as usize;
+		if ondisk::LVD_MAPS_OFS + table_len > vds.lvd_raw.len() {
+			log_notice!("UDF: Partition map table length {} is too large", table_len);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let table = &vds.lvd_raw[ondisk::LVD_MAPS_OFS..][..table_len];
+
+		// Split the table into maps
+		let mut maps = Vec::new();
+		let mut ofs = 0;
+		for _ in 0 .. vds.lvd.num_maps
+		{
+			if ofs + 2 > table.len() || table[ofs+1] < 2 || ofs + table[ofs+1] as usize > table.len() {
+				log_notice!("UDF: Partition map {} overruns the table", maps.len());
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			let len = table[ofs+1] as usize;
+			maps.push( &table[ofs..][..len] );
+			ofs += len;
+		}
+		let find_pd = |number: u16| match vds.pds.iter().find(|v| v.number == number)
+			{
+			Some(v) => Ok(v),
+			None => {
+				log_notice!("UDF: No partition descriptor for partition {}", number);
+				Err(vfs::Error::InconsistentFilesystem)
+				},
+			};
+
+		// Physical and sparable partitions first, metadata partitions need them to read the metadata file
+		let mut metadata_maps = Vec::new();
+		for (idx, map) in maps.iter().enumerate()
+		{
+			let part = match (map[0], map.len())
+				{
+				(ondisk::PMAP_TYPE1, 6) => {
+					let pd = try!(find_pd( LittleEndian::read_u16(&map[4..]) ));
+					Partition::Physical { start: pd.start, length: pd.length }
+					},
+				(ondisk::PMAP_TYPE2, 64) if ondisk::regid_is(&map[4..], ondisk::PMAP_SPARABLE) => {
+					let pd = try!(find_pd( LittleEndian::read_u16(&map[38..]) ));
+					let packet_len = LittleEndian::read_u16(&map[40..]) as u32;
+					if packet_len == 0 || packet_len & (packet_len - 1) != 0 {
+						log_notice!("UDF: Invalid sparable partition packet length {}", packet_len);
+						return Err(vfs::Error::InconsistentFilesystem);
+					}
+					let n_tables = ::core::cmp::min(map[42] as usize, 4);
+					let table_size = LittleEndian::read_u32(&map[44..]);
+					let locations: Vec<u32> = (0 .. n_tables).map(|i| LittleEndian::read_u32(&map[48 + i*4..])).collect();
+					Partition::Sparable {
+						start: pd.start,
+						length: pd.length,
+						packet_len: packet_len,
+						table: try!(self.read_sparing_table(&locations, table_size)),
+						}
+					},
+				(ondisk::PMAP_TYPE2, 64) if ondisk::regid_is(&map[4..], ondisk::PMAP_METADATA) => {
+					let number = LittleEndian::read_u16(&map[38..]);
+					let file = LittleEndian::read_u32(&map[40..]);
+					let mirror = LittleEndian::read_u32(&map[44..]);
+					metadata_maps.push( (idx, number, file, mirror) );
+					Partition::Metadata { underlying: 0, extents: Vec::new() }
+					},
+				(ondisk::PMAP_TYPE2, 64) if ondisk::regid_is(&map[4..], ondisk::PMAP_VIRTUAL) => {
+					return Err( vfs::Error::Unknown("UDF: Virtual partitions (write-once media) aren't supported") );
+					},
+				(ty, len) => {
+					log_notice!("UDF: Unknown partition map type {} (length {})", ty, len);
+					return Err( vfs::Error::Unknown("UDF: Unknown partition map type") );
+					},
+				};
+			self.partitions.push(part);
+		}
+
+		for (idx, number, file, mirror) in metadata_maps
+		{
+			// Find the map for the underlying partition
+			let underlying = match maps.iter().position(|m| (m[0] == ondisk::PMAP_TYPE1 && LittleEndian::read_u16(&m[4..]) == number)
+					|| (m[0] == ondisk::PMAP_TYPE2 && ondisk::regid_is(&m[4..], ondisk::PMAP_SPARABLE) && LittleEndian::read_u16(&m[38..]) == number))
+				{
+				Some(v) => v as u16,
+				None => {
+					log_notice!("UDF: No map for metadata partition's underlying partition {}", number);
+					return Err(vfs::Error::InconsistentFilesystem);
+					},
+				};
+			let extents = match self.read_metadata_extents(underlying, file)
+				{
+				Ok(v) => v,
+				Err(e) => {
+					log_notice!("UDF: Metadata file unusable ({:?}), trying the mirror", e);
+					try!(self.read_metadata_extents(underlying, mirror))
+					},
+				};
+			self.partitions[idx] = Partition::Metadata { underlying: underlying, extents: extents };
+		}
+		Ok( () )
+	}
+	/// Read the first valid sparing table
+	fn read_sparing_table(&self, locations: &[u32], size: u32) -> vfs::Result<Vec<(u32,u32)>> {
+		let n_sectors = (size as usize + self.lb_size - 1) / self.lb_size;
+		let mut buf = vec![0u8; n_sectors * self.lb_size];
+		for &loc in locations
+		{
+			try!(self.read_sectors(loc as u64, &mut buf));
+			// - The sparing table's tag identifier is zero
+			if Tag::read_checked(&buf, 0, loc).is_none() || !ondisk::regid_is(&buf[16..], ondisk::SPARING_TABLE) {
+				log_notice!("UDF: Sparing table at {:#x} is invalid", loc);
+				continue ;
+			}
+			let n_ents = LittleEndian::read_u16(&buf[48..]) as usize;
+			if 56 + n_ents * 8 > buf.len() {
+				log_notice!("UDF: Sparing table at {:#x} has too many entries ({})", loc, n_ents);
+				continue ;
+			}
+			let table = (0 .. n_ents)
+				.map(|i| (LittleEndian::read_u32(&buf[56 + i*8..]), LittleEndian::read_u32(&buf[56 + i*8 + 4..])))
+				// - 0xFFFFFFF0 and above are available/defective packets
+				.filter(|&(orig,_)| orig < 0xFFFF_FFF0)
+				.collect();
+			return Ok(table);
+		}
+		Err( vfs::Error::Unknown("UDF: No valid sparing table") )
+	}
+	/// Read the extents of a metadata file (in the `underlying` partition)
+	fn read_metadata_extents(&self, underlying: u16, block: u32) -> vfs::Result<Vec<(u32,u32)>> {
+		let icb = try!(icb::Icb::load(self, LbAddr { block: block, partition: underlying }));
+		if icb.file_type != ondisk::FT_METADATA && icb.file_type != ondisk::FT_METADATA_MIRROR {
+			log_notice!("UDF: Metadata file has file type {}", icb.file_type);
+			return Err(vfs::Error::InconsistentFilesystem);
+		}
+		let mut rv = Vec::new();
+		for e in try!(icb.data.extents())
+		{
+			if !e.recorded || e.location.partition != underlying || e.length as usize % self.lb_size != 0 {
+				log_notice!("UDF: Unexpected metadata file extent {:?}", e);
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			rv.push( (e.location.block, e.length / self.lb_size as u32) );
+		}
+		Ok(rv)
+	}
+
+	/// Get the sector holding a partition block
+	fn map_block(&self, partition: u16, block: u32) -> vfs::Result<u64> {
+		match self.partitions.get(partition as usize)
+		{
+		Some(&Partition::Physical { start, length }) => {
+			if block >= length {
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			Ok(start as u64 + block as u64)
+			},
+		Some(&Partition::Sparable { start, length, packet_len, ref table }) => {
+			if block >= length {
+				return Err(vfs::Error::InconsistentFilesystem);
+			}
+			let packet = block & !(packet_len - 1);
+			match table.iter().find(|&&(orig,_)| orig == packet)
+			{
+			Some(&(_, mapped)) => Ok(mapped as u64 + (block - packet) as u64),
+			None => Ok(start as u64 + block as u64),
+			}
+			},
+		Some(&Partition::Metadata { underlying, ref extents }) => {
+			let mut block = block;
+			for &(first, count) in extents
+			{
+				if block < count {
+					return self.map_block(underlying, first + block);
+				}
+				block -= count;
+			}
+			Err(vfs::Error::InconsistentFilesystem)
+			},
+		None => {
+			log_notice!("UDF: Reference to nonexistent partition {}", partition);
+			Err(vfs::Error::InconsistentFilesystem)
+			},
+		}
+	}
+
+	/// Read whole sectors directly from the volume
+	fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> vfs::Result<()> {
+		try!(self.vh.read_blocks(sector * self.scale, buf));
+		Ok( () )
+	}
+	/// Read a sector via the block cache (used for metadata)
+	fn read_sector(&self, sector: u64, buf: &mut [u8]) -> vfs::Result<()> {
+		assert_eq!(buf.len(), self.lb_size);
+		for (i, dst) in buf.chunks_mut(self.vh.block_size()).enumerate()
+		{
+			try!(self.vh.read_inner(sector * self.scale + i as u64, 0, dst));
+		}
+		Ok( () )
+	}
+	/// Read a logical block via the block cache
+	fn read_block(&self, addr: LbAddr, buf: &mut [u8]) -> vfs::Result<()> {
+		let sector = try!(self.map_block(addr.partition, addr.block));
+		self.read_sector(sector, buf)
+	}
+	/// Read data from a recorded extent starting at `start`
+	fn read_extent(&self, start: LbAddr, ofs: u64, dst: &mut [u8]) -> vfs::Result<()> {
+		let lb_size = self.lb_size;
+		let mut ofs = ofs;
+		let mut done = 0;
+		while done < dst.len()
+		{
+			let block = start.block + (ofs / lb_size as u64) as u32;
+			let in_ofs = (ofs % lb_size as u64) as usize;
+			let sector = try!(self.map_block(start.partition, block));
+			let bytes = if in_ofs == 0 && dst.len() - done >= lb_size {
+					// Read as many physically contiguous whole blocks as possible directly
+					let max = (dst.len() - done) / lb_size;
+					let mut count = 1;
+					while count < max && try!(self.map_block(start.partition, block + count as u32)) == sector + count as u64 {
+						count += 1;
+					}
+					try!(self.read_sectors(sector, &mut dst[done..][..count * lb_size]));
+					count * lb_size
+				}
+				else {
+					// Partial block, bounce through the cache
+					let mut tmp = vec![0u8; lb_size];
+					try!(self.read_sector(sector, &mut tmp));
+					let bytes = ::core::cmp::min(lb_size - in_ofs, dst.len() - done);
+					dst[done..][..bytes].clone_from_slice(&tmp[in_ofs..][..bytes]);
+					bytes
+				};
+			done += bytes;
+			ofs += bytes as u64;
+		}
+		Ok( () )
+	}
+}
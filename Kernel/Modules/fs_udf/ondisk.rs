@@ -0,0 +1,384 @@
+// "Tifflin" Kernel - UDF Filesystem Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/fs_udf/ondisk.rs
+//! On-disk structures (ECMA-167 and the OSTA UDF specification)
+#[allow(unused_imports)]
+use kernel::prelude::*;
+use kernel::lib::byteorder::{ByteOrder,LittleEndian,BigEndian};
+use utf16::Str16;
+
+// Descriptor tag identifiers
+pub const TAG_AVDP: u16 = 2;
+pub const TAG_VDP: u16 = 3;
+pub const TAG_PD: u16 = 5;
+pub const TAG_LVD: u16 = 6;
+pub const TAG_TD: u16 = 8;
+pub const TAG_FSD: u16 = 256;
+pub const TAG_FID: u16 = 257;
+pub const TAG_AED: u16 = 258;
+pub const TAG_FE: u16 = 261;
+pub const TAG_EFE: u16 = 266;
+
+/// Sector holding the (first) anchor volume descriptor pointer
+pub const AVDP_SECTOR: u64 = 256;
+
+// ICB file types
+pub const FT_DIRECTORY: u8 = 4;
+pub const FT_FILE: u8 = 5;
+pub const FT_SYMLINK: u8 = 12;
+pub const FT_METADATA: u8 = 250;
+pub const FT_METADATA_MIRROR: u8 = 251;
+
+// ICB flags
+/// Mask for the allocation descriptor type
+pub const ICB_FLAG_AD_MASK: u16 = 0x0007;
+pub const ICB_FLAG_SETUID: u16 = 0x0040;
+pub const ICB_FLAG_SETGID: u16 = 0x0080;
+pub const ICB_FLAG_STICKY: u16 = 0x0100;
+
+// Allocation descriptor types
+pub const AD_SHORT: u16 = 0;
+pub const AD_LONG: u16 = 1;
+pub const AD_EXTENDED: u16 = 2;
+pub const AD_EMBEDDED: u16 = 3;
+
+// Extent types (top two bits of the extent length)
+/// Recorded and allocated
+pub const EXT_RECORDED: u32 = 0;
+/// Next extent of allocation descriptors
+pub const EXT_NEXT_ADS: u32 = 3;
+/// Mask for the extent length
+pub const EXT_LEN_MASK: u32 = 0x3FFF_FFFF;
+
+// File characteristics (in a FID)
+pub const FID_DELETED: u8 = 0x04;
+pub const FID_PARENT: u8 = 0x08;
+
+// Partition map types
+pub const PMAP_TYPE1: u8 = 1;
+pub const PMAP_TYPE2: u8 = 2;
+
+/// Domain identifier for UDF volumes
+pub const DOMAIN_UDF: &'static [u8] = b"*OSTA UDF Compliant";
+pub const PMAP_SPARABLE: &'static [u8] = b"*UDF Sparable Partition";
+pub const PMAP_METADATA: &'static [u8] = b"*UDF Metadata Partition";
+pub const PMAP_VIRTUAL: &'static [u8] = b"*UDF Virtual Partition";
+pub const SPARING_TABLE: &'static [u8] = b"*UDF Sparing Table";
+
+/// Descriptor tag (the first 16 bytes of all descriptors)
+#[derive(Debug)]
+pub struct Tag
+{
+	pub ident: u16,
+	pub crc: u16,
+	pub crc_len: u16,
+	pub location: u32,
+}
+impl Tag
+{
+	/// Parse a tag, returning `None` if the checksum is invalid
+	pub fn read(src: &[u8]) -> Option<Tag> {
+		if src.len() < 16 {
+			return None;
+		}
+		let checksum = src[..16].iter().enumerate()
+			.filter(|&(i,_)| i != 4)
+			.fold(0u8, |s, (_,&b)| s.wrapping_add(b));
+		if checksum != src[4] {
+			return None;
+		}
+		Some(Tag {
+			ident: LittleEndian::read_u16(&src[0..]),
+			crc: LittleEndian::read_u16(&src[8..]),
+			crc_len: LittleEndian::read_u16(&src[10..]),
+			location: LittleEndian::read_u32(&src[12..]),
+			})
+	}
+	/// Parse and validate a tag (checksum, CRC, identifier and location) of a whole descriptor
+	pub fn read_checked(src: &[u8], ident: u16, location: u32) -> Option<Tag> {
+		match Tag::read(src)
+		{
+		Some(ref t) if t.ident != ident => None,
+		Some(ref t) if t.location != location => {
+			log_notice!("UDF: Descriptor {} at {:#x} has location {:#x}", ident, location, t.location);
+			None
+			},
+		Some(t) => if t.check_crc(src) { Some(t) } else { None },
+		None => None,
+		}
+	}
+	/// Check the descriptor CRC (covers the `crc_len` bytes following the tag)
+	pub fn check_crc(&self, src: &[u8]) -> bool {
+		let len = self.crc_len as usize;
+		if 16 + len > src.len() {
+			log_notice!("UDF: Descriptor {} CRC length {} exceeds the descriptor", self.ident, len);
+			false
+		}
+		else if crc_itu(&src[16..][..len]) != self.crc {
+			log_notice!("UDF: Descriptor {} at {:#x} has a bad CRC", self.ident, self.location);
+			false
+		}
+		else {
+			true
+		}
+	}
+}
+
+/// CRC-ITU-T (CCITT polynomial, zero initial value)
+fn crc_itu(data: &[u8]) -> u16 {
+	let mut crc = 0u16;
+	for &b in data
+	{
+		crc ^= (b as u16) << 8;
+		for _ in 0 .. 8
+		{
+			crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+		}
+	}
+	crc
+}
+
+/// Extent (`extent_ad`, in sectors)
+#[derive(Debug,Copy,Clone)]
+pub struct ExtentAd
+{
+	pub length: u32,
+	pub location: u32,
+}
+impl ExtentAd {
+	pub fn read(src: &[u8]) -> ExtentAd {
+		ExtentAd {
+			length: LittleEndian::read_u32(&src[0..]),
+			location: LittleEndian::read_u32(&src[4..]),
+		}
+	}
+}
+
+/// Logical block address (block within a partition)
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub struct LbAddr
+{
+	pub block: u32,
+	pub partition: u16,
+}
+impl LbAddr {
+	pub fn read(src: &[u8]) -> LbAddr {
+		LbAddr {
+			block: LittleEndian::read_u32(&src[0..]),
+			partition: LittleEndian::read_u16(&src[4..]),
+		}
+	}
+}
+
+/// Long allocation descriptor (`long_ad`)
+#[derive(Debug,Copy,Clone)]
+pub struct LongAd
+{
+	/// Extent length (with the type in the top two bits)
+	pub length: u32,
+	pub location: LbAddr,
+}
+impl LongAd {
+	pub const SIZE: usize = 16;
+	pub fn read(src: &[u8]) -> LongAd {
+		LongAd {
+			length: LittleEndian::read_u32(&src[0..]),
+			location: LbAddr::read(&src[4..]),
+		}
+	}
+}
+/// Short allocation descriptor (`short_ad`, block is in the same partition as the descriptor)
+#[derive(Debug,Copy,Clone)]
+pub struct ShortAd
+{
+	pub length: u32,
+	pub position: u32,
+}
+impl ShortAd {
+	pub const SIZE: usize = 8;
+	pub fn read(src: &[u8]) -> ShortAd {
+		ShortAd {
+			length: LittleEndian::read_u32(&src[0..]),
+			position: LittleEndian::read_u32(&src[4..]),
+		}
+	}
+}
+
+/// Check if an entity identifier (`regid`) has the given identifier
+pub fn regid_is(regid: &[u8], ident: &[u8]) -> bool {
+	// - Byte 0 is flags, followed by 23 bytes of NUL-padded identifier
+	let id = &regid[1..24];
+	id.starts_with(ident) && id[ident.len()..].iter().all(|&b| b == 0)
+}
+/// Get the UDF revision (BCD) from a domain identifier
+pub fn regid_udf_revision(regid: &[u8]) -> u16 {
+	LittleEndian::read_u16(&regid[24..])
+}
+
+/// Anchor volume descriptor pointer
+pub struct Avdp
+{
+	pub main_vds: ExtentAd,
+	pub reserve_vds: ExtentAd,
+}
+impl Avdp {
+	pub fn read(src: &[u8]) -> Avdp {
+		Avdp {
+			main_vds: ExtentAd::read(&src[16..]),
+			reserve_vds: ExtentAd::read(&src[24..]),
+		}
+	}
+}
+
+/// Partition descriptor
+pub struct PartitionDesc
+{
+	pub vds_seq: u32,
+	pub number: u16,
+	pub start: u32,
+	pub length: u32,
+}
+impl PartitionDesc {
+	pub fn read(src: &[u8]) -> PartitionDesc {
+		PartitionDesc {
+			vds_seq: LittleEndian::read_u32(&src[16..]),
+			number: LittleEndian::read_u16(&src[22..]),
+			start: LittleEndian::read_u32(&src[188..]),
+			length: LittleEndian::read_u32(&src[192..]),
+		}
+	}
+}
+
+/// Logical volume descriptor (the fixed part, partition maps follow at `LVD_MAPS_OFS`)
+pub struct LogicalVolumeDesc
+{
+	pub vds_seq: u32,
+	pub block_size: u32,
+	pub udf_revision: u16,
+	pub is_udf: bool,
+	/// Location of the file set descriptor
+	pub fsd: LongAd,
+	pub map_table_len: u32,
+	pub num_maps: u32,
+}
+pub const LVD_MAPS_OFS: usize = 440;
+impl LogicalVolumeDesc {
+	pub fn read(src: &[u8]) -> LogicalVolumeDesc {
+		LogicalVolumeDesc {
+			vds_seq: LittleEndian::read_u32(&src[16..]),
+			block_size: LittleEndian::read_u32(&src[212..]),
+			udf_revision: regid_udf_revision(&src[216..]),
+			is_udf: regid_is(&src[216..], DOMAIN_UDF),
+			fsd: LongAd::read(&src[248..]),
+			map_table_len: LittleEndian::read_u32(&src[264..]),
+			num_maps: LittleEndian::read_u32(&src[268..]),
+		}
+	}
+}
+
+/// Decoded timestamp (ECMA-167 1/7.3)
+pub fn timestamp(src: &[u8]) -> ::kernel::time::Timestamp {
+	let type_tz = LittleEndian::read_u16(&src[0..]);
+	let year = LittleEndian::read_i16(&src[2..]);
+	let (month, day, hour, minute, second) = (src[4], src[5], src[6], src[7], src[8]);
+	if month == 0 || day == 0 {
+		// Unset
+		return 0;
+	}
+	let ts = ::kernel::time::timestamp_from_date(year as i32, month, day, hour, minute, second);
+	// Type 1 is local time, with the offset from UTC in minutes (a 12-bit signed value, -2047 meaning unspecified)
+	let tz = ((type_tz << 4) as i16) >> 4;
+	if type_tz >> 12 == 1 && tz != -2047 {
+		ts - tz as i64 * 60
+	}
+	else {
+		ts
+	}
+}
+
+/// Decode a d-string/d-characters field (compressed unicode, OSTA CS0) into UTF-8
+///
+/// Returns `None` if the encoding is unknown or invalid.
+pub fn decode_dchars(src: &[u8]) -> Option<Vec<u8>> {
+	if src.len() == 0 {
+		return Some(Vec::new());
+	}
+	match src[0]
+	{
+	// 8-bit (values are the code point), 254 is the same but for deleted names
+	8 | 254 => {
+		let mut rv = Vec::with_capacity(src.len() - 1);
+		for &b in &src[1..]
+		{
+			let mut buf = [0; 4];
+			rv.extend_from_slice( (b as char).encode_utf8(&mut buf).as_bytes() );
+		}
+		Some(rv)
+		},
+	// 16-bit (big endian UCS-2)
+	16 | 255 => {
+		let units: Vec<u16> = (0 .. (src.len() - 1) / 2).map(|i| BigEndian::read_u16(&src[1 + i*2..])).collect();
+		Str16::new(&units).map(|s| s.wtf8().collect())
+		},
+	_ => None,
+	}
+}
+
+/// Convert UDF permissions (and ICB flags) into UNIX permission bits
+pub fn unix_permissions(perms: u32, icb_flags: u16) -> u16 {
+	let mut rv = 0;
+	// Other, group and owner each have five bits: execute, write, read, change attributes, delete
+	for class in 0 .. 3
+	{
+		let bits = perms >> (class * 5);
+		let mut v = 0;
+		if bits & 1 != 0 { v |= 0o1; }
+		if bits & 2 != 0 { v |= 0o2; }
+		if bits & 4 != 0 { v |= 0o4; }
+		rv |= v << (class * 3);
+	}
+	if icb_flags & ICB_FLAG_SETUID != 0 { rv |= 0o4000; }
+	if icb_flags & ICB_FLAG_SETGID != 0 { rv |= 0o2000; }
+	if icb_flags & ICB_FLAG_STICKY != 0 { rv |= 0o1000; }
+	rv
+}
+
+/// File identifier descriptor (directory entry)
+pub struct Fid<'a>
+{
+	pub characteristics: u8,
+	pub icb: LongAd,
+	/// Raw (compressed unicode) identifier
+	pub name: &'a [u8],
+	/// Total (padded) size of the descriptor
+	pub size: usize,
+}
+impl<'a> Fid<'a> {
+	/// Fixed size of a FID (before the implementation use and identifier)
+	pub const HDR_SIZE: usize = 38;
+
+	/// Parse a FID from the start of `src`, returns `None` if it's invalid
+	pub fn read(src: &'a [u8]) -> Option<Fid<'a>> {
+		if src.len() < Self::HDR_SIZE {
+			return None;
+		}
+		match Tag::read(src)
+		{
+		Some(ref t) if t.ident == TAG_FID => {},
+		_ => return None,
+		}
+		let name_len = src[19] as usize;
+		let iu_len = LittleEndian::read_u16(&src[36..]) as usize;
+		let size = (Self::HDR_SIZE + iu_len + name_len + 3) & !3;
+		if Self::HDR_SIZE + iu_len + name_len > src.len() {
+			return None;
+		}
+		Some(Fid {
+			characteristics: src[18],
+			icb: LongAd::read(&src[20..]),
+			name: &src[Self::HDR_SIZE + iu_len ..][.. name_len],
+			size: size,
+			})
+	}
+}
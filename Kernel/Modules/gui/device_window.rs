@@ -0,0 +1,62 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/gui/device_window.rs
+/// Window for raw display device output
+//
+// Pixels written to the display nodes in `/dev/video` are drawn into a maximised window (in its own
+// window group) instead of directly to the framebuffer, so they don't fight the compositor.
+#[allow(unused_imports)]
+use kernel::prelude::*;
+
+use super::windows::{WindowGroupHandle,WindowHandle};
+use super::{Pos,Rect};
+use kernel::sync::mutex::LazyMutex;
+
+struct DeviceWindow
+{
+	_wgh: WindowGroupHandle,
+	wh: WindowHandle,
+}
+
+static S_DEVICE_WINDOW: LazyMutex<DeviceWindow> = lazymutex_init!();
+
+#[doc(hidden)]
+pub fn init()
+{
+	::kernel::metadevs::video::register_devfs_write(write_row);
+}
+
+impl DeviceWindow
+{
+	fn new() -> DeviceWindow
+	{
+		let mut wgh = WindowGroupHandle::alloc("Display device");
+		let mut wh = wgh.create_window("Display device");
+		wh.maximise();
+		wh.show();
+		DeviceWindow {
+			_wgh: wgh,
+			wh: wh,
+		}
+	}
+}
+
+/// Draw a row of pixels written to a display node (`pos` is in the global display space)
+fn write_row(pos: Pos, data: &[u32])
+{
+	let mut lh = S_DEVICE_WINDOW.lock_init(|| DeviceWindow::new());
+	let win_pos = lh.wh.get_pos();
+	let dims = lh.wh.get_dims();
+	// Clip to the window (which only covers the display it was maximised on)
+	if pos.x < win_pos.x || pos.y < win_pos.y {
+		return ;
+	}
+	let (x, y) = (pos.x - win_pos.x, pos.y - win_pos.y);
+	if x >= dims.w || y >= dims.h {
+		return ;
+	}
+	let w = ::core::cmp::min(data.len() as u32, dims.w - x);
+	lh.wh.blit_rect( Rect::new(x, y, w, 1), &data[..w as usize], w as usize );
+	lh.wh.redraw();
+}
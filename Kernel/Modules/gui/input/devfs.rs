@@ -0,0 +1,96 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/gui/input/devfs.rs
+//! Raw event nodes for input devices (`/dev/input/*`)
+//!
+//! Reading a node returns whole `EVENT_SIZE` byte records (the offset is ignored), or zero bytes if
+//! no events are pending. The first byte of a record is one of the `EV_*` values:
+//! - `EV_KEYDOWN`/`EV_KEYUP` - byte 1 is the key code
+//! - `EV_MOUSESET` - bytes 2-3 and 4-5 are the absolute X and Y position (little endian, 0-0xFFFF)
+//! - `EV_MOUSEMOVE` - bytes 2-3 and 4-5 are the signed X and Y deltas (little endian)
+//! - `EV_MOUSEDOWN`/`EV_MOUSEUP` - byte 1 is the button index
+//!
+//! The nodes are a passive view of the device: events are still delivered to the GUI, and new events
+//! are dropped while a node's queue is full.
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::lib::ring_buffer::RingBuf;
+use kernel::sync::Mutex;
+use kernel::vfs::devfs;
+
+/// Size of a single event record
+pub const EVENT_SIZE: usize = 8;
+/// Maximum number of unread events kept for each device
+const QUEUE_LEN: usize = 32;
+
+pub const EV_KEYDOWN: u8 = 0;
+pub const EV_KEYUP: u8 = 1;
+pub const EV_MOUSESET: u8 = 2;
+pub const EV_MOUSEMOVE: u8 = 3;
+pub const EV_MOUSEDOWN: u8 = 4;
+pub const EV_MOUSEUP: u8 = 5;
+
+struct Queue
+{
+	typename: &'static str,
+	events: Mutex<RingBuf<[u8; EVENT_SIZE]>>,
+}
+
+/// Node registered with devfs
+struct Node(Arc<Queue>);
+impl devfs::Device for Node
+{
+	fn typename(&self) -> &str {
+		self.0.typename
+	}
+	fn read(&self, _ofs: u64, buf: &mut [u8]) -> ::kernel::vfs::Result<usize> {
+		let mut lh = self.0.events.lock();
+		let mut count = 0;
+		for dst in buf.chunks_mut(EVENT_SIZE)
+		{
+			if dst.len() < EVENT_SIZE {
+				break;
+			}
+			match lh.pop_front()
+			{
+			Some(ev) => dst.clone_from_slice(&ev),
+			None => break,
+			}
+			count += EVENT_SIZE;
+		}
+		Ok(count)
+	}
+}
+
+/// Handle to an input device's node, removes the node when dropped
+pub struct Handle
+{
+	queue: Arc<Queue>,
+	_reg: devfs::Registration,
+}
+impl Handle
+{
+	pub fn new(typename: &'static str, name: &str) -> Handle {
+		let queue = Arc::new(Queue {
+			typename: typename,
+			events: Mutex::new(RingBuf::new(QUEUE_LEN)),
+			});
+		Handle {
+			_reg: devfs::register(devfs::DeviceClass::Input, name, Box::new(Node(queue.clone()))),
+			queue: queue,
+		}
+	}
+
+	/// Queue an event with the specified type, byte argument and pair of 16-bit values
+	pub fn push(&self, ev: u8, arg: u8, a: u16, b: u16) {
+		let rec = [ev, arg, a as u8, (a >> 8) as u8, b as u8, (b >> 8) as u8, 0, 0];
+		// Dropped if nothing is reading the node
+		let _ = self.queue.events.lock().push_back(rec);
+	}
+}
+impl_fmt! {
+	Debug(self, f) for Handle {
+		write!(f, "input::devfs::Handle({})", self.queue.typename)
+	}
+}
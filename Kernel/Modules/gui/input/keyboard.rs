@@ -3,22 +3,34 @@
 //
 // Core/gui/input/keyboard.rs
 //! GUI Keyboard Arbitration
+use kernel::prelude::*;
 
 #[derive(Default,Debug)]
-pub struct Instance(usize);
+pub struct Instance(usize, Option<super::devfs::Handle>);
 
 impl Instance
 {
 	pub fn new() -> Instance {
-		Instance(1)
+		use core::sync::atomic::{AtomicUsize,Ordering};
+		static S_INDEX: AtomicUsize = AtomicUsize::new(0);
+		let name = format!("keyboard{}", S_INDEX.fetch_add(1, Ordering::Relaxed));
+		Instance(1, Some( super::devfs::Handle::new("keyboard", &name) ))
 	}
 	
 	pub fn press_key(&self, key: KeyCode) {
+		self.devfs_event(super::devfs::EV_KEYDOWN, key);
 		super::get_channel_by_index(0).handle_key(key, false);
 	}
 	pub fn release_key(&self, key: KeyCode) {
+		self.devfs_event(super::devfs::EV_KEYUP, key);
 		super::get_channel_by_index(0).handle_key(key, true);
 	}
+
+	fn devfs_event(&self, ev: u8, key: KeyCode) {
+		if let Some(ref h) = self.1 {
+			h.push(ev, key as u8, 0, 0);
+		}
+	}
 }
 
 include!("../../../../keycodes.inc.rs");
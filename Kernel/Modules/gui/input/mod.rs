@@ -12,6 +12,7 @@ use kernel::sync::Mutex;
 
 pub mod keyboard;
 pub mod mouse;
+pub mod devfs;
 
 #[derive(Debug)]
 pub enum Event
@@ -3,28 +3,42 @@
 //
 // Core/gui/input/mouse.rs
 //! GUI Mouse Interface
+use kernel::prelude::*;
 
 #[derive(Default,Debug)]
-pub struct Instance(usize);
+pub struct Instance(usize, Option<super::devfs::Handle>);
 
 impl Instance
 {
 	pub fn new() -> Instance {
-		Instance(1)
+		use core::sync::atomic::{AtomicUsize,Ordering};
+		static S_INDEX: AtomicUsize = AtomicUsize::new(0);
+		let name = format!("mouse{}", S_INDEX.fetch_add(1, Ordering::Relaxed));
+		Instance(1, Some( super::devfs::Handle::new("mouse", &name) ))
 	}
 	
 	// Provide an absolute cursor position (between 0 and 0xFFFF)
 	pub fn set_cursor(&self, x: u16, y: u16) {
+		self.devfs_event(super::devfs::EV_MOUSESET, 0, x, y);
 		super::get_channel_by_index(0).handle_mouse_set(x, y);
 	}
 	pub fn move_cursor(&self, dx: i16, dy: i16) {
+		self.devfs_event(super::devfs::EV_MOUSEMOVE, 0, dx as u16, dy as u16);
 		super::get_channel_by_index(0).handle_mouse_move(dx, dy);
 	}
 	pub fn press_button(&self, btn: u8) {
+		self.devfs_event(super::devfs::EV_MOUSEDOWN, btn, 0, 0);
 		super::get_channel_by_index(0).handle_mouse_btn(btn, false);
 	}
 	pub fn release_button(&self, btn: u8) {
+		self.devfs_event(super::devfs::EV_MOUSEUP, btn, 0, 0);
 		super::get_channel_by_index(0).handle_mouse_btn(btn, true);
 	}
+
+	fn devfs_event(&self, ev: u8, arg: u8, a: u16, b: u16) {
+		if let Some(ref h) = self.1 {
+			h.push(ev, arg, a, b);
+		}
+	}
 }
 
@@ -33,6 +33,7 @@ fn init()
 	// - Create kernel logging screen+window
 	windows::init();
 	kernel_log::init();
+	device_window::init();
 }
 
 fn display_geom_update(new_total: ::kernel::metadevs::video::Rect)
@@ -46,6 +47,8 @@ fn display_geom_update(new_total: ::kernel::metadevs::video::Rect)
 mod windows;
 /// Kernel log display
 mod kernel_log;
+/// Display device output
+mod device_window;
 
 pub mod input;
 
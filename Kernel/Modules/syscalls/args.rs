@@ -32,6 +32,7 @@ unsafe impl Pod for u32 {}
 unsafe impl Pod for ::values::WaitItem {}
 unsafe impl Pod for ::values::GuiEvent {}	// Kinda lies, but meh
 unsafe impl Pod for ::values::RpcMessage {}
+unsafe impl Pod for ::values::VFSNodeInfo {}
 
 
 #[cfg(feature="native")]
@@ -98,6 +99,7 @@ impl<T: Pod> SyscallArg for FreezeMut<T>
 		}
 		let ptr = args[0] as *mut T;
 		let blen = ::core::mem::size_of::<T>();
+		*args = &args[1..];
 
 		// SAFE: Performs data validation, and only accepts user pointers (which are checkable)
 		unsafe { 
@@ -350,6 +350,38 @@ pub fn give_object(target: &::kernel::threads::ProcessHandle, tag: &str, handle:
 	Ok( () )
 }
 
+/// Use another of the process's objects (e.g. one passed as a syscall argument)
+///
+/// The object is cloned out of its slot, and the slot released before `fcn` is called. The caller already holds
+/// its own object's slot, so this doesn't wait if the slot is being modified (i.e. the object is being released),
+/// as a writer waiting on the caller's slot would deadlock.
+pub fn with_object_ref<T: Object+Clone+'static, O, F>(handle: u32, fcn: F) -> Result<O,super::Error>
+where
+	F: FnOnce(&T) -> Result<O,super::Error>
+{
+	let obj: T = {
+		let objs = get_process_local::<ProcessObjects>();
+		let lh = match objs.get(handle).and_then(|h| h.try_read())
+			{
+			Some(v) => v,
+			None => return Err( super::Error::NoSuchObject(handle) ),
+			};
+		match *lh
+		{
+		Some(ref obj) => match obj.data.as_any().downcast_ref::<T>()
+			{
+			Some(v) => v.clone(),
+			None => {
+				log_notice!("with_object_ref - Object #{} is {}, not {}", handle, obj.data.type_name(), type_name!(T));
+				return Err( super::Error::BadValue );
+				},
+			},
+		None => return Err( super::Error::NoSuchObject(handle) ),
+		}
+		};
+	fcn(&obj)
+}
+
 pub fn take_object<T: Object+'static>(handle: u32) -> Result<T,super::Error> {
 	let obj = try!(get_process_local::<ProcessObjects>().take_object(handle));
 	// SAFE: ptr::read is called on a pointer to a value that is subsequently forgotten
@@ -46,6 +46,8 @@ impl ::objects::Object for CurProcess
 
 #[inline(never)]
 pub fn exit(status: u32) {
+	// File mappings must be written back while the process's address space is still active
+	::vfs::release_mappings();
 	::kernel::threads::exit_process(status);
 }
 #[inline(never)]
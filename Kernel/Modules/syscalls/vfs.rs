@@ -37,8 +37,23 @@ impl_from! {
 		Error::PermissionDenied => VFSError::PermissionDenied,
 		Error::Locked => VFSError::FileLocked,
 		Error::MalformedPath => VFSError::MalformedPath,
-		Error::Unknown(reason) => todo!("VFS Error Unknown - '{}'", reason),
-		_ => todo!("VFS Error - {:?}", v),
+		Error::ReadOnlyFilesystem => VFSError::ReadOnlyFilesystem,
+		Error::OutOfSpace => VFSError::OutOfSpace,
+		Error::InvalidParameter => VFSError::InvalidParameter,
+		Error::BlockIoError(_) => VFSError::IoError,
+		Error::CrossFilesystem => VFSError::CrossFilesystem,
+		Error::AlreadyExists => VFSError::AlreadyExists,
+		Error::RecursionDepthExceeded => VFSError::SymlinkLoop,
+		Error::DirectoryNotEmpty => VFSError::DirectoryNotEmpty,
+		Error::FileTooLarge => VFSError::FileTooLarge,
+		Error::NonDirComponent => VFSError::NotADirectory,
+		Error::InconsistentFilesystem => VFSError::InconsistentFilesystem,
+		Error::OutOfMemory => VFSError::OutOfMemory,
+		Error::TransientError => VFSError::TransientError,
+		Error::Unknown(reason) => {
+			log_notice!("VFS error - Unknown '{}'", reason);
+			VFSError::Unknown
+			},
 		}
 	}}
 	From<node::NodeClass>(v) for ::values::VFSNodeType {
@@ -73,6 +88,11 @@ fn to_result<T>(r: Result<T, ::kernel::vfs::Error>) -> Result<T, u32> {
 	r.map_err( |e| Into::into( <::values::VFSError as From<_>>::from(e) ) )
 }
 
+/// Limit an IO buffer length so the returned count fits in a syscall result (which must be below 2^31)
+fn max_io_len(len: usize) -> usize {
+	::core::cmp::min(len, i32::MAX as usize)
+}
+
 pub fn init_handles(init_handle: ::kernel::vfs::handle::File) {
 	// #1: Read-only root
 	::objects::push_as_unclaimed("ro:/", ::objects::new_object(Dir::new( {
@@ -100,7 +120,7 @@ impl objects::Object for Node
 	fn try_clone(&self) -> Option<u32> {
 		Some( ::objects::new_object( Node(self.0.clone()) ) )
 	}
-	fn handle_syscall_ref(&self, call: u16, _args: &mut Args) -> Result<u64,Error> {
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
 		match call
 		{
 		values::VFS_NODE_GETTYPE => {
@@ -108,6 +128,27 @@ impl objects::Object for Node
 			let v32: u32 = ::values::VFSNodeType::from( self.0.get_class() ).into();
 			Ok( v32 as u64 )
 			},
+		values::VFS_NODE_GETINFO => {
+			let mut info: FreezeMut<::values::VFSNodeInfo> = try!(args.get());
+			log_debug!("VFS_NODE_GETINFO({:p})", &*info);
+			Ok( super::from_result(to_result(self.0.get_metadata()).map(|md| {
+				let ty: u32 = ::values::VFSNodeType::from( self.0.get_class() ).into();
+				*info = ::values::VFSNodeInfo {
+					size: md.size,
+					inode: self.0.get_inode(),
+					ctime: md.ctime,
+					mtime: md.mtime,
+					atime: md.atime,
+					uid: md.uid,
+					gid: md.gid,
+					link_count: md.link_count,
+					permissions: md.permissions,
+					node_type: ty as u8,
+					_pad: 0,
+					};
+				0u32
+				})) )
+			},
 		_ => ::objects::object_has_no_such_method_ref("vfs::Node", call),
 		}
 	}
@@ -141,6 +182,11 @@ impl objects::Object for Node
 				.map( |h| objects::new_object(Link(h)) );
 			Ok(super::from_result( objres ))
 			},
+		values::VFS_NODE_TOSPECIAL => {
+			let objres = to_result(inner.to_special())
+				.map( |h| objects::new_object(Special(h)) );
+			Ok(super::from_result( objres ))
+			},
 		_ => ::objects::object_has_no_such_method_val("vfs::Node", call),
 		}
 	}
@@ -181,11 +227,8 @@ impl objects::Object for File
 			let ofs: u64 = try!(args.get());
 			let src: Freeze<[u8]> = try!(args.get());
 			log_debug!("File::writeat({}, {:p}+{} bytes)", ofs, src.as_ptr(), src.len());
-			match self.0.write(ofs, &src)
-			{
-			Ok(count) => Ok(count as u64),
-			Err(e) => todo!("File::handle_syscall WRITEAT Error {:?}", e),
-			}
+			let len = max_io_len(src.len());
+			Ok( super::from_result( to_result(self.0.write(ofs, &src[..len])).map(|count| count as u32) ) )
 			},
 		values::VFS_FILE_MEMMAP => {
 			let ofs: u64 = try!(args.get());
@@ -207,14 +250,28 @@ impl objects::Object for File
 			match self.0.memory_map(addr, ofs, size, mode)
 			{
 			Ok(h) => {
-				// TODO: I would like the map handle to be avaliable, but I'd like the user to be able to "forget" it
-				// (so it becomes an indelible part of the address space).
-				// - That would likely need a new system call similar to Drop
-				// - XXX: The handle here has borrow of the file handle, so can't be stored as-is
-				::core::mem::forget(h);
+				// TODO: There's no unmap call, so the mapping lasts as long as the process
+				::kernel::threads::get_process_local::<ProcessMappings>().0.lock().push(h);
 				Ok(0)
 				},
-			Err(e) => todo!("File::handle_syscall MEMMAP Error {:?}", e),
+			Err(e) => Ok( super::from_result::<u32,_>(to_result(Err(e))) ),
+			}
+			},
+		values::VFS_FILE_MEMSYNC => {
+			let ofs: u64 = try!(args.get());
+			let data: Freeze<[u8]> = try!(args.get());
+			log_debug!("VFS_FILE_MEMSYNC({:#x}, {:p}+{:#x})", ofs, data.as_ptr(), data.len());
+			let addr = data.as_ptr() as usize;
+			let maps = ::kernel::threads::get_process_local::<ProcessMappings>();
+			let lh = maps.0.lock();
+			// The buffer must be (part of) a `WriteBack` mapping of this file, at the matching offset
+			match lh.iter().find(|m| m.is_writeback_of(&self.0) && m.file_offset(addr, data.len()) == Some(ofs))
+			{
+			Some(m) => Ok( super::from_result( to_result(m.sync_range(addr, data.len())).map(|_| 0u32) ) ),
+			None => {
+				log_log!("VFS_FILE_MEMSYNC - {:p}+{:#x} isn't a write-back mapping of offset {:#x}", data.as_ptr(), data.len(), ofs);
+				Err( Error::BadValue )
+				},
 			}
 			},
 		_ => ::objects::object_has_no_such_method_ref("vfs::File", call),
@@ -224,6 +281,29 @@ impl objects::Object for File
 	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
 }
 
+/// Memory mappings created by the current process (using `VFS_FILE_MEMMAP`)
+#[derive(Default)]
+struct ProcessMappings(::kernel::sync::Mutex<Vec<handle::MemoryMapHandle>>);
+impl ::core::ops::Drop for ProcessMappings
+{
+	fn drop(&mut self) {
+		// Dropped with the process, after its address space is gone (so the mappings can't be written back)
+		let maps = ::core::mem::replace(self.0.get_mut(), Vec::new());
+		if maps.len() > 0 {
+			log_notice!("{} memory mappings discarded with the process", maps.len());
+		}
+		for m in maps {
+			m.abandon();
+		}
+	}
+}
+
+/// Write back and unmap all of the current process's file mappings (called on exit)
+pub fn release_mappings() {
+	let maps = ::core::mem::replace(&mut *::kernel::threads::get_process_local::<ProcessMappings>().0.lock(), Vec::new());
+	drop(maps);
+}
+
 #[cfg(feature="native")]
 /// Used by the native "kernel" to get a file object for `new_process`
 pub fn get_file_handle(obj: u32) -> Result<::kernel::vfs::handle::File, crate::Error> {
@@ -236,6 +316,7 @@ pub fn get_file_handle(obj: u32) -> Result<::kernel::vfs::handle::File, crate::E
 //
 // --------------------------------------------------------------------
 
+#[derive(Clone)]
 struct Dir {
 	handle: ::kernel::vfs::handle::Dir,
 }
@@ -281,6 +362,25 @@ impl objects::Object for Dir
 		values::VFS_DIR_ENUMERATE => {
 			objects::new_object( DirIter::new( self.handle.clone() ) ) as u64
 			},
+		values::VFS_DIR_RENAME => {
+			let src_name: Freeze<[u8]> = try!(args.get());
+			let dst_dir: u32 = try!(args.get());
+			let dst_name: Freeze<[u8]> = try!(args.get());
+
+			let src_name = ::kernel::lib::byte_str::ByteStr::new(&*src_name);
+			let dst_name = ::kernel::lib::byte_str::ByteStr::new(&*dst_name);
+			log_debug!("VFS_DIR_RENAME({:?}, #{} {:?})", src_name, dst_dir, dst_name);
+			try!(objects::with_object_ref(dst_dir, |dst: &Dir| {
+				Ok( super::from_result( to_result(self.handle.rename(src_name, &dst.handle, dst_name)).map(|_| 0u32) ) )
+				}))
+			},
+		values::VFS_DIR_WATCH => {
+			log_debug!("VFS_DIR_WATCH()");
+			super::from_result(
+				to_result( self.handle.watch() )
+					.map( |w| objects::new_object(Watch(w)) )
+				)
+			},
 		_ => return ::objects::object_has_no_such_method_ref("vfs::Dir", call),
 		})
 	}
@@ -435,6 +535,112 @@ impl objects::Object for Link
 }
 
 
+// --------------------------------------------------------------------
+//
+// --------------------------------------------------------------------
+
+struct Special(handle::Special);
+impl objects::Object for Special
+{
+	fn class(&self) -> u16 { values::CLASS_VFS_SPECIAL }
+	fn as_any(&self) -> &dyn Any { self }
+	fn try_clone(&self) -> Option<u32> {
+		Some( ::objects::new_object( Special(self.0.clone()) ) )
+	}
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
+		match call
+		{
+		values::VFS_SPECIAL_GETTYPE => {
+			let mut buf: FreezeMut<[u8]> = try!(args.get());
+			log_debug!("VFS_SPECIAL_GETTYPE({:p}+{})", buf.as_ptr(), buf.len());
+			// Returns the full length of the name (which may be longer than the buffer)
+			let res = to_result( self.0.typename() )
+				.map(|name| {
+					let len = ::core::cmp::min(buf.len(), name.len());
+					buf[..len].clone_from_slice(&name.as_bytes()[..len]);
+					name.len() as u32
+					});
+			Ok( super::from_result(res) )
+			},
+		values::VFS_SPECIAL_READAT => {
+			let ofs: u64 = try!(args.get());
+			let mut dest: FreezeMut<[u8]> = try!(args.get());
+			log_debug!("VFS_SPECIAL_READAT({}, {:p}+{} bytes)", ofs, dest.as_ptr(), dest.len());
+			let len = max_io_len(dest.len());
+			Ok( super::from_result( to_result(self.0.read(ofs, &mut dest[..len])).map(|count| count as u32) ) )
+			},
+		values::VFS_SPECIAL_WRITEAT => {
+			let ofs: u64 = try!(args.get());
+			let src: Freeze<[u8]> = try!(args.get());
+			log_debug!("VFS_SPECIAL_WRITEAT({}, {:p}+{} bytes)", ofs, src.as_ptr(), src.len());
+			let len = max_io_len(src.len());
+			Ok( super::from_result( to_result(self.0.write(ofs, &src[..len])).map(|count| count as u32) ) )
+			},
+		values::VFS_SPECIAL_CONTROL => {
+			let code: u32 = try!(args.get());
+			let mut data: FreezeMut<[u8]> = try!(args.get());
+			log_debug!("VFS_SPECIAL_CONTROL({}, {:p}+{} bytes)", code, data.as_ptr(), data.len());
+			let len = max_io_len(data.len());
+			Ok( super::from_result( to_result(self.0.control(code, &mut data[..len])).map(|count| count as u32) ) )
+			},
+		_ => ::objects::object_has_no_such_method_ref("vfs::Special", call),
+		}
+	}
+	fn bind_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
+	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
+}
+
+// --------------------------------------------------------------------
+//
+// --------------------------------------------------------------------
+
+// Watch event bits are passed to userland unchanged, so the kernel's values must match the syscall ABI's
+// (fails to compile if they don't)
+const _WATCH_EV_CHECK: [(); 1] = [(); (
+	values::VFS_WATCH_EV_CREATE == ::kernel::vfs::watch::EV_CREATE
+	&& values::VFS_WATCH_EV_UNLINK == ::kernel::vfs::watch::EV_UNLINK
+	&& values::VFS_WATCH_EV_RENAME == ::kernel::vfs::watch::EV_RENAME
+	&& values::VFS_WATCH_EV_WRITE == ::kernel::vfs::watch::EV_WRITE
+	) as usize];
+struct Watch(::kernel::vfs::watch::Watch);
+impl objects::Object for Watch
+{
+	fn class(&self) -> u16 { values::CLASS_VFS_WATCH }
+	fn as_any(&self) -> &dyn Any { self }
+	fn try_clone(&self) -> Option<u32> {
+		None
+	}
+	fn handle_syscall_ref(&self, call: u16, _args: &mut Args) -> Result<u64,Error> {
+		match call
+		{
+		values::VFS_WATCH_TAKEEVENTS => {
+			let ev = self.0.take_events();
+			log_debug!("VFS_WATCH_TAKEEVENTS() = {:#x}", ev);
+			Ok( ev as u64 )
+			},
+		_ => ::objects::object_has_no_such_method_ref("vfs::Watch", call),
+		}
+	}
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_VFS_WATCH_CHANGED != 0 {
+			self.0.wait_upon(obj);
+			ret |= values::EV_VFS_WATCH_CHANGED;
+		}
+		ret
+	}
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & values::EV_VFS_WATCH_CHANGED != 0 {
+			if self.0.clear_wait(obj) {
+				ret |= values::EV_VFS_WATCH_CHANGED;
+			}
+		}
+		ret
+	}
+}
+
+
 
 // -
 // -
@@ -89,7 +89,14 @@ fn sysinit() -> !
 	}
 	
 	// 1. Mount /system to the specified volume
-	let sysdisk = ::kernel::config::get_string(::kernel::config::Value::SysDisk);
+	// - A bootloader-provided initial ramdisk is used if present (unless a volume was explicitly requested),
+	//   so userland can start before the disk drivers have found the system volume.
+	let sysdisk = if !::kernel::config::is_set(::kernel::config::Value::SysDisk) && ::kernel::metadevs::storage::enum_lvs().iter().any(|&(_,ref n)| n == INITRD_VOLUME) {
+			INITRD_VOLUME
+		}
+		else {
+			::kernel::config::get_string(::kernel::config::Value::SysDisk)
+		};
 	match VolumeHandle::open_named(sysdisk)
 	{
 	Err(e) => {
@@ -122,6 +129,9 @@ fn sysinit() -> !
 	}
 }
 
+/// Logical volume name of the first bootloader-provided module (see `kernel::hw::initrd`)
+const INITRD_VOLUME: &str = "initrd0w";
+
 fn automount()
 {
 	use kernel::metadevs::storage::VolumeHandle;
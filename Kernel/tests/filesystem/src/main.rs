@@ -14,6 +14,7 @@ fn main()
 
     (::fs_fat::S_MODULE.init)();
     (::fs_extN::S_MODULE.init)();
+    (::fs_exfat::S_MODULE.init)();
     
     // 1. Load disks (physical volumes)
     let disks: [(&str, &::std::path::Path); 1] = [
@@ -38,7 +38,7 @@ impl mount::Driver for NativeFsDriver
 	fn detect(&self, vol: &VolumeHandle) -> vfs::Result<usize> {
         Ok(0)
     }
-	fn mount(&self, vol: VolumeHandle, handle: mount::SelfHandle) -> vfs::Result<Box<dyn mount::Filesystem>> {
+	fn mount(&self, vol: VolumeHandle, handle: mount::SelfHandle, _options: &[&str]) -> vfs::Result<Box<dyn mount::Filesystem>> {
         let root_path: PathBuf = ".native_fs".into();
         let mut rv = NativeFs::default();
         rv.inner.get_mut().unwrap().inodes.insert(0, Box::new(EntData::Dir(DirData{ path: root_path })));
@@ -152,6 +152,40 @@ impl vfs::node::Dir for DirNodeRef
 	fn unlink(&self, name: &ByteStr) -> vfs::node::Result<()> {
         todo!("unlink")
     }
+	/// Atomically move an entry to another directory
+	fn rename(&self, src_name: &ByteStr, dst_dir: &dyn vfs::node::Dir, dst_name: &ByteStr) -> vfs::node::Result<()> {
+		let dst_dir = match dst_dir.get_any().downcast_ref::<DirNodeRef>()
+			{
+			Some(v) if &*v.0 as *const NativeFs == &*self.0 as *const NativeFs => v,
+			_ => return Err(vfs::Error::CrossFilesystem),
+			};
+		let src_name = std::str::from_utf8(src_name.as_bytes()).map_err(|_| vfs::Error::MalformedPath)?;
+		let dst_name = std::str::from_utf8(dst_name.as_bytes()).map_err(|_| vfs::Error::MalformedPath)?;
+		let src_path = self.0.get_dir(self.1).path.join(src_name);
+		let dst_path = dst_dir.0.get_dir(dst_dir.1).path.join(dst_name);
+		if dst_path.exists() {
+			return Err(vfs::Error::AlreadyExists);
+		}
+		::std::fs::rename(&src_path, &dst_path).map_err(map_err)?;
+
+		// Nodes are identified by path, so move any at or below the renamed entry
+		let mut lh = self.0.inner.lock().unwrap();
+		for ent in lh.inodes.values_mut()
+		{
+			let path = match **ent
+				{
+				EntData::Dir(ref mut d) => &mut d.path,
+				EntData::File(ref mut f) => &mut f.path,
+				};
+			let new_path = match path.strip_prefix(&src_path)
+				{
+				Ok(rest) => dst_path.join(rest),
+				Err(_) => continue,
+				};
+			*path = new_path;
+		}
+		Ok( () )
+	}
 }
 
 #[derive(Clone)]
@@ -197,5 +231,15 @@ impl vfs::node::File for FileNodeRef
 }
 
 fn map_err(e: ::std::io::Error) -> vfs::Error {
-    todo!("Transform error {:?}", e)
+	match e.kind()
+	{
+	::std::io::ErrorKind::NotFound => vfs::Error::NotFound,
+	::std::io::ErrorKind::PermissionDenied => vfs::Error::PermissionDenied,
+	::std::io::ErrorKind::AlreadyExists => vfs::Error::AlreadyExists,
+	::std::io::ErrorKind::InvalidInput => vfs::Error::InvalidParameter,
+	_ => {
+		log_notice!("Unhandled native IO error: {:?}", e);
+		vfs::Error::Unknown("Native IO error")
+		},
+	}
 }
\ No newline at end of file
@@ -7,6 +7,7 @@ use listview::ListView;
 use std::cell::RefCell;
 use std::fs::Path;
 use wtk::WindowTrait;
+use syscalls::Object;
 
 pub struct FileList<'a>
 {
@@ -15,6 +16,8 @@ pub struct FileList<'a>
 	on_chdir: Box<dyn Fn(&mut dyn WindowTrait, &Path) + 'a>,
 
 	cur_paths: RefCell<Vec<OsString>>,
+	/// Currently displayed directory, and a watch on it (if the directory supports watching)
+	cur_dir: RefCell<Option<(::syscalls::vfs::Dir, Option<::syscalls::vfs::Watch>)>>,
 	
 	list: ListView<[&'static str; 2], FileEnt>,
 }
@@ -28,6 +31,7 @@ impl<'a> FileList<'a>
 			on_open: Box::new(|_,_,_|()),
 			on_chdir: Box::new(|_,_|()),
 			cur_paths: Default::default(),
+			cur_dir: Default::default(),
 			list: ListView::new(["T", "Filename"]),
 		}
 	}
@@ -45,6 +49,34 @@ impl<'a> FileList<'a>
 		{
 			self.list.append_item( FileEnt::new(dir, name) );
 		}
+		*self.cur_dir.borrow_mut() = Some( (dir.clone(), dir.watch().ok()) );
+	}
+
+	/// Wait item that fires when the current directory's entries change (None if not watched)
+	pub fn get_watch_wait(&self) -> Option<::syscalls::WaitItem> {
+		match *self.cur_dir.borrow()
+		{
+		Some( (_, Some(ref w)) ) => Some( w.get_wait(::syscalls::vfs::WatchWaits::new().changed()) ),
+		_ => None,
+		}
+	}
+
+	/// Handle a fired watch wait item, re-populating the list if the directory changed
+	///
+	/// Returns true if the list was re-populated (and needs to be redrawn)
+	pub fn handle_watch_wait(&self, wi: &::syscalls::WaitItem) -> bool {
+		let dir = match *self.cur_dir.borrow()
+			{
+			Some( (ref dir, Some(ref w)) ) => {
+				if !w.check_wait(wi).has_changed() || w.take_events() == 0 {
+					return false;
+				}
+				dir.clone()
+				},
+			_ => return false,
+			};
+		self.populate(&dir);
+		true
 	}
 
 	/// Bind to "Opening" a file (double-click or select+enter)
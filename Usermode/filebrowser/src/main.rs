@@ -8,6 +8,7 @@ extern crate vec_ring;
 #[macro_use(kernel_log)]
 extern crate syscalls;
 extern crate loader;
+extern crate async;
 
 mod listview;
 mod filelist;
@@ -37,7 +38,37 @@ fn main()
 	window.focus(&fl);
 	window.show();
 
-	window.idle_loop();
+	::async::idle_loop(&mut [
+		&mut Browser { window: window, list: &fl },
+		]);
+}
+
+/// Browser window, also refreshed when the displayed directory changes
+struct Browser<'a, 'b: 'a>
+{
+	window: ::wtk::Window<'a, ::wtk::decorator::Standard>,
+	list: &'a ::filelist::FileList<'b>,
+}
+impl<'a, 'b: 'a> ::async::WaitController for Browser<'a, 'b>
+{
+	fn get_count(&self) -> usize {
+		::async::WaitController::get_count(&self.window) + if self.list.get_watch_wait().is_some() { 1 } else { 0 }
+	}
+	fn populate(&self, cb: &mut dyn FnMut(::syscalls::WaitItem)) {
+		::async::WaitController::populate(&self.window, cb);
+		if let Some(wi) = self.list.get_watch_wait() {
+			cb(wi);
+		}
+	}
+	fn handle(&mut self, events: &[::syscalls::WaitItem]) {
+		let n_win = ::async::WaitController::get_count(&self.window);
+		// Check the watch first, as handling window input can change directory (replacing the watch)
+		if events.len() > n_win && self.list.handle_watch_wait(&events[n_win]) {
+			self.window.rerender();
+			self.window.redraw();
+		}
+		::async::WaitController::handle(&mut self.window, &events[..n_win]);
+	}
 }
 
 fn get_app_exe(name: &[u8]) -> Result<::syscalls::vfs::File, ()> {
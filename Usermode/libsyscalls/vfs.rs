@@ -13,11 +13,17 @@ pub struct Dir(super::ObjectHandle);
 pub struct DirIter(::ObjectHandle);
 /// Symbolic link
 pub struct Symlink(super::ObjectHandle);
+/// Special node (e.g. a device in `/dev`)
+pub struct Special(super::ObjectHandle);
+/// Directory change watch
+pub struct Watch(super::ObjectHandle);
 
 pub use ::values::VFSError as Error;
 pub use ::values::VFSNodeType as NodeType;
 pub use ::values::VFSFileOpenMode as FileOpenMode;
 pub use ::values::VFSMemoryMapMode as MemoryMapMode;
+pub use ::values::VFSNodeInfo as NodeInfo;
+pub use ::values::{VFS_WATCH_EV_CREATE, VFS_WATCH_EV_UNLINK, VFS_WATCH_EV_RENAME, VFS_WATCH_EV_WRITE};
 
 pub fn root() -> &'static Dir {
 	use ::core::sync::atomic::{Ordering,AtomicBool};
@@ -52,6 +58,15 @@ impl Node
 		NodeType::try_from( unsafe { self.0.call_0(::values::VFS_NODE_GETTYPE) } as u32 ).expect("Bad VFS Node Type")
 	}
 
+	/// Obtain the node's metadata (size, ownership, permissions, timestamps)
+	#[inline]
+	pub fn get_info(&self) -> Result<NodeInfo,Error> {
+		let mut info = NodeInfo::default();
+		// SAFE: Syscall with a valid output pointer
+		to_result( unsafe { self.0.call_1(::values::VFS_NODE_GETINFO, &mut info as *mut _ as usize) } as usize )
+			.map(|_| info)
+	}
+
 	/// Convert handle to a directory handle
 	#[inline]
 	pub fn into_dir(self) -> Result<Dir,Error> {
@@ -73,6 +88,14 @@ impl Node
 		to_obj( unsafe { self.0.call_0_v(::values::VFS_NODE_TOLINK) } as usize )
 			.map(|h| Symlink(h))
 	}
+
+	/// Convert handle to a special node handle
+	#[inline]
+	pub fn into_special(self) -> Result<Special,Error> {
+		// SAFE: Syscall
+		to_obj( unsafe { self.0.call_0_v(::values::VFS_NODE_TOSPECIAL) } as usize )
+			.map(|h| Special(h))
+	}
 }
 impl ::Object for Node {
 	const CLASS: u16 = ::values::CLASS_VFS_NODE;
@@ -133,6 +156,17 @@ impl File
 		to_result( unsafe { self.0.call_4l(::values::VFS_FILE_MEMMAP, ofs, read_size, mem_addr as usize, mode as u8 as usize) } as usize )
 			.map( |_| () )
 	}
+	/// Write back changes made to a `MemoryMapMode::WriteBack` mapping of this file
+	///
+	/// `data` must lie within a mapping of this file, and `ofs` be the file offset it was mapped from.
+	/// Only modified pages are written, and the file is never extended. Mappings are also written back
+	/// when the process exits.
+	#[inline]
+	pub fn memory_sync(&self, ofs: u64, data: &[u8]) -> Result<(),Error> {
+		// SAFE: Passes valid arguments to MEMSYNC
+		to_result( unsafe { self.0.call_3l(::values::VFS_FILE_MEMSYNC, ofs, data.as_ptr() as usize, data.len()) } as usize )
+			.map( |_| () )
+	}
 }
 impl ::Object for File {
 	const CLASS: u16 = ::values::CLASS_VFS_FILE;
@@ -182,6 +216,28 @@ impl Dir
 		Err(code) => Err( Error::try_from(code).expect("Bad VFS Error") ),
 		}
 	}
+
+	/// Rename (or move) a child of this directory
+	///
+	/// `dst_dir` must be on the same filesystem (can be this directory)
+	#[inline]
+	pub fn rename<P1: ?Sized+AsRef<[u8]>, P2: ?Sized+AsRef<[u8]>>(&self, src_name: &P1, dst_dir: &Dir, dst_name: &P2) -> Result<(), Error> {
+		let src_name = src_name.as_ref();
+		let dst_name = dst_name.as_ref();
+		// SAFE: Syscall (and `dst_dir` is borrowed, so the handle stays valid)
+		to_result(unsafe { self.0.call_5(::values::VFS_DIR_RENAME,
+			src_name.as_ptr() as usize, src_name.len(),
+			(dst_dir.0).0 as usize,
+			dst_name.as_ptr() as usize, dst_name.len()
+			) } as usize)?;
+		Ok( () )
+	}
+
+	/// Watch this directory for changes to its entries
+	pub fn watch(&self) -> Result<Watch, Error> {
+		// SAFE: Syscall
+		Ok( Watch( to_obj( unsafe { self.0.call_0(::values::VFS_DIR_WATCH) } as usize )? ) )
+	}
 }
 impl ::Object for Dir {
 	const CLASS: u16 = ::values::CLASS_VFS_DIR;
@@ -252,3 +308,74 @@ impl ::Object for Symlink {
 
 	type Waits = ();
 }
+
+
+impl Special
+{
+	/// Read the node's type name (e.g. "volume")
+	///
+	/// If the buffer is not long enough, the return value is truncated.
+	#[inline]
+	pub fn type_name<'a>(&self, buf: &'a mut [u8]) -> Result<&'a [u8], Error> {
+		// SAFE: Syscall with correct args
+		let len = to_result( unsafe { self.0.call_2(::values::VFS_SPECIAL_GETTYPE, buf.as_mut_ptr() as usize, buf.len()) } as usize )?;
+		Ok( &buf[ .. ::core::cmp::min(len as usize, buf.len())] )
+	}
+	/// Read from an arbitary location in the node
+	#[inline]
+	pub fn read_at(&self, ofs: u64, data: &mut [u8]) -> Result<usize,Error> {
+		// SAFE: Passes valid arguments to READAT
+		to_result( unsafe { self.0.call_3l(::values::VFS_SPECIAL_READAT, ofs, data.as_mut_ptr() as usize, data.len()) } as usize )
+			.map(|v| v as usize)
+	}
+	/// Write to an arbitary location in the node
+	#[inline]
+	pub fn write_at(&self, ofs: u64, data: &[u8]) -> Result<usize,Error> {
+		// SAFE: Passes valid arguments to WRITEAT
+		to_result( unsafe { self.0.call_3l(::values::VFS_SPECIAL_WRITEAT, ofs, data.as_ptr() as usize, data.len()) } as usize )
+			.map(|v| v as usize)
+	}
+	/// Device-specific control request, `data` is replaced with the response (and the response length returned)
+	#[inline]
+	pub fn control(&self, code: u32, data: &mut [u8]) -> Result<usize,Error> {
+		// SAFE: Passes valid arguments to CONTROL
+		to_result( unsafe { self.0.call_3(::values::VFS_SPECIAL_CONTROL, code as usize, data.as_mut_ptr() as usize, data.len()) } as usize )
+			.map(|v| v as usize)
+	}
+}
+impl ::Object for Special {
+	const CLASS: u16 = ::values::CLASS_VFS_SPECIAL;
+	fn class() -> u16 { Self::CLASS }
+	fn from_handle(handle: ::ObjectHandle) -> Self {
+		Special(handle)
+	}
+	fn into_handle(self) -> ::ObjectHandle { self.0 }
+	fn handle(&self) -> &::ObjectHandle { &self.0 }
+
+	type Waits = ();
+}
+
+
+impl Watch
+{
+	/// Fetch (and clear) the events raised since the last call, a bitmask of `VFS_WATCH_EV_*`
+	#[inline]
+	pub fn take_events(&self) -> u32 {
+		// SAFE: Syscall with no arguments
+		unsafe { self.0.call_0(::values::VFS_WATCH_TAKEEVENTS) as u32 }
+	}
+}
+impl ::Object for Watch {
+	const CLASS: u16 = ::values::CLASS_VFS_WATCH;
+	fn class() -> u16 { Self::CLASS }
+	fn from_handle(handle: ::ObjectHandle) -> Self {
+		Watch(handle)
+	}
+	fn into_handle(self) -> ::ObjectHandle { self.0 }
+	fn handle(&self) -> &::ObjectHandle { &self.0 }
+
+	type Waits = WatchWaits;
+}
+define_waits!{ WatchWaits => (
+	changed:has_changed = ::values::EV_VFS_WATCH_CHANGED,
+)}
@@ -173,6 +173,10 @@ impl<'a, D: 'a + Decorator> Window<'a, D>
 	pub fn rerender(&mut self)  {
 		WindowTrait::rerender(self)
 	}
+	/// Tell the window server to show the current contents (call after `rerender` outside of input handling)
+	pub fn redraw(&self) {
+		self.win.redraw();
+	}
 
 	/// Obtain the states of all "modifier" keys
 	pub fn get_modifiers(&self) -> &ModifierStates {
@@ -202,10 +202,13 @@ def_classes! {
 	/// Opened node
 	=3: CLASS_VFS_NODE = {
 		=0: VFS_NODE_GETTYPE,
+		/// Fill a `VFSNodeInfo` structure with the node's metadata
+		=1: VFS_NODE_GETINFO,
 		--
 		=0: VFS_NODE_TOFILE,
 		=1: VFS_NODE_TODIR,
 		=2: VFS_NODE_TOLINK,
+		=3: VFS_NODE_TOSPECIAL,
 	}|{
 	},
 	/// Opened file
@@ -218,6 +221,8 @@ def_classes! {
 		=2: VFS_FILE_WRITEAT,
 		/// Map part of the file into the current address space
 		=3: VFS_FILE_MEMMAP,
+		/// Write back changes to a `WriteBack` mapping of the file (file offset, mapped region)
+		=4: VFS_FILE_MEMSYNC,
 		--
 	}|{
 	},
@@ -229,6 +234,10 @@ def_classes! {
 		=1: VFS_DIR_OPENCHILD,
 		/// Open a sub-path
 		=2: VFS_DIR_OPENPATH,
+		/// Rename/move a child node (to another directory on the same filesystem)
+		=3: VFS_DIR_RENAME,
+		/// Watch the directory for changes (returns a `CLASS_VFS_WATCH` handle)
+		=4: VFS_DIR_WATCH,
 		--
 	}|{
 	},
@@ -322,6 +331,28 @@ def_classes! {
 	--
 	}|{
 	},
+	/// Opened special node (e.g. a device in `/dev`)
+	=14: CLASS_VFS_SPECIAL = {
+		/// Get the node's type name (e.g. "volume")
+		=0: VFS_SPECIAL_GETTYPE,
+		/// Read data from the specified position
+		=1: VFS_SPECIAL_READAT,
+		/// Write data to the specified position
+		=2: VFS_SPECIAL_WRITEAT,
+		/// Device-specific control operation (code, in/out buffer)
+		=3: VFS_SPECIAL_CONTROL,
+	--
+	}|{
+	},
+	/// Directory change watch
+	=15: CLASS_VFS_WATCH = {
+		/// Fetch and clear the raised events (a bitmask of `VFS_WATCH_EV_*`)
+		=0: VFS_WATCH_TAKEEVENTS,
+	--
+	}|{
+		/// Fires when events have been raised
+		=0: EV_VFS_WATCH_CHANGED,
+	},
 /*
 	/// A registered read/write buffer
 	=12: CLASS_BUFFER = {
@@ -380,6 +411,20 @@ enum_to_from!{ VFSError => u32:
 	PermissionDenied = 2,
 	FileLocked = 3,
 	MalformedPath = 4,
+	ReadOnlyFilesystem = 5,
+	OutOfSpace = 6,
+	InvalidParameter = 7,
+	IoError = 8,
+	CrossFilesystem = 9,
+	AlreadyExists = 10,
+	SymlinkLoop = 11,
+	DirectoryNotEmpty = 12,
+	FileTooLarge = 13,
+	NotADirectory = 14,
+	InconsistentFilesystem = 15,
+	OutOfMemory = 16,
+	TransientError = 17,
+	Unknown = 18,
 }
 enum_to_from!{ VFSNodeType => u32:
 	File = 0,
@@ -387,6 +432,31 @@ enum_to_from!{ VFSNodeType => u32:
 	Symlink = 2,
 	Special = 3,
 }
+/// Node metadata, returned by VFS_NODE_GETINFO
+#[derive(Default,Copy,Clone,Debug)]
+#[repr(C)]
+pub struct VFSNodeInfo
+{
+	/// Size in bytes
+	pub size: u64,
+	/// Inode number (unique within the node's filesystem)
+	pub inode: u64,
+	/// Creation/change time (seconds since 1970-01-01 UTC)
+	pub ctime: i64,
+	/// Modification time
+	pub mtime: i64,
+	/// Access time
+	pub atime: i64,
+	pub uid: u32,
+	pub gid: u32,
+	/// Number of directory entries referencing this node
+	pub link_count: u32,
+	/// UNIX-style permission bits
+	pub permissions: u16,
+	/// Node type (a `VFSNodeType` value)
+	pub node_type: u8,
+	pub _pad: u8,
+}
 enum_to_from!{ VFSFileOpenMode => u8:
 	ReadOnly = 1,
 	Execute  = 2,
@@ -407,6 +477,15 @@ enum_to_from!{ VFSMemoryMapMode => u8:
 	WriteBack = 3,
 }
 
+/// Watch event: An entry was created in the directory
+pub const VFS_WATCH_EV_CREATE: u32 = 1 << 0;
+/// Watch event: An entry was removed from the directory
+pub const VFS_WATCH_EV_UNLINK: u32 = 1 << 1;
+/// Watch event: An entry was renamed, or moved into/out of the directory
+pub const VFS_WATCH_EV_RENAME: u32 = 1 << 2;
+/// Watch event: A file in the directory was written
+pub const VFS_WATCH_EV_WRITE: u32 = 1 << 3;
+
 
 enum_to_from!{ GuiWinFlag => u8:
 	Visible = 0,